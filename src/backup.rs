@@ -0,0 +1,114 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use log::error;
+
+use crate::{
+    data::data_file::get_data_file_name,
+    db::Engine,
+    errors::{Errors, Result},
+    options::IOType,
+};
+
+impl Engine {
+    /// 把当前的全部数据复制到目录 dir 中，dir 之后可以作为数据目录打开
+    ///
+    /// 先切换活跃文件，之前写入的数据都在不再修改的旧文件中，再逐个复制这些文件并持久化。
+    /// 复制期间可以正常读写，但是合并需要等待复制完成，切换之后的写入不包含在备份中。
+    /// 内存 IO 的数据不在文件系统中，返回 IOTypeUnsupported。
+    pub fn backup(&self, dir: &Path) -> Result<()> {
+        let _guard = self.merge_lock.lock();
+        let file_ids = self.seal_data_files()?;
+        self.copy_data_files(&file_ids, dir)
+    }
+
+    /// 切换活跃文件，返回包含之前所有写入的旧文件
+    ///
+    /// 调用者需要持有 merge_lock，直到复制完这些文件，避免合并删除它们
+    pub(crate) fn seal_data_files(&self) -> Result<Vec<u32>> {
+        if self.options.io_type == IOType::Memory {
+            return Err(Errors::IOTypeUnsupported);
+        }
+        if self.active_file.read().get_write_offset() > 0 {
+            self.rotate_active_file(&mut [])?;
+        }
+        let mut file_ids: Vec<u32> = self.older_files.read().keys().copied().collect();
+        file_ids.sort_unstable();
+        Ok(file_ids)
+    }
+
+    /// 把旧文件复制到目录 dir 中并持久化
+    pub(crate) fn copy_data_files(&self, file_ids: &[u32], dir: &Path) -> Result<()> {
+        let res = (|| -> io::Result<()> {
+            fs::create_dir_all(dir)?;
+            for file_id in file_ids {
+                let src = get_data_file_name(self.options.dir_path.clone(), *file_id);
+                let dst = get_data_file_name(dir.to_path_buf(), *file_id);
+                fs::copy(&src, &dst)?;
+                File::open(&dst)?.sync_all()?;
+            }
+            File::open(dir)?.sync_all()
+        })();
+        if let Err(e) = res {
+            error!("failed to copy data files to {:?}: {}", dir, e);
+            return Err(Errors::FailedToCopyDataFile);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::options::test_options;
+
+    #[test]
+    fn test_engine_backup() {
+        let mut opts = test_options("bitcask-rs-backup");
+        opts.file_size = 100;
+        let backup_dir = std::env::temp_dir().join("bitcask-rs-backup-copy");
+        let _ = fs::remove_dir_all(&backup_dir);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..20 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value"))
+                .unwrap();
+        }
+        engine.delete(Bytes::from("key-0")).unwrap();
+        engine.backup(&backup_dir).unwrap();
+
+        // 备份之后的写入不包含在备份中
+        engine
+            .put(Bytes::from("after"), Bytes::from("value"))
+            .unwrap();
+        engine.merge().unwrap();
+
+        let mut backup_opts = opts.clone();
+        backup_opts.dir_path = backup_dir.clone();
+        let backup = Engine::open(backup_opts).expect("failed to open backup");
+        assert_eq!(backup.list_keys().len(), 19);
+        assert_eq!(
+            backup.get(Bytes::from("key-19")).unwrap(),
+            Bytes::from("value")
+        );
+        assert_eq!(
+            backup.get(Bytes::from("key-0")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        assert_eq!(
+            backup.get(Bytes::from("after")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        drop(engine);
+        drop(backup);
+        fs::remove_dir_all(opts.dir_path).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+}
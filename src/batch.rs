@@ -0,0 +1,259 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordType},
+    db::Engine,
+    errors::{Errors, Result},
+    family::{ColumnFamily, Family},
+};
+
+/// 原子批量写入，可以包含多个列族的写入，提交后要么全部生效，要么全部不生效
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+struct BatchOp {
+    family: Option<Arc<Family>>, // None 表示默认列族
+    record_type: LogRecordType,
+    key: Bytes,
+    value: Bytes,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    /// 在列族 cf 中写入 key/value
+    pub fn put(&mut self, cf: &ColumnFamily, key: Bytes, value: Bytes) -> Result<()> {
+        self.push(cf, LogRecordType::NORMAL, key, value)
+    }
+
+    /// 删除列族 cf 中的 key
+    pub fn delete(&mut self, cf: &ColumnFamily, key: Bytes) -> Result<()> {
+        self.push(cf, LogRecordType::DELETE, key, Bytes::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn push(
+        &mut self,
+        cf: &ColumnFamily,
+        record_type: LogRecordType,
+        key: Bytes,
+        value: Bytes,
+    ) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.ops.push(BatchOp {
+            family: cf.family().cloned(),
+            record_type,
+            key,
+            value,
+        });
+        Ok(())
+    }
+}
+
+impl Engine {
+    /// 原子地提交一个批量写入
+    ///
+    /// 批量写入的记录连续写在同一个数据文件中，前后分别是开始和结束记录。
+    /// 重新打开时只有读到结束记录的批量写入才会生效，没有写完的部分会被截断。
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.check_writable()?;
+
+        let mut sync = self.options.sync;
+        let mut logrecords = Vec::with_capacity(batch.len() + 2);
+        logrecords.push(batch_marker(LogRecordType::BATCHBEGIN));
+        for op in batch.ops.iter() {
+            let cf = match op.family.as_ref() {
+                Some(family) if family.dropped.load(Ordering::SeqCst) => {
+                    return Err(Errors::ColumnFamilyNotFound);
+                }
+                Some(family) => {
+                    sync |= family.options.sync;
+                    family.id
+                }
+                None => 0,
+            };
+            // TTL 列族中写入的 value 以过期时间开头
+            let value = match (op.family.as_ref(), op.record_type) {
+                (Some(family), LogRecordType::NORMAL) => family.encode_value(&op.value, None),
+                _ => op.value.to_vec(),
+            };
+            logrecords.push(LogRecord {
+                key: op.key.to_vec(),
+                value,
+                record_type: op.record_type,
+                seq: 0,
+                cf,
+            });
+        }
+        logrecords.push(batch_marker(LogRecordType::BATCHFINISHED));
+
+        // 按顺序获取所有 key 的写锁，避免死锁
+        let mut stripes: Vec<usize> = batch
+            .ops
+            .iter()
+            .map(|op| self.key_lock_index(&op.key))
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let guards: Vec<_> = stripes.iter().map(|i| self.key_locks[*i].lock()).collect();
+
+        let (positions, commit_seq) = self.write_log_records(&mut logrecords)?;
+
+        // 更新内存索引
+        let applied = batch.ops.iter().zip(logrecords.iter().skip(1));
+        for ((op, logrecord), pos) in applied.zip(positions.into_iter().skip(1)) {
+            let index = match op.family.as_ref() {
+                Some(family) => family.index.as_ref(),
+                None => self.index.as_ref(),
+            };
+            match op.record_type {
+                LogRecordType::DELETE => {
+                    index.delete(op.key.to_vec());
+                }
+                _ => {
+                    if !index.put(op.key.to_vec(), pos) {
+                        return Err(Errors::IndexUpdateError);
+                    }
+                }
+            }
+            // 二级索引只维护默认列族
+            if op.family.is_none() {
+                let value = match op.record_type {
+                    LogRecordType::DELETE => None,
+                    _ => Some(op.value.as_ref()),
+                };
+                self.update_secondary_indexes(&op.key, value, logrecord.seq);
+            }
+        }
+        drop(guards);
+
+        self.wait_for_sync(commit_seq, sync)
+    }
+}
+
+pub(crate) fn batch_marker(record_type: LogRecordType) -> LogRecord {
+    LogRecord {
+        key: Default::default(),
+        value: Default::default(),
+        record_type,
+        seq: 0,
+        cf: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+    };
+
+    use super::*;
+    use crate::options::{test_options, FamilyOptions};
+
+    #[test]
+    fn test_write_batch_across_families() {
+        let opts = test_options("bitcask-rs-write-batch");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine.create_cf("users", FamilyOptions::default()).unwrap();
+        let default = engine.cf("default").unwrap();
+        engine.put(Bytes::from("old"), Bytes::from("v")).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(&users, Bytes::from("u1"), Bytes::from("alice"))
+            .unwrap();
+        batch
+            .put(&default, Bytes::from("user-count"), Bytes::from("1"))
+            .unwrap();
+        batch.delete(&default, Bytes::from("old")).unwrap();
+        assert_eq!(
+            batch.put(&users, Bytes::new(), Bytes::from("v")).err(),
+            Some(Errors::KeyIsEmpty)
+        );
+        engine.write(batch).unwrap();
+
+        assert_eq!(users.get(Bytes::from("u1")).unwrap(), Bytes::from("alice"));
+        assert_eq!(
+            engine.get(Bytes::from("user-count")).unwrap(),
+            Bytes::from("1")
+        );
+        assert_eq!(
+            engine.get(Bytes::from("old")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        drop(users);
+        drop(default);
+        drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        let users = engine.cf("users").unwrap();
+        assert_eq!(users.get(Bytes::from("u1")).unwrap(), Bytes::from("alice"));
+        assert_eq!(
+            engine.get(Bytes::from("old")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_write_batch_unfinished_is_discarded() {
+        let opts = test_options("bitcask-rs-write-batch-unfinished");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        drop(engine);
+
+        // 模拟批量写入过程中崩溃：只写入了开始记录和部分数据
+        let mut begin = batch_marker(LogRecordType::BATCHBEGIN);
+        begin.seq = 2;
+        let put = LogRecord {
+            key: b"b".to_vec(),
+            value: b"2".to_vec(),
+            record_type: LogRecordType::NORMAL,
+            seq: 3,
+            cf: 0,
+        };
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(opts.dir_path.join("000000000.data"))
+            .unwrap();
+        file.write_all(&begin.encode()).unwrap();
+        file.write_all(&put.encode()).unwrap();
+        drop(file);
+
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("b")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        assert_eq!(engine.get(Bytes::from("a")).unwrap(), Bytes::from("1"));
+
+        // 截断之后新的写入不会被当作批量写入的一部分
+        engine.put(Bytes::from("c"), Bytes::from("3")).unwrap();
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(engine.get(Bytes::from("c")).unwrap(), Bytes::from("3"));
+        assert_eq!(engine.list_keys().len(), 2);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use std::{env, path::PathBuf, process};
+
+use rust_kv::{
+    config::parse_encryption_key,
+    options::{Options, RecoveryMode},
+    verify::verify,
+};
+
+const USAGE: &str = "usage:
+    rkv verify <dir> [--encryption-key <64 hex chars>] [--config <toml file>]
+
+configuration is also read from RKV_* environment variables, command line arguments take precedence";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match args.first().map(String::as_str) {
+        Some("verify") => run_verify(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    };
+    process::exit(code);
+}
+
+// 检查数据目录，发现损坏的记录时返回 1
+fn run_verify(args: &[String]) -> i32 {
+    let mut dir_path = None;
+    let mut encryption_key = None;
+    let mut config_file = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--encryption-key" => match iter.next().and_then(|hex| parse_encryption_key(hex)) {
+                Some(key) => encryption_key = Some(key),
+                None => {
+                    eprintln!("invalid encryption key, expected 64 hex chars");
+                    return 2;
+                }
+            },
+            "--config" => match iter.next() {
+                Some(path) => config_file = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            },
+            _ if dir_path.is_none() => dir_path = Some(PathBuf::from(arg)),
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+    }
+    let dir_path = match dir_path {
+        Some(dir_path) if dir_path.is_dir() => dir_path,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    // 配置文件和环境变量中的 IO 类型、加密密钥等对检查同样有效
+    let mut builder = Options::builder();
+    if let Some(path) = config_file {
+        builder = match builder.toml_file(path) {
+            Ok(builder) => builder,
+            Err(e) => {
+                eprintln!("failed to load config: {}", e);
+                return 2;
+            }
+        };
+    }
+    let mut builder = match builder.env() {
+        Ok(builder) => builder
+            .dir_path(dir_path)
+            .recovery_mode(RecoveryMode::Strict),
+        Err(e) => {
+            eprintln!("failed to load config: {}", e);
+            return 2;
+        }
+    };
+    if encryption_key.is_some() {
+        builder = builder.encryption_key(encryption_key);
+    }
+    let opts = match builder.build() {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("failed to load config: {}", e);
+            return 2;
+        }
+    };
+    let report = match verify(&opts) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("verify failed: {}", e);
+            return 2;
+        }
+    };
+
+    for corrupt in report.corrupt_records.iter() {
+        println!(
+            "corrupt record: file {:09}.data offset {}: {}",
+            corrupt.file_id, corrupt.offset, corrupt.error
+        );
+    }
+    println!(
+        "data files: {}, records: {}, live: {}, dead: {}, corrupt: {}",
+        report.data_files,
+        report.total_records,
+        report.live_records,
+        report.dead_records,
+        report.corrupt_records.len()
+    );
+
+    match report.is_ok() {
+        true => 0,
+        false => 1,
+    }
+}
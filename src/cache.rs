@@ -0,0 +1,144 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use parking_lot::Mutex;
+
+use crate::data::log_record::{LogRecord, LogRecordPos};
+
+/// 按记录位置缓存读取到的 LogRecord，按最近最少使用淘汰
+///
+/// 数据文件只追加写入，同一个位置的记录不会改变，覆盖写入的新记录位于新的位置，
+/// 所以缓存不需要在写入时失效。
+pub(crate) struct ValueCache {
+    capacity: u64, // 缓存的 key 和 value 的最大字节数
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CacheState {
+    entries: HashMap<LogRecordPos, (LogRecord, u64)>, // 记录和最近一次访问的时间
+    lru: BTreeMap<u64, LogRecordPos>,                 // 访问时间 -> 记录位置
+    size: u64,
+    tick: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        ValueCache {
+            capacity,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                size: 0,
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, pos: &LogRecordPos) -> Option<LogRecord> {
+        let mut state = self.state.lock();
+        state.tick += 1;
+        let tick = state.tick;
+        let (logrecord, last_access) = match state.entries.get_mut(pos) {
+            Some(entry) => entry,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        let logrecord = logrecord.clone();
+        let last_access = std::mem::replace(last_access, tick);
+        state.lru.remove(&last_access);
+        state.lru.insert(tick, *pos);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(logrecord)
+    }
+
+    pub(crate) fn insert(&self, pos: LogRecordPos, logrecord: &LogRecord) {
+        let size = entry_size(logrecord);
+        // 超过容量的记录不缓存，避免淘汰全部缓存
+        if size > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock();
+        if state.entries.contains_key(&pos) {
+            return;
+        }
+        while state.size + size > self.capacity {
+            let (_, oldest) = match state.lru.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some((evicted, _)) = state.entries.remove(&oldest) {
+                state.size -= entry_size(&evicted);
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.entries.insert(pos, (logrecord.clone(), tick));
+        state.lru.insert(tick, pos);
+        state.size += size;
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+fn entry_size(logrecord: &LogRecord) -> u64 {
+    (logrecord.key.len() + logrecord.value.len()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::log_record::LogRecordType;
+
+    fn record(value: &str) -> LogRecord {
+        LogRecord {
+            key: b"k".to_vec(),
+            value: value.as_bytes().to_vec(),
+            record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: 0,
+        }
+    }
+
+    fn pos(offset: u64) -> LogRecordPos {
+        LogRecordPos { file_id: 0, offset }
+    }
+
+    #[test]
+    fn test_value_cache_evicts_least_recently_used() {
+        // 每条记录 5 字节，最多缓存 3 条
+        let cache = ValueCache::new(15);
+        cache.insert(pos(0), &record("aaaa"));
+        cache.insert(pos(1), &record("bbbb"));
+        cache.insert(pos(2), &record("cccc"));
+        assert_eq!(cache.get(&pos(0)).unwrap().value, b"aaaa".to_vec());
+
+        // pos(1) 最久没有访问，被淘汰
+        cache.insert(pos(3), &record("dddd"));
+        assert!(cache.get(&pos(1)).is_none());
+        assert!(cache.get(&pos(0)).is_some());
+        assert!(cache.get(&pos(2)).is_some());
+        assert!(cache.get(&pos(3)).is_some());
+        assert_eq!(cache.hits(), 4);
+        assert_eq!(cache.misses(), 1);
+
+        // 超过容量的记录不缓存
+        cache.insert(pos(4), &record(&"x".repeat(20)));
+        assert!(cache.get(&pos(4)).is_none());
+        assert!(cache.get(&pos(3)).is_some());
+    }
+}
@@ -0,0 +1,577 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Read,
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver, RecvTimeoutError, TrySendError},
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::warn;
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordPos, LogRecordType},
+    db::Engine,
+    errors::{Errors, Result},
+    family::{decode_family_options, decode_family_value, DEFAULT_FAMILY_NAME},
+    options::FamilyOptions,
+    stream::ValueReader,
+};
+
+/// 每个订阅者最多缓存的实时推送记录数量，超过时断开推送，订阅者之后从数据文件中回放
+pub(crate) const SUBSCRIBER_BUFFER_SIZE: usize = 1024;
+
+/// 一次数据变更，cf 为变更所在列族的名称
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// expire_at 为 value 的过期时间，距 UNIX 纪元的毫秒数，列族没有配置 TTL 时为 None
+    Put {
+        seq: u64,
+        cf: String,
+        key: Bytes,
+        value: Bytes,
+        expire_at: Option<u64>,
+    },
+    Delete {
+        seq: u64,
+        cf: String,
+        key: Bytes,
+    },
+    /// 合并操作数，完整的 value 需要通过 get 读取
+    Merge {
+        seq: u64,
+        cf: String,
+        key: Bytes,
+        operand: Bytes,
+    },
+    /// 合并会在新的数据文件开头重新写入列族的创建记录，同一个列族可能收到多次
+    CreateFamily {
+        seq: u64,
+        name: String,
+        options: FamilyOptions,
+    },
+    DropFamily {
+        seq: u64,
+        name: String,
+    },
+}
+
+impl ChangeEvent {
+    /// 变更对应记录的序号
+    pub fn seq(&self) -> u64 {
+        match self {
+            ChangeEvent::Put { seq, .. } => *seq,
+            ChangeEvent::Delete { seq, .. } => *seq,
+            ChangeEvent::Merge { seq, .. } => *seq,
+            ChangeEvent::CreateFamily { seq, .. } => *seq,
+            ChangeEvent::DropFamily { seq, .. } => *seq,
+        }
+    }
+}
+
+impl Engine {
+    /// 最新写入记录的序号，没有任何记录时为 0
+    pub fn latest_seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    /// 订阅所有列族中序号不小于 from_seq 的数据变更
+    ///
+    /// 订阅时已经写入的记录从数据文件中回放，之后的记录实时推送，事件严格按照序号递增的顺序返回。
+    /// 消费速度跟不上写入时实时推送被断开，之后自动从数据文件中回放还没有收到的记录。
+    /// 消费者保存最后处理的序号，重启后传入该序号加一即可继续消费。
+    /// 对应的记录已经不在数据文件中时返回 SequenceNotRetained。
+    /// 已经删除并且创建记录不在数据文件中的列族，回放时跳过其中的变更，之后仍然会收到 DropFamily。
+    pub fn subscribe(&self, from_seq: u64) -> Result<Subscription<'_>> {
+        if from_seq.max(1) < self.min_seq.load(Ordering::SeqCst) {
+            return Err(Errors::SequenceNotRetained);
+        }
+
+        // 持有列族的读锁直到注册完成，之后创建的列族一定通过创建记录加入
+        let families = self.families.read();
+        let (cut_seq, file_ids, receiver) = self.register_subscriber();
+        let families = families
+            .values()
+            .map(|family| (family.id, (family.name.clone(), family.options)))
+            .collect();
+        Ok(Subscription {
+            engine: self,
+            from_seq,
+            next_seq: from_seq,
+            cut_seq,
+            replay_files: file_ids.into(),
+            replay_offset: 0,
+            receiver,
+            families,
+        })
+    }
+
+    /// 向所有订阅者推送新写入的记录，在持有活跃文件写锁时调用以保证顺序
+    ///
+    /// 推送不会阻塞写入，订阅者的缓存已满时断开它的推送。
+    pub(crate) fn publish(&self, logrecord: &LogRecord) {
+        if logrecord.record_type == LogRecordType::CHUNK {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock();
+        if subscribers.is_empty() {
+            return;
+        }
+        // 订阅已经被关闭的直接移除
+        subscribers.retain(|sender| match sender.try_send(logrecord.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("change subscriber is lagging behind, falling back to data file replay");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// 数据变更订阅，作为迭代器使用时会阻塞等待新的变更
+pub struct Subscription<'a> {
+    engine: &'a Engine,
+    from_seq: u64,               // 订阅的起始序号
+    next_seq: u64,               // 下一条需要处理的记录的序号，重新回放时从这里开始
+    cut_seq: u64,                // 订阅时最新的序号，不大于它的记录从数据文件中回放
+    replay_files: VecDeque<u32>, // 还需要回放的数据文件
+    replay_offset: u64,          // 当前回放文件中的偏移
+    receiver: Receiver<LogRecord>,
+    families: HashMap<u32, (String, FamilyOptions)>, // 列族 id 对应的名称和配置，随创建和删除记录更新
+}
+
+impl Subscription<'_> {
+    /// 获取下一个变更，最多等待 timeout，超时返回 None
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeEvent>> {
+        if let Some(event) = self.next_replayed()? {
+            return Ok(Some(event));
+        }
+        loop {
+            let logrecord = match self.receiver.recv_timeout(timeout) {
+                Ok(logrecord) => logrecord,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                // 缓存已满时推送被断开，收完缓存中的记录之后重新注册并回放
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.resubscribe()?;
+                    match self.next_replayed()? {
+                        Some(event) => return Ok(Some(event)),
+                        None => continue,
+                    }
+                }
+            };
+            if let Some(event) = self.decode_event(logrecord)? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    // 重新注册实时推送，从数据文件中回放 next_seq 及之后已经写入的记录
+    fn resubscribe(&mut self) -> Result<()> {
+        if self.next_seq.max(1) < self.engine.min_seq.load(Ordering::SeqCst) {
+            return Err(Errors::SequenceNotRetained);
+        }
+        let families = self.engine.families.read();
+        let (cut_seq, file_ids, receiver) = self.engine.register_subscriber();
+        for family in families.values() {
+            self.families
+                .entry(family.id)
+                .or_insert_with(|| (family.name.clone(), family.options));
+        }
+        self.from_seq = self.next_seq;
+        self.cut_seq = cut_seq;
+        self.replay_files = file_ids.into();
+        self.replay_offset = 0;
+        self.receiver = receiver;
+        Ok(())
+    }
+
+    // 从数据文件中回放订阅时已经写入的记录
+    fn next_replayed(&mut self) -> Result<Option<ChangeEvent>> {
+        while let Some(file_id) = self.replay_files.front().copied() {
+            let pos = LogRecordPos {
+                file_id,
+                offset: self.replay_offset,
+            };
+            let read = match self.engine.read_log_record_at(&pos) {
+                Ok(read) => read,
+                Err(Errors::ReadDataFileEOF) => {
+                    self.replay_files.pop_front();
+                    self.replay_offset = 0;
+                    continue;
+                }
+                // 还没有回放的文件已经被合并删除
+                Err(Errors::DataFileNotFound) => return Err(Errors::SequenceNotRetained),
+                Err(e) => return Err(e),
+            };
+            if read.record.seq > self.cut_seq {
+                // 之后的记录都会通过 channel 推送
+                self.replay_files.clear();
+                break;
+            }
+            self.replay_offset += read.size;
+            if let Some(event) = self.decode_event(read.record)? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_event(&mut self, logrecord: LogRecord) -> Result<Option<ChangeEvent>> {
+        let seq = logrecord.seq;
+        if seq >= self.from_seq {
+            self.next_seq = self.next_seq.max(seq + 1);
+        }
+        // 起始序号之前的列族创建和删除记录同样需要处理，维护列族 id 和名称的对应关系
+        match logrecord.record_type {
+            LogRecordType::FAMILY | LogRecordType::DROPFAMILY => {
+                let name = match String::from_utf8(logrecord.key) {
+                    Ok(name) => name,
+                    Err(_) => return Err(Errors::InvalidColumnFamilyName),
+                };
+                let event = match logrecord.record_type {
+                    LogRecordType::FAMILY => {
+                        let options = decode_family_options(&logrecord.value);
+                        self.families.insert(logrecord.cf, (name.clone(), options));
+                        ChangeEvent::CreateFamily { seq, name, options }
+                    }
+                    _ => {
+                        self.families.remove(&logrecord.cf);
+                        ChangeEvent::DropFamily { seq, name }
+                    }
+                };
+                return Ok((seq >= self.from_seq).then_some(event));
+            }
+            _ if seq < self.from_seq => return Ok(None),
+            _ => {}
+        }
+
+        let (cf, options) = match logrecord.cf {
+            0 => (DEFAULT_FAMILY_NAME.to_string(), FamilyOptions::default()),
+            id => match self.families.get(&id) {
+                Some((name, options)) => (name.clone(), *options),
+                // 列族已经被删除
+                None => return Ok(None),
+            },
+        };
+        let key = Bytes::copy_from_slice(&logrecord.key);
+        let event = match logrecord.record_type {
+            LogRecordType::NORMAL => {
+                let (value, expire_at) = decode_family_value(&options, logrecord.value)?;
+                ChangeEvent::Put {
+                    seq,
+                    cf,
+                    key,
+                    value: value.into(),
+                    expire_at,
+                }
+            }
+            LogRecordType::DELETE => ChangeEvent::Delete { seq, cf, key },
+            LogRecordType::MERGE => ChangeEvent::Merge {
+                seq,
+                cf,
+                key,
+                operand: logrecord.value.into(),
+            },
+            // 分块存储的大 value 读取完整内容
+            LogRecordType::MANIFEST => {
+                let mut value = Vec::new();
+                let mut reader = ValueReader::from_manifest(self.engine, &logrecord)?;
+                if reader.read_to_end(&mut value).is_err() {
+                    return Err(Errors::FailedToReadFromDataFile);
+                }
+                ChangeEvent::Put {
+                    seq,
+                    cf,
+                    key,
+                    value: value.into(),
+                    expire_at: None,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+}
+
+impl Iterator for Subscription<'_> {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_timeout(Duration::from_secs(1)) {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => continue,
+                Err(Errors::SubscriptionClosed) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::options::{test_options, Options};
+
+    fn collect(sub: &mut Subscription, n: usize) -> Vec<ChangeEvent> {
+        (0..n)
+            .map(|_| {
+                sub.next_timeout(Duration::from_secs(5))
+                    .unwrap()
+                    .expect("missing change event")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_subscribe_replay_and_live() {
+        let opts = Options {
+            file_size: 256,
+            ..test_options("bitcask-rs-cdc-replay")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..20 {
+            let key = Bytes::from(format!("key-{:02}", i));
+            engine.put(key, Bytes::from("value")).unwrap();
+        }
+        engine.delete(Bytes::from("key-03")).unwrap();
+        assert_eq!(engine.latest_seq(), 21);
+
+        // 从头订阅，先回放已有的记录，跨越多个数据文件
+        let mut sub = engine.subscribe(0).unwrap();
+        let events = collect(&mut sub, 21);
+        for (i, event) in events.iter().take(20).enumerate() {
+            assert_eq!(
+                *event,
+                ChangeEvent::Put {
+                    seq: i as u64 + 1,
+                    cf: DEFAULT_FAMILY_NAME.to_string(),
+                    key: Bytes::from(format!("key-{:02}", i)),
+                    value: Bytes::from("value"),
+                    expire_at: None,
+                }
+            );
+        }
+        assert_eq!(
+            events[20],
+            ChangeEvent::Delete {
+                seq: 21,
+                cf: DEFAULT_FAMILY_NAME.to_string(),
+                key: Bytes::from("key-03"),
+            }
+        );
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)).unwrap(), None);
+
+        // 之后写入的记录实时推送
+        engine.put(Bytes::from("live"), Bytes::from("1")).unwrap();
+        assert_eq!(
+            collect(&mut sub, 1)[0],
+            ChangeEvent::Put {
+                seq: 22,
+                cf: DEFAULT_FAMILY_NAME.to_string(),
+                key: Bytes::from("live"),
+                value: Bytes::from("1"),
+                expire_at: None,
+            }
+        );
+
+        // 从中间的序号订阅
+        let mut sub = engine.subscribe(21).unwrap();
+        let events = collect(&mut sub, 2);
+        assert_eq!(events[0].seq(), 21);
+        assert_eq!(events[1].seq(), 22);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_subscribe_resume_after_restart() {
+        let opts = Options {
+            file_size: 256,
+            ..test_options("bitcask-rs-cdc-resume")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        engine.put(Bytes::from("b"), Bytes::from("2")).unwrap();
+
+        let mut sub = engine.subscribe(1).unwrap();
+        let last_seq = collect(&mut sub, 1)[0].seq();
+        drop(sub);
+        drop(engine);
+
+        // 重启后序号继续递增，从保存的位置继续消费
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(engine.latest_seq(), 2);
+        engine.put(Bytes::from("c"), Bytes::from("3")).unwrap();
+
+        let mut sub = engine.subscribe(last_seq + 1).unwrap();
+        let events = collect(&mut sub, 2);
+        assert_eq!(events[0].seq(), 2);
+        assert_eq!(events[1].seq(), 3);
+        assert_eq!(
+            events[1],
+            ChangeEvent::Put {
+                seq: 3,
+                cf: DEFAULT_FAMILY_NAME.to_string(),
+                key: Bytes::from("c"),
+                value: Bytes::from("3"),
+                expire_at: None,
+            }
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_subscribe_concurrent_writes() {
+        let opts = Options {
+            file_size: 256,
+            ..test_options("bitcask-rs-cdc-concurrent")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.put(Bytes::from("before"), Bytes::from("v")).unwrap();
+
+        let mut sub = engine.subscribe(0).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..100 {
+                    let key = Bytes::from(format!("key-{}", i));
+                    engine.put(key, Bytes::from("v")).unwrap();
+                }
+            });
+            // 回放和实时推送之间不会丢失或重复
+            let events = collect(&mut sub, 101);
+            for (i, event) in events.iter().enumerate() {
+                assert_eq!(event.seq(), i as u64 + 1);
+            }
+        });
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_subscribe_lagging_subscriber() {
+        let opts = Options {
+            file_size: 256,
+            ..test_options("bitcask-rs-cdc-lagging")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // 订阅之后不消费，写入超过缓存的记录不会阻塞，推送被断开
+        let mut sub = engine.subscribe(0).unwrap();
+        let total = SUBSCRIBER_BUFFER_SIZE as u64 * 2;
+        for i in 0..total {
+            let key = Bytes::from(format!("key-{}", i));
+            engine.put(key, Bytes::from("v")).unwrap();
+        }
+        assert!(engine.subscribers.lock().is_empty());
+
+        // 收完缓存中的记录之后从数据文件中回放剩余的记录，之后继续实时推送
+        let events = collect(&mut sub, total as usize);
+        for (i, event) in events.iter().enumerate() {
+            assert_eq!(event.seq(), i as u64 + 1);
+        }
+        engine.put(Bytes::from("live"), Bytes::from("v")).unwrap();
+        assert_eq!(collect(&mut sub, 1)[0].seq(), total + 1);
+        assert_eq!(engine.subscribers.lock().len(), 1);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_subscribe_column_families() {
+        let opts = Options {
+            file_size: 256,
+            ..test_options("bitcask-rs-cdc-families")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let ttl = FamilyOptions {
+            ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let users = engine.create_cf("users", ttl).unwrap();
+        users.put(Bytes::from("u1"), Bytes::from("1")).unwrap();
+
+        // 订阅之后仍然可以创建和删除列族，变更带有列族的名称
+        let mut sub = engine.subscribe(0).unwrap();
+        let sessions = engine
+            .create_cf("sessions", FamilyOptions::default())
+            .unwrap();
+        sessions.put(Bytes::from("s1"), Bytes::from("2")).unwrap();
+        engine.drop_cf("users").unwrap();
+        engine.put(Bytes::from("k"), Bytes::from("3")).unwrap();
+
+        let events = collect(&mut sub, 6);
+        assert_eq!(
+            events[0],
+            ChangeEvent::CreateFamily {
+                seq: 1,
+                name: "users".to_string(),
+                options: ttl,
+            }
+        );
+        match &events[1] {
+            ChangeEvent::Put {
+                cf,
+                key,
+                value,
+                expire_at,
+                ..
+            } => {
+                assert_eq!(cf, "users");
+                assert_eq!(key, &Bytes::from("u1"));
+                assert_eq!(value, &Bytes::from("1"));
+                assert!(expire_at.is_some());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(
+            events[2],
+            ChangeEvent::CreateFamily {
+                seq: 3,
+                name: "sessions".to_string(),
+                options: FamilyOptions::default(),
+            }
+        );
+        assert_eq!(
+            events[3],
+            ChangeEvent::Put {
+                seq: 4,
+                cf: "sessions".to_string(),
+                key: Bytes::from("s1"),
+                value: Bytes::from("2"),
+                expire_at: None,
+            }
+        );
+        assert_eq!(
+            events[4],
+            ChangeEvent::DropFamily {
+                seq: 5,
+                name: "users".to_string(),
+            }
+        );
+        assert_eq!(events[5].seq(), 6);
+
+        // 从删除列族之后的序号订阅，回放时仍然能识别之前创建的列族
+        let mut sub = engine.subscribe(4).unwrap();
+        assert_eq!(
+            collect(&mut sub, 1)[0],
+            ChangeEvent::Put {
+                seq: 4,
+                cf: "sessions".to_string(),
+                key: Bytes::from("s1"),
+                value: Bytes::from("2"),
+                expire_at: None,
+            }
+        );
+
+        drop(users);
+        drop(sessions);
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::error;
+use parking_lot::{Condvar, Mutex, RwLock};
+
+use crate::{
+    data::data_file::DataFile,
+    errors::{Errors, Result},
+    listener::EventListener,
+    metrics::Histogram,
+};
+
+/// 组提交，多个并发写入共享同一次 fsync
+///
+/// 写入活跃文件时在写锁内累加已写入的字节数，返回值作为这次写入的序号。
+/// 写入者释放写锁后等待自己的序号被持久化：如果当前没有正在进行的 fsync，就成为 leader，
+/// 记录此刻已经写入的字节数并执行 fsync，完成后唤醒所有序号不大于该值的写入者；
+/// 否则等待当前 leader 完成后再判断。
+/// 切换活跃文件时旧文件已经在写锁内持久化，所以只需要对当前活跃文件执行 fsync。
+/// fsync 失败后内核可能已经丢弃了没有持久化的脏页，之后的 fsync 成功也不能说明之前的写入已经持久化，
+/// 因此一次失败之后所有还没有持久化的写入都返回错误，直到重新打开引擎。
+/// 没有其他写入者排队并且没有正在进行的 fsync 时，写入者可以直接成为 leader，
+/// 在写锁内把写入和 fsync 一起提交（io_uring 中通过 IO_LINK 链接），有并发写入时仍然共享 fsync。
+pub(crate) struct GroupCommit {
+    written_bytes: AtomicU64, // 已经写入的字节数
+    writers: AtomicUsize,     // 正在获取或者持有活跃文件写锁的写入者数量
+    state: Mutex<CommitState>,
+    cond: Condvar,
+    sync_count: AtomicU64,    // 实际执行的 fsync 次数
+    sync_duration: Histogram, // fsync 的耗时
+}
+
+struct CommitState {
+    synced_bytes: u64, // 已经持久化的字节数
+    syncing: bool,     // 是否有 leader 正在执行 fsync
+    failed: bool,      // 是否有 fsync 失败过
+}
+
+impl GroupCommit {
+    pub(crate) fn new() -> Self {
+        GroupCommit {
+            written_bytes: AtomicU64::new(0),
+            writers: AtomicUsize::new(0),
+            state: Mutex::new(CommitState {
+                synced_bytes: 0,
+                syncing: false,
+                failed: false,
+            }),
+            cond: Condvar::new(),
+            sync_count: AtomicU64::new(0),
+            sync_duration: Histogram::new(),
+        }
+    }
+
+    /// 记录写入了 size 字节，返回写入序号，必须在持有活跃文件写锁时调用
+    pub(crate) fn next_seq(&self, size: u64) -> u64 {
+        self.written_bytes.fetch_add(size, Ordering::SeqCst) + size
+    }
+
+    /// 写入者获取活跃文件写锁之前调用，返回的 guard 在释放写锁之后 drop
+    pub(crate) fn enter_writer(&self) -> WriterGuard<'_> {
+        self.writers.fetch_add(1, Ordering::SeqCst);
+        WriterGuard { commit: self }
+    }
+
+    /// 没有其他写入者排队并且没有正在进行的 fsync 时成为 leader，必须在持有活跃文件写锁时调用
+    ///
+    /// 返回 true 时调用者负责写入并持久化，完成之后调用 finish_linked_sync
+    pub(crate) fn try_lead_linked_sync(&self) -> bool {
+        if self.writers.load(Ordering::SeqCst) > 1 {
+            return false;
+        }
+        let mut state = self.state.lock();
+        if state.syncing || state.failed {
+            return false;
+        }
+        state.syncing = true;
+        true
+    }
+
+    /// 结束写入和 fsync 一起提交的 leader，成功时当前已经写入的所有记录都已经持久化
+    ///
+    /// 写入失败时没有执行 fsync，只有 fsync 失败才需要让之后的写入返回错误
+    pub(crate) fn finish_linked_sync(&self, res: &Result<u64>, elapsed: Duration) {
+        let target = self.written_bytes.load(Ordering::SeqCst);
+        let mut state = self.state.lock();
+        state.syncing = false;
+        match res {
+            Ok(_) => {
+                self.record_sync(elapsed);
+                if target > state.synced_bytes {
+                    state.synced_bytes = target;
+                }
+            }
+            Err(Errors::FailedToSyncDataFile) => {
+                self.record_sync(elapsed);
+                state.failed = true;
+            }
+            Err(_) => {}
+        }
+        self.cond.notify_all();
+    }
+
+    /// 等待序号 seq 之前的写入全部持久化，sync_fn 由 leader 调用执行真正的 fsync
+    pub(crate) fn wait_durable(&self, seq: u64, sync_fn: impl Fn() -> Result<()>) -> Result<()> {
+        let mut state = self.state.lock();
+        loop {
+            if state.synced_bytes >= seq {
+                return Ok(());
+            }
+            if state.failed {
+                return Err(Errors::FailedToSyncDataFile);
+            }
+            if state.syncing {
+                self.cond.wait(&mut state);
+                continue;
+            }
+
+            // 成为 leader，为当前已经写入的所有记录执行 fsync
+            state.syncing = true;
+            let target = self.written_bytes.load(Ordering::SeqCst);
+            drop(state);
+
+            let start = Instant::now();
+            let res = sync_fn();
+            self.record_sync(start.elapsed());
+
+            state = self.state.lock();
+            state.syncing = false;
+            match res.is_ok() {
+                true if target > state.synced_bytes => state.synced_bytes = target,
+                true => {}
+                false => state.failed = true,
+            }
+            self.cond.notify_all();
+            // fsync 失败时由 leader 返回错误，其余等待者被唤醒后同样返回错误
+            res?;
+        }
+    }
+
+    /// 持久化当前已经写入的所有数据
+    pub(crate) fn sync_all(&self, sync_fn: impl Fn() -> Result<()>) -> Result<()> {
+        let seq = self.written_bytes.load(Ordering::SeqCst);
+        self.wait_durable(seq, sync_fn)
+    }
+
+    /// 切换活跃文件时持久化旧文件，成功后当前已经写入的所有记录都已经持久化
+    ///
+    /// 之前有 fsync 失败过时直接返回错误，不能把已经丢失的写入标记为持久化
+    pub(crate) fn sync_sealed(&self, sync_fn: impl FnOnce() -> Result<()>) -> Result<()> {
+        if self.state.lock().failed {
+            return Err(Errors::FailedToSyncDataFile);
+        }
+        let start = Instant::now();
+        let res = sync_fn();
+        self.record_sync(start.elapsed());
+        match res {
+            Ok(_) => self.mark_all_synced(),
+            Err(_) => {
+                self.state.lock().failed = true;
+                self.cond.notify_all();
+            }
+        }
+        res
+    }
+
+    /// 标记当前已经写入的所有记录都已经持久化
+    pub(crate) fn mark_all_synced(&self) {
+        let target = self.written_bytes.load(Ordering::SeqCst);
+        let mut state = self.state.lock();
+        if target > state.synced_bytes {
+            state.synced_bytes = target;
+        }
+        self.cond.notify_all();
+    }
+
+    /// 是否有 fsync 失败过
+    pub(crate) fn is_failed(&self) -> bool {
+        self.state.lock().failed
+    }
+
+    /// 已经写入但还没有持久化的字节数
+    pub(crate) fn unsynced_bytes(&self) -> u64 {
+        let synced = self.state.lock().synced_bytes;
+        self.written_bytes
+            .load(Ordering::SeqCst)
+            .saturating_sub(synced)
+    }
+
+    pub(crate) fn sync_count(&self) -> u64 {
+        self.sync_count.load(Ordering::SeqCst)
+    }
+
+    /// 记录一次 fsync，组提交之外的 fsync 也需要调用
+    pub(crate) fn record_sync(&self, elapsed: Duration) {
+        self.sync_count.fetch_add(1, Ordering::SeqCst);
+        self.sync_duration.observe(elapsed);
+    }
+
+    pub(crate) fn sync_duration(&self) -> &Histogram {
+        &self.sync_duration
+    }
+}
+
+/// 写入者的计数，drop 时减少
+pub(crate) struct WriterGuard<'a> {
+    commit: &'a GroupCommit,
+}
+
+impl Drop for WriterGuard<'_> {
+    fn drop(&mut self) {
+        self.commit.writers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 后台定时持久化活跃文件的线程，Drop 时停止
+pub(crate) struct SyncWorker {
+    stop_sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SyncWorker {
+    pub(crate) fn start(
+        interval: Duration,
+        active_file: Arc<RwLock<DataFile>>,
+        group_commit: Arc<GroupCommit>,
+        listeners: Vec<Arc<dyn EventListener>>,
+    ) -> Self {
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || loop {
+            match stop_receiver.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    if group_commit.unsynced_bytes() == 0 {
+                        continue;
+                    }
+                    let res = group_commit.sync_all(|| active_file.read().sync());
+                    if let Err(e) = res.as_ref() {
+                        error!("background sync error: {}", e);
+                    }
+                    for listener in listeners.iter() {
+                        listener.on_background_sync(&res);
+                    }
+                }
+                _ => return,
+            }
+        });
+        SyncWorker {
+            stop_sender: Some(stop_sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for SyncWorker {
+    fn drop(&mut self) {
+        // 关闭 channel 通知后台线程退出
+        self.stop_sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_group_commit_single_writer() {
+        let commit = GroupCommit::new();
+        let seq = commit.next_seq(10);
+        assert_eq!(commit.unsynced_bytes(), 10);
+        commit.wait_durable(seq, || Ok(())).unwrap();
+        assert_eq!(commit.sync_count(), 1);
+        assert_eq!(commit.unsynced_bytes(), 0);
+
+        // 已经持久化的序号不需要再次 fsync
+        commit.wait_durable(seq, || Ok(())).unwrap();
+        assert_eq!(commit.sync_count(), 1);
+
+        let seq = commit.next_seq(10);
+        commit.mark_all_synced();
+        commit.wait_durable(seq, || Ok(())).unwrap();
+        assert_eq!(commit.sync_count(), 1);
+    }
+
+    #[test]
+    fn test_group_commit_concurrent_writers() {
+        let commit = Arc::new(GroupCommit::new());
+        let mut handles = Vec::new();
+        for _ in 0..8 {
+            let commit = commit.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..100 {
+                    let seq = commit.next_seq(1);
+                    commit
+                        .wait_durable(seq, || {
+                            thread::sleep(Duration::from_millis(1));
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(commit.sync_count() < 800);
+        assert_eq!(commit.unsynced_bytes(), 0);
+    }
+
+    #[test]
+    fn test_group_commit_waiters_share_sync() {
+        let commit = Arc::new(GroupCommit::new());
+        let (started_sender, started) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel::<()>();
+
+        // leader 的 fsync 阻塞，直到其余写入者都已经写入并开始等待
+        let leader = {
+            let commit = commit.clone();
+            thread::spawn(move || {
+                let seq = commit.next_seq(1);
+                commit
+                    .wait_durable(seq, || {
+                        started_sender.send(()).unwrap();
+                        release_receiver.recv().unwrap();
+                        Ok(())
+                    })
+                    .unwrap();
+            })
+        };
+        started.recv().unwrap();
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let commit = commit.clone();
+                thread::spawn(move || {
+                    let seq = commit.next_seq(1);
+                    commit.wait_durable(seq, || Ok(())).unwrap();
+                })
+            })
+            .collect();
+        while commit.unsynced_bytes() < 5 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        release.send(()).unwrap();
+
+        leader.join().unwrap();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        // leader 之后只需要一次 fsync 就能持久化所有等待者的写入
+        assert_eq!(commit.sync_count(), 2);
+        assert_eq!(commit.unsynced_bytes(), 0);
+    }
+}
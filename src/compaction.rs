@@ -0,0 +1,745 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use log::{error, info};
+
+use crate::{
+    batch::batch_marker,
+    data::{
+        data_file::get_data_file_name,
+        log_record::{LogRecord, LogRecordPos, LogRecordType},
+    },
+    db::Engine,
+    errors::{Errors, Result},
+    family::{encode_family_options, family_value_expire_at, is_expired, Family},
+    fio,
+    index::Indexer,
+    options::AutoMerge,
+    stream::ValueManifest,
+};
+
+/// 一次合并的结果
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeStats {
+    pub merged_files: usize,    // 合并后删除的数据文件数量
+    pub relocated_records: u64, // 重新写入活跃文件的有效记录数量
+    pub reclaimed_bytes: u64,   // 释放的磁盘空间
+}
+
+/// 数据文件的空间使用情况
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceStats {
+    pub total_bytes: u64,       // 所有数据文件的大小
+    pub live_bytes: u64,        // 仍然有效的记录的大小
+    pub reclaimable_bytes: u64, // 合并可以回收的空间
+}
+
+impl SpaceStats {
+    /// 可回收空间占全部数据文件的比例
+    pub fn reclaimable_ratio(&self) -> f64 {
+        match self.total_bytes {
+            0 => 0.0,
+            total => self.reclaimable_bytes as f64 / total as f64,
+        }
+    }
+}
+
+impl Engine {
+    /// 合并数据文件，回收被覆盖和删除的记录占用的空间
+    ///
+    /// 合并时先切换活跃文件，之前的数据文件都不再写入；然后把其中仍然有效的记录重新追加到
+    /// 新的活跃文件并更新索引，最后删除这些旧文件。合并期间可以正常读写，
+    /// 合并开始之前打开的 ValueReader 按 key 重新查找移动之后的分块继续读取。
+    /// 合并的读写按照 Options 中的 background_io_rate 限速。
+    /// 同一时间只能运行一个合并，否则返回 MergeInProgress。
+    pub fn merge(&self) -> Result<MergeStats> {
+        self.check_writable()?;
+        let _guard = match self.merge_lock.try_lock() {
+            Some(guard) => guard,
+            None => return Err(Errors::MergeInProgress),
+        };
+
+        for listener in self.options.event_listeners.iter() {
+            listener.on_merge_start();
+        }
+        let result = self.merge_sealed_files();
+        match result.as_ref() {
+            Ok(stats) => {
+                self.metrics.inc_merge_runs();
+                info!(
+                    "merged {} data files, reclaimed {} bytes",
+                    stats.merged_files, stats.reclaimed_bytes
+                );
+            }
+            Err(e) => error!("failed to merge data files: {}", e),
+        }
+        for listener in self.options.event_listeners.iter() {
+            listener.on_merge_finish(&result);
+        }
+        result
+    }
+
+    /// 统计数据文件的空间使用情况，需要读取每条有效记录的 header
+    pub fn space_stats(&self) -> Result<SpaceStats> {
+        let mut total_bytes = self.active_file.read().get_write_offset();
+        for data_file in self.older_files.read().values() {
+            total_bytes += data_file.file_size()?;
+        }
+
+        let mut live = HashSet::new();
+        let chains: Vec<_> = self.merge_chains.read().values().cloned().collect();
+        for chain in chains {
+            live.extend(chain.positions());
+        }
+        self.collect_live_positions(&*self.index, &mut live)?;
+        let families: Vec<_> = self.families.read().values().cloned().collect();
+        for family in families {
+            self.collect_live_positions(&*family.index, &mut live)?;
+        }
+
+        let mut live_bytes = 0;
+        for pos in live {
+            match self.read_log_record_size(&pos) {
+                Ok(size) => live_bytes += size,
+                // 统计期间记录被合并到了新的位置
+                Err(Errors::DataFileNotFound) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(SpaceStats {
+            total_bytes,
+            live_bytes,
+            reclaimable_bytes: total_bytes.saturating_sub(live_bytes),
+        })
+    }
+
+    // 收集索引引用的记录位置，分块存储的 value 包含所有分块
+    fn collect_live_positions(
+        &self,
+        index: &dyn Indexer,
+        live: &mut HashSet<LogRecordPos>,
+    ) -> Result<()> {
+        for key in index.list_keys() {
+            let pos = match index.get(key.to_vec()) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            if !live.insert(pos) {
+                continue;
+            }
+            let logrecord = match self.read_log_record_with(&pos, false) {
+                Ok(logrecord) => logrecord,
+                Err(Errors::DataFileNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            if logrecord.record_type == LogRecordType::MANIFEST {
+                let manifest = ValueManifest::decode(&logrecord.value)?;
+                live.extend(manifest.chunks.iter().map(|chunk| chunk.0));
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_sealed_files(&self) -> Result<MergeStats> {
+        // 切换活跃文件，新文件以所有列族的创建记录开头，之后可以删除旧文件中的创建记录
+        // 最后写入一个空的原子批量，保证新文件至少以一条新分配序号的记录开头，
+        // 之后移动的记录保留原来的序号，重新打开时以第一条记录的序号作为保留的最小序号
+        let sealed: HashSet<u32> = {
+            let _streams = self.stream_lock.write();
+            let families = self.families.write();
+            let mut logrecords: Vec<LogRecord> = families
+                .values()
+                .map(|family| LogRecord {
+                    key: family.name.as_bytes().to_vec(),
+                    value: encode_family_options(&family.options),
+                    record_type: LogRecordType::FAMILY,
+                    seq: 0,
+                    cf: family.id,
+                })
+                .collect();
+            logrecords.push(batch_marker(LogRecordType::BATCHBEGIN));
+            logrecords.push(batch_marker(LogRecordType::BATCHFINISHED));
+            self.rotate_active_file(&mut logrecords)?;
+            self.older_files.read().keys().copied().collect()
+        };
+
+        let mut stats = MergeStats {
+            merged_files: sealed.len(),
+            ..Default::default()
+        };
+        for key in self.index.list_keys() {
+            let size = self.relocate_key(None, &key, &sealed)?;
+            self.throttle_relocation(size, &mut stats);
+        }
+        let families: Vec<_> = self.families.read().values().cloned().collect();
+        for family in families {
+            for key in family.index.list_keys() {
+                if family.dropped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let size = self.relocate_key(Some(&family), &key, &sealed)?;
+                self.throttle_relocation(size, &mut stats);
+            }
+        }
+
+        // 有效记录都持久化之后才能删除旧文件
+        self.sync()?;
+
+        // 从最旧的文件开始删除，中途崩溃时剩余文件中的删除记录仍然能覆盖更旧的记录
+        let mut file_ids: Vec<u32> = sealed.into_iter().collect();
+        file_ids.sort_unstable();
+        for file_id in file_ids {
+            let data_file = self.older_files.write().remove(&file_id);
+            if let Some(data_file) = data_file {
+                stats.reclaimed_bytes += data_file.file_size()?;
+            }
+            let path = get_data_file_name(self.options.dir_path.clone(), file_id);
+            fio::remove_file(&path, self.options.io_type)?;
+        }
+
+        // 更早的序号已经不在数据文件中
+        let first_file_id = match self.older_files.read().keys().min() {
+            Some(file_id) => *file_id,
+            None => self.active_file.read().get_file_id(),
+        };
+        let pos = LogRecordPos {
+            file_id: first_file_id,
+            offset: 0,
+        };
+        let min_seq = match self.read_log_record_at(&pos) {
+            Ok(read) => read.record.seq,
+            Err(Errors::ReadDataFileEOF) => self.seq.load(Ordering::SeqCst) + 1,
+            Err(e) => return Err(e),
+        };
+        self.min_seq.fetch_max(min_seq, Ordering::SeqCst);
+
+        Ok(stats)
+    }
+
+    // 统计重新写入的记录，并按照读取和写入的字节数限速，size 为 0 表示没有写入
+    fn throttle_relocation(&self, size: u64, stats: &mut MergeStats) {
+        if size > 0 {
+            stats.relocated_records += 1;
+            self.background_limiter.acquire(2 * size);
+        }
+    }
+
+    // 把 key 位于 sealed 文件中的有效记录重新写入活跃文件，返回写入的 key 和 value 的字节数
+    // family 为 None 表示默认列族，TTL 列族中已经过期的记录不再写入，直接从索引中删除
+    //
+    // 限速在释放 key 的锁之后进行，避免前台写入同一个 key 时等待
+    fn relocate_key(
+        &self,
+        family: Option<&Family>,
+        key: &Bytes,
+        sealed: &HashSet<u32>,
+    ) -> Result<u64> {
+        let index = match family {
+            Some(family) => family.index.as_ref(),
+            None => self.index.as_ref(),
+        };
+        let _guard = self.lock_key(key);
+        let pos = match index.get(key.to_vec()) {
+            Some(pos) => pos,
+            None => return Ok(0),
+        };
+
+        // 还没有合并的操作数直接合并成完整的 value
+        if family.is_none() {
+            let chain = match self.merge_chains.read().get(key.as_ref()) {
+                Some(chain) if chain.is_current(pos) => Some(chain.clone()),
+                _ => None,
+            };
+            if let Some(chain) = chain {
+                if chain.positions().all(|p| !sealed.contains(&p.file_id)) {
+                    return Ok(0);
+                }
+                let value = match self.read_merged_value(key, pos)? {
+                    Some(value) => value,
+                    None => return Ok(0),
+                };
+                // 合并之后的 value 使用最后一个操作数的序号，和其他移动的记录一样不推送给订阅者
+                let seq = self.read_log_record_with(&pos, false)?.seq;
+                let mut logrecord = LogRecord {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                    record_type: LogRecordType::NORMAL,
+                    seq,
+                    cf: 0,
+                };
+                let (positions, _) =
+                    self.relocate_log_records(std::slice::from_mut(&mut logrecord))?;
+                if !index.put(key.to_vec(), positions[0]) {
+                    return Err(Errors::IndexUpdateError);
+                }
+                self.clear_merge_chain(key);
+                self.update_secondary_indexes(key, Some(&value), seq);
+                return Ok((key.len() + value.len()) as u64);
+            }
+        }
+
+        // 分块和 MANIFEST 在同一次流式写入中写入，MANIFEST 不在旧文件中时分块也不在
+        if !sealed.contains(&pos.file_id) {
+            return Ok(0);
+        }
+        let mut logrecord = self.read_log_record_with(&pos, false)?;
+        if let Some(family) = family {
+            let expired = logrecord.record_type == LogRecordType::NORMAL
+                && is_expired(family_value_expire_at(&family.options, &logrecord.value)?);
+            if expired {
+                index.delete(key.to_vec());
+                return Ok(0);
+            }
+        }
+        let mut size = 0;
+        if logrecord.record_type == LogRecordType::MANIFEST {
+            let mut manifest = ValueManifest::decode(&logrecord.value)?;
+            for chunk in manifest.chunks.iter_mut() {
+                if !sealed.contains(&chunk.0.file_id) {
+                    continue;
+                }
+                let mut chunk_record = self.read_log_record_with(&chunk.0, false)?;
+                size += chunk_record.value.len() as u64;
+                let (positions, _) =
+                    self.relocate_log_records(std::slice::from_mut(&mut chunk_record))?;
+                chunk.0 = positions[0];
+            }
+            logrecord.value = manifest.encode();
+        }
+
+        // 保留原来的序号，订阅者不会收到重复的变更
+        let (positions, _) = self.relocate_log_records(std::slice::from_mut(&mut logrecord))?;
+        if !index.put(key.to_vec(), positions[0]) {
+            return Err(Errors::IndexUpdateError);
+        }
+        Ok(size + (logrecord.key.len() + logrecord.value.len()) as u64)
+    }
+}
+
+/// 后台自动合并，根据 Options 中的 auto_merge 定时检查可回收空间
+///
+/// 可回收空间的比例和大小都达到阈值，并且当前时间在允许的时间段内时触发合并。
+/// Drop 时停止后台线程。
+pub struct MergeScheduler {
+    paused: Arc<AtomicBool>,
+    stop_sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MergeScheduler {
+    /// 启动后台线程，engine 没有配置 auto_merge 时返回 AutoMergeNotConfigured
+    pub fn start(engine: Arc<Engine>) -> Result<MergeScheduler> {
+        let config = match engine.options.auto_merge {
+            Some(config) => config,
+            None => return Err(Errors::AutoMergeNotConfigured),
+        };
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let paused_clone = paused.clone();
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let interval = Duration::from_millis(config.check_interval_ms);
+        let handle = thread::spawn(move || loop {
+            match stop_receiver.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    if paused_clone.load(Ordering::SeqCst) || !in_window(&config) {
+                        continue;
+                    }
+                    if let Err(e) = maybe_merge(&engine, &config) {
+                        error!("auto merge error: {}", e);
+                    }
+                }
+                _ => return,
+            }
+        });
+
+        Ok(MergeScheduler {
+            paused,
+            stop_sender: Some(stop_sender),
+            handle: Some(handle),
+        })
+    }
+
+    /// 暂停自动合并，正在进行的合并不受影响
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// 恢复自动合并
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// 自动合并是否已经暂停
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        // 关闭 channel 通知后台线程退出
+        self.stop_sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// 当前时间是否在允许合并的时间段内
+fn in_window(config: &AutoMerge) -> bool {
+    let window = match config.window {
+        Some(window) => window,
+        None => return true,
+    };
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    window.contains(((secs / 60) % (24 * 60)) as u32)
+}
+
+fn maybe_merge(engine: &Engine, config: &AutoMerge) -> Result<()> {
+    let stats = engine.space_stats()?;
+    if stats.reclaimable_bytes < config.min_reclaimable_bytes
+        || stats.reclaimable_ratio() < config.ratio_threshold
+    {
+        return Ok(());
+    }
+    match engine.merge() {
+        Ok(_) | Err(Errors::MergeInProgress) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read, sync::atomic::AtomicUsize};
+
+    use super::*;
+    use crate::{
+        listener::EventListener,
+        merge::MergeOperator,
+        options::{test_options, FamilyOptions, MergeWindow, Options},
+    };
+
+    // 用逗号连接所有操作数
+    struct Concat;
+
+    impl MergeOperator for Concat {
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes {
+            let mut parts: Vec<&[u8]> = existing.into_iter().collect();
+            parts.extend_from_slice(operands);
+            Bytes::from(parts.join(&b","[..]))
+        }
+    }
+
+    #[derive(Default)]
+    struct MergeCounter {
+        finished: AtomicUsize,
+    }
+
+    impl EventListener for MergeCounter {
+        fn on_merge_finish(&self, result: &Result<MergeStats>) {
+            assert!(result.is_ok());
+            self.finished.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn data_file_count(opts: &Options) -> usize {
+        fs::read_dir(&opts.dir_path).unwrap().count()
+    }
+
+    fn check_merged(engine: &Engine) {
+        for i in 0..10 {
+            let value = engine.get(Bytes::from(format!("key-{}", i)));
+            match i {
+                0..=4 => assert_eq!(value.unwrap(), Bytes::from("value-2")),
+                5 | 6 => assert_eq!(value.err(), Some(Errors::RecordNotFound)),
+                _ => assert_eq!(value.unwrap(), Bytes::from("value-1")),
+            }
+        }
+        assert_eq!(
+            engine.get(Bytes::from("m")).unwrap(),
+            Bytes::from("base,a,b")
+        );
+
+        let cf = engine.cf("cf").unwrap();
+        assert_eq!(cf.get(Bytes::from("a")).unwrap(), Bytes::from("2"));
+        assert_eq!(cf.get(Bytes::from("b")).err(), Some(Errors::RecordNotFound));
+        assert_eq!(engine.cf("gone").err(), Some(Errors::ColumnFamilyNotFound));
+
+        let mut value = Vec::new();
+        engine
+            .get_reader(Bytes::from("big"))
+            .unwrap()
+            .read_to_end(&mut value)
+            .unwrap();
+        assert_eq!(value, vec![7u8; 120]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let counter = Arc::new(MergeCounter::default());
+        let mut opts = test_options("bitcask-rs-compaction-merge");
+        opts.file_size = 100;
+        opts.event_listeners = vec![counter.clone()];
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.set_merge_operator(Arc::new(Concat));
+
+        for i in 0..10 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-1"))
+                .unwrap();
+        }
+        for i in 0..5 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-2"))
+                .unwrap();
+        }
+        engine.delete(Bytes::from("key-5")).unwrap();
+        engine.delete(Bytes::from("key-6")).unwrap();
+        engine.put(Bytes::from("m"), Bytes::from("base")).unwrap();
+        engine
+            .merge_value(Bytes::from("m"), Bytes::from("a"))
+            .unwrap();
+        engine
+            .merge_value(Bytes::from("m"), Bytes::from("b"))
+            .unwrap();
+
+        let cf = engine.create_cf("cf", FamilyOptions::default()).unwrap();
+        cf.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        cf.put(Bytes::from("a"), Bytes::from("2")).unwrap();
+        cf.put(Bytes::from("b"), Bytes::from("1")).unwrap();
+        cf.delete(Bytes::from("b")).unwrap();
+        let gone = engine.create_cf("gone", FamilyOptions::default()).unwrap();
+        gone.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        drop(gone);
+        engine.drop_cf("gone").unwrap();
+        engine
+            .put_stream(Bytes::from("big"), &vec![7u8; 120][..])
+            .unwrap();
+
+        let before = engine.space_stats().unwrap();
+        assert!(before.reclaimable_bytes > 0);
+        let files_before = data_file_count(&opts);
+
+        let stats = engine.merge().unwrap();
+        assert_eq!(stats.merged_files, files_before);
+        assert!(stats.relocated_records > 0);
+        assert!(stats.reclaimed_bytes > 0);
+        assert_eq!(counter.finished.load(Ordering::SeqCst), 1);
+        assert!(data_file_count(&opts) < files_before);
+        check_merged(&engine);
+
+        // 只剩下列族的创建记录可以回收
+        let after = engine.space_stats().unwrap();
+        assert!(after.reclaimable_bytes < before.reclaimable_bytes);
+        assert!(engine.export_metrics().contains("rkv_merge_runs_total 1"));
+
+        // 合并之后继续写入，重新打开后数据不变
+        engine
+            .put(Bytes::from("key-9"), Bytes::from("value-1"))
+            .unwrap();
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        engine.set_merge_operator(Arc::new(Concat));
+        check_merged(&engine);
+        let cf = engine.create_cf("cf2", FamilyOptions::default()).unwrap();
+        cf.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_keeps_sequence() {
+        let mut opts = test_options("bitcask-rs-compaction-seq");
+        opts.file_size = 200;
+        // 移动的记录使用原来的序号加密
+        opts.encryption_key = Some([1u8; 32]);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..10 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-1"))
+                .unwrap();
+        }
+        for i in 0..5 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-2"))
+                .unwrap();
+        }
+        let latest_seq = engine.latest_seq();
+
+        // 移动的记录保留原来的序号，订阅者收不到合并产生的变更
+        let mut sub = engine.subscribe(latest_seq + 1).unwrap();
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)).unwrap(), None);
+        let stats = engine.merge().unwrap();
+        assert!(stats.relocated_records > 0);
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)).unwrap(), None);
+        let pos = engine.index.get(b"key-7".to_vec()).unwrap();
+        assert_eq!(engine.read_log_record(&pos).unwrap().seq, 8);
+        drop(sub);
+
+        // 重新打开后更早的序号仍然不能订阅
+        let latest_seq = engine.latest_seq();
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(engine.latest_seq(), latest_seq);
+        assert_eq!(engine.subscribe(8).err(), Some(Errors::SequenceNotRetained));
+        let mut sub = engine.subscribe(latest_seq + 1).unwrap();
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)).unwrap(), None);
+        drop(sub);
+        assert_eq!(
+            engine.get(Bytes::from("key-7")).unwrap(),
+            Bytes::from("value-1")
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_folds_operands_with_sequence() {
+        let mut opts = test_options("bitcask-rs-compaction-fold");
+        opts.file_size = 200;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.set_merge_operator(Arc::new(Concat));
+        engine.put(Bytes::from("m"), Bytes::from("base")).unwrap();
+        engine
+            .merge_value(Bytes::from("m"), Bytes::from("a"))
+            .unwrap();
+        engine
+            .merge_value(Bytes::from("m"), Bytes::from("b"))
+            .unwrap();
+        let latest_seq = engine.latest_seq();
+
+        // 合并之后的 value 使用最后一个操作数的序号，不分配新的序号，也不推送给订阅者
+        let mut sub = engine.subscribe(latest_seq + 1).unwrap();
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)).unwrap(), None);
+        engine.merge().unwrap();
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)).unwrap(), None);
+        let pos = engine.index.get(b"m".to_vec()).unwrap();
+        let logrecord = engine.read_log_record(&pos).unwrap();
+        assert_eq!(logrecord.record_type, LogRecordType::NORMAL);
+        assert_eq!(logrecord.seq, latest_seq);
+        assert!(engine.merge_chains.read().get(&b"m"[..]).is_none());
+        drop(sub);
+
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("m")).unwrap(),
+            Bytes::from("base,a,b")
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_rate_limit() {
+        let mut opts = test_options("bitcask-rs-compaction-rate-limit");
+        opts.background_io_rate = 100_000;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(engine.background_io_rate(), 100_000);
+        for i in 0..40 {
+            engine
+                .put(
+                    Bytes::from(format!("key-{:02}", i)),
+                    Bytes::from(vec![1u8; 2000]),
+                )
+                .unwrap();
+        }
+
+        // 重新写入约 160KB，超过桶中一秒配额的部分需要等待
+        let start = std::time::Instant::now();
+        assert_eq!(engine.merge().unwrap().relocated_records, 40);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        // 取消限速后合并不再等待
+        engine.set_background_io_rate(0);
+        let start = std::time::Instant::now();
+        assert_eq!(engine.merge().unwrap().relocated_records, 40);
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(
+            engine.get(Bytes::from("key-00")).unwrap(),
+            Bytes::from(vec![1u8; 2000])
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_window() {
+        let window = MergeWindow {
+            start_minute: 60,
+            end_minute: 120,
+        };
+        assert!(!window.contains(59));
+        assert!(window.contains(60));
+        assert!(!window.contains(120));
+
+        // 跨越零点
+        let window = MergeWindow {
+            start_minute: 22 * 60,
+            end_minute: 6 * 60,
+        };
+        assert!(window.contains(23 * 60));
+        assert!(window.contains(0));
+        assert!(!window.contains(12 * 60));
+    }
+
+    #[test]
+    fn test_merge_scheduler() {
+        let counter = Arc::new(MergeCounter::default());
+        let mut opts = test_options("bitcask-rs-compaction-scheduler");
+        opts.file_size = 100;
+        opts.event_listeners = vec![counter.clone()];
+        assert!(matches!(
+            MergeScheduler::start(Arc::new(Engine::open(opts.clone()).unwrap())),
+            Err(Errors::AutoMergeNotConfigured)
+        ));
+
+        opts.auto_merge = Some(AutoMerge {
+            ratio_threshold: 0.5,
+            min_reclaimable_bytes: 100,
+            check_interval_ms: 10,
+            window: None,
+        });
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        let scheduler = MergeScheduler::start(engine.clone()).unwrap();
+        scheduler.pause();
+        assert!(scheduler.is_paused());
+
+        for _ in 0..20 {
+            engine
+                .put(Bytes::from("key-0"), Bytes::from("value-1"))
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(counter.finished.load(Ordering::SeqCst), 0);
+
+        scheduler.resume();
+        for _ in 0..200 {
+            if counter.finished.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(counter.finished.load(Ordering::SeqCst) > 0);
+        assert!(engine.space_stats().unwrap().reclaimable_ratio() < 0.5);
+        assert_eq!(
+            engine.get(Bytes::from("key-0")).unwrap(),
+            Bytes::from("value-1")
+        );
+        drop(scheduler);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
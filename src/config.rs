@@ -0,0 +1,430 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use log::warn;
+use toml::{Table, Value};
+
+use crate::{
+    errors::{Errors, Result},
+    options::{AutoMerge, IOType, IndexType, MergeWindow, Options, OptionsBuilder, RecoveryMode},
+};
+
+/// 环境变量的前缀，环境变量名为前缀加上大写的配置项名称，`.` 替换为 `_`，
+/// 例如 auto_merge.ratio_threshold 对应 RKV_AUTO_MERGE_RATIO_THRESHOLD
+pub const ENV_PREFIX: &str = "RKV_";
+
+/// 可以从配置文件和环境变量中设置的配置项
+///
+/// 配置文件中 auto_merge 开头的配置项写在 [auto_merge] 表中，设置其中任意一项即启用自动合并，
+/// 其余项使用默认值。事件监听器只能在代码中设置。
+pub const CONFIG_KEYS: &[&str] = &[
+    "dir_path",
+    "file_size",
+    "sync",
+    "index_type",
+    "io_type",
+    "encryption_key",
+    "recovery_mode",
+    "bytes_per_sync",
+    "sync_interval_ms",
+    "preallocate",
+    "cache_capacity",
+    "background_io_rate",
+    "auto_merge.ratio_threshold",
+    "auto_merge.min_reclaimable_bytes",
+    "auto_merge.check_interval_ms",
+    "auto_merge.window",
+];
+
+impl Options {
+    /// 依次应用默认配置、配置文件（可选）和环境变量，后面的覆盖前面的，最后检查配置项
+    pub fn load(config_file: Option<&Path>) -> Result<Options> {
+        let mut builder = Options::builder();
+        if let Some(path) = config_file {
+            builder = builder.toml_file(path)?;
+        }
+        builder.env()?.build()
+    }
+}
+
+impl OptionsBuilder {
+    /// 应用 TOML 格式的配置，没有出现的配置项保持不变
+    pub fn toml(mut self, content: &str) -> Result<Self> {
+        let table = match content.parse::<Table>() {
+            Ok(table) => table,
+            Err(e) => {
+                warn!("failed to parse config: {}", e);
+                return Err(Errors::InvalidConfig);
+            }
+        };
+        for (key, value) in table.iter() {
+            match value {
+                Value::Table(table) if key == "auto_merge" => {
+                    for (sub_key, value) in table.iter() {
+                        self.set_toml(&format!("{}.{}", key, sub_key), value)?;
+                    }
+                }
+                _ => self.set_toml(key, value)?,
+            }
+        }
+        Ok(self)
+    }
+
+    /// 从文件中读取 TOML 格式的配置
+    pub fn toml_file(self, path: impl AsRef<Path>) -> Result<Self> {
+        match fs::read_to_string(path.as_ref()) {
+            Ok(content) => self.toml(&content),
+            Err(e) => {
+                warn!("failed to read config file {:?}: {}", path.as_ref(), e);
+                Err(Errors::FailedToReadConfig)
+            }
+        }
+    }
+
+    /// 应用当前进程环境变量中的配置
+    pub fn env(self) -> Result<Self> {
+        self.env_vars_os(std::env::vars_os())
+    }
+
+    /// 其他程序的环境变量可能不是 UTF-8，只有以 ENV_PREFIX 开头的变量不是 UTF-8 时返回错误
+    fn env_vars_os(self, vars: impl IntoIterator<Item = (OsString, OsString)>) -> Result<Self> {
+        let mut utf8_vars = Vec::new();
+        for (name, value) in vars {
+            if !name.to_string_lossy().starts_with(ENV_PREFIX) {
+                continue;
+            }
+            match (name.into_string(), value.into_string()) {
+                (Ok(name), Ok(value)) => utf8_vars.push((name, value)),
+                (name, _) => {
+                    warn!("config environment variable {:?} is not valid UTF-8", name);
+                    return Err(Errors::InvalidConfig);
+                }
+            }
+        }
+        self.env_vars(utf8_vars)
+    }
+
+    /// 应用给定的环境变量中的配置，不以 ENV_PREFIX 开头的变量被忽略
+    pub fn env_vars(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        for (name, value) in vars {
+            let name = match name.strip_prefix(ENV_PREFIX) {
+                Some(name) => name.to_ascii_lowercase(),
+                None => continue,
+            };
+            let key = CONFIG_KEYS.iter().find(|key| key.replace('.', "_") == name);
+            match key {
+                Some(key) => self.set(key, &value)?,
+                None => {
+                    warn!("unknown config environment variable {}{}", ENV_PREFIX, name);
+                    return Err(Errors::InvalidConfig);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    fn set_toml(&mut self, key: &str, value: &Value) -> Result<()> {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Boolean(b) => b.to_string(),
+            _ => {
+                warn!("invalid value for config {}: {:?}", key, value);
+                return Err(Errors::InvalidConfig);
+            }
+        };
+        self.set(key, &value)
+    }
+
+    // 按名称设置一个配置项，值为字符串形式
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let opts = &mut self.options;
+        let parsed = match key {
+            "dir_path" => {
+                opts.dir_path = value.into();
+                Some(())
+            }
+            "file_size" => parse_u64(value).map(|v| opts.file_size = v),
+            "sync" => parse_bool(value).map(|v| opts.sync = v),
+            "index_type" => parse_index_type(value).map(|v| opts.index_type = v),
+            "io_type" => parse_io_type(value).map(|v| opts.io_type = v),
+            "encryption_key" => match value {
+                "" => {
+                    opts.encryption_key = None;
+                    Some(())
+                }
+                _ => parse_encryption_key(value).map(|v| opts.encryption_key = Some(v)),
+            },
+            "recovery_mode" => parse_recovery_mode(value).map(|v| opts.recovery_mode = v),
+            "bytes_per_sync" => parse_u64(value).map(|v| opts.bytes_per_sync = v),
+            "sync_interval_ms" => parse_u64(value).map(|v| opts.sync_interval_ms = v),
+            "preallocate" => parse_bool(value).map(|v| opts.preallocate = v),
+            "cache_capacity" => parse_u64(value).map(|v| opts.cache_capacity = v),
+            "background_io_rate" => parse_u64(value).map(|v| opts.background_io_rate = v),
+            _ => match key.strip_prefix("auto_merge.") {
+                Some(sub_key) => {
+                    let auto_merge = opts.auto_merge.get_or_insert_with(AutoMerge::default);
+                    set_auto_merge(auto_merge, sub_key, value)
+                }
+                None => {
+                    warn!("unknown config {}", key);
+                    return Err(Errors::InvalidConfig);
+                }
+            },
+        };
+        match parsed {
+            Some(_) => Ok(()),
+            None => {
+                warn!("invalid value for config {}: {:?}", key, value);
+                Err(Errors::InvalidConfig)
+            }
+        }
+    }
+}
+
+fn set_auto_merge(auto_merge: &mut AutoMerge, key: &str, value: &str) -> Option<()> {
+    match key {
+        "ratio_threshold" => value
+            .parse::<f64>()
+            .ok()
+            .map(|v| auto_merge.ratio_threshold = v),
+        "min_reclaimable_bytes" => parse_u64(value).map(|v| auto_merge.min_reclaimable_bytes = v),
+        "check_interval_ms" => parse_u64(value).map(|v| auto_merge.check_interval_ms = v),
+        "window" => match value {
+            "" => {
+                auto_merge.window = None;
+                Some(())
+            }
+            _ => parse_merge_window(value).map(|v| auto_merge.window = Some(v)),
+        },
+        _ => None,
+    }
+}
+
+fn parse_u64(value: &str) -> Option<u64> {
+    value.trim().parse().ok()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_index_type(value: &str) -> Option<IndexType> {
+    match value.trim() {
+        "btree" => Some(IndexType::BTree),
+        "skiplist" => Some(IndexType::SkipList),
+        _ => None,
+    }
+}
+
+fn parse_io_type(value: &str) -> Option<IOType> {
+    match value.trim() {
+        "standard" => Some(IOType::StandardFIO),
+        "direct" => Some(IOType::DirectIO),
+        "io_uring" => Some(IOType::IoUring),
+        "memory" => Some(IOType::Memory),
+        _ => None,
+    }
+}
+
+fn parse_recovery_mode(value: &str) -> Option<RecoveryMode> {
+    match value.trim() {
+        "truncate_tail" => Some(RecoveryMode::TruncateTail),
+        "strict" => Some(RecoveryMode::Strict),
+        _ => None,
+    }
+}
+
+/// 解析 64 个十六进制字符表示的加密密钥
+pub fn parse_encryption_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+// 解析 HH:MM-HH:MM 格式的时间段
+fn parse_merge_window(value: &str) -> Option<MergeWindow> {
+    let (start, end) = value.trim().split_once('-')?;
+    Some(MergeWindow {
+        start_minute: parse_minute(start)?,
+        end_minute: parse_minute(end)?,
+    })
+}
+
+fn parse_minute(value: &str) -> Option<u32> {
+    let (hour, minute) = value.trim().split_once(':')?;
+    let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+    match hour < 24 && minute < 60 {
+        true => Some(hour * 60 + minute),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_builder() {
+        let opts = Options::builder()
+            .dir_path("/tmp/bitcask-rs-builder")
+            .file_size(1024)
+            .bytes_per_sync(4096)
+            .build()
+            .unwrap();
+        assert_eq!(opts.file_size, 1024);
+        assert_eq!(opts.bytes_per_sync, 4096);
+        assert_eq!(opts.index_type, IndexType::BTree);
+
+        let invalid = [
+            (Options::builder().dir_path(""), Errors::DirPathIsEmpty),
+            (Options::builder().file_size(0), Errors::FileSizeTooSmall),
+            (Options::builder().file_size(29), Errors::FileSizeTooSmall),
+            (
+                Options::builder().file_size(u64::MAX),
+                Errors::FileSizeTooLarge,
+            ),
+            (
+                Options::builder().io_type(IOType::Memory).preallocate(true),
+                Errors::PreallocateWithMemoryIO,
+            ),
+            (
+                Options::builder().sync(true).sync_interval_ms(10),
+                Errors::ConflictingSyncOptions,
+            ),
+            (
+                Options::builder().index_type(IndexType::SkipList),
+                Errors::IndexTypeUnsupported,
+            ),
+            (
+                Options::builder().auto_merge(Some(AutoMerge {
+                    ratio_threshold: 1.5,
+                    ..AutoMerge::default()
+                })),
+                Errors::InvalidAutoMergeOptions,
+            ),
+            (
+                Options::builder().auto_merge(Some(AutoMerge {
+                    window: Some(MergeWindow {
+                        start_minute: 60,
+                        end_minute: 60,
+                    }),
+                    ..AutoMerge::default()
+                })),
+                Errors::InvalidAutoMergeOptions,
+            ),
+        ];
+        for (builder, err) in invalid {
+            assert_eq!(builder.build().err(), Some(err));
+        }
+    }
+
+    #[test]
+    fn test_options_from_toml_and_env() {
+        let config = r#"
+            dir_path = "/var/lib/rkv"
+            file_size = 1048576
+            io_type = "io_uring"
+            recovery_mode = "strict"
+            encryption_key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+
+            [auto_merge]
+            ratio_threshold = 0.3
+            window = "22:00-06:00"
+        "#;
+        let builder = Options::builder().toml(config).unwrap();
+        let opts = builder
+            .env_vars(vec![
+                ("RKV_FILE_SIZE".to_string(), "4096".to_string()),
+                (
+                    "RKV_AUTO_MERGE_CHECK_INTERVAL_MS".to_string(),
+                    "1000".to_string(),
+                ),
+                ("PATH".to_string(), "/usr/bin".to_string()),
+            ])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(opts.dir_path, Path::new("/var/lib/rkv"));
+        assert_eq!(opts.file_size, 4096);
+        assert_eq!(opts.io_type, IOType::IoUring);
+        assert_eq!(opts.recovery_mode, RecoveryMode::Strict);
+        assert_eq!(opts.encryption_key.unwrap()[31], 0x1f);
+        assert_eq!(
+            opts.auto_merge,
+            Some(AutoMerge {
+                ratio_threshold: 0.3,
+                check_interval_ms: 1000,
+                window: Some(MergeWindow {
+                    start_minute: 22 * 60,
+                    end_minute: 6 * 60,
+                }),
+                ..AutoMerge::default()
+            })
+        );
+
+        // 未知的配置项和非法的值
+        for config in [
+            "file_sise = 10",
+            "file_size = -1",
+            "sync = \"yes\"",
+            "io_type = \"mmap\"",
+            "[auto_merge]\nwindow = \"25:00-01:00\"",
+            "[index]\ntype = \"btree\"",
+        ] {
+            assert_eq!(
+                Options::builder().toml(config).err(),
+                Some(Errors::InvalidConfig)
+            );
+        }
+        assert_eq!(
+            Options::builder()
+                .env_vars(vec![("RKV_SYNCC".to_string(), "true".to_string())])
+                .err(),
+            Some(Errors::InvalidConfig)
+        );
+
+        // 只有 ENV_PREFIX 开头的环境变量需要是 UTF-8
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+
+            let invalid = || OsString::from_vec(vec![0xff, 0xfe]);
+            let opts = Options::builder()
+                .env_vars_os(vec![
+                    (invalid(), invalid()),
+                    (OsString::from("LANG"), invalid()),
+                    (OsString::from("RKV_FILE_SIZE"), OsString::from("4096")),
+                ])
+                .unwrap()
+                .build()
+                .unwrap();
+            assert_eq!(opts.file_size, 4096);
+            assert_eq!(
+                Options::builder()
+                    .env_vars_os(vec![(OsString::from("RKV_DIR_PATH"), invalid())])
+                    .err(),
+                Some(Errors::InvalidConfig)
+            );
+        }
+
+        // 配置文件中的配置项组合不合法时 build 失败
+        assert_eq!(
+            Options::builder()
+                .toml("sync = true\nbytes_per_sync = 100")
+                .unwrap()
+                .build()
+                .err(),
+            Some(Errors::ConflictingSyncOptions)
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use prost::{decode_length_delimiter, encode_length_delimiter};
+
+use crate::errors::{Errors, Result};
+
+use super::log_record::{LogRecord, LogRecordType};
+
+/// XChaCha20-Poly1305 nonce 长度
+const NONCE_SIZE: usize = 24;
+
+/// 数据文件记录加密器，使用 XChaCha20-Poly1305 对每条记录的 key 和 value 进行认证加密
+///
+/// 每条记录使用操作系统随机数生成独立的 192 位 nonce，并随记录一起存储。
+/// 192 位的随机 nonce 空间足够大，不需要在文件之间协调计数器，
+/// merge 等重写数据的场景也不会出现 nonce 复用。
+/// 明文存储的序号、记录类型和列族作为关联数据参与认证，
+/// 记录被替换到其他序号或列族时解密失败。
+pub struct RecordCipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl RecordCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        RecordCipher {
+            aead: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// 加密一条记录，返回可以直接编码写入数据文件的 ENCRYPTED 类型记录
+    ///
+    /// 加密后记录的 key 为 nonce，value 为密文：
+    ///  +--------+---------------+--------+--------+
+    ///  |  type  |   key size    |  key   | value  |
+    ///  +--------+---------------+--------+--------+
+    ///    1 字节    变长(最大5)     变长      变长
+    pub fn seal(&self, record: &LogRecord) -> Result<LogRecord> {
+        let mut plain = BytesMut::new();
+        plain.put_u8(record.record_type as u8);
+        encode_length_delimiter(record.key.len(), &mut plain).unwrap();
+        plain.extend_from_slice(&record.key);
+        plain.extend_from_slice(&record.value);
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(record.seq, record.cf);
+        let payload = Payload {
+            msg: plain.as_ref(),
+            aad: &aad,
+        };
+        let cipher_text = match self.aead.encrypt(&nonce, payload) {
+            Ok(c) => c,
+            Err(_) => return Err(Errors::FailedToEncryptLogRecord),
+        };
+
+        Ok(LogRecord {
+            key: nonce.to_vec(),
+            value: cipher_text,
+            record_type: LogRecordType::ENCRYPTED,
+            seq: record.seq,
+            cf: record.cf,
+        })
+    }
+
+    /// 解密一条 ENCRYPTED 类型的记录，还原出原始记录
+    pub fn open(&self, record: &LogRecord) -> Result<LogRecord> {
+        if record.key.len() != NONCE_SIZE {
+            return Err(Errors::FailedToDecryptLogRecord);
+        }
+        let nonce = XNonce::from_slice(&record.key);
+        let aad = associated_data(record.seq, record.cf);
+        let payload = Payload {
+            msg: record.value.as_ref(),
+            aad: &aad,
+        };
+        let plain = match self.aead.decrypt(nonce, payload) {
+            Ok(p) => p,
+            Err(_) => return Err(Errors::FailedToDecryptLogRecord),
+        };
+
+        let mut buf = plain.as_slice();
+        if !buf.has_remaining() {
+            return Err(Errors::FailedToDecryptLogRecord);
+        }
+        let record_type = match LogRecordType::from_u8(buf.get_u8()) {
+            Some(t) if t != LogRecordType::ENCRYPTED => t,
+            _ => return Err(Errors::FailedToDecryptLogRecord),
+        };
+        let key_size = match decode_length_delimiter(&mut buf) {
+            Ok(n) if n <= buf.len() => n,
+            _ => return Err(Errors::FailedToDecryptLogRecord),
+        };
+
+        Ok(LogRecord {
+            key: buf[..key_size].to_vec(),
+            value: buf[key_size..].to_vec(),
+            record_type,
+            seq: record.seq,
+            cf: record.cf,
+        })
+    }
+}
+
+// 关联数据：序号 | 记录类型 | 列族
+fn associated_data(seq: u64, cf: u32) -> Vec<u8> {
+    let mut aad = BytesMut::with_capacity(13);
+    aad.put_u64(seq);
+    aad.put_u8(LogRecordType::ENCRYPTED as u8);
+    aad.put_u32(cf);
+    aad.to_vec()
+}
+
+/// 还原从数据文件中读取的记录，加密的记录需要先解密
+pub fn decode_log_record(cipher: Option<&RecordCipher>, record: LogRecord) -> Result<LogRecord> {
+    match (record.record_type, cipher) {
+        (LogRecordType::ENCRYPTED, Some(cipher)) => cipher.open(&record),
+        (LogRecordType::ENCRYPTED, None) => Err(Errors::EncryptionKeyRequired),
+        (_, Some(_)) => Err(Errors::UnencryptedLogRecord),
+        (_, None) => Ok(record),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let cipher = RecordCipher::new(&[7u8; 32]);
+        let rec = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "bitcask-rs".as_bytes().to_vec(),
+            record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: 0,
+        };
+
+        let sealed1 = cipher.seal(&rec).unwrap();
+        let sealed2 = cipher.seal(&rec).unwrap();
+        assert_eq!(sealed1.record_type, LogRecordType::ENCRYPTED);
+        // 同一条记录每次加密使用不同的 nonce
+        assert_ne!(sealed1.key, sealed2.key);
+        assert_ne!(sealed1.value, sealed2.value);
+
+        assert_eq!(cipher.open(&sealed1).unwrap(), rec);
+        assert_eq!(cipher.open(&sealed2).unwrap(), rec);
+    }
+
+    #[test]
+    fn test_open_with_wrong_key() {
+        let cipher = RecordCipher::new(&[7u8; 32]);
+        let rec = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::DELETE,
+            seq: 0,
+            cf: 0,
+        };
+        let sealed = cipher.seal(&rec).unwrap();
+
+        let wrong = RecordCipher::new(&[8u8; 32]);
+        assert_eq!(
+            wrong.open(&sealed).unwrap_err(),
+            Errors::FailedToDecryptLogRecord
+        );
+
+        // 密文被篡改
+        let mut tampered = sealed.clone();
+        tampered.value[0] ^= 1;
+        assert_eq!(
+            cipher.open(&tampered).unwrap_err(),
+            Errors::FailedToDecryptLogRecord
+        );
+
+        // 记录被替换到其他序号或列族
+        let mut replayed = sealed.clone();
+        replayed.seq += 1;
+        assert_eq!(
+            cipher.open(&replayed).unwrap_err(),
+            Errors::FailedToDecryptLogRecord
+        );
+        let mut moved = sealed.clone();
+        moved.cf = 1;
+        assert_eq!(
+            cipher.open(&moved).unwrap_err(),
+            Errors::FailedToDecryptLogRecord
+        );
+    }
+}
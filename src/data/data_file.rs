@@ -1,10 +1,23 @@
 use std::{path::PathBuf, sync::Arc};
 
+use bytes::{Buf, BytesMut};
 use parking_lot::RwLock;
+use prost::{
+    decode_length_delimiter,
+    encoding::{decode_varint, encoded_len_varint},
+    length_delimiter_len,
+};
 
-use crate::{errors::Result, fio::IOManager};
+use crate::{
+    errors::{Errors, Result},
+    fio::{self, IOManager},
+    options::IOType,
+};
 
-use super::log_record::{LogRecord, ReadLogRecord};
+use super::log_record::{
+    family_id_len, max_log_record_header_size, LogRecord, LogRecordHeader, LogRecordType,
+    ReadLogRecord, FAMILY_FLAG,
+};
 
 pub const DATA_FILE_NAME_SUFFIX: &str = ".data";
 
@@ -12,12 +25,21 @@ pub struct DataFile {
     pub file_id: Arc<RwLock<u32>>,      // 文件 ID
     pub write_offset: Arc<RwLock<u64>>, // 写入偏移, 记录当前写到了文件的哪个位置
     pub io_manager: Box<dyn IOManager>, // IO 管理器
+    size: RwLock<u64>, // 文件大小，随写入、预分配和截断更新，读取时用于检查记录边界，避免每次读取都获取文件元数据
 }
 
 impl DataFile {
     /// 创建或打开一个数据文件
-    pub fn new(file_id: u32, dir_path: PathBuf) -> Result<DataFile> {
-        todo!()
+    pub fn new(file_id: u32, dir_path: PathBuf, io_type: IOType) -> Result<DataFile> {
+        let file_name = get_data_file_name(dir_path, file_id);
+        let io_manager = fio::new_io_manager(file_name, io_type)?;
+        let size = io_manager.size()?;
+        Ok(DataFile {
+            file_id: Arc::new(RwLock::new(file_id)),
+            write_offset: Arc::new(RwLock::new(0)),
+            io_manager,
+            size: RwLock::new(size),
+        })
     }
 
     pub fn get_write_offset(&self) -> u64 {
@@ -37,14 +59,372 @@ impl DataFile {
 
     /// 读取日志记录
     pub fn read_log_record(&self, offset: u64) -> Result<ReadLogRecord> {
-        todo!()
+        let header = self.read_log_record_header(offset)?;
+
+        // 读取实际的 key 和 value，最后 4 个字节是 crc 校验值
+        let mut kv_buf = BytesMut::zeroed(header.key_size + header.value_size + 4);
+        let n = self
+            .io_manager
+            .read(&mut kv_buf, offset + header.header_size as u64)?;
+        decode_log_record_body(header, kv_buf, n)
+    }
+
+    /// 批量读取多条日志记录，先在一次批量读取中读出所有 header，再读出所有的 key 和 value
+    pub fn read_log_records(&self, offsets: &[u64]) -> Vec<Result<ReadLogRecord>> {
+        let mut header_bufs: Vec<BytesMut> = offsets
+            .iter()
+            .map(|_| BytesMut::zeroed(max_log_record_header_size()))
+            .collect();
+        let mut requests: Vec<(u64, &mut [u8])> = offsets
+            .iter()
+            .zip(header_bufs.iter_mut())
+            .map(|(offset, buf)| (*offset, buf.as_mut()))
+            .collect();
+        if let Err(e) = self.io_manager.read_batch(&mut requests) {
+            return offsets.iter().map(|_| Err(e.clone())).collect();
+        }
+        let file_size = *self.size.read();
+        let headers: Vec<Result<LogRecordHeader>> = header_bufs
+            .into_iter()
+            .zip(offsets)
+            .map(|(buf, offset)| {
+                decode_log_record_header(buf)
+                    .and_then(|header| check_record_bounds(header, *offset, file_size))
+            })
+            .collect();
+
+        let mut kv_bufs: Vec<BytesMut> = headers
+            .iter()
+            .map(|header| match header {
+                Ok(header) => BytesMut::zeroed(header.key_size + header.value_size + 4),
+                Err(_) => BytesMut::new(),
+            })
+            .collect();
+        let mut requests: Vec<(u64, &mut [u8])> = offsets
+            .iter()
+            .zip(headers.iter())
+            .zip(kv_bufs.iter_mut())
+            .filter_map(|((offset, header), buf)| match header {
+                Ok(header) => Some((offset + header.header_size as u64, buf.as_mut())),
+                Err(_) => None,
+            })
+            .collect();
+        let mut sizes = match self.io_manager.read_batch(&mut requests) {
+            Ok(sizes) => sizes.into_iter(),
+            Err(e) => return offsets.iter().map(|_| Err(e.clone())).collect(),
+        };
+
+        headers
+            .into_iter()
+            .zip(kv_bufs)
+            .map(|(header, kv_buf)| {
+                let header = header?;
+                let n = sizes.next().unwrap_or(0);
+                decode_log_record_body(header, kv_buf, n)
+            })
+            .collect()
+    }
+
+    /// 读取日志记录的 header 部分
+    pub fn read_log_record_header(&self, offset: u64) -> Result<LogRecordHeader> {
+        let mut header_buf = BytesMut::zeroed(max_log_record_header_size());
+        self.io_manager.read(&mut header_buf, offset)?;
+        let header = decode_log_record_header(header_buf)?;
+        check_record_bounds(header, offset, *self.size.read())
+    }
+
+    /// offset 之后的任意位置能否解析出一条完整并且 crc 正确的记录
+    ///
+    /// 用于区分写入中断留下的不完整末尾和文件中间的损坏，后者不能截断。
+    pub fn has_valid_record_after(&self, offset: u64) -> Result<bool> {
+        let file_size = self.file_size()?;
+        if offset >= file_size {
+            return Ok(false);
+        }
+        let mut buf = vec![0u8; (file_size - offset) as usize];
+        let mut n = 0;
+        while n < buf.len() {
+            match self.io_manager.read(&mut buf[n..], offset + n as u64)? {
+                0 => break,
+                m => n += m,
+            }
+        }
+        buf.truncate(n);
+
+        for start in 1..buf.len() {
+            // 大部分位置的第一个字节不是合法的记录类型，直接跳过
+            if LogRecordType::from_u8(buf[start] & !FAMILY_FLAG).is_none() {
+                continue;
+            }
+            if decode_log_record_at(&buf[start..]).is_ok() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// 数据文件的实际大小，会获取文件元数据
+    pub fn file_size(&self) -> Result<u64> {
+        self.io_manager.size()
+    }
+
+    /// 在写入偏移处依次写入多条记录，返回写入的总字节数
+    ///
+    /// 预分配的文件大小和写入偏移不一致，所以总是按写入偏移写入
+    pub fn write_batch(&self, bufs: &[Vec<u8>]) -> Result<usize> {
+        self.write_batch_with(bufs, false)
+    }
+
+    /// 写入多条记录并持久化，支持的 IO 类型中写入和 fsync 在同一次提交中完成
+    pub fn write_batch_sync(&self, bufs: &[Vec<u8>]) -> Result<usize> {
+        self.write_batch_with(bufs, true)
     }
 
-    pub fn write(&self, buf: &[u8]) -> Result<usize> {
-        todo!()
+    fn write_batch_with(&self, bufs: &[Vec<u8>], sync: bool) -> Result<usize> {
+        let bufs: Vec<&[u8]> = bufs.iter().map(|buf| buf.as_slice()).collect();
+        let mut write_offset_guard = self.write_offset.write();
+        let n_bytes = match sync {
+            true => self
+                .io_manager
+                .write_batch_sync(&bufs, *write_offset_guard)?,
+            false => self.io_manager.write_batch(&bufs, *write_offset_guard)?,
+        };
+        *write_offset_guard += n_bytes as u64;
+        let mut size_guard = self.size.write();
+        *size_guard = (*size_guard).max(*write_offset_guard);
+        Ok(n_bytes)
     }
 
     pub fn sync(&self) -> Result<()> {
-        todo!()
+        self.io_manager.sync()
+    }
+
+    /// 预先分配 size 大小的磁盘空间，预分配的部分为 0，读取时当作文件末尾
+    pub fn preallocate(&self, size: u64) -> Result<()> {
+        self.io_manager.allocate(size)?;
+        let mut size_guard = self.size.write();
+        *size_guard = (*size_guard).max(size);
+        Ok(())
+    }
+
+    /// 将数据文件截断到指定大小，并更新写入偏移
+    pub fn truncate(&self, size: u64) -> Result<()> {
+        self.io_manager.truncate(size)?;
+        *self.size.write() = size;
+        self.set_write_offset(size);
+        Ok(())
+    }
+}
+
+// 从 header_buf 中解析 header，全部为 0 时说明读到了文件末尾
+fn decode_log_record_header(mut header_buf: BytesMut) -> Result<LogRecordHeader> {
+    // 取出 type，在第一个字节
+    let record_type = header_buf.get_u8();
+
+    // 取出序号、列族 id 以及 key 和 value 的长度
+    let seq = decode_varint(&mut header_buf);
+    let cf = match record_type & FAMILY_FLAG {
+        0 => Ok(0),
+        _ => decode_varint(&mut header_buf),
+    };
+    let key_size = decode_length_delimiter(&mut header_buf);
+    let value_size = decode_length_delimiter(&mut header_buf);
+    let (seq, cf, key_size, value_size) = match (seq, cf, key_size, value_size) {
+        (Ok(s), Ok(c), Ok(k), Ok(v)) if c <= u32::MAX as u64 => (s, c as u32, k, v),
+        _ => return Err(Errors::InvalidLogRecordHeader),
+    };
+
+    // 全部为空，说明读到了文件末尾
+    if record_type == 0 && seq == 0 && key_size == 0 && value_size == 0 {
+        return Err(Errors::ReadDataFileEOF);
+    }
+
+    let record_type = match LogRecordType::from_u8(record_type & !FAMILY_FLAG) {
+        Some(t) => t,
+        None => return Err(Errors::InvalidLogRecordHeader),
+    };
+
+    // 损坏的长度可能非常大，求和时不能溢出
+    let header_size = 1
+        + encoded_len_varint(seq)
+        + family_id_len(cf)
+        + length_delimiter_len(key_size)
+        + length_delimiter_len(value_size);
+    let record_size = header_size
+        .checked_add(key_size)
+        .and_then(|n| n.checked_add(value_size))
+        .and_then(|n| n.checked_add(4));
+    let record_size = match record_size {
+        Some(n) => n as u64,
+        None => return Err(Errors::InvalidLogRecordHeader),
+    };
+
+    Ok(LogRecordHeader {
+        record_type,
+        seq,
+        cf,
+        key_size,
+        value_size,
+        header_size,
+        record_size,
+    })
+}
+
+// 记录不能超出文件末尾，避免按损坏的长度分配内存
+fn check_record_bounds(
+    header: LogRecordHeader,
+    offset: u64,
+    file_size: u64,
+) -> Result<LogRecordHeader> {
+    match header.record_size <= file_size.saturating_sub(offset) {
+        true => Ok(header),
+        false => Err(Errors::InvalidLogRecordHeader),
+    }
+}
+
+// 从 kv_buf 中解析 key 和 value 并校验 crc，n 为实际读取的字节数
+fn decode_log_record_body(
+    header: LogRecordHeader,
+    mut kv_buf: BytesMut,
+    n: usize,
+) -> Result<ReadLogRecord> {
+    if n < kv_buf.len() {
+        return Err(Errors::InvalidLogRecordCrc);
+    }
+    let (key_size, value_size) = (header.key_size, header.value_size);
+
+    let record = LogRecord {
+        key: kv_buf[..key_size].to_vec(),
+        value: kv_buf[key_size..key_size + value_size].to_vec(),
+        record_type: header.record_type,
+        seq: header.seq,
+        cf: header.cf,
+    };
+
+    // 校验 crc
+    kv_buf.advance(key_size + value_size);
+    if kv_buf.get_u32() != record.get_crc() {
+        return Err(Errors::InvalidLogRecordCrc);
+    }
+
+    Ok(ReadLogRecord {
+        record,
+        size: header.record_size(),
+    })
+}
+
+// 从内存中的数据解析一条完整的记录
+fn decode_log_record_at(buf: &[u8]) -> Result<ReadLogRecord> {
+    let mut header_buf = BytesMut::zeroed(max_log_record_header_size());
+    let n = header_buf.len().min(buf.len());
+    header_buf[..n].copy_from_slice(&buf[..n]);
+    let header = check_record_bounds(decode_log_record_header(header_buf)?, 0, buf.len() as u64)?;
+    let kv_buf = BytesMut::from(&buf[header.header_size..header.record_size as usize]);
+    let n = kv_buf.len();
+    decode_log_record_body(header, kv_buf, n)
+}
+
+/// 获取数据文件名称，格式为 {id}.data
+pub fn get_data_file_name(dir_path: PathBuf, file_id: u32) -> PathBuf {
+    let name = format!("{:09}", file_id) + DATA_FILE_NAME_SUFFIX;
+    dir_path.join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bytes::BufMut;
+    use prost::encoding::encode_varint;
+
+    use super::*;
+
+    #[test]
+    fn test_new_data_file() {
+        let dir_path = std::env::temp_dir().join("bitcask-rs-data-file-new");
+        fs::create_dir_all(dir_path.clone()).unwrap();
+
+        let data_file = DataFile::new(0, dir_path.clone(), IOType::StandardFIO);
+        assert!(data_file.is_ok());
+        let data_file = data_file.unwrap();
+        assert_eq!(data_file.get_file_id(), 0);
+        assert_eq!(data_file.get_write_offset(), 0);
+
+        fs::remove_dir_all(dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_log_record() {
+        let dir_path = std::env::temp_dir().join("bitcask-rs-data-file-read");
+        fs::create_dir_all(dir_path.clone()).unwrap();
+        let data_file = DataFile::new(1, dir_path.clone(), IOType::StandardFIO).unwrap();
+
+        let rec1 = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "bitcask-rs".as_bytes().to_vec(),
+            record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: 0,
+        };
+        let rec2 = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::DELETE,
+            seq: 7,
+            cf: 300,
+        };
+        let enc1 = rec1.encode();
+        let enc2 = rec2.encode();
+        data_file
+            .write_batch(&[enc1.clone(), enc2.clone()])
+            .unwrap();
+        assert_eq!(
+            data_file.get_write_offset(),
+            (enc1.len() + enc2.len()) as u64
+        );
+
+        let read1 = data_file.read_log_record(0).unwrap();
+        assert!(read1.record == rec1);
+        assert_eq!(read1.size, enc1.len() as u64);
+
+        let read2 = data_file.read_log_record(read1.size).unwrap();
+        assert!(read2.record == rec2);
+        assert_eq!(read2.size, enc2.len() as u64);
+
+        let eof = data_file.read_log_record(read1.size + read2.size);
+        assert_eq!(eof.err(), Some(Errors::ReadDataFileEOF));
+
+        fs::remove_dir_all(dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_corrupted_record_size() {
+        let dir_path = std::env::temp_dir().join("bitcask-rs-data-file-corrupted-size");
+        let _ = fs::remove_dir_all(dir_path.clone());
+        fs::create_dir_all(dir_path.clone()).unwrap();
+
+        // 损坏的 key 长度会导致求和溢出或者分配巨大的内存
+        for key_size in [u64::MAX, 1 << 40, 100] {
+            let data_file =
+                DataFile::new(key_size as u32, dir_path.clone(), IOType::StandardFIO).unwrap();
+            let mut buf = BytesMut::new();
+            buf.put_u8(LogRecordType::NORMAL as u8);
+            encode_varint(1, &mut buf);
+            encode_varint(key_size, &mut buf);
+            encode_varint(0, &mut buf);
+            buf.extend_from_slice(&[1u8; 20]);
+            data_file.write_batch(&[buf.to_vec()]).unwrap();
+
+            assert_eq!(
+                data_file.read_log_record(0).err(),
+                Some(Errors::InvalidLogRecordHeader)
+            );
+            assert_eq!(
+                data_file.read_log_records(&[0])[0].as_ref().err(),
+                Some(&Errors::InvalidLogRecordHeader)
+            );
+        }
+
+        fs::remove_dir_all(dir_path).unwrap();
     }
 }
@@ -1,28 +1,138 @@
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+use bytes::{BufMut, BytesMut};
+use prost::{
+    encode_length_delimiter,
+    encoding::{encode_varint, encoded_len_varint},
+    length_delimiter_len,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct LogRecordPos {
     pub(crate) file_id: u32,
     pub(crate) offset: u64,
 }
 
-#[derive(PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum LogRecordType {
     // 正常记录
     NORMAL = 1,
     // 删除记录
     DELETE = 2,
+    // 加密记录，真实类型和 key/value 都保存在密文中
+    ENCRYPTED = 3,
+    // 大 value 的一个分块，不建立索引
+    CHUNK = 4,
+    // 大 value 的分块清单，索引指向该记录
+    MANIFEST = 5,
+    // 创建列族，key 为列族名称，value 为列族配置
+    FAMILY = 6,
+    // 删除列族，之前属于该列族的记录全部失效
+    DROPFAMILY = 7,
+    // 原子批量写入的开始
+    BATCHBEGIN = 8,
+    // 原子批量写入的结束，之前的批量记录全部生效
+    BATCHFINISHED = 9,
+    // 合并操作数，读取时和之前的 value 一起交给合并算子计算
+    MERGE = 10,
+}
+
+/// type 字节的最高位表示 header 中带有列族 id，默认列族的记录不带列族 id
+pub(crate) const FAMILY_FLAG: u8 = 0x80;
+
+impl LogRecordType {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(LogRecordType::NORMAL),
+            2 => Some(LogRecordType::DELETE),
+            3 => Some(LogRecordType::ENCRYPTED),
+            4 => Some(LogRecordType::CHUNK),
+            5 => Some(LogRecordType::MANIFEST),
+            6 => Some(LogRecordType::FAMILY),
+            7 => Some(LogRecordType::DROPFAMILY),
+            8 => Some(LogRecordType::BATCHBEGIN),
+            9 => Some(LogRecordType::BATCHFINISHED),
+            10 => Some(LogRecordType::MERGE),
+            _ => None,
+        }
+    }
 }
 
 /// 写入到日志文件的记录
 /// 之所以叫日志，是因为数据文件中的数据是追加写入的
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct LogRecord {
     pub(crate) key: Vec<u8>,
     pub(crate) value: Vec<u8>,
     pub(crate) record_type: LogRecordType,
+    pub(crate) seq: u64, // 序号，由 append_log_record 在写入时分配
+    pub(crate) cf: u32,  // 所属列族的 id，0 为默认列族
 }
 
 impl LogRecord {
+    /// 对 LogRecord 进行编码
+    ///
+    ///  +--------+-------------+-------------+------------+------------+--------+--------+---------+
+    ///  |  type  |     seq     |  family id  |  key size  | value size |  key   | value  |   crc   |
+    ///  +--------+-------------+-------------+------------+------------+--------+--------+---------+
+    ///    1 字节   变长(最大10)   变长(最大5)    变长(最大5)   变长(最大5)    变长     变长     4 字节
+    ///
+    /// 只有 type 带有 FAMILY_FLAG 时才有 family id
     pub fn encode(&self) -> Vec<u8> {
-        todo!()
+        let mut buf = BytesMut::with_capacity(self.encoded_length());
+        match self.cf {
+            0 => {
+                buf.put_u8(self.record_type as u8);
+                encode_varint(self.seq, &mut buf);
+            }
+            cf => {
+                buf.put_u8(self.record_type as u8 | FAMILY_FLAG);
+                encode_varint(self.seq, &mut buf);
+                encode_varint(cf as u64, &mut buf);
+            }
+        }
+        encode_length_delimiter(self.key.len(), &mut buf).unwrap();
+        encode_length_delimiter(self.value.len(), &mut buf).unwrap();
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+
+        // 计算并存储 CRC 校验值
+        let crc = crc32fast::hash(&buf);
+        buf.put_u32(crc);
+        buf.to_vec()
+    }
+
+    /// 计算 LogRecord 的 CRC 校验值，不包含末尾的 crc 字段
+    pub fn get_crc(&self) -> u32 {
+        let encoded = self.encode();
+        crc32fast::hash(&encoded[..encoded.len() - 4])
+    }
+
+    fn encoded_length(&self) -> usize {
+        1 + encoded_len_varint(self.seq)
+            + family_id_len(self.cf)
+            + length_delimiter_len(self.key.len())
+            + length_delimiter_len(self.value.len())
+            + self.key.len()
+            + self.value.len()
+            + 4
+    }
+}
+
+// 日志记录的 header 信息
+pub struct LogRecordHeader {
+    pub(crate) record_type: LogRecordType,
+    pub(crate) seq: u64,
+    pub(crate) cf: u32,
+    pub(crate) key_size: usize,
+    pub(crate) value_size: usize,
+    pub(crate) header_size: usize,
+    pub(crate) record_size: u64, // 整条记录编码后的大小，包含末尾的 crc
+}
+
+impl LogRecordHeader {
+    /// 整条记录编码后的大小，包含末尾的 crc
+    pub fn record_size(&self) -> u64 {
+        self.record_size
     }
 }
 
@@ -31,3 +141,68 @@ pub struct ReadLogRecord {
     pub(crate) record: LogRecord,
     pub(crate) size: u64,
 }
+
+/// LogRecord header 部分的最大长度
+pub fn max_log_record_header_size() -> usize {
+    std::mem::size_of::<u8>()
+        + encoded_len_varint(u64::MAX)
+        + encoded_len_varint(u32::MAX as u64)
+        + length_delimiter_len(u32::MAX as usize) * 2
+}
+
+/// header 中 family id 的长度，默认列族不占用空间
+pub(crate) fn family_id_len(cf: u32) -> usize {
+    match cf {
+        0 => 0,
+        cf => encoded_len_varint(cf as u64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_record_encode() {
+        let rec = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "bitcask-rs".as_bytes().to_vec(),
+            record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: 0,
+        };
+        let enc = rec.encode();
+        assert_eq!(enc.len(), 1 + 1 + 1 + 1 + 4 + 10 + 4);
+        assert_eq!(enc[0], LogRecordType::NORMAL as u8);
+        assert_eq!(enc[1], 0);
+        assert_eq!(enc[2], 4);
+        assert_eq!(enc[3], 10);
+
+        let crc = u32::from_be_bytes(enc[enc.len() - 4..].try_into().unwrap());
+        assert_eq!(crc, rec.get_crc());
+
+        // value 为空的删除记录
+        let rec = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::DELETE,
+            seq: 0,
+            cf: 0,
+        };
+        let enc = rec.encode();
+        assert_eq!(enc.len(), 1 + 1 + 1 + 1 + 4 + 4);
+        assert_eq!(enc[0], LogRecordType::DELETE as u8);
+
+        // 非默认列族的记录带有列族 id
+        let rec = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::DELETE,
+            seq: 0,
+            cf: 300,
+        };
+        let enc = rec.encode();
+        assert_eq!(enc.len(), 1 + 1 + 2 + 1 + 1 + 4 + 4);
+        assert_eq!(enc[0], LogRecordType::DELETE as u8 | FAMILY_FLAG);
+    }
+}
@@ -1,52 +1,93 @@
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc, u32};
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use log::warn;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use crate::{
+    cache::ValueCache,
+    cdc::SUBSCRIBER_BUFFER_SIZE,
+    commit::{GroupCommit, SyncWorker},
     data::{
-        data_file::{DataFile, DATA_FILE_NAME_SUFFIX},
-        log_record::{LogRecord, LogRecordPos, LogRecordType},
+        cipher::{self, RecordCipher},
+        data_file::{get_data_file_name, DataFile, DATA_FILE_NAME_SUFFIX},
+        log_record::{LogRecord, LogRecordPos, LogRecordType, ReadLogRecord},
     },
     errors::{Errors, Result},
-    index,
-    options::Options,
+    family::Family,
+    fio, index,
+    limiter::RateLimiter,
+    merge::{MergeChain, MergeOperator},
+    metrics::Metrics,
+    options::{IOType, Options, ReadOptions, RecoveryMode},
+    secondary::SecondaryIndex,
+    stream::ValueReader,
 };
 
 const INITIAL_FILE_ID: u32 = 0;
 
+/// key 写锁的分段数量
+const KEY_LOCK_STRIPES: usize = 64;
+
 /// 存储引擎实例
 pub struct Engine {
-    options: Arc<Options>,                            // 配置
-    active_file: Arc<RwLock<DataFile>>,               // 活跃数据文件
-    older_files: Arc<RwLock<HashMap<u32, DataFile>>>, // 旧数据文件
-    index: Box<dyn index::Indexer>,                   // 内存索引
-    files_id: Vec<u32>,                               // 文件 ID，只在初始化时使用
+    pub(crate) options: Arc<Options>,                            // 配置
+    pub(crate) active_file: Arc<RwLock<DataFile>>,               // 活跃数据文件
+    pub(crate) older_files: Arc<RwLock<HashMap<u32, DataFile>>>, // 旧数据文件
+    pub(crate) index: Box<dyn index::Indexer>,                   // 内存索引
+    files_id: Vec<u32>,                                          // 文件 ID，只在初始化时使用
+    cipher: Option<RecordCipher>,                                // 记录加密器
+    pub(crate) group_commit: Arc<GroupCommit>,                   // 组提交
+    sync_worker: Option<SyncWorker>,                             // 后台定时持久化线程
+    pub(crate) seq: AtomicU64,                                   // 最新写入记录的序号
+    pub(crate) min_seq: AtomicU64,                               // 数据文件中保留的最小序号
+    pub(crate) subscribers: Mutex<Vec<SyncSender<LogRecord>>>,   // 变更订阅者
+    read_only: AtomicBool,                                       // 是否只读
+    pub(crate) families: RwLock<HashMap<u32, Arc<Family>>>,      // 列族，不包含默认列族
+    pub(crate) next_family_id: AtomicU32,                        // 下一个新建列族的 id
+    pub(crate) secondary_indexes: RwLock<HashMap<String, Arc<SecondaryIndex>>>, // 二级索引
+    pub(crate) key_locks: Vec<Mutex<()>>,                        // 按 key 哈希分段的写锁
+    pub(crate) merge_operator: RwLock<Option<Arc<dyn MergeOperator>>>, // 合并算子
+    pub(crate) merge_chains: RwLock<HashMap<Vec<u8>, MergeChain>>, // 未合并的操作数
+    cache: Option<ValueCache>,                                   // 读取记录的缓存
+    pub(crate) metrics: Metrics,                                 // 运行指标
+    opened: bool,                                                // 是否已经打开完成
+    pub(crate) merge_lock: Mutex<()>,                            // 同一时间只运行一个合并
+    pub(crate) stream_lock: RwLock<()>,                          // 流式写入期间不能切换合并的文件
+    pub(crate) background_limiter: RateLimiter,                  // 后台任务的读写限速
+}
+
+/// 存储引擎的统计信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub data_file_num: usize, // 数据文件数量
+    pub unsynced_bytes: u64,  // 已经写入但还没有持久化的字节数
+    pub sync_count: u64,      // 执行 fsync 的次数
+    pub cache_hits: u64,      // 读取记录时命中缓存的次数
+    pub cache_misses: u64,    // 读取记录时没有命中缓存的次数
 }
 
 impl Engine {
     /// 打开一个存储引擎实例
     pub fn open(opts: Options) -> Result<Self> {
-        if let Some(e) = check_options(&opts) {
-            return Err(e);
-        }
+        opts.validate()?;
         let options = opts.clone();
 
         // 判断数据目录是否存在，不存在则创建
-        if !opts.dir_path.exists() {
-            let create_res = fs::create_dir(opts.dir_path.clone());
-            if create_res.is_err() {
-                warn!(
-                    "failed to create database dir: {:?}",
-                    create_res.unwrap_err()
-                );
-                return Err(Errors::FailedToCreateDataBaseDir);
-            }
-        }
+        fio::create_dir(&opts.dir_path, opts.io_type)?;
 
         // 从目录中读取数据文件
-        let mut data_files = load_data_files(opts.dir_path.clone())?;
+        let mut data_files = load_data_files(opts.dir_path.clone(), opts.io_type)?;
         // 设置 file_id 信息
         let mut files_id: Vec<u32> = Vec::new();
         for file in data_files.iter() {
@@ -54,69 +95,140 @@ impl Engine {
         }
 
         // 将旧的数据文件保存到 older_files 中
+        // 拿到活跃数据文件，即 id 最大的文件
+        let active_file = match data_files.pop() {
+            Some(file) => file,
+            None => DataFile::new(INITIAL_FILE_ID, opts.dir_path.clone(), opts.io_type)?,
+        };
+
         let mut older_files: HashMap<u32, DataFile> = HashMap::new();
-        if data_files.len() > 1 {
-            for _ in 0..data_files.len() - 2 {
-                let file = data_files.pop().unwrap();
-                older_files.insert(file.get_file_id(), file);
-            }
+        for file in data_files {
+            older_files.insert(file.get_file_id(), file);
         }
 
-        // 拿到活跃数据文件
-        let active_file = match data_files.pop() {
-            Some(file) => file,
-            None => DataFile::new(INITIAL_FILE_ID, opts.dir_path.clone())?,
+        let active_file = Arc::new(RwLock::new(active_file));
+        let group_commit = Arc::new(GroupCommit::new());
+        let sync_worker = match opts.sync_interval_ms {
+            0 => None,
+            ms => Some(SyncWorker::start(
+                Duration::from_millis(ms),
+                active_file.clone(),
+                group_commit.clone(),
+                opts.event_listeners.clone(),
+            )),
         };
 
-        let engine = Engine {
+        let mut engine = Engine {
             options: Arc::new(options),
-            active_file: Arc::new(RwLock::new(active_file)),
+            active_file,
             older_files: Arc::new(RwLock::new(older_files)),
             index: Box::new(index::create_indexer(opts.index_type)),
             files_id,
+            cipher: opts.encryption_key.as_ref().map(RecordCipher::new),
+            group_commit,
+            sync_worker: None,
+            seq: AtomicU64::new(0),
+            min_seq: AtomicU64::new(1),
+            subscribers: Mutex::new(Vec::new()),
+            read_only: AtomicBool::new(false),
+            families: RwLock::new(HashMap::new()),
+            next_family_id: AtomicU32::new(1),
+            secondary_indexes: RwLock::new(HashMap::new()),
+            key_locks: (0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            merge_operator: RwLock::new(None),
+            merge_chains: RwLock::new(HashMap::new()),
+            cache: match opts.cache_capacity {
+                0 => None,
+                capacity => Some(ValueCache::new(capacity)),
+            },
+            metrics: Metrics::new(),
+            opened: false,
+            merge_lock: Mutex::new(()),
+            stream_lock: RwLock::new(()),
+            background_limiter: RateLimiter::new(opts.background_io_rate),
         };
 
         // 从数据文件中加载内存索引
         engine.load_index_from_data_files()?;
+        // 打开时通过扫描找到预分配文件中有效记录的末尾，之后再补齐预分配的空间
+        if engine.options.preallocate {
+            engine
+                .active_file
+                .read()
+                .preallocate(engine.options.file_size)?;
+        }
+        engine.sync_worker = sync_worker;
+        engine.opened = true;
+        for listener in engine.options.event_listeners.iter() {
+            listener.on_open(&engine.options.dir_path);
+        }
 
         Ok(engine)
     }
 
     /// 存储 key/value 数据，key 不能为空
     pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        let _timer = self.metrics.time_put();
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
+        self.check_writable()?;
+
+        // 持有 key 的锁写入，与同一个 key 上的原子读改写互斥
+        let guard = self.lock_key(&key);
+        let commit_seq = self.put_locked(&key, &value)?;
+        drop(guard);
 
+        self.wait_for_sync(commit_seq, self.options.sync)
+    }
+
+    // 删除 key 对应的数据
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        let _timer = self.metrics.time_delete();
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_writable()?;
+
+        let guard = self.lock_key(&key);
+        let commit_seq = self.delete_locked(&key)?;
+        drop(guard);
+
+        self.wait_for_sync(commit_seq, self.options.sync)
+    }
+
+    /// 写入 key/value 并更新索引，调用者需要持有 key 的锁，返回用于等待持久化的写入序号
+    pub(crate) fn put_locked(&self, key: &Bytes, value: &Bytes) -> Result<u64> {
         // 构造 LogRecord
         let mut logrecord = LogRecord {
             key: key.to_vec(),
             value: value.to_vec(),
             record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: 0,
         };
 
         // 追加写到活跃数据文件中
-        let log_record_pos = self.append_log_record(&mut logrecord)?;
+        let (positions, commit_seq) =
+            self.write_log_records(std::slice::from_mut(&mut logrecord))?;
 
         // 更新内存索引
-        let ok = self.index.put(key.to_vec(), log_record_pos);
+        let ok = self.index.put(key.to_vec(), positions[0]);
         if !ok {
             return Err(Errors::IndexUpdateError);
         }
+        self.clear_merge_chain(key);
+        self.update_secondary_indexes(key, Some(value), logrecord.seq);
 
-        Ok(())
+        Ok(commit_seq)
     }
 
-    // 删除 key 对应的数据
-    pub fn delete(&self, key: Bytes) -> Result<()> {
-        if key.is_empty() {
-            return Err(Errors::KeyIsEmpty);
-        }
-
+    /// 删除 key 并更新索引，调用者需要持有 key 的锁，返回用于等待持久化的写入序号
+    pub(crate) fn delete_locked(&self, key: &Bytes) -> Result<u64> {
         // 从内存索引中查找
         let log_record_pos = self.index.get(key.to_vec());
         if log_record_pos.is_none() {
-            return Ok(());
+            return Ok(0);
         }
 
         // 构造 LogRecord
@@ -124,97 +236,448 @@ impl Engine {
             key: key.to_vec(),
             value: Default::default(),
             record_type: LogRecordType::DELETE,
+            seq: 0,
+            cf: 0,
         };
 
-        self.append_log_record(&mut logrecord)?;
+        let (_, commit_seq) = self.write_log_records(std::slice::from_mut(&mut logrecord))?;
 
         let ok = self.index.delete(key.to_vec());
         if !ok {
             return Err(Errors::IndexUpdateError);
         }
+        self.clear_merge_chain(key);
+        self.update_secondary_indexes(key, None, logrecord.seq);
 
-        Ok(())
+        Ok(commit_seq)
     }
 
     /// 获取 key 对应的 value
     pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    /// 按照 read_options 获取 key 对应的 value
+    pub fn get_with_options(&self, key: Bytes, read_options: &ReadOptions) -> Result<Bytes> {
+        let _timer = self.metrics.time_get();
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
 
-        // 从内存索引中查找
-        let log_record_pos = self.index.get(key.to_vec());
-        if log_record_pos.is_none() {
-            return Err(Errors::RecordNotFound);
+        loop {
+            // 从内存索引中查找
+            let log_record_pos = self.index.get(key.to_vec());
+            if log_record_pos.is_none() {
+                return Err(Errors::RecordNotFound);
+            }
+            let log_record_pos = log_record_pos.unwrap();
+
+            match self.read_value_at(&key, log_record_pos, read_options) {
+                Ok(Some(value)) => return Ok(value),
+                // 读取期间 key 被修改，或者记录被合并移动到了新的位置，重新读取
+                Ok(None) => continue,
+                Err(_) if self.index.get(key.to_vec()) != Some(log_record_pos) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 批量获取多个 key 的 value，结果和 keys 的顺序一致
+    ///
+    /// 同一个数据文件中的记录在一次批量读取中完成，使用 io_uring 时只需要一次提交。
+    pub fn multi_get(&self, keys: &[Bytes]) -> Vec<Result<Bytes>> {
+        let positions: Vec<Option<LogRecordPos>> = keys
+            .iter()
+            .map(|key| match key.is_empty() {
+                true => None,
+                false => self.index.get(key.to_vec()),
+            })
+            .collect();
+        let mut records = self.read_log_records(&positions);
+
+        keys.iter()
+            .zip(positions)
+            .zip(records.drain(..))
+            .map(|((key, pos), logrecord)| match (pos, logrecord) {
+                (None, _) if key.is_empty() => Err(Errors::KeyIsEmpty),
+                (None, _) => Err(Errors::RecordNotFound),
+                (Some(_), Some(Ok(logrecord)))
+                    if logrecord.record_type == LogRecordType::NORMAL =>
+                {
+                    Ok(logrecord.value.into())
+                }
+                (Some(_), Some(Ok(logrecord)))
+                    if logrecord.record_type == LogRecordType::DELETE =>
+                {
+                    Err(Errors::RecordNotFound)
+                }
+                // 分块存储的 value、合并操作数以及读取期间被移动的记录按单个 key 读取
+                _ => self.get(key.clone()),
+            })
+            .collect()
+    }
+
+    // 批量读取记录并放入缓存，同一个数据文件中的记录一次读取，位置为 None 时结果也为 None
+    fn read_log_records(
+        &self,
+        positions: &[Option<LogRecordPos>],
+    ) -> Vec<Option<Result<LogRecord>>> {
+        let mut records: Vec<Option<Result<LogRecord>>> = positions.iter().map(|_| None).collect();
+        let mut by_file: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, pos) in positions.iter().enumerate() {
+            let pos = match pos {
+                Some(pos) => pos,
+                None => continue,
+            };
+            match self.cache.as_ref().and_then(|cache| cache.get(pos)) {
+                Some(logrecord) => records[i] = Some(Ok(logrecord)),
+                None => by_file.entry(pos.file_id).or_default().push(i),
+            }
         }
 
+        let active_file = self.active_file.read();
+        let older_files = self.older_files.read();
+        for (file_id, indexes) in by_file {
+            let data_file = match active_file.get_file_id() == file_id {
+                true => Some(&*active_file),
+                false => older_files.get(&file_id),
+            };
+            let data_file = match data_file {
+                Some(data_file) => data_file,
+                None => {
+                    for i in indexes {
+                        records[i] = Some(Err(Errors::DataFileNotFound));
+                    }
+                    continue;
+                }
+            };
+            let offsets: Vec<u64> = indexes
+                .iter()
+                .map(|i| positions[*i].unwrap().offset)
+                .collect();
+            let results = data_file.read_log_records(&offsets);
+            // 读取失败的记录由调用者按单个 key 重新读取并通知损坏
+            for (i, res) in indexes.into_iter().zip(results) {
+                let res = res.and_then(|read| self.decode_log_record(read.record));
+                if let (Ok(logrecord), Some(cache)) = (res.as_ref(), self.cache.as_ref()) {
+                    cache.insert(positions[i].unwrap(), logrecord);
+                }
+                records[i] = Some(res);
+            }
+        }
+        records
+    }
+
+    // 读取索引指向 pos 的 key 的 value，返回 None 表示 key 在读取期间被修改
+    fn read_value_at(
+        &self,
+        key: &[u8],
+        log_record_pos: LogRecordPos,
+        read_options: &ReadOptions,
+    ) -> Result<Option<Bytes>> {
         // 从数据文件中读取 LogRecord
+        let logrecord = self.read_log_record_with(&log_record_pos, read_options.fill_cache)?;
+
+        // 判断类型
+        match logrecord.record_type {
+            LogRecordType::DELETE => Err(Errors::RecordNotFound),
+            // 合并操作数需要和之前的 value 一起计算
+            LogRecordType::MERGE => self.read_merged_value(key, log_record_pos),
+            _ => self.record_value(logrecord).map(Some),
+        }
+    }
+
+    /// NORMAL 或 MANIFEST 记录保存的完整 value
+    pub(crate) fn record_value(&self, logrecord: LogRecord) -> Result<Bytes> {
+        match logrecord.record_type {
+            // 大 value 被拆分成多个分块存储，读取全部分块后返回
+            LogRecordType::MANIFEST => {
+                let mut value = Vec::new();
+                let mut reader = ValueReader::from_manifest(self, &logrecord)?;
+                if reader.read_to_end(&mut value).is_err() {
+                    return Err(Errors::FailedToReadFromDataFile);
+                }
+                Ok(value.into())
+            }
+            _ => Ok(logrecord.value.into()),
+        }
+    }
+
+    /// 获取所有的 key，按顺序排列
+    pub fn list_keys(&self) -> Vec<Bytes> {
+        self.index.list_keys()
+    }
+
+    /// 设置只读模式，只读模式下 put 和 delete 返回错误
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    // fsync 失败之后内存索引可能包含已经丢失的写入，拒绝之后所有的写入，重新打开之后恢复
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.group_commit.is_failed() {
+            return Err(Errors::FailedToSyncDataFile);
+        }
+        match self.read_only.load(Ordering::SeqCst) {
+            true => Err(Errors::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// 根据索引位置信息读取 LogRecord，返回的记录已经解密
+    pub(crate) fn read_log_record(&self, log_record_pos: &LogRecordPos) -> Result<LogRecord> {
+        self.read_log_record_with(log_record_pos, true)
+    }
+
+    /// 读取指定位置的 LogRecord，优先从缓存中读取，fill_cache 为 false 时不放入缓存
+    pub(crate) fn read_log_record_with(
+        &self,
+        log_record_pos: &LogRecordPos,
+        fill_cache: bool,
+    ) -> Result<LogRecord> {
+        let cache = match self.cache.as_ref() {
+            Some(cache) => cache,
+            None => return Ok(self.read_log_record_at(log_record_pos)?.record),
+        };
+        if let Some(logrecord) = cache.get(log_record_pos) {
+            return Ok(logrecord);
+        }
+        let logrecord = self.read_log_record_at(log_record_pos)?.record;
+        if fill_cache {
+            cache.insert(*log_record_pos, &logrecord);
+        }
+        Ok(logrecord)
+    }
+
+    /// 指定位置的记录在数据文件中的大小，只读取 header
+    pub(crate) fn read_log_record_size(&self, log_record_pos: &LogRecordPos) -> Result<u64> {
+        let active_file = self.active_file.read();
+        let header = match active_file.get_file_id() == log_record_pos.file_id {
+            true => active_file.read_log_record_header(log_record_pos.offset)?,
+            false => match self.older_files.read().get(&log_record_pos.file_id) {
+                Some(data_file) => data_file.read_log_record_header(log_record_pos.offset)?,
+                None => return Err(Errors::DataFileNotFound),
+            },
+        };
+        Ok(header.record_size())
+    }
+
+    /// 读取指定位置的 LogRecord 及其在数据文件中的大小，返回的记录已经解密
+    pub(crate) fn read_log_record_at(
+        &self,
+        log_record_pos: &LogRecordPos,
+    ) -> Result<ReadLogRecord> {
         let active_file = self.active_file.read();
         let older_files = self.older_files.read();
-        let logrecord = match active_file.get_file_id() == log_record_pos.unwrap().file_id {
-            true => active_file.read_log_record(log_record_pos.unwrap().offset)?,
+        let logrecord = match active_file.get_file_id() == log_record_pos.file_id {
+            true => active_file.read_log_record(log_record_pos.offset),
             false => {
-                let data_file = older_files.get(&log_record_pos.unwrap().file_id);
+                let data_file = older_files.get(&log_record_pos.file_id);
                 if data_file.is_none() {
                     return Err(Errors::DataFileNotFound);
                 }
-                data_file
-                    .unwrap()
-                    .read_log_record(log_record_pos.unwrap().offset)?
+                data_file.unwrap().read_log_record(log_record_pos.offset)
             }
         };
-
-        // 判断类型
-        if logrecord.record.record_type == LogRecordType::DELETE {
-            return Err(Errors::RecordNotFound);
+        let res = logrecord.and_then(|logrecord| {
+            Ok(ReadLogRecord {
+                record: self.decode_log_record(logrecord.record)?,
+                size: logrecord.size,
+            })
+        });
+        if let Err(e) = res.as_ref() {
+            self.notify_corruption(log_record_pos.file_id, log_record_pos.offset, e);
         }
+        res
+    }
 
-        // 返回 value
-        Ok(logrecord.record.value.into())
+    /// 注册变更订阅者，返回注册时最新的序号、需要回放的数据文件以及接收新记录的 channel
+    pub(crate) fn register_subscriber(&self) -> (u64, Vec<u32>, Receiver<LogRecord>) {
+        // 持有活跃文件写锁，保证注册前后写入的记录不会丢失或重复
+        let active_file = self.active_file.write();
+        let older_files = self.older_files.read();
+        let mut file_ids: Vec<u32> = older_files.keys().copied().collect();
+        file_ids.sort();
+        file_ids.push(active_file.get_file_id());
+
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER_SIZE);
+        self.subscribers.lock().push(sender);
+        (self.seq.load(Ordering::SeqCst), file_ids, receiver)
     }
 
     /// 追加写数据到当前活跃文件中
     pub fn append_log_record(&self, logrecord: &mut LogRecord) -> Result<LogRecordPos> {
-        // 追加写到活跃数据文件中
-        let dirpath = self.options.dir_path.clone();
+        let positions =
+            self.append_log_records(std::slice::from_mut(logrecord), self.options.sync)?;
+        Ok(positions[0])
+    }
+
+    /// 在同一次写锁内连续追加多条记录，这些记录一定位于同一个数据文件中
+    /// sync 为 true 时等待记录持久化后返回
+    pub(crate) fn append_log_records(
+        &self,
+        logrecords: &mut [LogRecord],
+        sync: bool,
+    ) -> Result<Vec<LogRecordPos>> {
+        let (positions, commit_seq) = self.write_log_records(logrecords)?;
+        self.wait_for_sync(commit_seq, sync)?;
+        Ok(positions)
+    }
 
-        // 编码 LogRecord
-        let encoded = logrecord.encode();
-        let log_size = encoded.len() as u64;
+    /// 追加写入记录但不等待持久化，返回记录的位置和组提交的写入序号
+    pub(crate) fn write_log_records(
+        &self,
+        logrecords: &mut [LogRecord],
+    ) -> Result<(Vec<LogRecordPos>, u64)> {
+        self.write_log_records_with(logrecords, false, false)
+    }
+
+    /// 重新写入合并移动的记录，保留记录原来的序号，不推送给订阅者
+    pub(crate) fn relocate_log_records(
+        &self,
+        logrecords: &mut [LogRecord],
+    ) -> Result<(Vec<LogRecordPos>, u64)> {
+        self.write_log_records_with(logrecords, false, true)
+    }
+
+    /// 切换到新的活跃文件，并把记录写在新文件的开头
+    pub(crate) fn rotate_active_file(
+        &self,
+        logrecords: &mut [LogRecord],
+    ) -> Result<(Vec<LogRecordPos>, u64)> {
+        self.write_log_records_with(logrecords, true, false)
+    }
+
+    fn write_log_records_with(
+        &self,
+        logrecords: &mut [LogRecord],
+        rotate: bool,
+        relocate: bool,
+    ) -> Result<(Vec<LogRecordPos>, u64)> {
+        let dirpath = self.options.dir_path.clone();
 
         // 获取当前活跃文件
+        let writer_guard = self.group_commit.enter_writer();
         let mut active_file_guard = self.active_file.write();
 
+        // 在写锁内分配序号，保证序号和记录在数据文件中的顺序一致
+        // 序号参与加密认证，配置了加密密钥时分配序号之后再加密
+        // 合并移动的记录保留原来的序号，仍然使用原来的序号加密
+        let mut seq = self.seq.load(Ordering::SeqCst);
+        let mut encoded = Vec::with_capacity(logrecords.len());
+        for logrecord in logrecords.iter_mut() {
+            if !relocate {
+                seq += 1;
+                logrecord.seq = seq;
+            }
+            encoded.push(match self.cipher.as_ref() {
+                Some(cipher) => cipher.seal(logrecord)?.encode(),
+                None => logrecord.encode(),
+            });
+        }
+        let log_size: u64 = encoded.iter().map(|buf| buf.len() as u64).sum();
+
         // 判断是否需要切换文件
+        let mut sealed_file_id = None;
         let write_offset = active_file_guard.get_write_offset();
-        if write_offset + log_size > self.options.file_size {
-            // 将当前活跃文件持久化
-            active_file_guard.sync()?;
+        if rotate || write_offset + log_size > self.options.file_size {
+            // 截断预分配的空间，再将当前活跃文件持久化，之前写入的记录都已经持久化
+            if active_file_guard.file_size()? > write_offset {
+                active_file_guard.truncate(write_offset)?;
+            }
+            self.group_commit.sync_sealed(|| active_file_guard.sync())?;
             let cur_file_id = active_file_guard.get_file_id();
             // 旧数据文件存储到 Map
             let mut older_files_guard = self.older_files.write();
-            let old_file = DataFile::new(cur_file_id, dirpath.clone())?;
+            let old_file = DataFile::new(cur_file_id, dirpath.clone(), self.options.io_type)?;
             older_files_guard.insert(cur_file_id, old_file);
 
             // 创建新的活跃文件
-            let new_file = DataFile::new(cur_file_id + 1, dirpath)?;
+            let new_file = DataFile::new(cur_file_id + 1, dirpath, self.options.io_type)?;
+            if self.options.preallocate {
+                new_file.preallocate(self.options.file_size)?;
+            }
             *active_file_guard = new_file;
+            self.metrics.inc_file_rotations();
+            sealed_file_id = Some(cur_file_id);
         }
 
-        // 追加写到活跃数据文件中
-        let write_offset = active_file_guard.get_write_offset();
-        active_file_guard.write(&encoded)?;
+        // 追加写到活跃数据文件中，并构造数据索引信息
+        let mut positions = Vec::with_capacity(encoded.len());
+        let mut write_offset = active_file_guard.get_write_offset();
+        for buf in encoded.iter() {
+            positions.push(LogRecordPos {
+                file_id: active_file_guard.get_file_id(),
+                offset: write_offset,
+            });
+            write_offset += buf.len() as u64;
+        }
+        // 一次写入的所有记录批量提交，需要持久化并且没有其他写入者时 fsync 和写入一起提交
+        let link_sync = !relocate
+            && !encoded.is_empty()
+            && self.options.sync
+            && self.options.io_type == IOType::IoUring
+            && self.group_commit.try_lead_linked_sync();
+        let commit_seq = if link_sync {
+            let start = Instant::now();
+            let res = active_file_guard
+                .write_batch_sync(&encoded)
+                .map(|_| self.group_commit.next_seq(log_size));
+            self.group_commit.finish_linked_sync(&res, start.elapsed());
+            res?
+        } else {
+            active_file_guard.write_batch(&encoded)?;
+            self.group_commit.next_seq(log_size)
+        };
+        self.metrics.add_bytes_written(log_size);
+        self.seq.store(seq, Ordering::SeqCst);
+        if !relocate {
+            for logrecord in logrecords.iter() {
+                self.publish(logrecord);
+            }
+        }
+        drop(active_file_guard);
+        drop(writer_guard);
 
-        // 根据配置项决定是否立即持久化
-        if self.options.sync {
-            active_file_guard.sync()?;
+        if let Some(file_id) = sealed_file_id {
+            let sealed_path = get_data_file_name(self.options.dir_path.clone(), file_id);
+            for listener in self.options.event_listeners.iter() {
+                listener.on_file_rotated(file_id, &sealed_path);
+            }
         }
 
-        // 构造数据索引信息
-        Ok(LogRecordPos {
-            file_id: active_file_guard.get_file_id(),
-            offset: write_offset,
-        })
+        Ok((positions, commit_seq))
+    }
+
+    /// 根据配置项决定是否等待写入序号 commit_seq 之前的记录持久化
+    pub(crate) fn wait_for_sync(&self, commit_seq: u64, sync: bool) -> Result<()> {
+        // 并发的写入通过组提交共享同一次 fsync
+        if sync {
+            self.group_commit
+                .wait_durable(commit_seq, || self.active_file.read().sync())?;
+        } else if self.options.bytes_per_sync > 0
+            && self.group_commit.unsynced_bytes() >= self.options.bytes_per_sync
+        {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// 持久化当前活跃文件
+    pub fn sync(&self) -> Result<()> {
+        self.group_commit
+            .sync_all(|| self.active_file.read().sync())
+    }
+
+    /// 获取存储引擎的统计信息
+    pub fn stat(&self) -> Stat {
+        Stat {
+            data_file_num: self.older_files.read().len() + 1,
+            unsynced_bytes: self.group_commit.unsynced_bytes(),
+            sync_count: self.group_commit.sync_count(),
+            cache_hits: self.cache.as_ref().map_or(0, |cache| cache.hits()),
+            cache_misses: self.cache.as_ref().map_or(0, |cache| cache.misses()),
+        }
     }
 
     /// 从数据文件中加载内存索引
@@ -226,6 +689,10 @@ impl Engine {
 
         let active_file = self.active_file.read();
         let older_files = self.older_files.read();
+        let mut max_seq = 0;
+        let mut min_seq = u64::MAX;
+        // 还没有读到结束记录的原子批量写入：开始记录的位置和之后的记录
+        let mut batch: Option<(LogRecordPos, Vec<(LogRecord, LogRecordPos)>)> = None;
 
         // 遍历每个文件 id
         for (i, file_id) in self.files_id.iter().enumerate() {
@@ -239,66 +706,201 @@ impl Engine {
                     }
                 };
                 let (log_record, size) = match log_record_res {
-                    Ok(result) => (result.record, result.size),
+                    Ok(result) => match self.decode_log_record(result.record) {
+                        Ok(log_record) => (log_record, result.size),
+                        Err(e) => {
+                            self.notify_corruption(*file_id, offset, &e);
+                            return Err(e);
+                        }
+                    },
                     Err(e) => {
                         if e == Errors::ReadDataFileEOF {
                             break;
                         }
+                        self.notify_corruption(*file_id, offset, &e);
+                        // 掉电可能导致活跃文件末尾的记录只写入了一部分
+                        if *file_id == active_file.get_file_id()
+                            && self.options.recovery_mode == RecoveryMode::TruncateTail
+                            && is_torn_tail(&active_file, offset)
+                        {
+                            warn!(
+                                "truncating torn tail of data file {}: dropped {} bytes after offset {}: {}",
+                                file_id,
+                                active_file.file_size()?.saturating_sub(offset),
+                                offset,
+                                e
+                            );
+                            active_file.truncate(offset)?;
+                            break;
+                        }
                         return Err(e);
                     }
                 };
 
+                // 合并移动的记录保留了原来较小的序号，第一条记录才是数据文件中连续保留的最小序号
+                max_seq = max_seq.max(log_record.seq);
+                if min_seq == u64::MAX {
+                    min_seq = log_record.seq;
+                }
+
                 // 构建索引
                 let log_record_pos = LogRecordPos {
                     file_id: *file_id,
                     offset,
                 };
 
-                let ok = match log_record.record_type {
-                    LogRecordType::NORMAL => {
-                        self.index.put(log_record.key.to_vec(), log_record_pos)
+                // 批量写入的记录读到结束记录之后才生效
+                match log_record.record_type {
+                    LogRecordType::BATCHBEGIN => batch = Some((log_record_pos, Vec::new())),
+                    LogRecordType::BATCHFINISHED => {
+                        if let Some((_, records)) = batch.take() {
+                            for (record, pos) in records {
+                                self.load_log_record(record, pos)?;
+                            }
+                        }
                     }
-                    LogRecordType::DELETE => self.index.delete(log_record.key.to_vec()),
-                };
-                if !ok {
-                    return Err(Errors::IndexUpdateError);
+                    _ => match batch.as_mut() {
+                        Some((_, records)) => records.push((log_record, log_record_pos)),
+                        None => self.load_log_record(log_record, log_record_pos)?,
+                    },
                 }
 
                 offset += size;
             }
             // 设置活跃文件的写入偏移
             if i == self.files_id.len() - 1 {
+                // 丢弃没有写完的批量写入，截断之后新写入的记录才不会被当作批量写入的一部分
+                if let Some((begin, records)) = batch.take() {
+                    warn!(
+                        "discarding unfinished write batch of {} records at offset {} of data file {}",
+                        records.len(),
+                        begin.offset,
+                        file_id
+                    );
+                    active_file.truncate(begin.offset)?;
+                    offset = begin.offset;
+                }
                 active_file.set_write_offset(offset);
             }
         }
+
+        self.seq.store(max_seq, Ordering::SeqCst);
+        self.min_seq
+            .store(min_seq.min(max_seq + 1), Ordering::SeqCst);
         Ok(())
     }
+
+    // 将一条从数据文件中读取的记录应用到列族和内存索引
+    fn load_log_record(&self, log_record: LogRecord, log_record_pos: LogRecordPos) -> Result<()> {
+        match log_record.record_type {
+            LogRecordType::FAMILY => return self.load_family(log_record),
+            LogRecordType::DROPFAMILY => {
+                self.families.write().remove(&log_record.cf);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // 已经被删除的列族中的记录直接忽略
+        let family = match log_record.cf {
+            0 => None,
+            cf => match self.families.read().get(&cf) {
+                Some(family) => Some(family.clone()),
+                None => return Ok(()),
+            },
+        };
+        let index = match family.as_ref() {
+            Some(family) => family.index.as_ref(),
+            None => self.index.as_ref(),
+        };
+
+        if family.is_none() {
+            match log_record.record_type {
+                LogRecordType::MERGE => {
+                    let current = index.get(log_record.key.to_vec());
+                    self.push_merge_operand(&log_record.key, current, log_record_pos);
+                }
+                LogRecordType::NORMAL | LogRecordType::MANIFEST | LogRecordType::DELETE => {
+                    self.clear_merge_chain(&log_record.key)
+                }
+                _ => {}
+            }
+        }
+
+        let ok = match log_record.record_type {
+            LogRecordType::NORMAL | LogRecordType::MANIFEST | LogRecordType::MERGE => {
+                index.put(log_record.key.to_vec(), log_record_pos)
+            }
+            // 批量写入中可能包含删除不存在的 key 的记录
+            LogRecordType::DELETE => {
+                index.delete(log_record.key.to_vec());
+                true
+            }
+            // 分块只通过 manifest 记录访问，不需要建立索引
+            LogRecordType::CHUNK
+            | LogRecordType::FAMILY
+            | LogRecordType::DROPFAMILY
+            | LogRecordType::BATCHBEGIN
+            | LogRecordType::BATCHFINISHED => true,
+            LogRecordType::ENCRYPTED => unreachable!(),
+        };
+        if !ok {
+            return Err(Errors::IndexUpdateError);
+        }
+        Ok(())
+    }
+
+    // 通知监听器发现了损坏的记录
+    fn notify_corruption(&self, file_id: u32, offset: u64, error: &Errors) {
+        let corrupt = matches!(
+            error,
+            Errors::InvalidLogRecordCrc
+                | Errors::InvalidLogRecordHeader
+                | Errors::FailedToDecryptLogRecord
+        );
+        if corrupt {
+            for listener in self.options.event_listeners.iter() {
+                listener.on_corruption(file_id, offset, error);
+            }
+        }
+    }
+
+    /// 还原从数据文件中读取的 LogRecord，加密的记录需要先解密
+    fn decode_log_record(&self, logrecord: LogRecord) -> Result<LogRecord> {
+        cipher::decode_log_record(self.cipher.as_ref(), logrecord)
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if self.opened {
+            for listener in self.options.event_listeners.iter() {
+                listener.on_close(&self.options.dir_path);
+            }
+        }
+    }
+}
+
+// 判断 offset 处读取失败的记录是否位于文件末尾
+// 损坏记录的长度不可信，之后任意位置还能读到完整的记录，说明是文件中间的损坏而不是写入中断，不能截断
+fn is_torn_tail(data_file: &DataFile, offset: u64) -> bool {
+    matches!(data_file.has_valid_record_after(offset), Ok(false))
 }
 
 // 从目录中读取数据文件
-fn load_data_files(dir_path: PathBuf) -> Result<Vec<DataFile>> {
+pub(crate) fn load_data_files(dir_path: PathBuf, io_type: IOType) -> Result<Vec<DataFile>> {
     let mut dir_files: Vec<DataFile> = Vec::new();
-    let dir = fs::read_dir(dir_path.clone());
-    if dir.is_err() {
-        return Err(Errors::FailedToReadDataBaseDir);
-    }
     let mut files_id: Vec<u32> = Vec::new();
-    for entry in dir.unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() {
-            // 拿到文件名
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-            if file_name.ends_with(DATA_FILE_NAME_SUFFIX) {
-                // 解析文件 ID，文件名格式为 {id}.data
-                let file_id = file_name
-                    .trim_end_matches(DATA_FILE_NAME_SUFFIX)
-                    .parse::<u32>();
-                if file_id.is_err() {
-                    return Err(Errors::DataDirectoryInvalid);
-                }
-                files_id.push(file_id.unwrap());
+    for file_name in fio::list_files(&dir_path, io_type)? {
+        if file_name.ends_with(DATA_FILE_NAME_SUFFIX) {
+            // 解析文件 ID，文件名格式为 {id}.data
+            let file_id = file_name
+                .trim_end_matches(DATA_FILE_NAME_SUFFIX)
+                .parse::<u32>();
+            if file_id.is_err() {
+                return Err(Errors::DataDirectoryInvalid);
             }
+            files_id.push(file_id.unwrap());
         }
     }
     // 判空
@@ -308,21 +910,587 @@ fn load_data_files(dir_path: PathBuf) -> Result<Vec<DataFile>> {
     files_id.sort();
     // 遍历文件 ID，加载数据文件
     for file_id in files_id {
-        let file = DataFile::new(file_id, dir_path.clone())?;
+        let file = DataFile::new(file_id, dir_path.clone(), io_type)?;
         dir_files.push(file);
     }
     Ok(dir_files)
 }
 
-// 检查配置项
-fn check_options(opts: &Options) -> Option<Errors> {
-    let dir_path = opts.dir_path.to_str();
-    if dir_path.is_none() || dir_path.unwrap().is_empty() {
-        return Some(Errors::DirPathIsEmpty);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+    };
+
+    use crate::options::test_options;
+
+    #[test]
+    fn test_engine_put_get_delete() {
+        let opts = test_options("bitcask-rs-put-get-delete");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        engine
+            .put(Bytes::from("name"), Bytes::from("bitcask-rs"))
+            .unwrap();
+        engine
+            .put(Bytes::from("lang"), Bytes::from("rust"))
+            .unwrap();
+        engine
+            .put(Bytes::from("name"), Bytes::from("rust-kv"))
+            .unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("name")).unwrap(),
+            Bytes::from("rust-kv")
+        );
+
+        engine.delete(Bytes::from("lang")).unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("lang")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        assert_eq!(
+            engine.put(Bytes::new(), Bytes::from("v")).err(),
+            Some(Errors::KeyIsEmpty)
+        );
+
+        // 重启后数据依然存在
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("name")).unwrap(),
+            Bytes::from("rust-kv")
+        );
+        assert_eq!(
+            engine.get(Bytes::from("lang")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_multiple_data_files() {
+        let mut opts = test_options("bitcask-rs-multiple-files");
+        opts.file_size = 256;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            let key = Bytes::from(format!("key-{:03}", i));
+            engine
+                .put(key, Bytes::from(format!("value-{:03}", i)))
+                .unwrap();
+        }
+        assert!(engine.older_files.read().len() > 1);
+
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        for i in 0..100 {
+            let key = Bytes::from(format!("key-{:03}", i));
+            assert_eq!(
+                engine.get(key).unwrap(),
+                Bytes::from(format!("value-{:03}", i))
+            );
+        }
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_encryption() {
+        let mut opts = test_options("bitcask-rs-encryption");
+        opts.encryption_key = Some([42u8; 32]);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine
+            .put(Bytes::from("secret-key"), Bytes::from("secret-value"))
+            .unwrap();
+        engine
+            .put(Bytes::from("other"), Bytes::from("value"))
+            .unwrap();
+        engine.delete(Bytes::from("other")).unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("secret-key")).unwrap(),
+            Bytes::from("secret-value")
+        );
+        drop(engine);
+
+        // 数据文件中不应出现明文
+        let raw = fs::read(opts.dir_path.join("000000000.data")).unwrap();
+        assert!(!raw.windows(10).any(|w| w == b"secret-key"));
+        assert!(!raw.windows(12).any(|w| w == b"secret-value"));
+
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("secret-key")).unwrap(),
+            Bytes::from("secret-value")
+        );
+        assert_eq!(
+            engine.get(Bytes::from("other")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        drop(engine);
+
+        // 错误的密钥
+        let mut wrong_opts = opts.clone();
+        wrong_opts.encryption_key = Some([43u8; 32]);
+        assert_eq!(
+            Engine::open(wrong_opts).err(),
+            Some(Errors::FailedToDecryptLogRecord)
+        );
+
+        // 没有配置密钥
+        let mut no_key_opts = opts.clone();
+        no_key_opts.encryption_key = None;
+        assert_eq!(
+            Engine::open(no_key_opts).err(),
+            Some(Errors::EncryptionKeyRequired)
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
     }
 
-    if opts.file_size <= 0 {
-        return Some(Errors::FileSizeTooSmall);
+    #[test]
+    fn test_engine_recover_torn_tail() {
+        let opts = test_options("bitcask-rs-torn-tail");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine
+            .put(Bytes::from("key-1"), Bytes::from("value-1"))
+            .unwrap();
+        engine
+            .put(Bytes::from("key-2"), Bytes::from("value-2"))
+            .unwrap();
+        drop(engine);
+
+        // 模拟掉电时只写入了一部分的记录
+        let path = opts.dir_path.join("000000000.data");
+        let valid_size = fs::metadata(path.clone()).unwrap().len();
+        let torn = LogRecord {
+            key: "key-3".as_bytes().to_vec(),
+            value: "value-3".as_bytes().to_vec(),
+            record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: 0,
+        }
+        .encode();
+        let mut file = OpenOptions::new().append(true).open(path.clone()).unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+        drop(file);
+
+        // 严格模式拒绝打开，记录超出了文件末尾
+        let mut strict_opts = opts.clone();
+        strict_opts.recovery_mode = RecoveryMode::Strict;
+        assert_eq!(
+            Engine::open(strict_opts).err(),
+            Some(Errors::InvalidLogRecordHeader)
+        );
+
+        // 恢复模式截断不完整的记录后继续打开
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(fs::metadata(path.clone()).unwrap().len(), valid_size);
+        assert_eq!(
+            engine.get(Bytes::from("key-2")).unwrap(),
+            Bytes::from("value-2")
+        );
+        assert_eq!(
+            engine.get(Bytes::from("key-3")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        // 截断后可以继续正常写入
+        engine
+            .put(Bytes::from("key-3"), Bytes::from("value-3"))
+            .unwrap();
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("key-3")).unwrap(),
+            Bytes::from("value-3")
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_refuse_corruption_before_tail() {
+        let opts = test_options("bitcask-rs-corrupt-middle");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine
+            .put(Bytes::from("key-1"), Bytes::from("value-1"))
+            .unwrap();
+        engine
+            .put(Bytes::from("key-2"), Bytes::from("value-2"))
+            .unwrap();
+        engine
+            .put(Bytes::from("key-3"), Bytes::from("value-3"))
+            .unwrap();
+        drop(engine);
+
+        // 破坏中间的记录，之后还有完整的记录，不能当作末尾截断
+        let path = opts.dir_path.join("000000000.data");
+        let mut data = fs::read(path.clone()).unwrap();
+        data[20 + 10] ^= 0xff;
+        fs::write(path, data).unwrap();
+
+        assert_eq!(
+            Engine::open(opts.clone()).err(),
+            Some(Errors::InvalidLogRecordCrc)
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_refuse_corrupted_size_before_tail() {
+        let opts = test_options("bitcask-rs-corrupt-middle-size");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 1..=3 {
+            engine
+                .put(
+                    Bytes::from(format!("key-{}", i)),
+                    Bytes::from(format!("value-{}", i)),
+                )
+                .unwrap();
+        }
+        drop(engine);
+
+        // 破坏中间记录的 key 长度，使它超出文件末尾或者 header 无法解析
+        let path = opts.dir_path.join("000000000.data");
+        let data = fs::read(path.clone()).unwrap();
+        for key_size in [0x7f, 0xff] {
+            let mut corrupted = data.clone();
+            corrupted[20 + 2] = key_size;
+            fs::write(path.clone(), corrupted).unwrap();
+            assert_eq!(
+                Engine::open(opts.clone()).err(),
+                Some(Errors::InvalidLogRecordHeader)
+            );
+        }
+
+        // 拒绝打开时没有截断文件，修复后之后的记录仍然存在
+        assert_eq!(fs::metadata(path.clone()).unwrap().len(), 60);
+        fs::write(path, data).unwrap();
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("key-3")).unwrap(),
+            Bytes::from("value-3")
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_group_commit() {
+        for io_type in [IOType::StandardFIO, IOType::IoUring] {
+            let mut opts = test_options("bitcask-rs-group-commit");
+            opts.sync = true;
+            opts.io_type = io_type;
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+            // 没有并发写入时每次写入一次 fsync，io_uring 中 fsync 和写入一起提交
+            let sync_count = engine.stat().sync_count;
+            engine
+                .put(Bytes::from("key"), Bytes::from("value"))
+                .unwrap();
+            assert_eq!(engine.stat().sync_count, sync_count + 1);
+            assert_eq!(engine.stat().unsynced_bytes, 0);
+
+            std::thread::scope(|scope| {
+                for t in 0..8 {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        for i in 0..50 {
+                            let key = Bytes::from(format!("key-{}-{}", t, i));
+                            engine.put(key, Bytes::from("value")).unwrap();
+                        }
+                    });
+                }
+            });
+            // 并发写入共享 fsync，次数少于写入次数
+            assert!(engine.stat().sync_count < sync_count + 400);
+            assert_eq!(engine.stat().unsynced_bytes, 0);
+
+            drop(engine);
+            let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+            for t in 0..8 {
+                for i in 0..50 {
+                    let key = Bytes::from(format!("key-{}-{}", t, i));
+                    assert_eq!(engine.get(key).unwrap(), Bytes::from("value"));
+                }
+            }
+
+            drop(engine);
+            fs::remove_dir_all(opts.dir_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_engine_bytes_per_sync() {
+        let mut opts = test_options("bitcask-rs-bytes-per-sync");
+        opts.bytes_per_sync = 100;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // 每条记录 1 + 1 + 1 + 1 + 5 + 7 + 4 = 20 字节
+        for i in 0..4 {
+            let key = Bytes::from(format!("key-{}", i));
+            engine.put(key, Bytes::from("value-1")).unwrap();
+        }
+        assert_eq!(engine.stat().unsynced_bytes, 80);
+        assert_eq!(engine.stat().sync_count, 0);
+
+        engine
+            .put(Bytes::from("key-4"), Bytes::from("value-1"))
+            .unwrap();
+        assert_eq!(engine.stat().unsynced_bytes, 0);
+        assert_eq!(engine.stat().sync_count, 1);
+
+        engine
+            .put(Bytes::from("key-6"), Bytes::from("value-1"))
+            .unwrap();
+        assert_eq!(engine.stat().unsynced_bytes, 20);
+        engine.sync().unwrap();
+        assert_eq!(engine.stat().unsynced_bytes, 0);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_background_sync() {
+        let mut opts = test_options("bitcask-rs-background-sync");
+        opts.sync_interval_ms = 10;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        engine
+            .put(Bytes::from("key"), Bytes::from("value"))
+            .unwrap();
+        let mut synced = false;
+        for _ in 0..200 {
+            if engine.stat().unsynced_bytes == 0 {
+                synced = true;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(synced);
+        assert!(engine.stat().sync_count >= 1);
+
+        // 关闭引擎时后台线程退出
+        drop(engine);
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_value_cache() {
+        let mut opts = test_options("bitcask-rs-value-cache");
+        opts.cache_capacity = 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        engine.put(Bytes::from("hot"), Bytes::from("v1")).unwrap();
+        engine.put(Bytes::from("cold"), Bytes::from("v1")).unwrap();
+        assert_eq!(engine.get(Bytes::from("hot")).unwrap(), Bytes::from("v1"));
+        assert_eq!(engine.get(Bytes::from("hot")).unwrap(), Bytes::from("v1"));
+        assert_eq!(engine.stat().cache_misses, 1);
+        assert_eq!(engine.stat().cache_hits, 1);
+
+        // 覆盖写入之后索引指向新的位置，不会读到缓存中的旧值
+        engine.put(Bytes::from("hot"), Bytes::from("v2")).unwrap();
+        assert_eq!(engine.get(Bytes::from("hot")).unwrap(), Bytes::from("v2"));
+        assert_eq!(engine.stat().cache_misses, 2);
+
+        // 不填充缓存的读取每次都读数据文件
+        let scan = ReadOptions { fill_cache: false };
+        for _ in 0..2 {
+            assert_eq!(
+                engine.get_with_options(Bytes::from("cold"), &scan).unwrap(),
+                Bytes::from("v1")
+            );
+        }
+        assert_eq!(engine.stat().cache_misses, 4);
+        assert_eq!(engine.stat().cache_hits, 1);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_preallocate() {
+        let mut opts = test_options("bitcask-rs-preallocate");
+        opts.file_size = 100;
+        opts.preallocate = true;
+        let file_len = |id: u32| {
+            fs::metadata(get_data_file_name(opts.dir_path.clone(), id))
+                .unwrap()
+                .len()
+        };
+
+        // 每条记录 20 字节，新文件直接分配到 file_size
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(file_len(0), 100);
+        for i in 0..3 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-1"))
+                .unwrap();
+        }
+        assert_eq!(file_len(0), 100);
+        drop(engine);
+
+        // 重新打开时扫描到预分配部分的 0 即为有效记录的末尾
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(engine.active_file.read().get_write_offset(), 60);
+        engine
+            .put(Bytes::from("key-3"), Bytes::from("value-1"))
+            .unwrap();
+        assert_eq!(file_len(0), 100);
+
+        // 放不下 21 字节的记录时切换，旧文件截断到有效记录的末尾
+        engine
+            .put(Bytes::from("key-0"), Bytes::from("value-22"))
+            .unwrap();
+        assert_eq!(file_len(0), 80);
+        assert_eq!(file_len(1), 100);
+        drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("key-0")).unwrap(),
+            Bytes::from("value-22")
+        );
+        assert_eq!(engine.list_keys().len(), 4);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_engine_direct_io() {
+        let mut opts = test_options("bitcask-rs-direct-io");
+        opts.io_type = IOType::DirectIO;
+        opts.file_size = 10 * 1024;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // 记录不按块对齐，并且跨越多个数据文件
+        for i in 0..500 {
+            engine
+                .put(
+                    Bytes::from(format!("key-{}", i)),
+                    Bytes::from(format!("value-{}", i)),
+                )
+                .unwrap();
+        }
+        engine.delete(Bytes::from("key-0")).unwrap();
+        assert!(engine.stat().data_file_num > 1);
+        assert_eq!(
+            engine.get(Bytes::from("key-499")).unwrap(),
+            Bytes::from("value-499")
+        );
+        drop(engine);
+
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(engine.list_keys().len(), 499);
+        engine
+            .put(Bytes::from("key-0"), Bytes::from("value-0"))
+            .unwrap();
+        for i in 0..500 {
+            assert_eq!(
+                engine.get(Bytes::from(format!("key-{}", i))).unwrap(),
+                Bytes::from(format!("value-{}", i))
+            );
+        }
+
+        // 合并删除数据文件时一起删除最后一个块的副本
+        engine.sync().unwrap();
+        engine
+            .put(Bytes::from("key-1"), Bytes::from("value-1"))
+            .unwrap();
+        engine.merge().unwrap();
+        for entry in fs::read_dir(&opts.dir_path).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "tail") {
+                assert!(path.with_extension("data").exists());
+            }
+        }
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_engine_memory() {
+        let mut opts = test_options("bitcask-rs-memory");
+        opts.io_type = IOType::Memory;
+        opts.file_size = 1024;
+        opts.preallocate = true;
+        assert_eq!(
+            Engine::open(opts.clone()).err(),
+            Some(Errors::PreallocateWithMemoryIO)
+        );
+        opts.preallocate = false;
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..200 {
+            engine
+                .put(
+                    Bytes::from(format!("key-{}", i)),
+                    Bytes::from(format!("value-{}", i)),
+                )
+                .unwrap();
+        }
+        for i in 0..100 {
+            engine.delete(Bytes::from(format!("key-{}", i))).unwrap();
+        }
+        assert!(engine.stat().data_file_num > 1);
+        engine.merge().unwrap();
+        assert_eq!(engine.list_keys().len(), 100);
+        assert_eq!(
+            engine.get(Bytes::from("key-150")).unwrap(),
+            Bytes::from("value-150")
+        );
+        // 不访问文件系统
+        assert!(!opts.dir_path.exists());
+
+        // 引擎打开期间同一个目录中的数据文件仍然存在
+        let data_files = load_data_files(opts.dir_path.clone(), opts.io_type).unwrap();
+        assert_eq!(data_files.len(), engine.stat().data_file_num);
+        drop(data_files);
+
+        // 引擎关闭后数据随之释放
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert!(engine.list_keys().is_empty());
+        assert_eq!(engine.stat().data_file_num, 1);
+    }
+
+    #[test]
+    fn test_engine_multi_get() {
+        for io_type in [IOType::StandardFIO, IOType::IoUring] {
+            let mut opts = test_options("bitcask-rs-multi-get");
+            opts.io_type = io_type;
+            opts.file_size = 100;
+            opts.cache_capacity = 64;
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+            for i in 0..10 {
+                engine
+                    .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-1"))
+                    .unwrap();
+            }
+            engine.delete(Bytes::from("key-3")).unwrap();
+            engine
+                .put_stream(Bytes::from("big"), &b"streamed"[..])
+                .unwrap();
+
+            // 一部分记录已经在缓存中
+            engine.get(Bytes::from("key-1")).unwrap();
+            let keys: Vec<Bytes> = ["key-0", "key-1", "key-3", "missing", "big", "", "key-9"]
+                .iter()
+                .map(|key| Bytes::from(*key))
+                .collect();
+            let values = engine.multi_get(&keys);
+            assert_eq!(values[0], Ok(Bytes::from("value-1")));
+            assert_eq!(values[1], Ok(Bytes::from("value-1")));
+            assert_eq!(values[2], Err(Errors::RecordNotFound));
+            assert_eq!(values[3], Err(Errors::RecordNotFound));
+            assert_eq!(values[4], Ok(Bytes::from("streamed")));
+            assert_eq!(values[5], Err(Errors::KeyIsEmpty));
+            assert_eq!(values[6], Ok(Bytes::from("value-1")));
+            drop(engine);
+
+            fs::remove_dir_all(opts.dir_path).unwrap();
+        }
     }
-    None
 }
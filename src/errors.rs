@@ -2,7 +2,7 @@ use std::result;
 
 use thiserror::Error;
 
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum Errors {
     #[error("failed to read from data file")]
     FailedToReadFromDataFile,
@@ -10,6 +10,13 @@ pub enum Errors {
     FailedToWriteToDataFile,
     #[error("failed to sync data file")]
     FailedToSyncDataFile,
+    #[error("failed to get data file size")]
+    FailedToGetDataFileSize,
+    #[error("failed to truncate data file")]
+    FailedToTruncateDataFile,
+    #[error("failed to allocate data file")]
+    FailedToAllocateDataFile,
+
     #[error("failed to open data file")]
     FailedToOpenDataFile,
     #[error("key is empty")]
@@ -24,6 +31,8 @@ pub enum Errors {
     DirPathIsEmpty,
     #[error("file size too small")]
     FileSizeTooSmall,
+    #[error("file size too large")]
+    FileSizeTooLarge,
     #[error("failed to create database dir")]
     FailedToCreateDataBaseDir,
     #[error("failed to read database dir")]
@@ -32,6 +41,127 @@ pub enum Errors {
     DataDirectoryInvalid,
     #[error("failed to read data file EOF")]
     ReadDataFileEOF,
+    #[error("invalid log record header")]
+    InvalidLogRecordHeader,
+    #[error("invalid crc value, log record maybe corrupted")]
+    InvalidLogRecordCrc,
+    #[error("failed to encrypt log record")]
+    FailedToEncryptLogRecord,
+    #[error("failed to decrypt log record, the encryption key may be wrong")]
+    FailedToDecryptLogRecord,
+    #[error("data files are encrypted but no encryption key is configured")]
+    EncryptionKeyRequired,
+    #[error("found unencrypted log record while encryption is enabled")]
+    UnencryptedLogRecord,
+    #[error("invalid value manifest")]
+    InvalidValueManifest,
+    #[error("failed to read value stream")]
+    FailedToReadValueStream,
+    #[error("requested sequence is no longer retained in data files")]
+    SequenceNotRetained,
+    #[error("subscription closed")]
+    SubscriptionClosed,
+    #[error("engine is in read-only mode")]
+    ReadOnly,
+
+    #[error("failed to connect replication peer")]
+    ReplicationNetworkError,
+
+    #[error("invalid replication position file")]
+    InvalidReplicationPosition,
+
+    #[error("invalid replication database id file")]
+    InvalidDatabaseId,
+
+    #[error("this raft node is not the leader")]
+    NotLeader,
+
+    #[error("raft proposal was overwritten by another leader")]
+    ProposalDropped,
+
+    #[error("timed out waiting for raft proposal to commit")]
+    ProposalTimeout,
+
+    #[error("data directory belongs to another raft node or has data written outside raft")]
+    RaftDirectoryInUse,
+
+    #[error("failed to persist raft state")]
+    FailedToPersistRaftState,
+
+    #[error("invalid raft state file")]
+    InvalidRaftState,
+
+    #[error("shard count must be greater than 0")]
+    InvalidShardCount,
+
+    #[error("shard count does not match the one the directory was created with")]
+    ShardCountMismatch,
+
+    #[error("column family not found")]
+    ColumnFamilyNotFound,
+
+    #[error("column family already exists")]
+    ColumnFamilyExists,
+
+    #[error("invalid column family name")]
+    InvalidColumnFamilyName,
+
+    #[error("value in a column family with ttl has no expiration time")]
+    InvalidExpiringValue,
+
+    #[error("secondary index not found")]
+    IndexNotFound,
+
+    #[error("secondary index already exists")]
+    IndexAlreadyExists,
+
+    #[error("secondary index is still being backfilled")]
+    IndexNotReady,
+
+    #[error("value is not a decimal integer")]
+    ValueNotInteger,
+
+    #[error("integer overflow")]
+    IntegerOverflow,
+
+    #[error("merge operator is not set")]
+    MergeOperatorNotSet,
+
+    #[error("metrics network error")]
+    MetricsNetworkError,
+
+    #[error("failed to remove data file")]
+    FailedToRemoveDataFile,
+
+    #[error("failed to copy data files")]
+    FailedToCopyDataFile,
+
+    #[error("merge is in progress")]
+    MergeInProgress,
+
+    #[error("auto merge is not configured")]
+    AutoMergeNotConfigured,
+
+    #[error("bytes_per_sync and sync_interval_ms cannot be used when sync is enabled")]
+    ConflictingSyncOptions,
+
+    #[error("preallocate cannot be used with memory io")]
+    PreallocateWithMemoryIO,
+
+    #[error("index type is not supported")]
+    IndexTypeUnsupported,
+
+    #[error("io type is not supported on this platform")]
+    IOTypeUnsupported,
+
+    #[error("invalid auto merge options")]
+    InvalidAutoMergeOptions,
+
+    #[error("failed to read config file")]
+    FailedToReadConfig,
+
+    #[error("invalid config")]
+    InvalidConfig,
 }
 
 pub type Result<T> = result::Result<T, Errors>;
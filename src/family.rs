@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordType},
+    db::Engine,
+    errors::{Errors, Result},
+    index::{self, Indexer},
+    options::FamilyOptions,
+};
+
+/// 默认列族的名称，Engine 上的 put/get/delete 都作用于默认列族
+pub const DEFAULT_FAMILY_NAME: &str = "default";
+
+/// 一个列族，拥有独立的内存索引和配置
+pub(crate) struct Family {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) options: FamilyOptions,
+    pub(crate) index: Box<dyn Indexer>,
+    pub(crate) dropped: AtomicBool,
+}
+
+impl Family {
+    /// 现在写入的 value 的过期时间，列族没有 TTL 时为 None
+    pub(crate) fn expire_at(&self) -> Option<u64> {
+        let ttl = self.options.ttl?;
+        Some(now_millis().saturating_add(ttl.as_millis() as u64))
+    }
+
+    /// 写入数据文件的 value，TTL 列族的 value 以过期时间开头
+    pub(crate) fn encode_value(&self, value: &[u8], expire_at: Option<u64>) -> Vec<u8> {
+        match self.options.ttl {
+            Some(_) => {
+                let expire_at = expire_at.or_else(|| self.expire_at()).unwrap_or_default();
+                let mut buf = Vec::with_capacity(8 + value.len());
+                buf.put_u64(expire_at);
+                buf.extend_from_slice(value);
+                buf
+            }
+            None => value.to_vec(),
+        }
+    }
+}
+
+/// 从数据文件中读取的 value 去掉过期时间，返回 value 和过期时间
+pub(crate) fn decode_family_value(
+    options: &FamilyOptions,
+    mut value: Vec<u8>,
+) -> Result<(Vec<u8>, Option<u64>)> {
+    let expire_at = family_value_expire_at(options, &value)?;
+    if expire_at.is_some() {
+        value.drain(..8);
+    }
+    Ok((value, expire_at))
+}
+
+/// 数据文件中 value 的过期时间，列族没有 TTL 时为 None
+pub(crate) fn family_value_expire_at(options: &FamilyOptions, value: &[u8]) -> Result<Option<u64>> {
+    if options.ttl.is_none() {
+        return Ok(None);
+    }
+    if value.len() < 8 {
+        return Err(Errors::InvalidExpiringValue);
+    }
+    Ok(Some((&value[..8]).get_u64()))
+}
+
+/// 过期时间是否已经到达
+pub(crate) fn is_expired(expire_at: Option<u64>) -> bool {
+    expire_at.is_some_and(|expire_at| expire_at <= now_millis())
+}
+
+/// 列族创建记录的 value：是否持久化，之后是可选的 TTL 毫秒数
+pub(crate) fn encode_family_options(options: &FamilyOptions) -> Vec<u8> {
+    let mut buf = vec![options.sync as u8];
+    if let Some(ttl) = options.ttl {
+        buf.put_u64(ttl.as_millis() as u64);
+    }
+    buf
+}
+
+pub(crate) fn decode_family_options(mut buf: &[u8]) -> FamilyOptions {
+    let sync = !buf.is_empty() && buf.get_u8() == 1;
+    let ttl = match buf.len() >= 8 {
+        true => Some(Duration::from_millis(buf.get_u64())),
+        false => None,
+    };
+    FamilyOptions { sync, ttl }
+}
+
+// 距 UNIX 纪元的毫秒数
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Engine {
+    /// 创建一个列族，返回它的句柄
+    ///
+    /// 列族的创建记录写入数据文件，重新打开后仍然存在。
+    pub fn create_cf(&self, name: &str, options: FamilyOptions) -> Result<ColumnFamily<'_>> {
+        if name.is_empty() || name == DEFAULT_FAMILY_NAME {
+            return Err(Errors::InvalidColumnFamilyName);
+        }
+        self.check_writable()?;
+
+        let mut families = self.families.write();
+        if families.values().any(|family| family.name == name) {
+            return Err(Errors::ColumnFamilyExists);
+        }
+        let family = self.create_family_locked(&mut families, name, options)?;
+        Ok(ColumnFamily {
+            engine: self,
+            family: Some(family),
+        })
+    }
+
+    /// 获取名称为 name 的列族句柄
+    pub fn cf(&self, name: &str) -> Result<ColumnFamily<'_>> {
+        if name == DEFAULT_FAMILY_NAME {
+            return Ok(ColumnFamily {
+                engine: self,
+                family: None,
+            });
+        }
+        match self
+            .families
+            .read()
+            .values()
+            .find(|family| family.name == name)
+        {
+            Some(family) => Ok(ColumnFamily {
+                engine: self,
+                family: Some(family.clone()),
+            }),
+            None => Err(Errors::ColumnFamilyNotFound),
+        }
+    }
+
+    /// 删除整个列族
+    ///
+    /// 只写入一条删除记录并丢弃列族的内存索引，不需要逐条删除列族中的数据。
+    pub fn drop_cf(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_FAMILY_NAME {
+            return Err(Errors::InvalidColumnFamilyName);
+        }
+        self.check_writable()?;
+
+        let mut families = self.families.write();
+        let id = match families.values().find(|family| family.name == name) {
+            Some(family) => family.id,
+            None => return Err(Errors::ColumnFamilyNotFound),
+        };
+        self.drop_family_locked(&mut families, id)
+    }
+
+    /// 所有列族的名称，包含默认列族
+    pub fn list_cfs(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .families
+            .read()
+            .values()
+            .map(|family| family.name.clone())
+            .collect();
+        names.sort();
+        names.insert(0, DEFAULT_FAMILY_NAME.to_string());
+        names
+    }
+
+    /// 应用复制的列族创建，不受只读模式的限制
+    ///
+    /// 同名的列族已经存在并且配置相同时直接返回，配置不同时删除后重新创建。
+    pub(crate) fn apply_create_cf(&self, name: &str, options: FamilyOptions) -> Result<()> {
+        if name.is_empty() || name == DEFAULT_FAMILY_NAME {
+            return Err(Errors::InvalidColumnFamilyName);
+        }
+        let mut families = self.families.write();
+        if let Some(family) = families.values().find(|family| family.name == name) {
+            if family.options == options {
+                return Ok(());
+            }
+            let id = family.id;
+            self.drop_family_locked(&mut families, id)?;
+        }
+        self.create_family_locked(&mut families, name, options)?;
+        Ok(())
+    }
+
+    /// 应用复制的列族删除，不受只读模式的限制，列族不存在时直接返回
+    pub(crate) fn apply_drop_cf(&self, name: &str) -> Result<()> {
+        let mut families = self.families.write();
+        match families.values().find(|family| family.name == name) {
+            Some(family) => {
+                let id = family.id;
+                self.drop_family_locked(&mut families, id)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // 写入列族的创建记录，调用者持有列族的写锁
+    fn create_family_locked(
+        &self,
+        families: &mut HashMap<u32, Arc<Family>>,
+        name: &str,
+        options: FamilyOptions,
+    ) -> Result<Arc<Family>> {
+        // 列族 id 不会复用，已删除列族的旧记录不会被当作新列族的数据
+        let id = self.next_family_id.fetch_add(1, Ordering::SeqCst);
+        let mut logrecord = LogRecord {
+            key: name.as_bytes().to_vec(),
+            value: encode_family_options(&options),
+            record_type: LogRecordType::FAMILY,
+            seq: 0,
+            cf: id,
+        };
+        self.append_log_records(std::slice::from_mut(&mut logrecord), true)?;
+
+        let family = Arc::new(self.new_family(id, name.to_string(), options));
+        families.insert(id, family.clone());
+        Ok(family)
+    }
+
+    // 写入列族的删除记录，调用者持有列族的写锁
+    fn drop_family_locked(&self, families: &mut HashMap<u32, Arc<Family>>, id: u32) -> Result<()> {
+        let family = match families.get(&id) {
+            Some(family) => family.clone(),
+            None => return Err(Errors::ColumnFamilyNotFound),
+        };
+        let mut logrecord = LogRecord {
+            key: family.name.as_bytes().to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::DROPFAMILY,
+            seq: 0,
+            cf: id,
+        };
+        self.append_log_records(std::slice::from_mut(&mut logrecord), true)?;
+
+        families.remove(&id);
+        family.dropped.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// 在列族中写入 key/value 并更新索引，调用者需要持有 key 的锁，返回用于等待持久化的写入序号
+    ///
+    /// expire_at 为 None 时按照列族的 TTL 计算过期时间
+    pub(crate) fn put_family_locked(
+        &self,
+        family: &Family,
+        key: &Bytes,
+        value: &Bytes,
+        expire_at: Option<u64>,
+    ) -> Result<u64> {
+        let mut logrecord = LogRecord {
+            key: key.to_vec(),
+            value: family.encode_value(value, expire_at),
+            record_type: LogRecordType::NORMAL,
+            seq: 0,
+            cf: family.id,
+        };
+        let (positions, commit_seq) =
+            self.write_log_records(std::slice::from_mut(&mut logrecord))?;
+        if !family.index.put(key.to_vec(), positions[0]) {
+            return Err(Errors::IndexUpdateError);
+        }
+        Ok(commit_seq)
+    }
+
+    /// 删除列族中的 key 并更新索引，调用者需要持有 key 的锁，返回用于等待持久化的写入序号
+    pub(crate) fn delete_family_locked(&self, family: &Family, key: &Bytes) -> Result<u64> {
+        if family.index.get(key.to_vec()).is_none() {
+            return Ok(0);
+        }
+        let mut logrecord = LogRecord {
+            key: key.to_vec(),
+            value: Default::default(),
+            record_type: LogRecordType::DELETE,
+            seq: 0,
+            cf: family.id,
+        };
+        let (_, commit_seq) = self.write_log_records(std::slice::from_mut(&mut logrecord))?;
+        if !family.index.delete(key.to_vec()) {
+            return Err(Errors::IndexUpdateError);
+        }
+        Ok(commit_seq)
+    }
+
+    /// 从数据文件中加载列族的创建记录
+    pub(crate) fn load_family(&self, logrecord: LogRecord) -> Result<()> {
+        let name = match String::from_utf8(logrecord.key) {
+            Ok(name) => name,
+            Err(_) => return Err(Errors::InvalidColumnFamilyName),
+        };
+        let options = decode_family_options(&logrecord.value);
+        let id = logrecord.cf;
+        // 合并会在新的数据文件开头重新写入列族的创建记录，已经存在的列族保留它的索引
+        self.families
+            .write()
+            .entry(id)
+            .or_insert_with(|| Arc::new(self.new_family(id, name, options)));
+        self.next_family_id.fetch_max(id + 1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn new_family(&self, id: u32, name: String, options: FamilyOptions) -> Family {
+        Family {
+            id,
+            name,
+            options,
+            index: Box::new(index::create_indexer(self.options.index_type.clone())),
+            dropped: AtomicBool::new(false),
+        }
+    }
+}
+
+/// 列族句柄，通过它读写列族中的数据
+pub struct ColumnFamily<'a> {
+    engine: &'a Engine,
+    family: Option<Arc<Family>>, // None 表示默认列族
+}
+
+impl ColumnFamily<'_> {
+    /// 列族的名称
+    pub fn name(&self) -> &str {
+        match self.family.as_ref() {
+            Some(family) => &family.name,
+            None => DEFAULT_FAMILY_NAME,
+        }
+    }
+
+    /// 列族的配置，默认列族使用数据库的 sync 配置，没有 TTL
+    pub fn options(&self) -> FamilyOptions {
+        match self.family.as_ref() {
+            Some(family) => family.options,
+            None => FamilyOptions {
+                sync: self.engine.options.sync,
+                ttl: None,
+            },
+        }
+    }
+
+    /// 存储 key/value 数据，key 不能为空，列族配置了 TTL 时从现在开始计算过期时间
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        let family = match self.family.as_ref() {
+            Some(family) => family,
+            None => return self.engine.put(key, value),
+        };
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_alive()?;
+        self.engine.check_writable()?;
+
+        let guard = self.engine.lock_key(&key);
+        let commit_seq = self.engine.put_family_locked(family, &key, &value, None)?;
+        drop(guard);
+
+        self.engine.wait_for_sync(commit_seq, self.sync())
+    }
+
+    /// 删除 key 对应的数据
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        let family = match self.family.as_ref() {
+            Some(family) => family,
+            None => return self.engine.delete(key),
+        };
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_alive()?;
+        self.engine.check_writable()?;
+
+        let guard = self.engine.lock_key(&key);
+        let commit_seq = self.engine.delete_family_locked(family, &key)?;
+        drop(guard);
+
+        self.engine.wait_for_sync(commit_seq, self.sync())
+    }
+
+    /// 获取 key 对应的 value，已经过期的 value 返回 RecordNotFound
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        self.get_with_expiry(key).map(|(value, _)| value)
+    }
+
+    /// 获取 key 对应的 value 和过期时间，过期时间为距 UNIX 纪元的毫秒数，没有 TTL 时为 None
+    pub(crate) fn get_with_expiry(&self, key: Bytes) -> Result<(Bytes, Option<u64>)> {
+        let family = match self.family.as_ref() {
+            Some(family) => family,
+            None => return self.engine.get(key).map(|value| (value, None)),
+        };
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_alive()?;
+
+        loop {
+            let log_record_pos = match family.index.get(key.to_vec()) {
+                Some(pos) => pos,
+                None => return Err(Errors::RecordNotFound),
+            };
+            let logrecord = match self.engine.read_log_record(&log_record_pos) {
+                Ok(logrecord) => logrecord,
+                // 记录被合并移动到了新的位置，重新读取
+                Err(_) if family.index.get(key.to_vec()) != Some(log_record_pos) => continue,
+                Err(e) => return Err(e),
+            };
+            if logrecord.record_type == LogRecordType::DELETE {
+                return Err(Errors::RecordNotFound);
+            }
+            let (value, expire_at) = decode_family_value(&family.options, logrecord.value)?;
+            return match is_expired(expire_at) {
+                true => Err(Errors::RecordNotFound),
+                false => Ok((value.into(), expire_at)),
+            };
+        }
+    }
+
+    /// 获取列族中所有的 key，按顺序排列，配置了 TTL 时不包含已经过期的 key
+    pub fn list_keys(&self) -> Vec<Bytes> {
+        match self.family.as_ref() {
+            Some(family) if family.dropped.load(Ordering::SeqCst) => Vec::new(),
+            // 过期时间保存在 value 中，需要逐个读取
+            Some(family) if family.options.ttl.is_some() => family
+                .index
+                .list_keys()
+                .into_iter()
+                .filter(|key| self.get(key.clone()).is_ok())
+                .collect(),
+            Some(family) => family.index.list_keys(),
+            None => self.engine.list_keys(),
+        }
+    }
+
+    pub(crate) fn family(&self) -> Option<&Arc<Family>> {
+        self.family.as_ref()
+    }
+
+    // 列族被删除之后句柄不能再使用
+    pub(crate) fn check_alive(&self) -> Result<()> {
+        match self.family.as_ref() {
+            Some(family) if family.dropped.load(Ordering::SeqCst) => {
+                Err(Errors::ColumnFamilyNotFound)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // 写入该列族时是否需要持久化
+    pub(crate) fn sync(&self) -> bool {
+        self.engine.options.sync || self.family.as_ref().is_some_and(|f| f.options.sync)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::options::test_options;
+
+    #[test]
+    fn test_column_families() {
+        let opts = test_options("bitcask-rs-column-families");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine
+            .create_cf(
+                "users",
+                FamilyOptions {
+                    sync: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let sessions = engine
+            .create_cf("sessions", FamilyOptions::default())
+            .unwrap();
+        assert_eq!(
+            engine.create_cf("users", FamilyOptions::default()).err(),
+            Some(Errors::ColumnFamilyExists)
+        );
+
+        // 不同列族中相同的 key 互不影响
+        engine
+            .put(Bytes::from("k"), Bytes::from("default"))
+            .unwrap();
+        users.put(Bytes::from("k"), Bytes::from("user")).unwrap();
+        sessions
+            .put(Bytes::from("k"), Bytes::from("session"))
+            .unwrap();
+        sessions
+            .put(Bytes::from("s2"), Bytes::from("session"))
+            .unwrap();
+        users.delete(Bytes::from("k")).unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("k")).unwrap(),
+            Bytes::from("default")
+        );
+        assert_eq!(
+            users.get(Bytes::from("k")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        assert_eq!(
+            engine
+                .cf("sessions")
+                .unwrap()
+                .get(Bytes::from("k"))
+                .unwrap(),
+            Bytes::from("session")
+        );
+        assert_eq!(engine.list_keys().len(), 1);
+        assert_eq!(sessions.list_keys().len(), 2);
+        assert_eq!(engine.list_cfs(), vec!["default", "sessions", "users"]);
+
+        // 删除整个列族
+        engine.drop_cf("sessions").unwrap();
+        assert_eq!(
+            engine.cf("sessions").err(),
+            Some(Errors::ColumnFamilyNotFound)
+        );
+        assert_eq!(
+            sessions.put(Bytes::from("k"), Bytes::from("v")).err(),
+            Some(Errors::ColumnFamilyNotFound)
+        );
+        users.put(Bytes::from("u1"), Bytes::from("user")).unwrap();
+        drop(users);
+        drop(sessions);
+
+        // 重新打开后列族和数据仍然存在，被删除的列族不会恢复
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(engine.list_cfs(), vec!["default", "users"]);
+        let users = engine.cf("users").unwrap();
+        assert_eq!(users.get(Bytes::from("u1")).unwrap(), Bytes::from("user"));
+        assert_eq!(
+            users.get(Bytes::from("k")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        assert_eq!(
+            engine.get(Bytes::from("k")).unwrap(),
+            Bytes::from("default")
+        );
+
+        // 同名的新列族是空的
+        let sessions = engine
+            .create_cf("sessions", FamilyOptions::default())
+            .unwrap();
+        assert!(sessions.list_keys().is_empty());
+        drop(sessions);
+        drop(users);
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert!(engine.cf("sessions").unwrap().list_keys().is_empty());
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_column_family_ttl() {
+        let opts = test_options("bitcask-rs-column-family-ttl");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let ttl = FamilyOptions {
+            ttl: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let cache = engine.create_cf("cache", ttl).unwrap();
+        cache.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        let mut batch = crate::batch::WriteBatch::new();
+        batch
+            .put(&cache, Bytes::from("b"), Bytes::from("2"))
+            .unwrap();
+        engine.write(batch).unwrap();
+        assert_eq!(cache.get(Bytes::from("a")).unwrap(), Bytes::from("1"));
+        assert_eq!(cache.get(Bytes::from("b")).unwrap(), Bytes::from("2"));
+        assert_eq!(cache.list_keys().len(), 2);
+
+        // 过期之后读取不到，合并时从索引和数据文件中移除
+        std::thread::sleep(Duration::from_millis(600));
+        cache.put(Bytes::from("c"), Bytes::from("3")).unwrap();
+        assert_eq!(
+            cache.get(Bytes::from("a")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        assert_eq!(cache.list_keys(), vec![Bytes::from("c")]);
+        engine.merge().unwrap();
+        assert!(cache.family().unwrap().index.get(b"a".to_vec()).is_none());
+        assert_eq!(cache.get(Bytes::from("c")).unwrap(), Bytes::from("3"));
+
+        // TTL 保存在列族的创建记录中，重新打开后仍然生效
+        drop(cache);
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        let cache = engine.cf("cache").unwrap();
+        assert_eq!(cache.options(), ttl);
+        assert_eq!(cache.get(Bytes::from("c")).unwrap(), Bytes::from("3"));
+        assert_eq!(
+            cache.get(Bytes::from("b")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        drop(cache);
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
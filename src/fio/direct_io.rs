@@ -0,0 +1,461 @@
+use std::{
+    alloc::{self, Layout},
+    fs::{self, File, OpenOptions},
+    io,
+    ops::{Deref, DerefMut},
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    slice,
+};
+
+use bytes::{Buf, BufMut};
+use log::{error, warn};
+use parking_lot::{Mutex, RwLock};
+
+use super::{fallocate, IOManager};
+
+use crate::errors::{Errors, Result};
+
+/// O_DIRECT 要求缓冲区地址、偏移和长度都按块对齐
+const BLOCK_SIZE: usize = 4096;
+
+/// 不满的块的副本文件的扩展名，和数据文件放在同一个目录中
+const TAIL_FILE_EXTENSION: &str = "tail";
+
+/// 绕过 page cache 的文件 IO
+///
+/// 读取时按块对齐读出包含目标区间的所有块再拷贝；写入时最后一个块不满也整块写入，
+/// 不满的部分填充 0，并在内存中保留这个块，下一次追加时补齐后重新写入。
+/// 因此文件大小会超过有效数据的末尾，和预分配的文件一样，打开时扫描到 0 即为末尾，
+/// 文件写满切换时截断。
+///
+/// 追加时最后一个块在原来的位置整块重写，如果块中有已经持久化的数据，重写之前先把这部分数据
+/// 写入副本文件并持久化，重写时掉电损坏了这个块，重新打开时从副本中恢复，已经持久化的记录不会丢失。
+/// 每次持久化之后第一次重写最后一个块时需要多一次副本文件的 fsync。
+/// 只在 Linux 上可用，其他平台打开时返回 IOTypeUnsupported。
+pub struct DirectIO {
+    fd: RwLock<File>,
+    tail_path: PathBuf,
+    tail: Mutex<TailState>,
+}
+
+// 文件末尾的写入状态
+struct TailState {
+    block: Option<TailBlock>,    // 最后一次写入的不满的块
+    written_end: u64,            // 已经写入的数据的末尾
+    synced_end: u64,             // 已经持久化的数据的末尾
+    saved: Option<(u64, usize)>, // 副本文件中保存的块的偏移和长度
+    tail_file: Option<File>,     // 副本文件，第一次保存时创建
+}
+
+// 文件中最后一个不满的块
+struct TailBlock {
+    start: u64,    // 块在文件中的偏移
+    data: Vec<u8>, // 块中的有效数据
+}
+
+impl DirectIO {
+    pub fn new(file_name: PathBuf) -> Result<Self> {
+        let tail_path = tail_file_name(&file_name);
+        let fd = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .custom_flags(libc::O_DIRECT)
+            .open(file_name)
+        {
+            Ok(fd) => fd,
+            Err(e) => {
+                error!("open file with O_DIRECT error: {}", e);
+                return Err(Errors::FailedToOpenDataFile);
+            }
+        };
+        // 打开之前已经在文件中的数据都当作已经持久化
+        let size = match fd.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                error!("get file metadata error: {}", e);
+                return Err(Errors::FailedToGetDataFileSize);
+            }
+        };
+        let dio = DirectIO {
+            fd: RwLock::new(fd),
+            tail_path,
+            tail: Mutex::new(TailState {
+                block: None,
+                written_end: size,
+                synced_end: size,
+                saved: None,
+                tail_file: None,
+            }),
+        };
+        dio.restore_tail(size)?;
+        Ok(dio)
+    }
+
+    // 上次重写最后一个块时掉电，块中已经持久化的数据可能损坏，从副本文件中恢复
+    fn restore_tail(&self, size: u64) -> Result<()> {
+        let data = match fs::read(&self.tail_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                error!("read tail file error: {}", e);
+                return Err(Errors::FailedToOpenDataFile);
+            }
+        };
+        // 副本写入一半时对应的块还没有开始重写，不需要恢复
+        let (start, saved) = match decode_tail(&data) {
+            Some((start, saved)) if start + saved.len() as u64 <= size => (start, saved),
+            _ => return Ok(()),
+        };
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let n = self.read(&mut block, start)?;
+        if n < saved.len() || block[..saved.len()] != *saved {
+            warn!("restore torn block at {} from {:?}", start, self.tail_path);
+            block[..saved.len()].copy_from_slice(saved);
+            let mut aligned = AlignedBuf::new(BLOCK_SIZE);
+            aligned.copy_from_slice(&block);
+            let fd = self.fd.write();
+            if let Err(e) = fd.write_all_at(&aligned, start).and_then(|_| fd.sync_all()) {
+                error!("restore tail block error: {}", e);
+                return Err(Errors::FailedToOpenDataFile);
+            }
+        }
+        self.tail.lock().saved = Some((start, saved.len()));
+        Ok(())
+    }
+}
+
+impl TailState {
+    // 把块中已经持久化的数据写入副本文件并持久化，之后才能在原来的位置重写这个块
+    fn save(&mut self, tail_path: &Path, start: u64, data: &[u8]) -> Result<()> {
+        if matches!(self.saved, Some((s, len)) if s == start && len >= data.len()) {
+            return Ok(());
+        }
+        let mut buf = Vec::with_capacity(data.len() + 16);
+        buf.put_u64(start);
+        buf.put_u32(data.len() as u32);
+        buf.extend_from_slice(data);
+        buf.put_u32(crc32fast::hash(&buf));
+
+        // 保存新的副本之前已经执行过 sync，上一个副本保护的重写都已经持久化，可以直接覆盖
+        let res = (|| -> io::Result<()> {
+            if self.tail_file.is_none() {
+                self.tail_file = Some(
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(tail_path)?,
+                );
+            }
+            let tail_file = self.tail_file.as_ref().unwrap();
+            tail_file.write_all_at(&buf, 0)?;
+            tail_file.set_len(buf.len() as u64)?;
+            tail_file.sync_data()
+        })();
+        if let Err(e) = res {
+            error!("write tail file error: {}", e);
+            return Err(Errors::FailedToWriteToDataFile);
+        }
+        self.saved = Some((start, data.len()));
+        Ok(())
+    }
+}
+
+impl IOManager for DirectIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let start = align_down(offset);
+        let end = align_up(offset + buf.len() as u64);
+        let mut aligned = AlignedBuf::new((end - start) as usize);
+
+        let read_guard = self.fd.read();
+        let mut n = 0;
+        while n < aligned.len() {
+            match read_guard.read_at(&mut aligned[n..], start + n as u64) {
+                Ok(0) => break,
+                Ok(m) => n += m,
+                Err(e) => {
+                    error!("read file error: {}", e);
+                    return Err(Errors::FailedToReadFromDataFile);
+                }
+            }
+        }
+
+        let skip = (offset - start) as usize;
+        let copied = n.saturating_sub(skip).min(buf.len());
+        buf[..copied].copy_from_slice(&aligned[skip..skip + copied]);
+        Ok(copied)
+    }
+
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let start = align_down(offset);
+        let mut tail = self.tail.lock();
+
+        // 补齐写入位置所在块中之前的数据
+        let prefix_len = (offset - start) as usize;
+        let mut data = match tail.block.take() {
+            Some(block) if block.start == start && block.data.len() >= prefix_len => block.data,
+            _ => {
+                let mut prefix = vec![0u8; prefix_len];
+                let n = self.read(&mut prefix, start)?;
+                prefix.truncate(n);
+                prefix
+            }
+        };
+        data.resize(prefix_len, 0);
+
+        // 块中已经持久化的数据先写入副本，不在原来的位置直接覆盖唯一的一份
+        let synced = (tail.synced_end.saturating_sub(start) as usize).min(prefix_len);
+        if synced > 0 {
+            tail.save(&self.tail_path, start, &data[..synced])?;
+        }
+        data.extend_from_slice(buf);
+
+        let mut aligned = AlignedBuf::new(align_up(data.len() as u64) as usize);
+        aligned[..data.len()].copy_from_slice(&data);
+
+        let write_guard = self.fd.write();
+        if let Err(e) = write_guard.write_all_at(&aligned, start) {
+            error!("write file error: {}", e);
+            return Err(Errors::FailedToWriteToDataFile);
+        }
+        tail.written_end = tail.written_end.max(offset + buf.len() as u64);
+
+        // 保留最后一个不满的块，下次追加时不需要从文件中读取
+        let tail_len = data.len() % BLOCK_SIZE;
+        if tail_len > 0 {
+            let tail_start = data.len() - tail_len;
+            tail.block = Some(TailBlock {
+                start: start + tail_start as u64,
+                data: data[tail_start..].to_vec(),
+            });
+        }
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        // O_DIRECT 不经过 page cache，但文件元数据和磁盘缓存仍然需要 fsync
+        let written_end = self.tail.lock().written_end;
+        let read_guard = self.fd.read();
+        if let Err(e) = read_guard.sync_all() {
+            error!("sync file error: {}", e);
+            return Err(Errors::FailedToSyncDataFile);
+        }
+        let mut tail = self.tail.lock();
+        tail.synced_end = tail.synced_end.max(written_end);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        let read_guard = self.fd.read();
+        match read_guard.metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) => {
+                error!("get file metadata error: {}", e);
+                Err(Errors::FailedToGetDataFileSize)
+            }
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let mut tail = self.tail.lock();
+        let write_guard = self.fd.write();
+        match write_guard.set_len(size) {
+            Ok(_) => {
+                tail.block = None;
+                tail.written_end = size;
+                tail.synced_end = tail.synced_end.min(size);
+                // 副本中被截断的部分不能在重新打开时恢复
+                if matches!(tail.saved, Some((start, len)) if start + len as u64 > size) {
+                    tail.saved = None;
+                    tail.tail_file = None;
+                    remove_tail_file(&self.tail_path)?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("truncate file error: {}", e);
+                Err(Errors::FailedToTruncateDataFile)
+            }
+        }
+    }
+
+    fn allocate(&self, size: u64) -> Result<()> {
+        let write_guard = self.fd.write();
+        if let Err(e) = fallocate(&write_guard, size) {
+            error!("allocate file error: {}", e);
+            return Err(Errors::FailedToAllocateDataFile);
+        }
+        Ok(())
+    }
+}
+
+/// 数据文件对应的副本文件
+pub(crate) fn tail_file_name(file_name: &Path) -> PathBuf {
+    file_name.with_extension(TAIL_FILE_EXTENSION)
+}
+
+/// 删除副本文件，不存在时直接返回
+pub(crate) fn remove_tail_file(tail_path: &Path) -> Result<()> {
+    match fs::remove_file(tail_path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            error!("failed to remove tail file {:?}: {}", tail_path, e);
+            Err(Errors::FailedToRemoveDataFile)
+        }
+    }
+}
+
+// 副本文件编码为 块偏移 | 长度 | 数据 | crc，crc 校验之前的所有字节
+fn decode_tail(data: &[u8]) -> Option<(u64, &[u8])> {
+    if data.len() < 16 {
+        return None;
+    }
+    let (body, mut crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != crc.get_u32() {
+        return None;
+    }
+    let mut header = &body[..12];
+    let start = header.get_u64();
+    let len = header.get_u32() as usize;
+    if body.len() != 12 + len || len >= BLOCK_SIZE {
+        return None;
+    }
+    Some((start, &body[12..]))
+}
+
+fn align_down(offset: u64) -> u64 {
+    offset / BLOCK_SIZE as u64 * BLOCK_SIZE as u64
+}
+
+fn align_up(offset: u64) -> u64 {
+    offset.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64
+}
+
+/// 按块对齐的缓冲区，内容初始化为 0
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len.max(BLOCK_SIZE), BLOCK_SIZE).unwrap();
+        // layout 的大小不为 0
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // ptr 指向 layout.size() 字节已经初始化的内存
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_direct_io_unaligned_appends() {
+        let path = std::env::temp_dir().join("bitcask-rs-direct-io.data");
+        let _ = fs::remove_file(&path);
+        let dio = DirectIO::new(path.clone()).unwrap();
+
+        // 跨越块边界的不对齐追加写入
+        let mut expected = Vec::new();
+        let mut offset = 0;
+        for i in 0..20u8 {
+            let chunk = vec![i + 1; 1000];
+            assert_eq!(dio.write(&chunk, offset).unwrap(), chunk.len());
+            offset += chunk.len() as u64;
+            expected.extend_from_slice(&chunk);
+        }
+        dio.sync().unwrap();
+        assert_eq!(dio.size().unwrap(), align_up(offset));
+
+        let mut buf = vec![0u8; 3000];
+        assert_eq!(dio.read(&mut buf, 4000).unwrap(), 3000);
+        assert_eq!(buf, expected[4000..7000].to_vec());
+
+        // 重新打开后从文件中读出不满的块继续追加
+        drop(dio);
+        let dio = DirectIO::new(path.clone()).unwrap();
+        dio.write(b"tail", offset).unwrap();
+        let mut buf = vec![0u8; 8];
+        assert_eq!(dio.read(&mut buf, offset - 4).unwrap(), 8);
+        assert_eq!(&buf[..4], &expected[expected.len() - 4..]);
+        assert_eq!(&buf[4..], b"tail");
+
+        // 截断到有效数据的末尾，读到文件末尾时返回实际读取的字节数
+        dio.truncate(offset + 4).unwrap();
+        assert_eq!(dio.size().unwrap(), offset + 4);
+        let mut buf = vec![0u8; 100];
+        assert_eq!(dio.read(&mut buf, offset).unwrap(), 4);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_direct_io_restores_torn_tail_block() {
+        let path = std::env::temp_dir().join("bitcask-rs-direct-io-torn.data");
+        let tail_path = tail_file_name(&path);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&tail_path);
+        let dio = DirectIO::new(path.clone()).unwrap();
+
+        // 没有持久化的数据所在的块直接重写，不需要副本
+        dio.write(&[1; 100], 0).unwrap();
+        dio.write(&[2; 100], 100).unwrap();
+        assert!(!tail_path.exists());
+
+        // 持久化之后重写块之前先保存副本
+        dio.sync().unwrap();
+        dio.write(&[3; 100], 200).unwrap();
+        assert!(tail_path.exists());
+        drop(dio);
+
+        // 模拟重写时掉电，块中已经持久化的数据损坏，重新打开时恢复
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[9; 300], 0).unwrap();
+        drop(file);
+        let dio = DirectIO::new(path.clone()).unwrap();
+        let mut buf = vec![0u8; 200];
+        assert_eq!(dio.read(&mut buf, 0).unwrap(), 200);
+        assert_eq!(&buf[..100], &[1; 100]);
+        assert_eq!(&buf[100..], &[2; 100]);
+
+        // 截断到副本保存的数据之前时删除副本
+        dio.truncate(150).unwrap();
+        assert!(!tail_path.exists());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use super::{file_io::FileIO, IOManager};
+
+use crate::errors::{Errors, Result};
+
+/// 每个目录的故障注入器，目录中以 IOType::FaultInjection 打开的文件都经过注入器
+static INJECTORS: Mutex<BTreeMap<PathBuf, Arc<FaultInjector>>> = Mutex::new(BTreeMap::new());
+
+/// 注入的故障
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    // 写入失败，不写入任何数据
+    FailWrite,
+    // sync 失败，数据没有持久化
+    FailSync,
+    // 写入只持久化了前 n 个字节，随后立即崩溃
+    TornWrite(usize),
+}
+
+/// 测试使用的故障注入器，模拟一个目录中的文件写入失败、sync 失败、写入不完整和崩溃
+///
+/// 数据写在真实的文件中，同时记录每个文件已经持久化的长度。崩溃时把所有文件截断到
+/// 持久化的长度，丢弃没有 sync 的数据，之后所有的读写都返回错误，直到重新打开。
+/// 数据文件只会追加写入，因此已经持久化的部分就是文件的一个前缀。
+#[derive(Default)]
+pub struct FaultInjector {
+    state: Mutex<InjectorState>,
+}
+
+#[derive(Default)]
+struct InjectorState {
+    writes: u64,               // 已经执行的写入次数
+    syncs: u64,                // 已经执行的 sync 次数
+    faults: Vec<(Fault, u64)>, // 计划的故障和触发故障的写入或 sync 次数
+    crashed: bool,
+    files: HashMap<PathBuf, Arc<FaultFile>>,
+}
+
+// 同一个文件多次打开时共享持久化的长度
+struct FaultFile {
+    inner: FileIO,
+    synced_len: Mutex<u64>,
+}
+
+impl FaultInjector {
+    /// 为目录安装新的故障注入器，替换之前的注入器
+    pub fn install(dir_path: &Path) -> Arc<Self> {
+        let injector = Arc::new(FaultInjector::default());
+        INJECTORS
+            .lock()
+            .insert(dir_path.to_path_buf(), injector.clone());
+        injector
+    }
+
+    /// 移除目录的故障注入器
+    pub fn uninstall(dir_path: &Path) {
+        INJECTORS.lock().remove(dir_path);
+    }
+
+    /// 计划在之后的第 nth 次写入（或 sync）时注入故障，nth 从 1 开始
+    pub fn inject(&self, fault: Fault, nth: u64) {
+        let mut state = self.state.lock();
+        let at = match fault {
+            Fault::FailSync => state.syncs + nth,
+            _ => state.writes + nth,
+        };
+        state.faults.push((fault, at));
+    }
+
+    /// 模拟崩溃，丢弃所有文件中没有持久化的数据
+    pub fn crash(&self) {
+        self.state.lock().crash();
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.state.lock().crashed
+    }
+}
+
+impl InjectorState {
+    // 取出当前这次写入或 sync 需要注入的故障
+    fn take_fault(&mut self, sync: bool) -> Option<Fault> {
+        let count = match sync {
+            true => {
+                self.syncs += 1;
+                self.syncs
+            }
+            false => {
+                self.writes += 1;
+                self.writes
+            }
+        };
+        let i = self
+            .faults
+            .iter()
+            .position(|(fault, at)| *at == count && (*fault == Fault::FailSync) == sync)?;
+        Some(self.faults.remove(i).0)
+    }
+
+    fn crash(&mut self) {
+        self.crashed = true;
+        for file in self.files.values() {
+            let synced_len = *file.synced_len.lock();
+            if file.inner.size().is_ok_and(|size| size > synced_len) {
+                let _ = file.inner.truncate(synced_len);
+            }
+        }
+    }
+}
+
+/// 按故障注入器的计划读写文件
+pub struct FaultIO {
+    injector: Arc<FaultInjector>,
+    file: Arc<FaultFile>,
+}
+
+impl FaultIO {
+    /// 打开文件，所在目录必须已经安装了故障注入器
+    pub fn open(file_name: PathBuf) -> Result<Self> {
+        let dir_path = file_name.parent().unwrap_or(Path::new(""));
+        let injector = match INJECTORS.lock().get(dir_path) {
+            Some(injector) => injector.clone(),
+            None => return Err(Errors::IOTypeUnsupported),
+        };
+        let mut state = injector.state.lock();
+        let file = match state.files.get(&file_name) {
+            Some(file) => file.clone(),
+            None => {
+                // 打开之前已经在文件中的数据都已经持久化
+                let inner = FileIO::new(file_name.clone())?;
+                let synced_len = Mutex::new(inner.size()?);
+                let file = Arc::new(FaultFile { inner, synced_len });
+                state.files.insert(file_name, file.clone());
+                file
+            }
+        };
+        drop(state);
+        Ok(FaultIO { injector, file })
+    }
+}
+
+impl IOManager for FaultIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if self.injector.is_crashed() {
+            return Err(Errors::FailedToReadFromDataFile);
+        }
+        self.file.inner.read(buf, offset)
+    }
+
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let mut state = self.injector.state.lock();
+        if state.crashed {
+            return Err(Errors::FailedToWriteToDataFile);
+        }
+        match state.take_fault(false) {
+            Some(Fault::TornWrite(n)) => {
+                // 不完整的写入没有持久化，崩溃丢弃没有 sync 的数据之后，
+                // 紧接着持久化部分的前 n 个字节留在文件末尾，形成不完整的记录
+                state.crash();
+                if *self.file.synced_len.lock() == offset {
+                    self.file.inner.write(&buf[..n.min(buf.len())], offset)?;
+                }
+                Err(Errors::FailedToWriteToDataFile)
+            }
+            Some(_) => Err(Errors::FailedToWriteToDataFile),
+            None => self.file.inner.write(buf, offset),
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut state = self.injector.state.lock();
+        if state.crashed {
+            return Err(Errors::FailedToSyncDataFile);
+        }
+        if state.take_fault(true).is_some() {
+            // 和 Linux 一样，sync 失败后没有持久化的脏页被丢弃，之后读到的是 0
+            let synced_len = *self.file.synced_len.lock();
+            let size = self.file.inner.size()?;
+            if size > synced_len {
+                self.file
+                    .inner
+                    .write(&vec![0; (size - synced_len) as usize], synced_len)?;
+            }
+            return Err(Errors::FailedToSyncDataFile);
+        }
+        self.file.inner.sync()?;
+        *self.file.synced_len.lock() = self.file.inner.size()?;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.file.inner.size()
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        if self.injector.is_crashed() {
+            return Err(Errors::FailedToTruncateDataFile);
+        }
+        self.file.inner.truncate(size)?;
+        let mut synced_len = self.file.synced_len.lock();
+        *synced_len = (*synced_len).min(size);
+        Ok(())
+    }
+
+    fn allocate(&self, size: u64) -> Result<()> {
+        if self.injector.is_crashed() {
+            return Err(Errors::FailedToAllocateDataFile);
+        }
+        self.file.inner.allocate(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        fs,
+    };
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        batch::WriteBatch,
+        db::Engine,
+        options::{test_options, IOType, Options},
+    };
+
+    /// 每个场景执行的操作数量
+    const OPS: usize = 40;
+
+    // 已经确认持久化的写入，None 表示 key 已经被删除
+    // 失败的写入可能持久化也可能没有，之后 key 的值不确定
+    #[derive(Default)]
+    struct Model {
+        durable: HashMap<Bytes, Option<Bytes>>,
+        pending: Vec<(Bytes, Option<Bytes>)>,
+        uncertain: HashSet<Bytes>,
+    }
+
+    impl Model {
+        fn apply(&mut self, ops: Vec<(Bytes, Option<Bytes>)>, res: Result<()>, synced: bool) {
+            for (key, value) in ops {
+                match (&res, synced) {
+                    (Ok(_), true) => {
+                        self.uncertain.remove(&key);
+                        self.durable.insert(key, value);
+                    }
+                    (Ok(_), false) => self.pending.push((key, value)),
+                    (Err(_), _) => {
+                        self.uncertain.insert(key);
+                    }
+                }
+            }
+        }
+
+        // sync 成功之后之前的写入都已经持久化
+        fn synced(&mut self) {
+            for (key, value) in self.pending.drain(..) {
+                self.uncertain.remove(&key);
+                self.durable.insert(key, value);
+            }
+        }
+
+        // 崩溃时没有 sync 的写入可能丢失
+        fn crashed(&mut self) {
+            for (key, _) in self.pending.drain(..) {
+                self.uncertain.insert(key);
+            }
+        }
+    }
+
+    // 执行第 i 个操作：写入、删除之前写入的 key 或者原子批量写入
+    fn run_op(engine: &Engine, model: &mut Model, i: usize) {
+        let key = |i: usize| Bytes::from(format!("key-{:03}", i));
+        let value = |i: usize| Bytes::from(format!("value-{:03}-{}", i, "x".repeat(i % 17)));
+        let (ops, res) = match i % 7 {
+            6 => {
+                let res = engine.delete(key(i - 3));
+                (vec![(key(i - 3), None)], res)
+            }
+            4 => {
+                let default = engine.cf("default").unwrap();
+                let mut batch = WriteBatch::new();
+                batch.put(&default, key(i), value(i)).unwrap();
+                batch.put(&default, key(i + 1000), value(i)).unwrap();
+                batch.delete(&default, key(i - 1)).unwrap();
+                let ops = vec![
+                    (key(i), Some(value(i))),
+                    (key(i + 1000), Some(value(i))),
+                    (key(i - 1), None),
+                ];
+                (ops, engine.write(batch))
+            }
+            _ => (vec![(key(i), Some(value(i)))], engine.put(key(i), value(i))),
+        };
+        model.apply(ops, res, engine.options.sync);
+    }
+
+    // 不注入故障重新打开，检查所有确认持久化的写入
+    fn check_recovered(opts: &Options, model: &Model, case: &str) {
+        FaultInjector::uninstall(&opts.dir_path);
+        let opts = Options {
+            io_type: IOType::StandardFIO,
+            ..opts.clone()
+        };
+        let engine = match Engine::open(opts.clone()) {
+            Ok(engine) => engine,
+            Err(e) => panic!("{}: failed to reopen engine: {}", case, e),
+        };
+        for (key, value) in model.durable.iter() {
+            if model.uncertain.contains(key) {
+                continue;
+            }
+            match value {
+                Some(value) => assert_eq!(engine.get(key.clone()).as_ref(), Ok(value), "{}", case),
+                None => assert_eq!(
+                    engine.get(key.clone()),
+                    Err(Errors::RecordNotFound),
+                    "{}",
+                    case
+                ),
+            }
+        }
+        // 恢复之后可以继续写入
+        engine
+            .put(Bytes::from("after-crash"), Bytes::from("value"))
+            .unwrap();
+        drop(engine);
+        fs::remove_dir_all(&opts.dir_path).unwrap();
+    }
+
+    // 在第 nth 次写入或 sync 时注入故障，故障之后继续执行剩余的操作，最后崩溃并重新打开
+    fn run_fault_case(opts: &Options, fault: Fault, nth: u64) {
+        let case = format!("{:?} at {}", fault, nth);
+        let _ = fs::remove_dir_all(opts.dir_path.clone());
+        let injector = FaultInjector::install(&opts.dir_path);
+        injector.inject(fault, nth);
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let mut model = Model::default();
+        for i in 0..OPS {
+            run_op(&engine, &mut model, i);
+            if !opts.sync && i % 4 == 3 && engine.sync().is_ok() {
+                model.synced();
+            }
+        }
+        injector.crash();
+        model.crashed();
+        drop(engine);
+        check_recovered(opts, &model, &case);
+    }
+
+    #[test]
+    fn test_crash_after_failed_write() {
+        let opts = Options {
+            file_size: 256,
+            sync: true,
+            io_type: IOType::FaultInjection,
+            ..test_options("bitcask-rs-crash-failed-write")
+        };
+        for nth in 1..=OPS as u64 + 10 {
+            run_fault_case(&opts, Fault::FailWrite, nth);
+        }
+    }
+
+    #[test]
+    fn test_crash_after_failed_sync() {
+        let mut opts = Options {
+            file_size: 256,
+            sync: true,
+            io_type: IOType::FaultInjection,
+            ..test_options("bitcask-rs-crash-failed-sync")
+        };
+        for nth in 1..=OPS as u64 + 10 {
+            run_fault_case(&opts, Fault::FailSync, nth);
+        }
+
+        // 不同步写入时只有 sync 成功之前的写入需要保留
+        opts.sync = false;
+        for nth in 1..=OPS as u64 / 4 + 5 {
+            run_fault_case(&opts, Fault::FailSync, nth);
+        }
+    }
+
+    #[test]
+    fn test_crash_with_torn_write() {
+        let mut opts = Options {
+            file_size: 256,
+            sync: true,
+            io_type: IOType::FaultInjection,
+            ..test_options("bitcask-rs-crash-torn-write")
+        };
+        for nth in 1..=OPS as u64 + 10 {
+            run_fault_case(&opts, Fault::TornWrite(nth as usize % 13), nth);
+        }
+
+        opts.sync = false;
+        for nth in 1..=OPS as u64 + 10 {
+            run_fault_case(&opts, Fault::TornWrite(nth as usize % 13 + 3), nth);
+        }
+    }
+
+    #[test]
+    fn test_crash_discards_unsynced_writes() {
+        let mut opts = Options {
+            file_size: 256,
+            sync: true,
+            io_type: IOType::FaultInjection,
+            ..test_options("bitcask-rs-crash-unsynced")
+        };
+        opts.sync = false;
+        for crash_at in 0..OPS {
+            let _ = fs::remove_dir_all(opts.dir_path.clone());
+            let injector = FaultInjector::install(&opts.dir_path);
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+            let mut model = Model::default();
+            for i in 0..crash_at {
+                run_op(&engine, &mut model, i);
+                if i % 4 == 3 {
+                    engine.sync().unwrap();
+                    model.synced();
+                }
+            }
+            injector.crash();
+            model.crashed();
+            drop(engine);
+            check_recovered(&opts, &model, &format!("crash after {} ops", crash_at));
+        }
+    }
+}
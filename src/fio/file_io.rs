@@ -1,6 +1,5 @@
 use std::{
     fs::{File, OpenOptions},
-    io::Write,
     os::unix::fs::FileExt,
     path::PathBuf,
     sync::Arc,
@@ -8,7 +7,7 @@ use std::{
 
 use parking_lot::RwLock;
 
-use super::IOManager;
+use super::{fallocate, IOManager};
 
 use log::error;
 
@@ -23,8 +22,8 @@ impl FileIO {
         match OpenOptions::new()
             .read(true)
             .write(true)
-            .append(true)
             .create(true)
+            .truncate(false)
             .open(file_name)
         {
             Ok(fd) => Ok(FileIO {
@@ -32,7 +31,7 @@ impl FileIO {
             }),
             Err(e) => {
                 error!("open file error: {}", e);
-                return Err(Errors::FailedToOpenDataFile);
+                Err(Errors::FailedToOpenDataFile)
             }
         }
     }
@@ -42,21 +41,21 @@ impl IOManager for FileIO {
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         let read_guard = self.fd.read();
         match read_guard.read_at(buf, offset) {
-            Ok(n) => return Ok(n),
+            Ok(n) => Ok(n),
             Err(e) => {
                 error!("read file error: {}", e);
-                return Err(Errors::FailedToReadFromDataFile);
+                Err(Errors::FailedToReadFromDataFile)
             }
-        };
+        }
     }
 
-    fn write(&self, buf: &[u8]) -> Result<usize> {
-        let mut write_guard = self.fd.write();
-        match write_guard.write(buf) {
-            Ok(n) => return Ok(n),
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let write_guard = self.fd.write();
+        match write_guard.write_all_at(buf, offset) {
+            Ok(_) => Ok(buf.len()),
             Err(e) => {
                 error!("write file error: {}", e);
-                return Err(Errors::FailedToWriteToDataFile);
+                Err(Errors::FailedToWriteToDataFile)
             }
         }
     }
@@ -64,13 +63,44 @@ impl IOManager for FileIO {
     fn sync(&self) -> Result<()> {
         let read_guard = self.fd.read();
         match read_guard.sync_all() {
-            Ok(_) => return Ok(()),
+            Ok(_) => Ok(()),
             Err(e) => {
                 error!("sync file error: {}", e);
-                return Err(Errors::FailedToSyncDataFile);
+                Err(Errors::FailedToSyncDataFile)
+            }
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        let read_guard = self.fd.read();
+        match read_guard.metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) => {
+                error!("get file metadata error: {}", e);
+                Err(Errors::FailedToGetDataFileSize)
             }
         }
     }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let write_guard = self.fd.write();
+        match write_guard.set_len(size) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("truncate file error: {}", e);
+                Err(Errors::FailedToTruncateDataFile)
+            }
+        }
+    }
+
+    fn allocate(&self, size: u64) -> Result<()> {
+        let write_guard = self.fd.write();
+        if let Err(e) = fallocate(&write_guard, size) {
+            error!("allocate file error: {}", e);
+            return Err(Errors::FailedToAllocateDataFile);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -85,7 +115,7 @@ mod tests {
         let file_io = FileIO::new(path.clone());
         assert!(file_io.is_ok());
         let fio = file_io.unwrap();
-        let result = fio.write("hello world!!Q".as_bytes());
+        let result = fio.write("hello world!!Q".as_bytes(), 0);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 14);
 
@@ -99,7 +129,7 @@ mod tests {
         let file_io = FileIO::new(path.clone());
         assert!(file_io.is_ok());
         let fio = file_io.unwrap();
-        let result = fio.write("hello world!!Q".as_bytes());
+        let result = fio.write("hello world!!Q".as_bytes(), 0);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 14);
 
@@ -119,12 +149,13 @@ mod tests {
         let file_io = FileIO::new(path.clone());
         assert!(file_io.is_ok());
         let fio = file_io.unwrap();
-        let result = fio.write("hello world!!Q".as_bytes());
+        let result = fio.write("hello world!!Q".as_bytes(), 0);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 14);
 
         let res = fio.sync();
         assert!(res.is_ok());
+        assert_eq!(fio.size().unwrap(), 14);
 
         let res3 = fs::remove_file(path);
         assert!(res3.is_ok());
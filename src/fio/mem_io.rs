@@ -0,0 +1,159 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+};
+
+use parking_lot::{Mutex, RwLock};
+
+use super::IOManager;
+
+use crate::errors::Result;
+
+/// 进程内所有内存目录，目录中的文件全部关闭之后目录随之释放
+static MEM_DIRS: Mutex<BTreeMap<PathBuf, Weak<MemDir>>> = Mutex::new(BTreeMap::new());
+
+// 内存中的目录，文件名到文件内容的映射
+#[derive(Default)]
+struct MemDir {
+    files: Mutex<HashMap<String, Arc<RwLock<Vec<u8>>>>>,
+}
+
+/// 数据保存在内存中的文件 IO，不访问文件系统
+///
+/// 同一个目录中的文件在进程内共享，重新打开同名文件时读到之前写入的内容；
+/// 目录中所有打开的文件都关闭之后（例如引擎关闭）数据随之释放。
+pub struct MemIO {
+    _dir: Arc<MemDir>, // 持有目录，保证打开期间其他文件不会被释放
+    data: Arc<RwLock<Vec<u8>>>,
+}
+
+impl MemIO {
+    pub fn new(file_name: PathBuf) -> Result<Self> {
+        let (dir_path, name) = split_path(&file_name);
+        let dir = open_dir(dir_path);
+        let data = dir.files.lock().entry(name).or_default().clone();
+        Ok(MemIO { _dir: dir, data })
+    }
+}
+
+impl IOManager for MemIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let data = self.data.read();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let mut data = self.data.write();
+        let start = offset as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.read().len() as u64)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.data.write().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn allocate(&self, size: u64) -> Result<()> {
+        let mut data = self.data.write();
+        if data.len() < size as usize {
+            data.resize(size as usize, 0);
+        }
+        Ok(())
+    }
+}
+
+/// 列出内存目录中的文件名，目录不存在时返回空
+pub(crate) fn list_files(dir_path: &Path) -> Vec<String> {
+    match MEM_DIRS.lock().get(dir_path).and_then(Weak::upgrade) {
+        Some(dir) => dir.files.lock().keys().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// 从内存目录中删除文件，已经打开的文件仍然可以读写，关闭后释放
+pub(crate) fn remove_file(file_name: &Path) {
+    let (dir_path, name) = split_path(file_name);
+    if let Some(dir) = MEM_DIRS.lock().get(dir_path).and_then(Weak::upgrade) {
+        dir.files.lock().remove(&name);
+    }
+}
+
+// 打开内存目录，不存在或者已经释放时创建新的目录
+fn open_dir(dir_path: &Path) -> Arc<MemDir> {
+    let mut dirs = MEM_DIRS.lock();
+    if let Some(dir) = dirs.get(dir_path).and_then(Weak::upgrade) {
+        return dir;
+    }
+    dirs.retain(|_, dir| dir.strong_count() > 0);
+    let dir = Arc::new(MemDir::default());
+    dirs.insert(dir_path.to_path_buf(), Arc::downgrade(&dir));
+    dir
+}
+
+fn split_path(file_name: &Path) -> (&Path, String) {
+    let dir_path = file_name.parent().unwrap_or(Path::new(""));
+    let name = file_name
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (dir_path, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_io() {
+        let dir_path = PathBuf::from("/bitcask-rs-mem-io");
+        let path = dir_path.join("000000000.data");
+        let mio = MemIO::new(path.clone()).unwrap();
+        assert_eq!(mio.write(b"key-a", 0).unwrap(), 5);
+        assert_eq!(mio.write(b"key-b", 10).unwrap(), 5);
+        assert_eq!(mio.size().unwrap(), 15);
+
+        let mut buf = [0u8; 10];
+        assert_eq!(mio.read(&mut buf, 5).unwrap(), 10);
+        assert_eq!(&buf, b"\0\0\0\0\0key-b");
+        assert_eq!(mio.read(&mut buf, 12).unwrap(), 3);
+        assert_eq!(mio.read(&mut buf, 100).unwrap(), 0);
+
+        // 同一个目录中重新打开时读到之前写入的内容
+        let reopened = MemIO::new(path.clone()).unwrap();
+        assert_eq!(reopened.size().unwrap(), 15);
+        assert_eq!(list_files(&dir_path), vec!["000000000.data".to_string()]);
+
+        mio.allocate(100).unwrap();
+        assert_eq!(reopened.size().unwrap(), 100);
+        mio.truncate(5).unwrap();
+        assert_eq!(reopened.size().unwrap(), 5);
+
+        remove_file(&path);
+        assert!(list_files(&dir_path).is_empty());
+        assert_eq!(mio.read(&mut buf, 0).unwrap(), 5);
+
+        // 所有文件关闭后目录被释放
+        let other = MemIO::new(dir_path.join("000000001.data")).unwrap();
+        drop(other);
+        drop(mio);
+        drop(reopened);
+        assert!(list_files(&dir_path).is_empty());
+    }
+}
@@ -1,14 +1,181 @@
+#[cfg(target_os = "linux")]
+pub mod direct_io;
+#[cfg(test)]
+pub mod fault_io;
 pub mod file_io;
-use crate::errors::Result;
+pub mod mem_io;
+#[cfg(target_os = "linux")]
+pub mod uring_io;
 
-// 抽象 IO 管理接口，可以接入不同的 IO 管理器，目前支持标准文件 IO
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use log::{error, warn};
+
+use crate::{
+    errors::{Errors, Result},
+    options::IOType,
+};
+
+// 抽象 IO 管理接口，可以接入不同的 IO 管理器，目前支持标准文件 IO、Direct IO、io_uring 和内存
 pub trait IOManager: Sync + Send {
     /// 从文件给定偏移量处读取数据
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
 
-    /// 写入字节数组到文件中
-    fn write(&self, buf: &[u8]) -> Result<usize>;
+    /// 在文件给定偏移量处写入字节数组
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize>;
 
     /// 持久化数据
     fn sync(&self) -> Result<()>;
+
+    /// 获取文件大小
+    fn size(&self) -> Result<u64>;
+
+    /// 将文件截断到指定大小
+    fn truncate(&self, size: u64) -> Result<()>;
+
+    /// 预先为文件分配 size 大小的磁盘空间，文件大小会增长到 size，新分配的部分填充 0
+    fn allocate(&self, size: u64) -> Result<()>;
+
+    /// 批量读取，每个请求为偏移量和缓冲区，返回每个请求实际读取的字节数
+    fn read_batch(&self, requests: &mut [(u64, &mut [u8])]) -> Result<Vec<usize>> {
+        requests
+            .iter_mut()
+            .map(|(offset, buf)| self.read(buf, *offset))
+            .collect()
+    }
+
+    /// 从 offset 开始依次连续写入多个字节数组，返回写入的总字节数
+    fn write_batch(&self, bufs: &[&[u8]], offset: u64) -> Result<usize> {
+        let mut n = 0;
+        for buf in bufs {
+            n += self.write(buf, offset + n as u64)?;
+        }
+        Ok(n)
+    }
+
+    /// 依次连续写入多个字节数组之后持久化，返回写入的总字节数
+    fn write_batch_sync(&self, bufs: &[&[u8]], offset: u64) -> Result<usize> {
+        let n = self.write_batch(bufs, offset)?;
+        self.sync()?;
+        Ok(n)
+    }
+}
+
+/// 根据文件名称和 IO 类型初始化 IOManager
+pub fn new_io_manager(file_name: PathBuf, io_type: IOType) -> Result<Box<dyn IOManager>> {
+    match io_type {
+        IOType::StandardFIO => Ok(Box::new(file_io::FileIO::new(file_name)?)),
+        IOType::DirectIO => new_direct_io_manager(file_name),
+        IOType::IoUring => new_uring_io_manager(file_name),
+        IOType::Memory => Ok(Box::new(mem_io::MemIO::new(file_name)?)),
+        #[cfg(test)]
+        IOType::FaultInjection => Ok(Box::new(fault_io::FaultIO::open(file_name)?)),
+    }
+}
+
+/// 创建数据目录，已经存在时直接返回
+pub fn create_dir(dir_path: &Path, io_type: IOType) -> Result<()> {
+    if io_type == IOType::Memory || dir_path.exists() {
+        return Ok(());
+    }
+    if let Err(e) = fs::create_dir_all(dir_path) {
+        warn!("failed to create database dir: {:?}", e);
+        return Err(Errors::FailedToCreateDataBaseDir);
+    }
+    Ok(())
+}
+
+/// 列出目录中所有文件的名称
+pub fn list_files(dir_path: &Path, io_type: IOType) -> Result<Vec<String>> {
+    if io_type == IOType::Memory {
+        return Ok(mem_io::list_files(dir_path));
+    }
+    let dir = match fs::read_dir(dir_path) {
+        Ok(dir) => dir,
+        Err(e) => {
+            warn!("failed to read database dir: {:?}", e);
+            return Err(Errors::FailedToReadDataBaseDir);
+        }
+    };
+    let mut file_names = Vec::new();
+    for entry in dir {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => return Err(Errors::FailedToReadDataBaseDir),
+        };
+        if path.is_file() {
+            if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+                file_names.push(file_name.to_string());
+            }
+        }
+    }
+    Ok(file_names)
+}
+
+/// 删除文件
+pub fn remove_file(file_name: &Path, io_type: IOType) -> Result<()> {
+    if io_type == IOType::Memory {
+        mem_io::remove_file(file_name);
+        return Ok(());
+    }
+    if let Err(e) = fs::remove_file(file_name) {
+        error!("failed to remove data file {:?}: {}", file_name, e);
+        return Err(Errors::FailedToRemoveDataFile);
+    }
+    // Direct IO 的数据文件可能有保存最后一个块的副本文件
+    #[cfg(target_os = "linux")]
+    if io_type == IOType::DirectIO {
+        direct_io::remove_tail_file(&direct_io::tail_file_name(file_name))?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn new_direct_io_manager(file_name: PathBuf) -> Result<Box<dyn IOManager>> {
+    Ok(Box::new(direct_io::DirectIO::new(file_name)?))
+}
+
+// O_DIRECT 只在 Linux 上使用，其他平台的页缓存语义不同，不静默退化成标准文件 IO
+#[cfg(not(target_os = "linux"))]
+fn new_direct_io_manager(_file_name: PathBuf) -> Result<Box<dyn IOManager>> {
+    Err(Errors::IOTypeUnsupported)
+}
+
+// 内核不支持 io_uring 时使用标准文件 IO
+#[cfg(target_os = "linux")]
+fn new_uring_io_manager(file_name: PathBuf) -> Result<Box<dyn IOManager>> {
+    match uring_io::UringIO::new(file_name.clone()) {
+        Ok(Some(uring_io)) => Ok(Box::new(uring_io)),
+        Ok(None) => Ok(Box::new(file_io::FileIO::new(file_name)?)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn new_uring_io_manager(file_name: PathBuf) -> Result<Box<dyn IOManager>> {
+    Ok(Box::new(file_io::FileIO::new(file_name)?))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn fallocate(file: &File, size: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // 文件描述符在 file 的生命周期内一直有效
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+// 其他平台没有 fallocate，只扩展文件大小
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn fallocate(file: &File, size: u64) -> std::io::Result<()> {
+    match file.metadata()?.len() < size {
+        true => file.set_len(size),
+        false => Ok(()),
+    }
 }
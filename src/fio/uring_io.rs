@@ -0,0 +1,457 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use log::{error, warn};
+use parking_lot::Mutex;
+
+use super::{fallocate, IOManager};
+
+use crate::errors::{Errors, Result};
+
+/// 每个 ring 的提交队列深度，超过的批量请求分多次提交
+const RING_ENTRIES: u32 = 64;
+
+/// 每个文件最多缓存的空闲 ring 数量，并发更高时临时创建的 ring 用完之后直接释放
+const MAX_IDLE_RINGS: usize = 4;
+
+/// 基于 io_uring 的文件 IO
+///
+/// 批量读取和批量写入在一次提交中完成，减少系统调用的次数，写入之后需要持久化时
+/// fsync 通过 IO_LINK 链接在写入之后一起提交。
+/// 每次提交从空闲的 ring 中取出一个独占使用，等待完成时不持有任何锁，并发的请求使用不同的 ring。
+/// 单个读写请求只完成了一部分时（例如读到文件末尾），剩余的部分使用普通的 pread/pwrite 补齐。
+pub struct UringIO {
+    fd: File,
+    rings: Mutex<Vec<IoUring>>, // 空闲的 ring，提交失败无法恢复的 ring 直接丢弃
+    next_user_data: AtomicU64,  // 下一个请求的 user_data，不同批次的请求不会重复
+}
+
+impl UringIO {
+    /// 打开文件并创建 io_uring，内核不支持 io_uring 时返回 None
+    pub fn new(file_name: PathBuf) -> Result<Option<Self>> {
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(ring) => ring,
+            Err(e) => {
+                warn!(
+                    "io_uring is unavailable, fall back to standard file io: {}",
+                    e
+                );
+                return Ok(None);
+            }
+        };
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file_name)
+        {
+            Ok(fd) => Ok(Some(UringIO {
+                fd,
+                rings: Mutex::new(vec![ring]),
+                next_user_data: AtomicU64::new(0),
+            })),
+            Err(e) => {
+                error!("open file error: {}", e);
+                Err(Errors::FailedToOpenDataFile)
+            }
+        }
+    }
+
+    // 提交一批请求并等待全部完成，返回每个请求的结果，按提交的顺序排列
+    //
+    // 请求完成之前内核一直持有请求引用的缓冲区，提交出错（例如被信号中断）时也要等到
+    // 所有已经被内核接收的请求完成之后才能返回，否则调用者释放缓冲区之后内核仍然可能读写。
+    fn submit(&self, entries: Vec<io_uring::squeue::Entry>) -> io::Result<Vec<i32>> {
+        let ring = self.rings.lock().pop();
+        let mut ring = match ring {
+            Some(ring) => ring,
+            None => IoUring::new(RING_ENTRIES)?,
+        };
+        let results = self.submit_on(&mut ring, &entries)?;
+        let mut rings = self.rings.lock();
+        if rings.len() < MAX_IDLE_RINGS {
+            rings.push(ring);
+        }
+        Ok(results)
+    }
+
+    // 在独占的 ring 上提交请求，返回错误时 ring 中可能残留失效的请求，调用者必须丢弃 ring
+    fn submit_on(
+        &self,
+        ring: &mut IoUring,
+        entries: &[io_uring::squeue::Entry],
+    ) -> io::Result<Vec<i32>> {
+        let mut results = vec![0; entries.len()];
+        for (batch_start, batch) in entries.chunks(RING_ENTRIES as usize).enumerate() {
+            let base = batch_start * RING_ENTRIES as usize;
+            let first = self
+                .next_user_data
+                .fetch_add(batch.len() as u64, Ordering::SeqCst);
+            let user_data = first..first + batch.len() as u64;
+            for (i, entry) in batch.iter().enumerate() {
+                let entry = entry.clone().user_data(first + i as u64);
+                // 上一批请求全部完成之后队列为空，容量不小于 batch 的长度；
+                // 缓冲区在这一批请求全部完成或者被丢弃之前一直有效
+                unsafe { ring.submission().push(&entry).unwrap() };
+            }
+
+            let mut completed = 0;
+            while completed < batch.len() {
+                if let Err(e) = ring.submit_and_wait(1) {
+                    let retry = matches!(
+                        e.raw_os_error(),
+                        Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)
+                    );
+                    if !retry && completed + ring.submission().len() == batch.len() {
+                        // 剩余的请求都还在提交队列中，没有被内核接收，由调用者丢弃整个 ring，
+                        // 避免之后的提交带上已经失效的缓冲区
+                        return Err(e);
+                    }
+                    // 还有请求在内核中执行，必须等待它们完成
+                    warn!(
+                        "io_uring submit error, waiting for in-flight requests: {}",
+                        e
+                    );
+                }
+                for cqe in ring.completion() {
+                    // 只接受这一批请求的结果，其他的完成事件不能覆盖结果
+                    if !user_data.contains(&cqe.user_data()) {
+                        warn!("ignore unexpected io_uring completion {}", cqe.user_data());
+                        continue;
+                    }
+                    results[base + (cqe.user_data() - first) as usize] = cqe.result();
+                    completed += 1;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    // 构造从 offset 开始依次写入 bufs 的请求，返回请求和每个请求的偏移量
+    fn write_entries(
+        &self,
+        bufs: &[&[u8]],
+        offset: u64,
+    ) -> (Vec<io_uring::squeue::Entry>, Vec<u64>) {
+        let fd = types::Fd(self.fd.as_raw_fd());
+        let mut offsets = Vec::with_capacity(bufs.len());
+        let mut next = offset;
+        for buf in bufs {
+            offsets.push(next);
+            next += buf.len() as u64;
+        }
+        let entries = bufs
+            .iter()
+            .zip(offsets.iter())
+            .map(|(buf, offset)| {
+                opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32)
+                    .offset(*offset)
+                    .build()
+            })
+            .collect();
+        (entries, offsets)
+    }
+
+    // 检查写入请求的结果，只写入了一部分时补齐剩余的部分，返回是否有请求没有完整写入
+    //
+    // 链接的写入只写入了一部分时链接中断，之后的写入被取消，同样使用 pwrite 补齐
+    fn complete_writes(&self, bufs: &[&[u8]], offsets: &[u64], results: &[i32]) -> Result<bool> {
+        let mut short = false;
+        for ((buf, offset), res) in bufs.iter().zip(offsets).zip(results) {
+            let n = match *res {
+                res if res == -libc::ECANCELED && short => 0,
+                res if res < 0 => {
+                    error!(
+                        "write file error: {}",
+                        std::io::Error::from_raw_os_error(-res)
+                    );
+                    return Err(Errors::FailedToWriteToDataFile);
+                }
+                res => res as usize,
+            };
+            if n < buf.len() {
+                short = true;
+                if let Err(e) = self.fd.write_all_at(&buf[n..], offset + n as u64) {
+                    error!("write file error: {}", e);
+                    return Err(Errors::FailedToWriteToDataFile);
+                }
+            }
+        }
+        Ok(short)
+    }
+}
+
+impl IOManager for UringIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let sizes = self.read_batch(&mut [(offset, buf)])?;
+        Ok(sizes[0])
+    }
+
+    fn write(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        self.write_batch(&[buf], offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let fsync = opcode::Fsync::new(types::Fd(self.fd.as_raw_fd())).build();
+        match self.submit(vec![fsync]) {
+            Ok(results) => check_sync(results[0]),
+            Err(e) => {
+                error!("sync file error: {}", e);
+                Err(Errors::FailedToSyncDataFile)
+            }
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        match self.fd.metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) => {
+                error!("get file metadata error: {}", e);
+                Err(Errors::FailedToGetDataFileSize)
+            }
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        match self.fd.set_len(size) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("truncate file error: {}", e);
+                Err(Errors::FailedToTruncateDataFile)
+            }
+        }
+    }
+
+    fn allocate(&self, size: u64) -> Result<()> {
+        if let Err(e) = fallocate(&self.fd, size) {
+            error!("allocate file error: {}", e);
+            return Err(Errors::FailedToAllocateDataFile);
+        }
+        Ok(())
+    }
+
+    fn read_batch(&self, requests: &mut [(u64, &mut [u8])]) -> Result<Vec<usize>> {
+        let fd = types::Fd(self.fd.as_raw_fd());
+        let entries = requests
+            .iter_mut()
+            .map(|(offset, buf)| {
+                opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(*offset)
+                    .build()
+            })
+            .collect();
+        let results = match self.submit(entries) {
+            Ok(results) => results,
+            Err(e) => {
+                error!("read file error: {}", e);
+                return Err(Errors::FailedToReadFromDataFile);
+            }
+        };
+
+        let mut sizes = Vec::with_capacity(requests.len());
+        for ((offset, buf), res) in requests.iter_mut().zip(results) {
+            if res < 0 {
+                error!(
+                    "read file error: {}",
+                    std::io::Error::from_raw_os_error(-res)
+                );
+                return Err(Errors::FailedToReadFromDataFile);
+            }
+            // 读取了一部分时继续读取，直到读满或者读到文件末尾
+            let mut n = res as usize;
+            while n > 0 && n < buf.len() {
+                match self.fd.read_at(&mut buf[n..], *offset + n as u64) {
+                    Ok(0) => break,
+                    Ok(m) => n += m,
+                    Err(e) => {
+                        error!("read file error: {}", e);
+                        return Err(Errors::FailedToReadFromDataFile);
+                    }
+                }
+            }
+            sizes.push(n);
+        }
+        Ok(sizes)
+    }
+
+    fn write_batch(&self, bufs: &[&[u8]], offset: u64) -> Result<usize> {
+        let (entries, offsets) = self.write_entries(bufs, offset);
+        let results = match self.submit(entries) {
+            Ok(results) => results,
+            Err(e) => {
+                error!("write file error: {}", e);
+                return Err(Errors::FailedToWriteToDataFile);
+            }
+        };
+        self.complete_writes(bufs, &offsets, &results)?;
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    fn write_batch_sync(&self, bufs: &[&[u8]], offset: u64) -> Result<usize> {
+        // 链接的请求必须在同一次提交中，超过队列深度时分开提交
+        if bufs.len() >= RING_ENTRIES as usize {
+            let n = self.write_batch(bufs, offset)?;
+            self.sync()?;
+            return Ok(n);
+        }
+
+        // 每个写入都链接到下一个请求，前面的写入全部完成之后才会执行 fsync
+        let (mut entries, offsets) = self.write_entries(bufs, offset);
+        for entry in entries.iter_mut() {
+            *entry = entry.clone().flags(squeue::Flags::IO_LINK);
+        }
+        entries.push(opcode::Fsync::new(types::Fd(self.fd.as_raw_fd())).build());
+        let results = match self.submit(entries) {
+            Ok(results) => results,
+            Err(e) => {
+                error!("write file error: {}", e);
+                return Err(Errors::FailedToWriteToDataFile);
+            }
+        };
+        let (fsync_res, write_results) = results.split_last().unwrap();
+        let n = bufs.iter().map(|buf| buf.len()).sum();
+        // 写入失败时后面链接的 fsync 被取消；只写入了一部分时链接同样中断，补齐之后单独 fsync
+        if self.complete_writes(bufs, &offsets, write_results)? {
+            self.sync()?;
+            return Ok(n);
+        }
+        check_sync(*fsync_res)?;
+        Ok(n)
+    }
+}
+
+// 检查 fsync 请求的结果
+fn check_sync(res: i32) -> Result<()> {
+    if res < 0 {
+        error!(
+            "sync file error: {}",
+            std::io::Error::from_raw_os_error(-res)
+        );
+        return Err(Errors::FailedToSyncDataFile);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, thread};
+
+    use super::*;
+    use crate::fio::file_io::FileIO;
+
+    #[test]
+    fn test_uring_io_batch() {
+        let path = std::env::temp_dir().join("bitcask-rs-uring-io.data");
+        let _ = fs::remove_file(&path);
+        // 内核不支持 io_uring 时和标准文件 IO 的行为一致
+        let fio: Box<dyn IOManager> = match UringIO::new(path.clone()).unwrap() {
+            Some(uring_io) => Box::new(uring_io),
+            None => Box::new(FileIO::new(path.clone()).unwrap()),
+        };
+
+        let bufs: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize + 1]).collect();
+        let refs: Vec<&[u8]> = bufs.iter().map(|buf| buf.as_slice()).collect();
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        assert_eq!(fio.write_batch(&refs, 0).unwrap(), total);
+        fio.sync().unwrap();
+        assert_eq!(fio.size().unwrap(), total as u64);
+
+        let mut read_bufs: Vec<Vec<u8>> = bufs.iter().map(|buf| vec![0; buf.len()]).collect();
+        let mut offset = 0;
+        let mut requests: Vec<(u64, &mut [u8])> = Vec::new();
+        for buf in read_bufs.iter_mut() {
+            let len = buf.len() as u64;
+            requests.push((offset, buf.as_mut_slice()));
+            offset += len;
+        }
+        let sizes = fio.read_batch(&mut requests).unwrap();
+        assert_eq!(sizes, bufs.iter().map(|buf| buf.len()).collect::<Vec<_>>());
+        assert_eq!(read_bufs, bufs);
+
+        // 读到文件末尾时返回实际读取的字节数
+        let mut buf = vec![0u8; 10];
+        assert_eq!(fio.read(&mut buf, total as u64 - 4).unwrap(), 4);
+        assert_eq!(fio.read(&mut buf, total as u64 + 10).unwrap(), 0);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_uring_io_ignores_stale_completions() {
+        let path = std::env::temp_dir().join("bitcask-rs-uring-io-stale.data");
+        let _ = fs::remove_file(&path);
+        let uring_io = match UringIO::new(path.clone()).unwrap() {
+            Some(uring_io) => uring_io,
+            None => return,
+        };
+        uring_io.write(b"value", 0).unwrap();
+
+        // 留在完成队列中的其他完成事件不会覆盖这一批请求的结果
+        {
+            let mut rings = uring_io.rings.lock();
+            let ring = rings.last_mut().unwrap();
+            let nop = opcode::Nop::new().build().user_data(0);
+            unsafe { ring.submission().push(&nop).unwrap() };
+            let far = opcode::Nop::new().build().user_data(1000);
+            unsafe { ring.submission().push(&far).unwrap() };
+            ring.submit_and_wait(2).unwrap();
+        }
+        let mut buf = [0u8; 5];
+        assert_eq!(uring_io.read(&mut buf, 0).unwrap(), 5);
+        assert_eq!(&buf, b"value");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_uring_io_write_batch_sync() {
+        let path = std::env::temp_dir().join("bitcask-rs-uring-io-sync.data");
+        let _ = fs::remove_file(&path);
+        let uring_io = match UringIO::new(path.clone()).unwrap() {
+            Some(uring_io) => Arc::new(uring_io),
+            None => return,
+        };
+
+        // 写入和链接的 fsync 一起提交，超过队列深度时分开提交
+        let bufs: Vec<Vec<u8>> = (0..RING_ENTRIES as u8 + 10).map(|i| vec![i; 16]).collect();
+        let refs: Vec<&[u8]> = bufs.iter().map(|buf| buf.as_slice()).collect();
+        assert_eq!(uring_io.write_batch_sync(&refs[..3], 0).unwrap(), 48);
+        assert_eq!(
+            uring_io.write_batch_sync(&refs, 48).unwrap(),
+            refs.len() * 16
+        );
+
+        // 并发的请求各自使用一个 ring，不会互相等待
+        let handles: Vec<_> = (0..8u64)
+            .map(|i| {
+                let uring_io = uring_io.clone();
+                thread::spawn(move || {
+                    let offset = 4096 * (i + 1);
+                    for _ in 0..50 {
+                        uring_io.write_batch_sync(&[b"value"], offset).unwrap();
+                        let mut buf = [0u8; 5];
+                        assert_eq!(uring_io.read(&mut buf, offset).unwrap(), 5);
+                        assert_eq!(&buf, b"value");
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(uring_io.rings.lock().len() <= MAX_IDLE_RINGS);
+
+        let mut buf = vec![0u8; 16];
+        uring_io.read(&mut buf, 48 + 16 * 5).unwrap();
+        assert_eq!(buf, vec![5u8; 16]);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
 use std::{collections::BTreeMap, sync::Arc};
 
+use bytes::Bytes;
+
 use parking_lot::RwLock;
 
 use crate::data::log_record::LogRecordPos;
@@ -34,6 +36,16 @@ impl Indexer for BTree {
         let result = write_guard.remove(&key);
         result.is_some()
     }
+    fn list_keys(&self) -> Vec<Bytes> {
+        let read_guard = self.tree.read();
+        read_guard
+            .keys()
+            .map(|k| Bytes::copy_from_slice(k))
+            .collect()
+    }
+    fn len(&self) -> usize {
+        self.tree.read().len()
+    }
 }
 
 #[cfg(test)]
@@ -50,7 +62,7 @@ mod tests {
                 offset: 10,
             },
         );
-        assert_eq!(result1, true);
+        assert!(result1);
 
         let result2 = btree.put(
             "aa".as_bytes().to_vec(),
@@ -59,7 +71,7 @@ mod tests {
                 offset: 20,
             },
         );
-        assert_eq!(result2, true);
+        assert!(result2);
     }
 
     #[test]
@@ -118,10 +130,10 @@ mod tests {
         );
 
         let result1 = btree.delete("".as_bytes().to_vec());
-        assert_eq!(result1, true);
+        assert!(result1);
 
         let result2 = btree.delete("aa".as_bytes().to_vec());
-        assert_eq!(result2, true);
+        assert!(result2);
 
         let res1 = btree.get("".as_bytes().to_vec());
         assert_eq!(res1, None);
@@ -130,6 +142,26 @@ mod tests {
         assert_eq!(res2, None);
 
         let result3 = btree.delete("not exist".as_bytes().to_vec());
-        assert_eq!(result3, false);
+        assert!(!result3);
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let btree = BTree::new();
+        assert!(btree.list_keys().is_empty());
+        for key in ["bb", "aa", "cc"] {
+            btree.put(
+                key.as_bytes().to_vec(),
+                LogRecordPos {
+                    file_id: 1,
+                    offset: 10,
+                },
+            );
+        }
+        btree.delete("cc".as_bytes().to_vec());
+        assert_eq!(
+            btree.list_keys(),
+            vec![Bytes::from("aa"), Bytes::from("bb")]
+        );
     }
 }
@@ -1,5 +1,7 @@
 pub mod btree;
 
+use bytes::Bytes;
+
 use crate::data::log_record::LogRecordPos;
 use crate::options::IndexType;
 
@@ -7,6 +9,10 @@ pub trait Indexer: Sync + Send {
     fn put(&self, key: Vec<u8>, pos: LogRecordPos) -> bool;
     fn get(&self, key: Vec<u8>) -> Option<LogRecordPos>;
     fn delete(&self, key: Vec<u8>) -> bool;
+    /// 按顺序返回所有的 key
+    fn list_keys(&self) -> Vec<Bytes>;
+    /// 索引中 key 的数量
+    fn len(&self) -> usize;
 }
 
 /// 根据配置创建索引
@@ -14,6 +20,5 @@ pub fn create_indexer(index_type: IndexType) -> impl Indexer {
     match index_type {
         IndexType::BTree => btree::BTree::new(),
         IndexType::SkipList => todo!(),
-        _ => panic!("Unsupported index type"),
     }
 }
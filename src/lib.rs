@@ -1,7 +1,26 @@
+mod cache;
+mod commit;
 mod data;
 mod errors;
 mod fio;
 mod index;
+mod limiter;
 
+pub mod backup;
+pub mod batch;
+pub mod cdc;
+pub mod compaction;
+pub mod config;
 pub mod db;
+pub mod family;
+pub mod listener;
+pub mod merge;
+pub mod metrics;
 pub mod options;
+pub mod raft;
+pub mod replication;
+pub mod rmw;
+pub mod secondary;
+pub mod sharded;
+pub mod stream;
+pub mod verify;
@@ -0,0 +1,116 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::db::Engine;
+
+/// 令牌桶限速器，限制后台任务每秒读写的字节数
+///
+/// 桶的容量为一秒的配额，超过容量的请求先透支，调用者等待令牌补足之后返回。
+pub(crate) struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    rate: u64,      // 每秒的字节数，0 表示不限速
+    available: f64, // 当前可用的令牌，透支时为负数
+    last: Instant,  // 上一次补充令牌的时间
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u64) -> Self {
+        RateLimiter {
+            state: Mutex::new(LimiterState {
+                rate,
+                available: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn rate(&self) -> u64 {
+        self.state.lock().rate
+    }
+
+    /// 调整速率，之前透支的令牌保留，桶中多余的令牌丢弃；从不限速开始限速时桶是满的
+    pub(crate) fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock();
+        state.refill();
+        state.available = match state.rate {
+            0 => rate as f64,
+            _ => state.available.min(rate as f64),
+        };
+        state.rate = rate;
+    }
+
+    /// 获取 bytes 个令牌，令牌不足时阻塞等待
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock();
+            if state.rate == 0 {
+                return;
+            }
+            state.refill();
+            state.available -= bytes as f64;
+            match state.available < 0.0 {
+                true => Duration::from_secs_f64(-state.available / state.rate as f64),
+                false => return,
+            }
+        };
+        thread::sleep(wait);
+    }
+}
+
+impl LimiterState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.available = (self.available + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+impl Engine {
+    /// 调整后台任务（例如合并）每秒读写的字节数，0 表示不限速
+    ///
+    /// 前台的读写不受限制，新的速率对正在运行的合并立即生效。
+    pub fn set_background_io_rate(&self, bytes_per_sec: u64) {
+        self.background_limiter.set_rate(bytes_per_sec);
+    }
+
+    /// 后台任务当前每秒读写的字节数，0 表示不限速
+    pub fn background_io_rate(&self) -> u64 {
+        self.background_limiter.rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        // 不限速时直接返回
+        let limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        limiter.acquire(u64::MAX / 2);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // 桶中初始有一秒的配额，透支的部分需要等待
+        limiter.set_rate(100_000);
+        let start = Instant::now();
+        limiter.acquire(100_000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire(20_000);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        // 调低速率后等待时间变长
+        limiter.set_rate(50_000);
+        let start = Instant::now();
+        limiter.acquire(10_000);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
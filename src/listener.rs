@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use crate::{
+    compaction::MergeStats,
+    errors::{Errors, Result},
+};
+
+/// 引擎事件的监听器，通过 Options 注册
+///
+/// 回调在触发事件的线程中同步执行，耗时的操作（例如上传文件）应该交给其他线程处理。
+/// 所有方法都有空的默认实现，只需要实现关心的事件。
+pub trait EventListener: Send + Sync {
+    /// 数据库打开完成，内存索引已经加载
+    fn on_open(&self, _dir_path: &Path) {}
+
+    /// 数据库实例关闭
+    fn on_close(&self, _dir_path: &Path) {}
+
+    /// 活跃文件写满后切换，sealed_path 为已经持久化、之后不会再写入的旧文件
+    fn on_file_rotated(&self, _sealed_file_id: u32, _sealed_path: &Path) {}
+
+    /// 后台线程定时持久化活跃文件完成，result 为持久化的结果
+    fn on_background_sync(&self, _result: &Result<()>) {}
+
+    /// 发现损坏的记录，包括打开时截断的不完整记录
+    fn on_corruption(&self, _file_id: u32, _offset: u64, _error: &Errors) {}
+
+    /// 开始合并数据文件
+    fn on_merge_start(&self) {}
+
+    /// 合并结束，result 为合并的结果
+    fn on_merge_finish(&self, _result: &Result<MergeStats>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::{Seek, SeekFrom, Write},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        db::Engine,
+        options::{test_options, RecoveryMode},
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventListener for Recorder {
+        fn on_open(&self, _dir_path: &Path) {
+            self.events.lock().push("open".to_string());
+        }
+
+        fn on_close(&self, _dir_path: &Path) {
+            self.events.lock().push("close".to_string());
+        }
+
+        fn on_file_rotated(&self, sealed_file_id: u32, sealed_path: &Path) {
+            assert!(sealed_path.exists());
+            self.events
+                .lock()
+                .push(format!("rotated {}", sealed_file_id));
+        }
+
+        fn on_background_sync(&self, result: &Result<()>) {
+            assert!(result.is_ok());
+            self.events.lock().push("sync".to_string());
+        }
+
+        fn on_corruption(&self, file_id: u32, offset: u64, error: &Errors) {
+            self.events
+                .lock()
+                .push(format!("corruption {} {} {}", file_id, offset, error));
+        }
+    }
+
+    #[test]
+    fn test_event_listener() {
+        let recorder = Arc::new(Recorder::default());
+        let mut opts = test_options("bitcask-rs-event-listener");
+        opts.file_size = 60;
+        opts.sync_interval_ms = 10;
+        opts.event_listeners = vec![recorder.clone()];
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // 每条记录 20 字节，第 4 条写入时切换文件
+        for i in 0..4 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value-1"))
+                .unwrap();
+        }
+        for _ in 0..200 {
+            if recorder.events.lock().iter().any(|e| e == "sync") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(engine);
+        {
+            let events = recorder.events.lock();
+            assert_eq!(events[0], "open");
+            assert!(events.iter().any(|e| e == "rotated 0"));
+            assert!(events.iter().any(|e| e == "sync"));
+            assert_eq!(events.last().unwrap(), "close");
+        }
+
+        // 损坏第一个文件中第二条记录的 value
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(opts.dir_path.join("000000000.data"))
+            .unwrap();
+        file.seek(SeekFrom::Start(35)).unwrap();
+        file.write_all(b"X").unwrap();
+        drop(file);
+
+        recorder.events.lock().clear();
+        opts.sync_interval_ms = 0;
+        opts.recovery_mode = RecoveryMode::Strict;
+        assert!(Engine::open(opts.clone()).is_err());
+        assert_eq!(
+            *recorder.events.lock(),
+            vec![format!("corruption 0 20 {}", Errors::InvalidLogRecordCrc)]
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
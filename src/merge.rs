@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordPos, LogRecordType},
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// 一个 key 最多保留的未合并操作数，超过之后写入时直接合并成完整的 value
+const MAX_MERGE_OPERANDS: usize = 32;
+
+/// 合并算子，把 key 之前的 value 和按写入顺序排列的操作数合并成新的 value
+///
+/// 例如计数器可以把操作数解析成增量并累加，不需要在写入时读取当前值。
+pub trait MergeOperator: Send + Sync {
+    /// existing 为 None 表示 key 之前不存在或者已经被删除
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes;
+}
+
+/// 一个 key 还没有合并的操作数，索引指向最后一个操作数
+#[derive(Clone)]
+pub(crate) struct MergeChain {
+    base: Option<LogRecordPos>,  // 第一个操作数之前的 value
+    operands: Vec<LogRecordPos>, // 按写入顺序排列的操作数
+}
+
+impl MergeChain {
+    /// 索引是否仍然指向最后一个操作数
+    pub(crate) fn is_current(&self, pos: LogRecordPos) -> bool {
+        self.operands.last() == Some(&pos)
+    }
+
+    /// 读取合并后的 value 需要的所有记录位置
+    pub(crate) fn positions(&self) -> impl Iterator<Item = LogRecordPos> + '_ {
+        self.base.iter().chain(self.operands.iter()).copied()
+    }
+}
+
+impl Engine {
+    /// 设置合并算子，读取带有操作数的 key 之前需要设置
+    ///
+    /// 操作数和算子无关地保存在数据文件中，重新打开数据库之后需要设置同样的算子。
+    pub fn set_merge_operator(&self, operator: Arc<dyn MergeOperator>) {
+        *self.merge_operator.write() = Some(operator);
+    }
+
+    /// 为 key 写入一个合并操作数，写入时不读取当前的 value
+    ///
+    /// 读取时依次应用所有操作数，操作数过多时写入会把它们合并成一条完整的记录。
+    /// 合并得到的 value 不建立二级索引。
+    pub fn merge_value(&self, key: Bytes, operand: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_writable()?;
+        let operator = self.merge_operator()?;
+
+        let guard = self.lock_key(&key);
+        let current = self.index.get(key.to_vec());
+        let operands = match self.merge_chains.read().get(key.as_ref()) {
+            Some(chain) if current.is_some() && chain.operands.last() == current.as_ref() => {
+                chain.operands.len()
+            }
+            _ => 0,
+        };
+
+        let commit_seq = if operands >= MAX_MERGE_OPERANDS {
+            let existing = match self.get(key.clone()) {
+                Ok(value) => Some(value),
+                Err(Errors::RecordNotFound) => None,
+                Err(e) => return Err(e),
+            };
+            let value = operator.full_merge(&key, existing.as_deref(), &[&operand]);
+            self.put_locked(&key, &value)?
+        } else {
+            let mut logrecord = LogRecord {
+                key: key.to_vec(),
+                value: operand.to_vec(),
+                record_type: LogRecordType::MERGE,
+                seq: 0,
+                cf: 0,
+            };
+            let (positions, commit_seq) =
+                self.write_log_records(std::slice::from_mut(&mut logrecord))?;
+
+            // 先记录操作数再更新索引，读取时总能找到索引指向的操作数
+            self.push_merge_operand(&key, current, positions[0]);
+            if !self.index.put(key.to_vec(), positions[0]) {
+                return Err(Errors::IndexUpdateError);
+            }
+            self.update_secondary_indexes(&key, None, logrecord.seq);
+            commit_seq
+        };
+        drop(guard);
+
+        self.wait_for_sync(commit_seq, self.options.sync)
+    }
+
+    /// 读取索引指向 pos 处操作数的 key 合并之后的 value
+    ///
+    /// 返回 None 表示 key 在读取期间被修改，索引已经不再指向 pos。
+    pub(crate) fn read_merged_value(&self, key: &[u8], pos: LogRecordPos) -> Result<Option<Bytes>> {
+        let operator = self.merge_operator()?;
+        let chain = match self.merge_chains.read().get(key) {
+            Some(chain) if chain.is_current(pos) => chain.clone(),
+            _ => return Ok(None),
+        };
+
+        let existing = match chain.base {
+            Some(base) => {
+                let logrecord = self.read_log_record(&base)?;
+                Some(self.record_value(logrecord)?)
+            }
+            None => None,
+        };
+        let mut operands = Vec::with_capacity(chain.operands.len());
+        for pos in chain.operands.iter() {
+            operands.push(self.read_log_record(pos)?.value);
+        }
+        let operands: Vec<&[u8]> = operands.iter().map(|operand| operand.as_slice()).collect();
+        Ok(Some(operator.full_merge(
+            key,
+            existing.as_deref(),
+            &operands,
+        )))
+    }
+
+    /// 记录 key 的一个新操作数，current 为写入之前索引中的位置
+    pub(crate) fn push_merge_operand(
+        &self,
+        key: &[u8],
+        current: Option<LogRecordPos>,
+        pos: LogRecordPos,
+    ) {
+        let mut chains = self.merge_chains.write();
+        match chains.get_mut(key) {
+            // 索引仍然指向最后一个操作数，继续追加
+            Some(chain) if current.is_some() && chain.operands.last() == current.as_ref() => {
+                chain.operands.push(pos)
+            }
+            // 之前的 value 被覆盖或者删除过，从 current 开始新的操作数序列
+            _ => {
+                chains.insert(
+                    key.to_vec(),
+                    MergeChain {
+                        base: current,
+                        operands: vec![pos],
+                    },
+                );
+            }
+        }
+    }
+
+    /// key 被写入完整的 value 或者删除后，之前的操作数不再需要
+    pub(crate) fn clear_merge_chain(&self, key: &[u8]) {
+        if self.merge_chains.read().contains_key(key) {
+            self.merge_chains.write().remove(key);
+        }
+    }
+
+    fn merge_operator(&self) -> Result<Arc<dyn MergeOperator>> {
+        match self.merge_operator.read().as_ref() {
+            Some(operator) => Ok(operator.clone()),
+            None => Err(Errors::MergeOperatorNotSet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::options::test_options;
+
+    // 用逗号连接所有操作数
+    struct Concat;
+
+    impl MergeOperator for Concat {
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes {
+            let mut parts: Vec<&[u8]> = existing.into_iter().collect();
+            parts.extend_from_slice(operands);
+            Bytes::from(parts.join(&b","[..]))
+        }
+    }
+
+    #[test]
+    fn test_merge_value() {
+        let opts = test_options("bitcask-rs-merge-value");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            engine.merge_value(Bytes::from("k"), Bytes::from("a")).err(),
+            Some(Errors::MergeOperatorNotSet)
+        );
+        engine.set_merge_operator(Arc::new(Concat));
+
+        engine.put(Bytes::from("k"), Bytes::from("base")).unwrap();
+        engine
+            .merge_value(Bytes::from("k"), Bytes::from("a"))
+            .unwrap();
+        engine
+            .merge_value(Bytes::from("k"), Bytes::from("b"))
+            .unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("k")).unwrap(),
+            Bytes::from("base,a,b")
+        );
+
+        // 覆盖写入和删除之后重新开始合并
+        engine.put(Bytes::from("k"), Bytes::from("new")).unwrap();
+        engine
+            .merge_value(Bytes::from("k"), Bytes::from("c"))
+            .unwrap();
+        assert_eq!(engine.get(Bytes::from("k")).unwrap(), Bytes::from("new,c"));
+        engine.delete(Bytes::from("k")).unwrap();
+        engine
+            .merge_value(Bytes::from("k"), Bytes::from("d"))
+            .unwrap();
+        assert_eq!(engine.get(Bytes::from("k")).unwrap(), Bytes::from("d"));
+        assert_eq!(engine.list_keys(), vec![Bytes::from("k")]);
+
+        // 操作数过多时合并成完整的 value
+        for i in 0..MAX_MERGE_OPERANDS * 2 {
+            engine
+                .merge_value(Bytes::from("long"), Bytes::from(i.to_string()))
+                .unwrap();
+        }
+        let expected: Vec<String> = (0..MAX_MERGE_OPERANDS * 2).map(|i| i.to_string()).collect();
+        assert_eq!(
+            engine.get(Bytes::from("long")).unwrap(),
+            Bytes::from(expected.join(","))
+        );
+        assert!(
+            engine
+                .merge_chains
+                .read()
+                .get(&b"long"[..])
+                .unwrap()
+                .operands
+                .len()
+                < MAX_MERGE_OPERANDS
+        );
+        drop(engine);
+
+        // 重新打开后从数据文件中恢复操作数
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        assert_eq!(
+            engine.get(Bytes::from("k")).err(),
+            Some(Errors::MergeOperatorNotSet)
+        );
+        engine.set_merge_operator(Arc::new(Concat));
+        assert_eq!(engine.get(Bytes::from("k")).unwrap(), Bytes::from("d"));
+        assert_eq!(
+            engine.get(Bytes::from("long")).unwrap(),
+            Bytes::from(expected.join(","))
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
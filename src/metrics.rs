@@ -0,0 +1,436 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// 延迟直方图的桶上界（秒）
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+/// 延迟直方图，各个桶分别计数，导出时再累加
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Self {
+        Histogram {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(out, "{}_bucket{{{}}} {}", name, inf_labels(labels), count);
+        let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), count);
+    }
+}
+
+/// 引擎运行过程中的指标
+pub(crate) struct Metrics {
+    put: Histogram,
+    get: Histogram,
+    delete: Histogram,
+    bytes_written: AtomicU64,
+    file_rotations: AtomicU64,
+    merge_runs: AtomicU64,
+}
+
+/// 操作计时器，Drop 时记录一次操作的耗时
+pub(crate) struct Timer<'a> {
+    histogram: &'a Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed());
+    }
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Metrics {
+            put: Histogram::new(),
+            get: Histogram::new(),
+            delete: Histogram::new(),
+            bytes_written: AtomicU64::new(0),
+            file_rotations: AtomicU64::new(0),
+            merge_runs: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn time_put(&self) -> Timer<'_> {
+        Timer {
+            histogram: &self.put,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn time_get(&self) -> Timer<'_> {
+        Timer {
+            histogram: &self.get,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn time_delete(&self) -> Timer<'_> {
+        Timer {
+            histogram: &self.delete,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn add_bytes_written(&self, size: u64) {
+        self.bytes_written.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_file_rotations(&self) {
+        self.file_rotations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_merge_runs(&self) {
+        self.merge_runs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Engine {
+    /// 以 Prometheus 文本格式导出运行指标
+    pub fn export_metrics(&self) -> String {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+
+        out.push_str("# HELP rkv_operations_total Number of engine operations.\n");
+        out.push_str("# TYPE rkv_operations_total counter\n");
+        let ops = [
+            ("put", &metrics.put),
+            ("get", &metrics.get),
+            ("delete", &metrics.delete),
+        ];
+        for (op, histogram) in ops.iter() {
+            let _ = writeln!(
+                out,
+                "rkv_operations_total{{op=\"{}\"}} {}",
+                op,
+                histogram.count.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# HELP rkv_operation_duration_seconds Latency of engine operations.\n");
+        out.push_str("# TYPE rkv_operation_duration_seconds histogram\n");
+        for (op, histogram) in ops.iter() {
+            histogram.write(
+                &mut out,
+                "rkv_operation_duration_seconds",
+                &format!("op=\"{}\",", op),
+            );
+        }
+
+        write_counter(
+            &mut out,
+            "rkv_bytes_written_total",
+            "Bytes appended to data files.",
+            metrics.bytes_written.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "rkv_fsync_total",
+            "Number of fsync calls on data files.",
+            self.group_commit.sync_count(),
+        );
+        out.push_str("# HELP rkv_fsync_duration_seconds Latency of fsync calls.\n");
+        out.push_str("# TYPE rkv_fsync_duration_seconds histogram\n");
+        self.group_commit
+            .sync_duration()
+            .write(&mut out, "rkv_fsync_duration_seconds", "");
+        write_counter(
+            &mut out,
+            "rkv_file_rotations_total",
+            "Number of active data file rotations.",
+            metrics.file_rotations.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "rkv_merge_runs_total",
+            "Number of merges that reclaimed data files.",
+            metrics.merge_runs.load(Ordering::Relaxed),
+        );
+
+        let stat = self.stat();
+        out.push_str("# HELP rkv_index_keys Number of keys in the default family index.\n");
+        out.push_str("# TYPE rkv_index_keys gauge\n");
+        let _ = writeln!(out, "rkv_index_keys {}", self.index.len());
+        out.push_str("# HELP rkv_data_files Number of data files.\n");
+        out.push_str("# TYPE rkv_data_files gauge\n");
+        let _ = writeln!(out, "rkv_data_files {}", stat.data_file_num);
+        write_counter(
+            &mut out,
+            "rkv_cache_hits_total",
+            "Record reads served from the cache.",
+            stat.cache_hits,
+        );
+        write_counter(
+            &mut out,
+            "rkv_cache_misses_total",
+            "Record reads that missed the cache.",
+            stat.cache_misses,
+        );
+        out.push_str(
+            "# HELP rkv_cache_hit_ratio Fraction of record reads served from the cache.\n",
+        );
+        out.push_str("# TYPE rkv_cache_hit_ratio gauge\n");
+        let lookups = stat.cache_hits + stat.cache_misses;
+        let ratio = match lookups {
+            0 => 0.0,
+            _ => stat.cache_hits as f64 / lookups as f64,
+        };
+        let _ = writeln!(out, "rkv_cache_hit_ratio {}", ratio);
+        out
+    }
+}
+
+/// 同时处理的指标请求连接数上限，超过时直接关闭新的连接
+const MAX_METRICS_CONNECTIONS: usize = 16;
+
+/// 读取请求和写入响应的超时时间
+const METRICS_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 通过 HTTP 暴露运行指标，GET /metrics 返回 Prometheus 文本格式，Drop 时停止
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// 在 addr 上监听 HTTP 请求
+    pub fn start(engine: Arc<Engine>, addr: &str) -> Result<MetricsServer> {
+        let bound = TcpListener::bind(addr).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok((listener.local_addr()?, listener))
+        });
+        let (local_addr, listener) = match bound {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!("failed to bind metrics address {}: {}", addr, e);
+                return Err(Errors::MetricsNetworkError);
+            }
+        };
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_clone = stopped.clone();
+        let handle = thread::spawn(move || {
+            let connections = Arc::new(AtomicUsize::new(0));
+            while !stopped_clone.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    // 每个连接在单独的线程中处理，慢的客户端不会阻塞其他抓取
+                    Ok((stream, peer)) => {
+                        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_METRICS_CONNECTIONS {
+                            connections.fetch_sub(1, Ordering::SeqCst);
+                            warn!("too many metrics connections, drop {}", peer);
+                            continue;
+                        }
+                        let engine = engine.clone();
+                        let connections = connections.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve_metrics(&engine, stream) {
+                                warn!("failed to serve metrics to {}: {}", peer, e);
+                            }
+                            connections.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => error!("failed to accept metrics connection: {}", e),
+                }
+            }
+        });
+
+        Ok(MetricsServer {
+            local_addr,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+
+    /// 实际监听的地址
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// 处理一个 HTTP 请求，只读取请求行，响应后关闭连接
+fn serve_metrics(engine: &Engine, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(METRICS_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(METRICS_IO_TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > 64 * 1024 {
+            break;
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            engine.export_metrics(),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn inf_labels(labels: &str) -> String {
+    match labels.is_empty() {
+        true => "le=\"+Inf\"".to_string(),
+        false => format!("{},le=\"+Inf\"", labels),
+    }
+}
+
+fn braces(labels: &str) -> String {
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::options::{test_options, Options};
+
+    #[test]
+    fn test_export_metrics() {
+        let opts = Options {
+            cache_capacity: 1024,
+            ..test_options("bitcask-rs-metrics")
+        };
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        for i in 0..3 {
+            engine
+                .put(Bytes::from(format!("key-{}", i)), Bytes::from("value"))
+                .unwrap();
+        }
+        engine.get(Bytes::from("key-0")).unwrap();
+        engine.get(Bytes::from("key-0")).unwrap();
+        engine.delete(Bytes::from("key-1")).unwrap();
+        engine.sync().unwrap();
+
+        let text = engine.export_metrics();
+        assert!(text.contains("rkv_operations_total{op=\"put\"} 3\n"));
+        assert!(text.contains("rkv_operations_total{op=\"get\"} 2\n"));
+        assert!(text.contains("rkv_operations_total{op=\"delete\"} 1\n"));
+        assert!(text.contains("rkv_operation_duration_seconds_bucket{op=\"put\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("rkv_operation_duration_seconds_count{op=\"get\"} 2\n"));
+        // 3 条 18 字节的写入和 1 条 13 字节的删除
+        assert!(text.contains("rkv_bytes_written_total 67\n"));
+        assert!(text.contains("rkv_fsync_total 1\n"));
+        assert!(text.contains("rkv_fsync_duration_seconds_count 1\n"));
+        assert!(text.contains("rkv_index_keys 2\n"));
+        assert!(text.contains("rkv_cache_hits_total 1\n"));
+        assert!(text.contains("rkv_cache_hit_ratio 0.5\n"));
+
+        // 通过 HTTP 获取
+        let server = MetricsServer::start(engine.clone(), "127.0.0.1:0").unwrap();
+        // 没有发送请求的连接不会阻塞其他连接
+        let _idle = TcpStream::connect(server.local_addr()).unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("rkv_operations_total{op=\"put\"} 3\n"));
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        drop(server);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
@@ -1,4 +1,16 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    data::log_record::max_log_record_header_size,
+    errors::{Errors, Result},
+    listener::EventListener,
+};
+
+/// 一天的分钟数
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// 数据文件的最大大小，打开时会把活跃文件末尾读入内存，内存 IO 也在内存中保存整个文件
+const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct Options {
@@ -10,10 +22,303 @@ pub struct Options {
     pub sync: bool,
     // 索引类型
     pub index_type: IndexType,
+    // 数据文件的 IO 类型
+    pub io_type: IOType,
+    // 数据加密密钥，设置后使用 XChaCha20-Poly1305 加密每条记录的 key 和 value
+    pub encryption_key: Option<[u8; 32]>,
+    // 打开时发现活跃文件末尾记录损坏的处理方式
+    pub recovery_mode: RecoveryMode,
+    // sync 为 false 时，累计写入多少字节后持久化一次，0 表示不启用
+    pub bytes_per_sync: u64,
+    // 后台线程定时持久化活跃文件的间隔（毫秒），0 表示不启用
+    pub sync_interval_ms: u64,
+    // 创建数据文件时预先分配 file_size 大小的磁盘空间，文件写满切换时截断多余的部分
+    pub preallocate: bool,
+    // 读取记录的缓存大小（字节），0 表示不启用
+    pub cache_capacity: u64,
+    // 引擎事件的监听器
+    pub event_listeners: Vec<Arc<dyn EventListener>>,
+    // 合并等后台任务每秒读写的字节数，0 表示不限速，可以通过 Engine::set_background_io_rate 调整
+    pub background_io_rate: u64,
+    // 自动合并的配置，None 表示只能手动合并
+    pub auto_merge: Option<AutoMerge>,
 }
 
-#[derive(Clone)]
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            dir_path: std::env::temp_dir().join("bitcask-rs"),
+            file_size: 256 * 1024 * 1024,
+            sync: false,
+            index_type: IndexType::BTree,
+            io_type: IOType::StandardFIO,
+            encryption_key: None,
+            recovery_mode: RecoveryMode::TruncateTail,
+            bytes_per_sync: 0,
+            sync_interval_ms: 0,
+            preallocate: false,
+            cache_capacity: 0,
+            event_listeners: Vec::new(),
+            background_io_rate: 0,
+            auto_merge: None,
+        }
+    }
+}
+
+impl Options {
+    /// 从默认配置开始构造 Options
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::default(),
+        }
+    }
+
+    /// 检查配置项是否合法，Engine 打开时也会检查
+    pub fn validate(&self) -> Result<()> {
+        match self.dir_path.to_str() {
+            Some(dir_path) if !dir_path.is_empty() => {}
+            _ => return Err(Errors::DirPathIsEmpty),
+        }
+
+        // 数据文件至少能放下一条 key 和 value 都为空的记录，加上末尾 4 字节的 crc
+        if self.file_size < (max_log_record_header_size() + 4) as u64 {
+            return Err(Errors::FileSizeTooSmall);
+        }
+        if self.file_size > MAX_FILE_SIZE {
+            return Err(Errors::FileSizeTooLarge);
+        }
+
+        // 每次写都持久化时，按字节数和定时持久化没有意义
+        if self.sync && (self.bytes_per_sync > 0 || self.sync_interval_ms > 0) {
+            return Err(Errors::ConflictingSyncOptions);
+        }
+
+        if let IndexType::SkipList = self.index_type {
+            return Err(Errors::IndexTypeUnsupported);
+        }
+
+        // 内存中的文件不需要预分配磁盘空间
+        if self.preallocate && self.io_type == IOType::Memory {
+            return Err(Errors::PreallocateWithMemoryIO);
+        }
+
+        // Direct IO 只支持 Linux，io_uring 在其他平台上使用标准文件 IO
+        if self.io_type == IOType::DirectIO && !cfg!(target_os = "linux") {
+            return Err(Errors::IOTypeUnsupported);
+        }
+
+        if let Some(auto_merge) = self.auto_merge.as_ref() {
+            auto_merge.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// 测试使用的配置，数据目录在系统临时目录下，先删除上次测试留下的数据
+#[cfg(test)]
+pub(crate) fn test_options(name: &str) -> Options {
+    let dir_path = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir_path);
+    Options {
+        dir_path,
+        file_size: 64 * 1024,
+        ..Options::default()
+    }
+}
+
+/// Options 的构造器，build 时检查配置项
+pub struct OptionsBuilder {
+    pub(crate) options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn dir_path(mut self, dir_path: impl Into<PathBuf>) -> Self {
+        self.options.dir_path = dir_path.into();
+        self
+    }
+
+    pub fn file_size(mut self, file_size: u64) -> Self {
+        self.options.file_size = file_size;
+        self
+    }
+
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.options.sync = sync;
+        self
+    }
+
+    pub fn index_type(mut self, index_type: IndexType) -> Self {
+        self.options.index_type = index_type;
+        self
+    }
+
+    pub fn io_type(mut self, io_type: IOType) -> Self {
+        self.options.io_type = io_type;
+        self
+    }
+
+    pub fn encryption_key(mut self, encryption_key: Option<[u8; 32]>) -> Self {
+        self.options.encryption_key = encryption_key;
+        self
+    }
+
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.options.recovery_mode = recovery_mode;
+        self
+    }
+
+    pub fn bytes_per_sync(mut self, bytes_per_sync: u64) -> Self {
+        self.options.bytes_per_sync = bytes_per_sync;
+        self
+    }
+
+    pub fn sync_interval_ms(mut self, sync_interval_ms: u64) -> Self {
+        self.options.sync_interval_ms = sync_interval_ms;
+        self
+    }
+
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.options.preallocate = preallocate;
+        self
+    }
+
+    pub fn cache_capacity(mut self, cache_capacity: u64) -> Self {
+        self.options.cache_capacity = cache_capacity;
+        self
+    }
+
+    pub fn event_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.options.event_listeners.push(listener);
+        self
+    }
+
+    pub fn background_io_rate(mut self, background_io_rate: u64) -> Self {
+        self.options.background_io_rate = background_io_rate;
+        self
+    }
+
+    pub fn auto_merge(mut self, auto_merge: Option<AutoMerge>) -> Self {
+        self.options.auto_merge = auto_merge;
+        self
+    }
+
+    /// 检查配置项并返回 Options
+    pub fn build(self) -> Result<Options> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum IndexType {
     BTree,
     SkipList,
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IOType {
+    // 标准文件 IO，读写经过 page cache
+    StandardFIO,
+    // 使用 O_DIRECT 绕过 page cache，适合数据量远大于内存并且使用记录缓存的场景，只支持 Linux
+    DirectIO,
+    // Linux 的 io_uring，批量读写在一次提交中完成，其他平台和内核不支持时使用标准文件 IO
+    IoUring,
+    // 数据文件保存在内存中，不访问文件系统，引擎关闭后数据随之释放，适合单元测试和临时缓存
+    Memory,
+    // 测试使用的故障注入文件 IO，数据写在真实的文件中，按目录中安装的故障注入器读写
+    #[cfg(test)]
+    FaultInjection,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecoveryMode {
+    // 截断活跃文件末尾不完整的记录，记录日志后继续打开
+    TruncateTail,
+    // 发现损坏的记录时拒绝打开
+    Strict,
+}
+
+/// 自动合并的配置，由 MergeScheduler 使用
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AutoMerge {
+    // 可回收空间占全部数据文件的比例达到该值时合并
+    pub ratio_threshold: f64,
+    // 可回收空间不少于该字节数时才合并，避免频繁合并很小的数据库
+    pub min_reclaimable_bytes: u64,
+    // 检查可回收空间的间隔（毫秒）
+    pub check_interval_ms: u64,
+    // 只在该时间段内合并，None 表示任何时间都可以合并
+    pub window: Option<MergeWindow>,
+}
+
+/// 一天中允许合并的时间段（UTC），按从零点开始的分钟数表示，区间为 [start, end)
+///
+/// start 大于 end 时表示跨越零点，例如 22:00 到 06:00。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MergeWindow {
+    pub start_minute: u32,
+    pub end_minute: u32,
+}
+
+impl Default for AutoMerge {
+    fn default() -> Self {
+        AutoMerge {
+            ratio_threshold: 0.5,
+            min_reclaimable_bytes: 64 * 1024 * 1024,
+            check_interval_ms: 60 * 1000,
+            window: None,
+        }
+    }
+}
+
+impl AutoMerge {
+    fn validate(&self) -> Result<()> {
+        // 比例在 (0, 1] 之间，NaN 也不合法
+        if !(self.ratio_threshold > 0.0 && self.ratio_threshold <= 1.0) {
+            return Err(Errors::InvalidAutoMergeOptions);
+        }
+        if self.check_interval_ms == 0 {
+            return Err(Errors::InvalidAutoMergeOptions);
+        }
+        if let Some(window) = self.window.as_ref() {
+            let (start, end) = (window.start_minute, window.end_minute);
+            if start >= MINUTES_PER_DAY || end >= MINUTES_PER_DAY || start == end {
+                return Err(Errors::InvalidAutoMergeOptions);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MergeWindow {
+    /// 一天中的第 minute 分钟是否在时间段内
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start_minute <= self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+/// 列族的配置，创建之后不能修改
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FamilyOptions {
+    // 是否每次写都持久化，数据库的 sync 为 true 时总是持久化
+    pub sync: bool,
+    // 列族中每次写入的 value 的存活时间，精确到毫秒，过期之后读取不到，合并时回收，None 表示永不过期
+    pub ttl: Option<Duration>,
+}
+
+/// 读取数据时的配置
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReadOptions {
+    // 是否把读取到的记录放入缓存，大范围扫描时关闭以免淘汰热点数据
+    pub fill_cache: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions { fill_cache: true }
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+
+/// 写入 Raft 日志的命令，提交后应用到 Engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Put {
+        key: Bytes,
+        value: Bytes,
+    },
+    Delete {
+        key: Bytes,
+    },
+    /// 空命令，新 leader 用来提交之前任期的日志，也用于线性一致读
+    Noop,
+}
+
+/// Raft 日志条目
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+/// 状态机快照，包含 last_index 之前所有日志应用后的全部 key/value
+///
+/// 数据是 Engine 备份出来的数据文件，保存在发送方的目录 dir 中，接收方先复制到自己的目录。
+/// 跨进程的 Transport 需要把 dir 中的文件一起传输。
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub dir: Option<PathBuf>, // 还没有生成过快照时为 None
+}
+
+/// 节点之间的消息
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub from: u64,
+    pub to: u64,
+    pub term: u64,
+    pub body: MessageBody,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageBody {
+    RequestVote {
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteResponse {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// 成功时 match_index 为已经匹配的最大日志序号，失败时为 leader 下次尝试的 prev_log_index
+    AppendEntriesResponse {
+        success: bool,
+        match_index: u64,
+    },
+    InstallSnapshot {
+        snapshot: Snapshot,
+    },
+    InstallSnapshotResponse {
+        last_index: u64,
+    },
+}
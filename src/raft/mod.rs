@@ -0,0 +1,1247 @@
+pub mod message;
+mod storage;
+pub mod transport;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use log::error;
+use parking_lot::{Condvar, Mutex};
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+    options::IOType,
+};
+
+use self::{
+    message::{Command, Entry, Message, MessageBody, Snapshot},
+    storage::{HardState, RaftStorage},
+    transport::Transport,
+};
+
+/// 数据目录中保存 Raft 日志、任期、投票和快照的子目录
+const RAFT_DIR_NAME: &str = "raft";
+
+/// 后台线程检查超时的间隔
+const TICK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// 一条 AppendEntries 消息中最多携带的日志条目数
+const MAX_ENTRIES_PER_MESSAGE: usize = 64;
+
+/// Raft 节点的配置
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    // 选举超时的下限，实际超时在 [election_timeout_ms, 2 * election_timeout_ms) 之间随机
+    pub election_timeout_ms: u64,
+
+    // leader 发送心跳的间隔
+    pub heartbeat_interval_ms: u64,
+
+    // 快照之后已应用的日志数量达到该值时生成新的快照并截断日志
+    pub snapshot_threshold: u64,
+
+    // 客户端请求等待提交的超时时间
+    pub proposal_timeout_ms: u64,
+}
+
+/// 节点的角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 节点当前的状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub role: Role,
+    pub term: u64,
+    pub leader_id: Option<u64>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_index: u64, // 最新快照包含的最后一条日志
+}
+
+/// Raft 集群中的一个节点，状态机是一个 Engine
+///
+/// 客户端的 put 和 delete 只能发送给 leader，写入 Raft 日志并被多数节点复制之后才应用到 Engine，
+/// 保证线性一致。Engine 只应该通过 RaftNode 写入。
+/// 日志、任期和投票保存在数据目录的 raft 子目录中，持久化之后才回复其他节点，节点可以从自己的目录重启。
+/// 快照是 Engine 的备份，由后台线程在状态锁之外复制数据文件生成，集群中所有节点需要使用相同的加密密钥。
+pub struct RaftNode {
+    shared: Arc<Shared>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    id: u64,
+    peers: Vec<u64>,
+    config: RaftConfig,
+    engine: Arc<Engine>,
+    transport: Arc<dyn Transport>,
+    state: Mutex<RaftState>,
+    applied: Condvar, // 有新的日志被应用时通知等待中的请求
+    stopped: AtomicBool,
+}
+
+struct RaftState {
+    storage: RaftStorage,
+    role: Role,
+    current_term: u64,
+    voted_for: Option<u64>,
+    leader_id: Option<u64>,
+
+    log: Vec<Entry>,    // 快照之后的日志
+    snapshot: Snapshot, // 最新的快照
+    commit_index: u64,
+    last_applied: u64,
+
+    votes: HashSet<u64>,            // candidate 收到的选票
+    next_index: HashMap<u64, u64>,  // leader 下次发送给各节点的日志序号
+    match_index: HashMap<u64, u64>, // leader 已知各节点复制的最大日志序号
+
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    rng: u64,
+
+    proposals: HashMap<u64, u64>, // 等待应用的请求，日志序号 -> 任期
+    results: HashMap<u64, Result<()>>, // 已经应用的请求的结果
+    error: Option<Errors>,        // 应用日志或者持久化失败的错误，之后节点停止工作
+}
+
+impl RaftNode {
+    /// 启动节点，peers 是集群中其他节点的 id，inbox 接收其他节点发送过来的消息
+    ///
+    /// 数据目录中有这个节点保存的 Raft 状态时从中恢复，重新应用最新快照之后的日志；
+    /// 否则 engine 必须是空的。目录属于其他节点或者 engine 中有 Raft 之外写入的数据时返回
+    /// RaftDirectoryInUse，内存 IO 的 engine 无法持久化，返回 IOTypeUnsupported。
+    pub fn start(
+        id: u64,
+        peers: Vec<u64>,
+        engine: Arc<Engine>,
+        transport: Arc<dyn Transport>,
+        inbox: Receiver<Message>,
+        config: RaftConfig,
+    ) -> Result<RaftNode> {
+        let (storage, hard_state, log) = open_storage(&engine, id)?;
+        let snapshot = Snapshot {
+            last_index: hard_state.snapshot_index,
+            last_term: hard_state.snapshot_term,
+            dir: match hard_state.snapshot_index {
+                0 => None,
+                index => Some(storage.snapshot_dir(index)),
+            },
+        };
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        // engine 在快照时已经持久化，之后应用过的日志可能丢失，从快照之后重新应用，
+        // 重新执行已经应用过的 put 和 delete 不会改变结果
+        let mut state = RaftState {
+            storage,
+            role: Role::Follower,
+            current_term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            leader_id: None,
+            log,
+            commit_index: snapshot.last_index,
+            last_applied: snapshot.last_index,
+            snapshot,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: Instant::now(),
+            heartbeat_deadline: Instant::now(),
+            rng: (seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1,
+            proposals: HashMap::new(),
+            results: HashMap::new(),
+            error: None,
+        };
+        state.reset_election_deadline(config.election_timeout_ms);
+
+        let shared = Arc::new(Shared {
+            id,
+            peers,
+            config,
+            engine,
+            transport,
+            state: Mutex::new(state),
+            applied: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+
+        let shared_clone = shared.clone();
+        let handle = thread::spawn(move || shared_clone.run(inbox));
+        let shared_clone = shared.clone();
+        let snapshot_handle = thread::spawn(move || shared_clone.run_snapshots());
+
+        Ok(RaftNode {
+            shared,
+            handles: vec![handle, snapshot_handle],
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.shared.id
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        let state = self.shared.state.lock();
+        RaftStatus {
+            role: state.role,
+            term: state.current_term,
+            leader_id: state.leader_id,
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+            snapshot_index: state.snapshot.last_index,
+        }
+    }
+
+    /// 写入 key/value，当前节点不是 leader 时返回 NotLeader
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.propose(Command::Put { key, value })
+    }
+
+    /// 删除 key，当前节点不是 leader 时返回 NotLeader
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.propose(Command::Delete { key })
+    }
+
+    /// 线性一致读，先通过一条空日志确认自己仍然是 leader 并且之前的写入都已经应用
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        self.propose(Command::Noop)?;
+        self.shared.engine.get(key)
+    }
+
+    // 写入一条日志并等待它被应用
+    fn propose(&self, command: Command) -> Result<()> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock();
+        if let Some(e) = state.error.as_ref() {
+            return Err(e.clone());
+        }
+        if state.role != Role::Leader {
+            return Err(Errors::NotLeader);
+        }
+        let index = state.last_index() + 1;
+        let term = state.current_term;
+        state.log.push(Entry {
+            term,
+            index,
+            command,
+        });
+        // leader 自己也算作一个副本，持久化之后才能计入多数
+        let from = state.log.len() - 1;
+        if !shared.persist_log(&mut state, from) {
+            return Err(state.error.clone().unwrap());
+        }
+        state.proposals.insert(index, term);
+        shared.broadcast(&mut state);
+
+        let deadline = Instant::now() + Duration::from_millis(shared.config.proposal_timeout_ms);
+        loop {
+            if let Some(res) = state.results.remove(&index) {
+                return res;
+            }
+            if shared.applied.wait_until(&mut state, deadline).timed_out()
+                && !state.results.contains_key(&index)
+            {
+                state.proposals.remove(&index);
+                return Err(Errors::ProposalTimeout);
+            }
+        }
+    }
+}
+
+impl Drop for RaftNode {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Shared {
+    // 后台线程，处理收到的消息和超时，出错之后不再处理
+    fn run(&self, inbox: Receiver<Message>) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let message = match inbox.recv_timeout(TICK_INTERVAL) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(TICK_INTERVAL);
+                    None
+                }
+            };
+            let mut state = self.state.lock();
+            if state.error.is_some() {
+                continue;
+            }
+            if let Some(message) = message {
+                self.step(&mut state, message);
+            }
+            if state.error.is_none() {
+                self.tick(&mut state);
+            }
+        }
+    }
+
+    // 后台线程，已应用的日志足够多时生成快照
+    fn run_snapshots(&self) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            thread::sleep(TICK_INTERVAL);
+            if let Err(e) = self.maybe_snapshot() {
+                error!("failed to create raft snapshot: {}", e);
+            }
+        }
+    }
+
+    // 节点无法继续工作，等待中和之后的请求都返回这个错误
+    fn fail(&self, state: &mut RaftState, e: Errors) {
+        for (index, _) in std::mem::take(&mut state.proposals) {
+            state.results.insert(index, Err(e.clone()));
+        }
+        state.error.get_or_insert(e);
+        self.applied.notify_all();
+    }
+
+    // 持久化任期和投票，失败时节点停止工作并返回 false
+    fn persist_state(&self, state: &mut RaftState) -> bool {
+        let hard_state = state.hard_state(self.id);
+        match state.storage.save_state(hard_state) {
+            Ok(()) => true,
+            Err(e) => {
+                self.fail(state, e);
+                false
+            }
+        }
+    }
+
+    // 持久化快照之后第 from 条开始的日志，失败时节点停止工作并返回 false
+    fn persist_log(&self, state: &mut RaftState, from: usize) -> bool {
+        match state.storage.append(from, &state.log[from..]) {
+            Ok(()) => true,
+            Err(e) => {
+                self.fail(state, e);
+                false
+            }
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn send(&self, state: &RaftState, to: u64, body: MessageBody) {
+        self.transport.send(Message {
+            from: self.id,
+            to,
+            term: state.current_term,
+            body,
+        });
+    }
+
+    fn tick(&self, state: &mut RaftState) {
+        let now = Instant::now();
+        match state.role {
+            Role::Leader => {
+                if now >= state.heartbeat_deadline {
+                    self.broadcast(state);
+                }
+            }
+            _ => {
+                if now >= state.election_deadline {
+                    self.start_election(state);
+                }
+            }
+        }
+    }
+
+    fn start_election(&self, state: &mut RaftState) {
+        state.role = Role::Candidate;
+        state.current_term += 1;
+        state.voted_for = Some(self.id);
+        state.leader_id = None;
+        state.votes = HashSet::from([self.id]);
+        state.reset_election_deadline(self.config.election_timeout_ms);
+        if !self.persist_state(state) {
+            return;
+        }
+
+        if state.votes.len() >= self.quorum() {
+            self.become_leader(state);
+            return;
+        }
+        for peer in &self.peers {
+            let body = MessageBody::RequestVote {
+                last_log_index: state.last_index(),
+                last_log_term: state.last_term(),
+            };
+            self.send(state, *peer, body);
+        }
+    }
+
+    fn become_follower(&self, state: &mut RaftState, term: u64, leader_id: Option<u64>) {
+        if term > state.current_term {
+            state.current_term = term;
+            state.voted_for = None;
+        }
+        state.role = Role::Follower;
+        state.leader_id = leader_id;
+    }
+
+    fn become_leader(&self, state: &mut RaftState) {
+        state.role = Role::Leader;
+        state.leader_id = Some(self.id);
+        let last_index = state.last_index();
+        for peer in &self.peers {
+            state.next_index.insert(*peer, last_index + 1);
+            state.match_index.insert(*peer, 0);
+        }
+        // 之前任期的日志只能随当前任期的日志一起提交
+        let entry = Entry {
+            term: state.current_term,
+            index: last_index + 1,
+            command: Command::Noop,
+        };
+        state.log.push(entry);
+        let from = state.log.len() - 1;
+        if !self.persist_log(state, from) {
+            return;
+        }
+        self.broadcast(state);
+    }
+
+    // 向所有节点发送日志或者心跳
+    fn broadcast(&self, state: &mut RaftState) {
+        for peer in &self.peers {
+            self.send_append(state, *peer);
+        }
+        state.heartbeat_deadline =
+            Instant::now() + Duration::from_millis(self.config.heartbeat_interval_ms);
+        if self.peers.is_empty() {
+            self.advance_commit(state);
+        }
+    }
+
+    fn send_append(&self, state: &RaftState, peer: u64) {
+        let next_index = state.next_index[&peer];
+        // 需要的日志已经被快照截断，直接发送快照
+        if next_index <= state.snapshot.last_index {
+            let body = MessageBody::InstallSnapshot {
+                snapshot: state.snapshot.clone(),
+            };
+            self.send(state, peer, body);
+            return;
+        }
+        let prev_log_index = next_index - 1;
+        let start = (next_index - state.snapshot.last_index - 1) as usize;
+        let end = state.log.len().min(start + MAX_ENTRIES_PER_MESSAGE);
+        let body = MessageBody::AppendEntries {
+            prev_log_index,
+            prev_log_term: state.term_at(prev_log_index).unwrap(),
+            entries: state.log[start..end].to_vec(),
+            leader_commit: state.commit_index,
+        };
+        self.send(state, peer, body);
+    }
+
+    fn step(&self, state: &mut RaftState, message: Message) {
+        if message.term > state.current_term {
+            let leader_id = match message.body {
+                MessageBody::AppendEntries { .. } | MessageBody::InstallSnapshot { .. } => {
+                    Some(message.from)
+                }
+                _ => None,
+            };
+            self.become_follower(state, message.term, leader_id);
+            if !self.persist_state(state) {
+                return;
+            }
+        }
+
+        let from = message.from;
+        let term = message.term;
+        match message.body {
+            MessageBody::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date = last_log_term > state.last_term()
+                    || (last_log_term == state.last_term() && last_log_index >= state.last_index());
+                let granted = term == state.current_term
+                    && state.voted_for.is_none_or(|id| id == from)
+                    && up_to_date;
+                if granted {
+                    state.voted_for = Some(from);
+                    state.reset_election_deadline(self.config.election_timeout_ms);
+                    if !self.persist_state(state) {
+                        return;
+                    }
+                }
+                self.send(state, from, MessageBody::RequestVoteResponse { granted });
+            }
+            MessageBody::RequestVoteResponse { granted } => {
+                if state.role == Role::Candidate && term == state.current_term && granted {
+                    state.votes.insert(from);
+                    if state.votes.len() >= self.quorum() {
+                        self.become_leader(state);
+                    }
+                }
+            }
+            MessageBody::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append_entries(
+                state,
+                from,
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            ),
+            MessageBody::AppendEntriesResponse {
+                success,
+                match_index,
+            } => {
+                if state.role != Role::Leader || term != state.current_term {
+                    return;
+                }
+                let next_index = state.next_index[&from];
+                if success {
+                    if match_index > state.match_index[&from] {
+                        state.match_index.insert(from, match_index);
+                    }
+                    state
+                        .next_index
+                        .insert(from, next_index.max(match_index + 1));
+                    self.advance_commit(state);
+                    if state.next_index[&from] <= state.last_index() {
+                        self.send_append(state, from);
+                    }
+                } else {
+                    let next_index = (match_index + 1).min(next_index - 1).max(1);
+                    state.next_index.insert(from, next_index);
+                    self.send_append(state, from);
+                }
+            }
+            MessageBody::InstallSnapshot { snapshot } => {
+                self.handle_install_snapshot(state, from, term, snapshot)
+            }
+            MessageBody::InstallSnapshotResponse { last_index } => {
+                if state.role != Role::Leader || term != state.current_term {
+                    return;
+                }
+                if last_index > state.match_index[&from] {
+                    state.match_index.insert(from, last_index);
+                }
+                let next_index = state.next_index[&from].max(last_index + 1);
+                state.next_index.insert(from, next_index);
+                self.advance_commit(state);
+                if next_index <= state.last_index() {
+                    self.send_append(state, from);
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_append_entries(
+        &self,
+        state: &mut RaftState,
+        from: u64,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        mut entries: Vec<Entry>,
+        leader_commit: u64,
+    ) {
+        let reject = |state: &RaftState, match_index: u64| {
+            let body = MessageBody::AppendEntriesResponse {
+                success: false,
+                match_index,
+            };
+            self.send(state, from, body);
+        };
+        if term < state.current_term {
+            reject(state, 0);
+            return;
+        }
+        self.become_follower(state, term, Some(from));
+        state.reset_election_deadline(self.config.election_timeout_ms);
+
+        // 缺少 prev_log_index 之前的日志，从自己的最后一条日志开始重试
+        if prev_log_index > state.last_index() {
+            reject(state, state.last_index());
+            return;
+        }
+
+        let mut prev_log_index = prev_log_index;
+        let snapshot_index = state.snapshot.last_index;
+        if prev_log_index < snapshot_index {
+            // 快照中的日志都已经提交，一定与 leader 一致
+            let skip = ((snapshot_index - prev_log_index) as usize).min(entries.len());
+            prev_log_index += skip as u64;
+            entries.drain(..skip);
+        } else if state.term_at(prev_log_index) != Some(prev_log_term) {
+            // 跳过冲突任期的所有日志
+            let conflict_term = state.term_at(prev_log_index);
+            let mut index = prev_log_index;
+            while index > snapshot_index + 1 && state.term_at(index - 1) == conflict_term {
+                index -= 1;
+            }
+            reject(state, index - 1);
+            return;
+        }
+
+        let match_index = prev_log_index + entries.len() as u64;
+        let mut changed = None;
+        for entry in entries {
+            if let Some(term) = state.term_at(entry.index) {
+                if term == entry.term {
+                    continue;
+                }
+                // 删除冲突的日志以及之后的所有日志
+                let len = (entry.index - state.snapshot.last_index - 1) as usize;
+                state.log.truncate(len);
+            }
+            changed.get_or_insert(state.log.len());
+            state.log.push(entry);
+        }
+        if let Some(from) = changed {
+            if !self.persist_log(state, from) {
+                return;
+            }
+        }
+
+        let commit_index = leader_commit.min(match_index);
+        if commit_index > state.commit_index {
+            state.commit_index = commit_index;
+            self.apply(state);
+        }
+        let body = MessageBody::AppendEntriesResponse {
+            success: true,
+            match_index,
+        };
+        self.send(state, from, body);
+    }
+
+    fn handle_install_snapshot(
+        &self,
+        state: &mut RaftState,
+        from: u64,
+        term: u64,
+        snapshot: Snapshot,
+    ) {
+        if term < state.current_term {
+            return;
+        }
+        self.become_follower(state, term, Some(from));
+        state.reset_election_deadline(self.config.election_timeout_ms);
+
+        let last_index = snapshot.last_index;
+        if last_index > state.commit_index {
+            let dir = state.storage.snapshot_dir(last_index);
+            if let Err(e) = self.restore_snapshot(&snapshot, &dir) {
+                error!("failed to restore raft snapshot: {}", e);
+                return;
+            }
+            let snapshot = Snapshot {
+                last_index,
+                last_term: snapshot.last_term,
+                dir: Some(dir),
+            };
+            if let Err(e) = self.save_snapshot(state, snapshot) {
+                self.fail(state, e);
+                return;
+            }
+            state.commit_index = last_index;
+            state.last_applied = last_index;
+
+            // 被快照覆盖的请求无法确认是否生效
+            let covered: Vec<u64> = state
+                .proposals
+                .keys()
+                .copied()
+                .filter(|index| *index <= last_index)
+                .collect();
+            for index in covered {
+                state.proposals.remove(&index);
+                state.results.insert(index, Err(Errors::ProposalDropped));
+            }
+            self.applied.notify_all();
+        }
+        self.send(
+            state,
+            from,
+            MessageBody::InstallSnapshotResponse { last_index },
+        );
+    }
+
+    // 提交当前任期内已经被多数节点复制的日志
+    fn advance_commit(&self, state: &mut RaftState) {
+        let mut index = state.last_index();
+        while index > state.commit_index {
+            // 更早的日志都属于之前的任期，不能通过计数提交
+            if state.term_at(index) != Some(state.current_term) {
+                break;
+            }
+            let replicas = 1 + self
+                .peers
+                .iter()
+                .filter(|peer| state.match_index[peer] >= index)
+                .count();
+            if replicas >= self.quorum() {
+                state.commit_index = index;
+                self.apply(state);
+                break;
+            }
+            index -= 1;
+        }
+    }
+
+    // 将已经提交的日志应用到 Engine
+    //
+    // 已经提交的日志不能跳过，应用失败后节点停止应用日志，等待中和之后的请求都返回这个错误
+    fn apply(&self, state: &mut RaftState) {
+        while state.error.is_none() && state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+            let entry = state.entry(index).clone();
+            let res = match entry.command {
+                Command::Put { key, value } => self.engine.put(key, value),
+                Command::Delete { key } => self.engine.delete(key),
+                Command::Noop => Ok(()),
+            };
+            if let Err(e) = res {
+                error!("failed to apply raft log {}: {}", index, e);
+                self.fail(state, e);
+                break;
+            }
+            state.last_applied = index;
+            if let Some(term) = state.proposals.remove(&index) {
+                // 同一个位置被新 leader 的日志覆盖时，请求没有生效
+                let res = match term == entry.term {
+                    true => Ok(()),
+                    false => Err(Errors::ProposalDropped),
+                };
+                state.results.insert(index, res);
+            }
+        }
+        self.applied.notify_all();
+    }
+
+    // 已应用的日志足够多时生成快照并截断日志
+    //
+    // 复制数据文件在状态锁之外进行，期间可以继续选举、复制和应用日志
+    fn maybe_snapshot(&self) -> Result<()> {
+        {
+            let state = self.state.lock();
+            if state.error.is_some()
+                || state.last_applied - state.snapshot.last_index < self.config.snapshot_threshold
+            {
+                return Ok(());
+            }
+        }
+
+        // 复制完成之前不能合并，否则需要复制的数据文件可能被删除
+        let merge_guard = self.engine.merge_lock.lock();
+        let (last_index, last_term, file_ids, dir) = {
+            let state = self.state.lock();
+            let last_index = state.last_applied;
+            if last_index <= state.snapshot.last_index {
+                return Ok(());
+            }
+            // 日志只在状态锁内应用，切换活跃文件之后 last_index 之前应用的数据都在旧文件中，并且已经持久化
+            let file_ids = self.engine.seal_data_files()?;
+            let last_term = state.term_at(last_index).unwrap();
+            (
+                last_index,
+                last_term,
+                file_ids,
+                state.storage.snapshot_dir(last_index),
+            )
+        };
+        self.engine.copy_data_files(&file_ids, &dir)?;
+        drop(merge_guard);
+
+        let mut state = self.state.lock();
+        // 复制期间安装了 leader 发送的更新的快照
+        if last_index <= state.snapshot.last_index {
+            let _ = fs::remove_dir_all(&dir);
+            return Ok(());
+        }
+        let snapshot = Snapshot {
+            last_index,
+            last_term,
+            dir: Some(dir),
+        };
+        if let Err(e) = self.save_snapshot(&mut state, snapshot) {
+            self.fail(&mut state, e.clone());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // 记录新的快照并截断快照之前的日志，快照的数据和 engine 都已经持久化
+    fn save_snapshot(&self, state: &mut RaftState, snapshot: Snapshot) -> Result<()> {
+        // 快照之后的日志如果与快照一致则保留
+        match state.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+            true => {
+                let len = (snapshot.last_index - state.snapshot.last_index) as usize;
+                state.log.drain(..len);
+            }
+            false => state.log.clear(),
+        }
+        state.snapshot = snapshot;
+        // 先记录快照再截断日志，中途崩溃时加载会跳过快照之前的日志
+        state.storage.save_state(state.hard_state(self.id))?;
+        state.storage.compact(&state.log)?;
+        state
+            .storage
+            .remove_stale_snapshots(state.snapshot.last_index);
+        Ok(())
+    }
+
+    // 把 leader 的快照复制到 dir，再用其中的数据替换 engine 中的全部数据并持久化
+    fn restore_snapshot(&self, snapshot: &Snapshot, dir: &Path) -> Result<()> {
+        match snapshot.dir.as_ref() {
+            Some(src) => storage::copy_snapshot(src, dir)?,
+            None => fs::create_dir_all(dir).map_err(|_| Errors::FailedToCreateDataBaseDir)?,
+        }
+        let mut opts = (*self.engine.options).clone();
+        opts.dir_path = dir.to_path_buf();
+        opts.event_listeners = Vec::new();
+        opts.sync_interval_ms = 0;
+        opts.preallocate = false;
+        opts.cache_capacity = 0;
+        opts.auto_merge = None;
+        let data = Engine::open(opts)?;
+        data.set_read_only(true);
+
+        for key in data.list_keys() {
+            let value = data.get(key.clone())?;
+            self.engine.put(key, value)?;
+        }
+        for key in self.engine.list_keys() {
+            match data.get(key.clone()) {
+                Ok(_) => continue,
+                Err(Errors::RecordNotFound) => self.engine.delete(key)?,
+                Err(e) => return Err(e),
+            }
+        }
+        self.engine.sync()
+    }
+}
+
+// 打开数据目录中保存的 Raft 状态，没有时初始化
+fn open_storage(engine: &Engine, id: u64) -> Result<(RaftStorage, HardState, Vec<Entry>)> {
+    if engine.options.io_type == IOType::Memory {
+        return Err(Errors::IOTypeUnsupported);
+    }
+    let dir = engine.options.dir_path.join(RAFT_DIR_NAME);
+    let (mut storage, hard_state, log) = RaftStorage::open(&dir)?;
+    match hard_state {
+        Some(hard_state) if hard_state.id == id => Ok((storage, hard_state, log)),
+        Some(_) => Err(Errors::RaftDirectoryInUse),
+        None => {
+            if engine.latest_seq() > 0 || !engine.list_keys().is_empty() {
+                return Err(Errors::RaftDirectoryInUse);
+            }
+            let hard_state = HardState {
+                id,
+                term: 0,
+                voted_for: None,
+                snapshot_index: 0,
+                snapshot_term: 0,
+            };
+            storage.save_state(hard_state)?;
+            Ok((storage, hard_state, log))
+        }
+    }
+}
+
+impl RaftState {
+    fn hard_state(&self, id: u64) -> HardState {
+        HardState {
+            id,
+            term: self.current_term,
+            voted_for: self.voted_for,
+            snapshot_index: self.snapshot.last_index,
+            snapshot_term: self.snapshot.last_term,
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        match self.log.last() {
+            Some(entry) => entry.term,
+            None => self.snapshot.last_term,
+        }
+    }
+
+    // 日志 index 的任期，已经被快照截断或者不存在时返回 None
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        if index < self.snapshot.last_index || index > self.last_index() {
+            return None;
+        }
+        Some(self.entry(index).term)
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.snapshot.last_index - 1) as usize]
+    }
+
+    fn reset_election_deadline(&mut self, election_timeout_ms: u64) {
+        // xorshift 生成随机的超时时间，避免多个节点同时发起选举
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let timeout = election_timeout_ms + self.rng % election_timeout_ms.max(1);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{test_options, Options};
+    use transport::LocalNetwork;
+
+    struct Cluster {
+        network: Arc<LocalNetwork>,
+        nodes: Vec<RaftNode>,
+        engines: Vec<Arc<Engine>>,
+        options: Vec<Options>,
+        snapshot_threshold: u64,
+    }
+
+    impl Cluster {
+        fn start(name: &str, size: u64, snapshot_threshold: u64) -> Cluster {
+            let mut cluster = Cluster {
+                network: LocalNetwork::new(),
+                nodes: Vec::new(),
+                engines: Vec::new(),
+                options: (1..=size)
+                    .map(|id| test_options(&format!("bitcask-rs-raft-{}-{}", name, id)))
+                    .collect(),
+                snapshot_threshold,
+            };
+            cluster.start_nodes();
+            cluster
+        }
+
+        // 用每个节点自己的数据目录启动所有节点
+        fn start_nodes(&mut self) {
+            let size = self.options.len() as u64;
+            for id in 1..=size {
+                let opts = self.options[id as usize - 1].clone();
+                let engine = Arc::new(Engine::open(opts).unwrap());
+                let (transport, inbox) = self.network.register(id);
+                let peers = (1..=size).filter(|peer| *peer != id).collect();
+                let config = RaftConfig {
+                    election_timeout_ms: 100,
+                    heartbeat_interval_ms: 20,
+                    snapshot_threshold: self.snapshot_threshold,
+                    proposal_timeout_ms: 1000,
+                };
+                let node =
+                    RaftNode::start(id, peers, engine.clone(), transport, inbox, config).unwrap();
+                self.nodes.push(node);
+                self.engines.push(engine);
+            }
+        }
+
+        // 停止所有节点并关闭 engine，再从原来的目录重新启动
+        fn restart(&mut self) {
+            self.nodes.clear();
+            self.engines.clear();
+            self.start_nodes();
+        }
+
+        // 等待 candidates 中选出唯一的 leader
+        fn wait_leader(&self, candidates: &[u64]) -> u64 {
+            wait_until(|| {
+                let leaders: Vec<u64> = candidates
+                    .iter()
+                    .filter(|id| self.node(**id).status().role == Role::Leader)
+                    .copied()
+                    .collect();
+                leaders.len() == 1
+            });
+            candidates
+                .iter()
+                .copied()
+                .find(|id| self.node(*id).status().role == Role::Leader)
+                .unwrap()
+        }
+
+        fn node(&self, id: u64) -> &RaftNode {
+            &self.nodes[id as usize - 1]
+        }
+
+        fn engine(&self, id: u64) -> &Engine {
+            &self.engines[id as usize - 1]
+        }
+
+        // 写入直到成功，选举期间的请求可能失败
+        fn put(&self, candidates: &[u64], key: &str, value: &str) {
+            wait_until(|| {
+                let leader = self.wait_leader(candidates);
+                self.node(leader)
+                    .put(Bytes::from(key.to_string()), Bytes::from(value.to_string()))
+                    .is_ok()
+            });
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            self.nodes.clear();
+            self.engines.clear();
+            for opts in &self.options {
+                let _ = fs::remove_dir_all(&opts.dir_path);
+            }
+        }
+    }
+
+    fn wait_until(f: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_raft_replicate() {
+        let cluster = Cluster::start("replicate", 3, 1000);
+        let all = [1, 2, 3];
+        let leader = cluster.wait_leader(&all);
+
+        cluster.put(&all, "a", "1");
+        cluster.put(&all, "b", "2");
+        let leader_node = cluster.node(leader);
+        leader_node.delete(Bytes::from("a")).unwrap();
+        assert_eq!(leader_node.get(Bytes::from("b")).unwrap(), Bytes::from("2"));
+
+        // follower 不能处理写入
+        let follower = all.iter().copied().find(|id| *id != leader).unwrap();
+        assert_eq!(
+            cluster
+                .node(follower)
+                .put(Bytes::from("c"), Bytes::from("3"))
+                .err(),
+            Some(Errors::NotLeader)
+        );
+
+        let commit_index = leader_node.status().commit_index;
+        for id in all {
+            wait_until(|| cluster.node(id).status().last_applied >= commit_index);
+            let engine = cluster.engine(id);
+            assert_eq!(engine.get(Bytes::from("b")).unwrap(), Bytes::from("2"));
+            assert_eq!(
+                engine.get(Bytes::from("a")).err(),
+                Some(Errors::RecordNotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn test_raft_leader_partition() {
+        let cluster = Cluster::start("partition", 5, 1000);
+        let all = [1, 2, 3, 4, 5];
+        cluster.put(&all, "key", "v1");
+        let old_leader = cluster.wait_leader(&all);
+        let old_term = cluster.node(old_leader).status().term;
+
+        // 隔离 leader，剩下的多数节点选出新的 leader
+        cluster.network.isolate(old_leader);
+        let majority: Vec<u64> = all.iter().copied().filter(|id| *id != old_leader).collect();
+        cluster.put(&majority, "key", "v2");
+        let new_leader = cluster.wait_leader(&majority);
+        assert!(cluster.node(new_leader).status().term > old_term);
+
+        // 少数派的旧 leader 无法提交写入
+        let res = cluster
+            .node(old_leader)
+            .put(Bytes::from("key"), Bytes::from("stale"));
+        assert!(res.is_err());
+
+        // 网络恢复后旧 leader 退位并追上最新的数据
+        cluster.network.heal();
+        cluster.put(&all, "key", "v3");
+        let leader = cluster.wait_leader(&all);
+        let commit_index = cluster.node(leader).status().commit_index;
+        for id in all {
+            wait_until(|| cluster.node(id).status().last_applied >= commit_index);
+            assert_eq!(
+                cluster.engine(id).get(Bytes::from("key")).unwrap(),
+                Bytes::from("v3")
+            );
+        }
+    }
+
+    #[test]
+    fn test_raft_snapshot_catch_up() {
+        let cluster = Cluster::start("snapshot", 3, 10);
+        let all = [1, 2, 3];
+        let leader = cluster.wait_leader(&all);
+        let lagging = all.iter().copied().find(|id| *id != leader).unwrap();
+
+        // 落后的节点需要的日志被快照截断，只能通过快照追赶
+        cluster.network.isolate(lagging);
+        let others: Vec<u64> = all.iter().copied().filter(|id| *id != lagging).collect();
+        for i in 0..50 {
+            cluster.put(&others, &format!("key-{}", i), "value");
+        }
+        let leader = cluster.wait_leader(&others);
+        cluster.node(leader).delete(Bytes::from("key-0")).unwrap();
+        wait_until(|| cluster.node(leader).status().snapshot_index > 0);
+
+        cluster.network.heal();
+        let commit_index = cluster.node(leader).status().commit_index;
+        wait_until(|| cluster.node(lagging).status().last_applied >= commit_index);
+        let engine = cluster.engine(lagging);
+        assert_eq!(engine.list_keys().len(), 49);
+        assert_eq!(
+            engine.get(Bytes::from("key-49")).unwrap(),
+            Bytes::from("value")
+        );
+        assert_eq!(
+            engine.get(Bytes::from("key-0")).err(),
+            Some(Errors::RecordNotFound)
+        );
+    }
+
+    #[test]
+    fn test_raft_refuse_used_directory() {
+        let opts = test_options("bitcask-rs-raft-used-dir");
+        let engine = Arc::new(Engine::open(opts.clone()).unwrap());
+        let network = LocalNetwork::new();
+        let config = RaftConfig {
+            election_timeout_ms: 100,
+            heartbeat_interval_ms: 20,
+            snapshot_threshold: 1000,
+            proposal_timeout_ms: 1000,
+        };
+
+        let (transport, inbox) = network.register(1);
+        let node = RaftNode::start(1, vec![], engine.clone(), transport, inbox, config.clone());
+        drop(node.unwrap());
+
+        // 目录属于节点 1，其他节点不能使用
+        let (transport, inbox) = network.register(2);
+        let res = RaftNode::start(2, vec![], engine.clone(), transport, inbox, config.clone());
+        assert_eq!(res.err(), Some(Errors::RaftDirectoryInUse));
+
+        // 节点 1 可以从自己的目录重启
+        let (transport, inbox) = network.register(1);
+        let node = RaftNode::start(1, vec![], engine.clone(), transport, inbox, config.clone());
+        drop(node.unwrap());
+
+        // 已经有数据的 engine 不能作为新节点的状态机
+        let opts2 = test_options("bitcask-rs-raft-used-dir-2");
+        let engine2 = Arc::new(Engine::open(opts2.clone()).unwrap());
+        engine2.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        let (transport, inbox) = network.register(3);
+        let res = RaftNode::start(3, vec![], engine2.clone(), transport, inbox, config);
+        assert_eq!(res.err(), Some(Errors::RaftDirectoryInUse));
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+        fs::remove_dir_all(opts2.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_raft_restart() {
+        let mut cluster = Cluster::start("restart", 3, 10);
+        let all = [1, 2, 3];
+        for i in 0..25 {
+            cluster.put(&all, &format!("key-{}", i), &format!("value-{}", i));
+        }
+        let leader = cluster.wait_leader(&all);
+        cluster.node(leader).delete(Bytes::from("key-0")).unwrap();
+        wait_until(|| cluster.node(leader).status().snapshot_index > 0);
+        let term = cluster.node(leader).status().term;
+
+        // 整个集群重启，已经提交的日志、任期和快照都从各自的目录恢复
+        cluster.restart();
+        let leader = cluster.wait_leader(&all);
+        assert!(cluster.node(leader).status().term > term);
+        let leader_node = cluster.node(leader);
+        assert_eq!(
+            leader_node.get(Bytes::from("key-24")).unwrap(),
+            Bytes::from("value-24")
+        );
+        assert_eq!(
+            leader_node.get(Bytes::from("key-0")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        cluster.put(&all, "after-restart", "value");
+        let leader = cluster.wait_leader(&all);
+        let commit_index = cluster.node(leader).status().commit_index;
+        for id in all {
+            wait_until(|| cluster.node(id).status().last_applied >= commit_index);
+            let engine = cluster.engine(id);
+            assert_eq!(engine.list_keys().len(), 25);
+            assert_eq!(
+                engine.get(Bytes::from("after-restart")).unwrap(),
+                Bytes::from("value")
+            );
+        }
+    }
+
+    #[test]
+    fn test_raft_apply_error() {
+        let cluster = Cluster::start("apply-error", 3, 1000);
+        let all = [1, 2, 3];
+        cluster.put(&all, "a", "1");
+        let leader = cluster.wait_leader(&all);
+        let applied = cluster.node(leader).status().last_applied;
+
+        // 应用到 engine 失败时请求返回错误，之后的日志不再应用
+        cluster.engine(leader).set_read_only(true);
+        let leader_node = cluster.node(leader);
+        assert_eq!(
+            leader_node.put(Bytes::from("b"), Bytes::from("2")).err(),
+            Some(Errors::ReadOnly)
+        );
+        assert_eq!(
+            leader_node.put(Bytes::from("c"), Bytes::from("3")).err(),
+            Some(Errors::ReadOnly)
+        );
+        assert_eq!(leader_node.status().last_applied, applied);
+    }
+}
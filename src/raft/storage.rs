@@ -0,0 +1,334 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use bytes::{Buf, BufMut, Bytes};
+use log::{error, warn};
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::errors::{Errors, Result};
+
+use super::message::{Command, Entry};
+
+const STATE_FILE_NAME: &str = "state";
+const LOG_FILE_NAME: &str = "log";
+const SNAPSHOT_DIR_PREFIX: &str = "snapshot-";
+
+/// 需要在回复其他节点之前持久化的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HardState {
+    pub(crate) id: u64, // 使用这个目录的节点
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<u64>,
+    pub(crate) snapshot_index: u64, // 最新快照包含的最后一条日志
+    pub(crate) snapshot_term: u64,
+}
+
+/// Raft 节点保存在数据目录的 raft 子目录中的持久化状态
+///
+/// - state：HardState，先写临时文件再重命名，整体替换
+/// - log：快照之后的日志，每条日志编码为 crc | 长度 | 内容，追加写入，冲突时截断
+/// - snapshot-{index}：快照包含的数据文件
+///
+/// 写入之后都会 fsync，调用返回之后才能回复其他节点。
+pub(crate) struct RaftStorage {
+    dir: PathBuf,
+    log_file: File,
+    offsets: Vec<u64>, // 每条日志在 log 文件中的位置，与内存中快照之后的日志一一对应
+    log_size: u64,
+    saved: Option<HardState>, // 最近一次持久化的 HardState
+}
+
+impl RaftStorage {
+    /// 打开目录，返回保存的 HardState 和快照之后的日志，目录中没有状态时返回 None
+    ///
+    /// 写入中断的日志末尾被截断；截断快照之前的日志时中途崩溃，log 中可能还有快照包含的日志，加载时跳过
+    pub(crate) fn open(dir: &Path) -> Result<(RaftStorage, Option<HardState>, Vec<Entry>)> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("failed to create raft dir: {}", e);
+            return Err(Errors::FailedToCreateDataBaseDir);
+        }
+        let hard_state = load_hard_state(&dir.join(STATE_FILE_NAME))?;
+        let log_path = dir.join(LOG_FILE_NAME);
+        let data = match fs::read(&log_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(persist_error("failed to read raft log", e)),
+        };
+
+        let (snapshot_index, snapshot_term) = match hard_state {
+            Some(state) => (state.snapshot_index, state.snapshot_term),
+            None => (0, 0),
+        };
+        let mut entries = decode_entries(&data);
+        // 快照位置的日志与快照不一致时，之后的日志属于被覆盖的历史
+        let conflict = entries
+            .iter()
+            .any(|entry| entry.index == snapshot_index && entry.term != snapshot_term);
+        entries.retain(|entry| !conflict && entry.index > snapshot_index);
+        let contiguous = entries
+            .iter()
+            .enumerate()
+            .take_while(|(i, entry)| entry.index == snapshot_index + 1 + *i as u64)
+            .count();
+        entries.truncate(contiguous);
+
+        let log_file = open_log_file(&log_path)?;
+        let mut storage = RaftStorage {
+            dir: dir.to_path_buf(),
+            log_file,
+            offsets: Vec::new(),
+            log_size: 0,
+            saved: hard_state,
+        };
+        storage.compact(&entries)?;
+        storage.remove_stale_snapshots(snapshot_index);
+        Ok((storage, hard_state, entries))
+    }
+
+    /// 保存快照数据文件的目录
+    pub(crate) fn snapshot_dir(&self, index: u64) -> PathBuf {
+        self.dir.join(format!("{}{}", SNAPSHOT_DIR_PREFIX, index))
+    }
+
+    /// 持久化 HardState，与上次保存的相同时直接返回
+    pub(crate) fn save_state(&mut self, state: HardState) -> Result<()> {
+        if self.saved == Some(state) {
+            return Ok(());
+        }
+        let mut buf = Vec::with_capacity(48);
+        buf.put_u64(state.id);
+        buf.put_u64(state.term);
+        buf.put_u64(state.voted_for.unwrap_or(0));
+        buf.put_u64(state.snapshot_index);
+        buf.put_u64(state.snapshot_term);
+        buf.put_u32(crc32fast::hash(&buf));
+        let path = self.dir.join(STATE_FILE_NAME);
+        if let Err(e) = write_file_atomic(&self.dir, &path, &buf) {
+            return Err(persist_error("failed to write raft state", e));
+        }
+        self.saved = Some(state);
+        Ok(())
+    }
+
+    /// 用 entries 替换位置 from 及之后的日志，entries 为内存中快照之后的第 from 条开始的日志
+    pub(crate) fn append(&mut self, from: usize, entries: &[Entry]) -> Result<()> {
+        let res = (|| -> io::Result<()> {
+            if from < self.offsets.len() {
+                self.log_size = self.offsets[from];
+                self.offsets.truncate(from);
+                self.log_file.set_len(self.log_size)?;
+            }
+            let mut buf = Vec::new();
+            for entry in entries {
+                self.offsets.push(self.log_size + buf.len() as u64);
+                encode_entry(entry, &mut buf);
+            }
+            self.log_file.write_all(&buf)?;
+            self.log_file.sync_data()?;
+            self.log_size += buf.len() as u64;
+            Ok(())
+        })();
+        res.map_err(|e| persist_error("failed to write raft log", e))
+    }
+
+    /// 重写 log 文件，只保留 entries，用于截断快照之前的日志
+    pub(crate) fn compact(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            offsets.push(buf.len() as u64);
+            encode_entry(entry, &mut buf);
+        }
+        let path = self.dir.join(LOG_FILE_NAME);
+        if let Err(e) = write_file_atomic(&self.dir, &path, &buf) {
+            return Err(persist_error("failed to rewrite raft log", e));
+        }
+        self.log_file = open_log_file(&path)?;
+        self.offsets = offsets;
+        self.log_size = buf.len() as u64;
+        Ok(())
+    }
+
+    /// 删除 keep 之外的快照目录
+    pub(crate) fn remove_stale_snapshots(&self, keep: u64) {
+        let keep = self.snapshot_dir(keep);
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("failed to read raft dir: {}", e);
+                return;
+            }
+        };
+        for entry in dir.flatten() {
+            let path = entry.path();
+            let is_snapshot = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(SNAPSHOT_DIR_PREFIX));
+            if is_snapshot && path != keep {
+                if let Err(e) = fs::remove_dir_all(&path) {
+                    warn!("failed to remove raft snapshot {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+/// 把目录 src 中的文件复制到 dst 并持久化，dst 已经存在时先删除
+pub(crate) fn copy_snapshot(src: &Path, dst: &Path) -> Result<()> {
+    let res = (|| -> io::Result<()> {
+        if dst.exists() {
+            fs::remove_dir_all(dst)?;
+        }
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let path = entry?.path();
+            if let Some(name) = path.file_name() {
+                fs::copy(&path, dst.join(name))?;
+                File::open(dst.join(name))?.sync_all()?;
+            }
+        }
+        File::open(dst)?.sync_all()
+    })();
+    if let Err(e) = res {
+        error!("failed to copy raft snapshot from {:?}: {}", src, e);
+        return Err(Errors::FailedToCopyDataFile);
+    }
+    Ok(())
+}
+
+fn persist_error(msg: &str, e: io::Error) -> Errors {
+    error!("{}: {}", msg, e);
+    Errors::FailedToPersistRaftState
+}
+
+fn open_log_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| persist_error("failed to open raft log", e))
+}
+
+// 先写临时文件再重命名，崩溃时文件要么是旧的内容，要么是新的内容
+fn write_file_atomic(dir: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    File::open(dir)?.sync_all()
+}
+
+fn load_hard_state(path: &Path) -> Result<Option<HardState>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(persist_error("failed to read raft state", e)),
+    };
+    if data.len() != 44 || crc32fast::hash(&data[..40]) != (&data[40..]).get_u32() {
+        return Err(Errors::InvalidRaftState);
+    }
+    let mut buf = &data[..40];
+    Ok(Some(HardState {
+        id: buf.get_u64(),
+        term: buf.get_u64(),
+        voted_for: match buf.get_u64() {
+            0 => None,
+            id => Some(id),
+        },
+        snapshot_index: buf.get_u64(),
+        snapshot_term: buf.get_u64(),
+    }))
+}
+
+// 日志编码为 crc | 长度 | term | index | 命令类型 | key | value，crc 校验之后的所有字节
+fn encode_entry(entry: &Entry, buf: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    encode_varint(entry.term, &mut body);
+    encode_varint(entry.index, &mut body);
+    match &entry.command {
+        Command::Noop => body.put_u8(0),
+        Command::Put { key, value } => {
+            body.put_u8(1);
+            put_bytes(&mut body, key);
+            put_bytes(&mut body, value);
+        }
+        Command::Delete { key } => {
+            body.put_u8(2);
+            put_bytes(&mut body, key);
+        }
+    }
+    let mut len = Vec::with_capacity(4);
+    len.put_u32(body.len() as u32);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len);
+    hasher.update(&body);
+    buf.put_u32(hasher.finalize());
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(&body);
+}
+
+// 依次解码日志，遇到不完整或者损坏的日志时停止
+fn decode_entries(mut data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    while data.len() >= 8 {
+        let crc = (&data[..4]).get_u32();
+        let len = (&data[4..8]).get_u32() as usize;
+        if data.len() - 8 < len || crc32fast::hash(&data[4..8 + len]) != crc {
+            break;
+        }
+        match decode_entry(&data[8..8 + len]) {
+            Some(entry) => entries.push(entry),
+            None => break,
+        }
+        data = &data[8 + len..];
+    }
+    if !data.is_empty() {
+        warn!("discarding {} bytes of torn raft log", data.len());
+    }
+    entries
+}
+
+fn decode_entry(mut body: &[u8]) -> Option<Entry> {
+    let term = decode_varint(&mut body).ok()?;
+    let index = decode_varint(&mut body).ok()?;
+    if body.is_empty() {
+        return None;
+    }
+    let command = match body.get_u8() {
+        0 => Command::Noop,
+        1 => {
+            let key = get_bytes(&mut body)?;
+            let value = get_bytes(&mut body)?;
+            Command::Put { key, value }
+        }
+        2 => Command::Delete {
+            key: get_bytes(&mut body)?,
+        },
+        _ => return None,
+    };
+    Some(Entry {
+        term,
+        index,
+        command,
+    })
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    encode_varint(data.len() as u64, buf);
+    buf.extend_from_slice(data);
+}
+
+fn get_bytes(buf: &mut &[u8]) -> Option<Bytes> {
+    let len = decode_varint(buf).ok()? as usize;
+    if buf.len() < len {
+        return None;
+    }
+    let data = Bytes::copy_from_slice(&buf[..len]);
+    buf.advance(len);
+    Some(data)
+}
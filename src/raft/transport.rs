@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use super::message::Message;
+
+/// 节点之间传输消息的接口
+///
+/// 发送不需要保证送达，失败时直接丢弃即可，由 Raft 协议负责重试。
+pub trait Transport: Send + Sync {
+    /// 将消息发送给 message.to 对应的节点
+    fn send(&self, message: Message);
+}
+
+/// 进程内的模拟网络，用于在一个进程中运行整个集群，支持模拟网络分区
+pub struct LocalNetwork {
+    inboxes: Mutex<HashMap<u64, Sender<Message>>>,
+    blocked: Mutex<HashSet<(u64, u64)>>, // 不能通信的 (from, to)
+}
+
+impl LocalNetwork {
+    pub fn new() -> Arc<LocalNetwork> {
+        Arc::new(LocalNetwork {
+            inboxes: Mutex::new(HashMap::new()),
+            blocked: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// 加入一个节点，返回节点使用的 Transport 和接收消息的 channel
+    pub fn register(self: &Arc<Self>, id: u64) -> (Arc<dyn Transport>, Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel();
+        self.inboxes.lock().insert(id, sender);
+        let transport = LocalTransport {
+            network: self.clone(),
+        };
+        (Arc::new(transport), receiver)
+    }
+
+    /// 断开两组节点之间双向的通信
+    pub fn partition(&self, group_a: &[u64], group_b: &[u64]) {
+        let mut blocked = self.blocked.lock();
+        for a in group_a {
+            for b in group_b {
+                blocked.insert((*a, *b));
+                blocked.insert((*b, *a));
+            }
+        }
+    }
+
+    /// 断开节点 id 与其他所有节点的通信
+    pub fn isolate(&self, id: u64) {
+        let others: Vec<u64> = self
+            .inboxes
+            .lock()
+            .keys()
+            .copied()
+            .filter(|other| *other != id)
+            .collect();
+        self.partition(&[id], &others);
+    }
+
+    /// 恢复所有节点之间的通信
+    pub fn heal(&self) {
+        self.blocked.lock().clear();
+    }
+}
+
+struct LocalTransport {
+    network: Arc<LocalNetwork>,
+}
+
+impl Transport for LocalTransport {
+    fn send(&self, message: Message) {
+        if self
+            .network
+            .blocked
+            .lock()
+            .contains(&(message.from, message.to))
+        {
+            return;
+        }
+        if let Some(sender) = self.network.inboxes.lock().get(&message.to) {
+            let _ = sender.send(message);
+        }
+    }
+}
@@ -0,0 +1,900 @@
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes};
+use log::{error, info, warn};
+
+use crate::{
+    cdc::ChangeEvent,
+    db::Engine,
+    errors::{Errors, Result},
+    family::{decode_family_options, encode_family_options, DEFAULT_FAMILY_NAME},
+    options::IOType,
+};
+
+/// 从节点保存已应用位置的文件名，内容为主节点的数据库 id 和已应用的序号
+const REPLICATION_POSITION_FILE_NAME: &str = "replication-position";
+
+/// 主节点保存数据库 id 的文件名，数据目录重建之后 id 随之改变
+const DATABASE_ID_FILE_NAME: &str = "database-id";
+
+/// 区分不同主节点数据库的随机 id
+type DatabaseId = [u8; 16];
+
+/// 一个 key 或者 value 的最大长度，超过时认为数据帧损坏
+const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// 没有新的变更时，主节点发送心跳的间隔
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 从节点断开后重新连接的间隔
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+// 主节点发送给从节点的消息类型
+const FRAME_SNAPSHOT_BEGIN: u8 = 1;
+const FRAME_SNAPSHOT_ENTRY: u8 = 2;
+const FRAME_SNAPSHOT_END: u8 = 3;
+const FRAME_PUT: u8 = 4;
+const FRAME_DELETE: u8 = 5;
+const FRAME_HEARTBEAT: u8 = 6;
+const FRAME_SNAPSHOT_FAMILY: u8 = 7;
+const FRAME_CREATE_FAMILY: u8 = 8;
+const FRAME_DROP_FAMILY: u8 = 9;
+
+/// 复制的主节点，将 Engine 追加写入的记录通过 TCP 推送给从节点
+///
+/// 从节点连接后先发送之前同步的主节点数据库 id 和已经应用的序号，主节点从下一个序号开始推送变更。
+/// 数据库 id 不一致、对应的记录已经不在数据文件中，或者从节点的位置比主节点还新时，
+/// 先发送一份完整的快照。数据库 id 保存在主节点的数据目录中，内存中的主节点每次启动生成新的 id。
+/// 复制所有列族，包括列族的创建和删除，TTL 列族中的 value 保留主节点上的过期时间。
+pub struct Primary {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    /// 在 addr 上监听从节点的连接
+    pub fn start(engine: Arc<Engine>, addr: &str) -> Result<Primary> {
+        let database_id = match engine.options.io_type {
+            IOType::Memory => new_database_id(),
+            _ => load_database_id(&engine.options.dir_path.join(DATABASE_ID_FILE_NAME))?,
+        };
+        let bound = TcpListener::bind(addr).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok((listener.local_addr()?, listener))
+        });
+        let (local_addr, listener) = match bound {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!("failed to bind replication address {}: {}", addr, e);
+                return Err(Errors::ReplicationNetworkError);
+            }
+        };
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_clone = stopped.clone();
+        let handle = thread::spawn(move || {
+            let mut workers = Vec::new();
+            while !stopped_clone.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        info!("follower connected from {}", peer);
+                        let engine = engine.clone();
+                        let stopped = stopped_clone.clone();
+                        // 回收已经断开的从节点线程，避免从节点反复重连时句柄不断累积
+                        workers.retain(|worker: &JoinHandle<()>| !worker.is_finished());
+                        workers.push(thread::spawn(move || {
+                            if let Err(e) = serve_follower(&engine, database_id, stream, &stopped) {
+                                warn!("replication to follower {} stopped: {}", peer, e);
+                            }
+                        }));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => error!("failed to accept follower: {}", e),
+                }
+            }
+            for worker in workers {
+                let _ = worker.join();
+            }
+        });
+
+        Ok(Primary {
+            local_addr,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+
+    /// 实际监听的地址
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// 向一个从节点推送变更，直到连接断开或者主节点停止
+fn serve_follower(
+    engine: &Engine,
+    database_id: DatabaseId,
+    stream: TcpStream,
+    stopped: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut follower_id = DatabaseId::default();
+    reader.read_exact(&mut follower_id)?;
+    let position = read_u64(&mut reader)?;
+
+    // 从节点同步的是另一个数据库，序号没有可比性；从节点的位置比主节点新，说明主节点的数据被重建过
+    let resumed = match follower_id == database_id && position <= engine.latest_seq() {
+        true => engine.subscribe(position + 1),
+        false => Err(Errors::SequenceNotRetained),
+    };
+    let mut subscription = match resumed {
+        Ok(subscription) => subscription,
+        Err(Errors::SequenceNotRetained) => {
+            // 先注册订阅再读取快照，快照期间的写入会在之后重新推送，重复应用是幂等的
+            let snapshot_seq = engine.latest_seq();
+            let subscription = engine
+                .subscribe(snapshot_seq + 1)
+                .map_err(io::Error::other)?;
+            send_snapshot(engine, database_id, snapshot_seq, &mut writer)?;
+            subscription
+        }
+        Err(e) => return Err(io::Error::other(e)),
+    };
+
+    while !stopped.load(Ordering::SeqCst) {
+        let frame = match subscription.next_timeout(HEARTBEAT_INTERVAL) {
+            Ok(Some(ChangeEvent::Put {
+                seq,
+                cf,
+                key,
+                value,
+                expire_at,
+            })) => put_frame(seq, &cf, &key, &value, expire_at)?,
+            Ok(Some(ChangeEvent::Delete { seq, cf, key })) => delete_frame(seq, &cf, &key)?,
+            // 合并操作数重复应用的结果不同，发送合并之后的当前值，之后的变更会再次覆盖
+            Ok(Some(ChangeEvent::Merge { seq, cf, key, .. })) => {
+                match engine
+                    .cf(&cf)
+                    .and_then(|handle| handle.get_with_expiry(key.clone()))
+                {
+                    Ok((value, expire_at)) => put_frame(seq, &cf, &key, &value, expire_at)?,
+                    Err(Errors::RecordNotFound | Errors::ColumnFamilyNotFound) => {
+                        delete_frame(seq, &cf, &key)?
+                    }
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            }
+            Ok(Some(ChangeEvent::CreateFamily { seq, name, options })) => {
+                let mut frame = vec![FRAME_CREATE_FAMILY];
+                frame.put_u64(seq);
+                put_bytes(&mut frame, name.as_bytes())?;
+                put_bytes(&mut frame, &encode_family_options(&options))?;
+                frame
+            }
+            Ok(Some(ChangeEvent::DropFamily { seq, name })) => {
+                let mut frame = vec![FRAME_DROP_FAMILY];
+                frame.put_u64(seq);
+                put_bytes(&mut frame, name.as_bytes())?;
+                frame
+            }
+            Ok(None) => vec![FRAME_HEARTBEAT],
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        writer.write_all(&frame)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// 发送当前所有 key/value 的快照
+fn send_snapshot(
+    engine: &Engine,
+    database_id: DatabaseId,
+    seq: u64,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let mut frame = vec![FRAME_SNAPSHOT_BEGIN];
+    frame.extend_from_slice(&database_id);
+    frame.put_u64(seq);
+    writer.write_all(&frame)?;
+    for name in engine.list_cfs() {
+        let handle = match engine.cf(&name) {
+            Ok(handle) => handle,
+            // 读取快照期间被删除
+            Err(Errors::ColumnFamilyNotFound) => continue,
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        if handle.family().is_some() {
+            let mut frame = vec![FRAME_SNAPSHOT_FAMILY];
+            put_bytes(&mut frame, name.as_bytes())?;
+            put_bytes(&mut frame, &encode_family_options(&handle.options()))?;
+            writer.write_all(&frame)?;
+        }
+        for key in handle.list_keys() {
+            let (value, expire_at) = match handle.get_with_expiry(key.clone()) {
+                Ok(entry) => entry,
+                Err(Errors::RecordNotFound) => continue,
+                Err(Errors::ColumnFamilyNotFound) => break,
+                Err(e) => return Err(io::Error::other(e)),
+            };
+            let mut frame = vec![FRAME_SNAPSHOT_ENTRY];
+            put_bytes(&mut frame, name.as_bytes())?;
+            put_bytes(&mut frame, &key)?;
+            put_bytes(&mut frame, &value)?;
+            frame.put_u64(expire_at.unwrap_or(0));
+            writer.write_all(&frame)?;
+        }
+    }
+    writer.write_all(&[FRAME_SNAPSHOT_END])?;
+    writer.flush()
+}
+
+/// 复制的从节点，从主节点接收记录并应用到本地的 Engine
+///
+/// 从节点的 Engine 处于只读模式，只能读取数据。主节点的数据库 id 和已经应用的序号保存在数据目录中，
+/// 断开连接或者重启之后从该位置继续同步，连接到另一个数据库的主节点时重新同步快照。
+pub struct Follower {
+    engine: Arc<Engine>,
+    position: Arc<AtomicU64>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    /// 连接 primary_addr 开始同步，连接断开后会自动重连
+    pub fn start(engine: Arc<Engine>, primary_addr: SocketAddr) -> Result<Follower> {
+        let position_path = engine.options.dir_path.join(REPLICATION_POSITION_FILE_NAME);
+        // 内存中的从节点重启后数据为空，不保存位置
+        let (primary_id, position) = match engine.options.io_type {
+            IOType::Memory => (DatabaseId::default(), 0),
+            _ => load_position(&position_path)?,
+        };
+        let position = Arc::new(AtomicU64::new(position));
+        engine.set_read_only(true);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let engine = engine.clone();
+            let position = position.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut applier = Applier {
+                    engine: &engine,
+                    primary_id,
+                    position: &position,
+                    position_path,
+                };
+                while !stopped.load(Ordering::SeqCst) {
+                    if let Err(e) = applier.replicate(primary_addr, &stopped) {
+                        warn!(
+                            "replication from primary {} interrupted: {}",
+                            primary_addr, e
+                        );
+                        thread::sleep(RECONNECT_INTERVAL);
+                    }
+                }
+                // 停止前保存最后应用的位置
+                if let Err(e) = applier.set_position(position.load(Ordering::SeqCst), true) {
+                    error!("failed to save replication position: {}", e);
+                }
+            })
+        };
+
+        Ok(Follower {
+            engine,
+            position,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+
+    /// 已经应用的主节点序号
+    pub fn position(&self) -> u64 {
+        self.position.load(Ordering::SeqCst)
+    }
+
+    /// 等待应用到主节点的序号 seq，超时返回 false
+    pub fn wait_for(&self, seq: u64, timeout: Duration) -> bool {
+        let start = std::time::Instant::now();
+        while self.position() < seq {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.engine.set_read_only(false);
+    }
+}
+
+// 从节点正在接收的快照
+struct SnapshotState {
+    primary_id: DatabaseId,
+    seq: u64,
+    families: HashSet<String>,      // 快照中的列族，不包含默认列族
+    keys: HashSet<(String, Bytes)>, // 快照中每个列族的 key，快照结束后删除本地多余的 key
+}
+
+// 从节点应用变更的状态
+struct Applier<'a> {
+    engine: &'a Engine,
+    primary_id: DatabaseId, // 已应用的位置所属的主节点数据库
+    position: &'a AtomicU64,
+    position_path: PathBuf,
+}
+
+impl Applier<'_> {
+    // 建立一次连接并持续应用变更，直到连接断开或者从节点停止
+    fn replicate(&mut self, primary_addr: SocketAddr, stopped: &AtomicBool) -> io::Result<()> {
+        let stream = TcpStream::connect_timeout(&primary_addr, Duration::from_secs(1))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HEARTBEAT_INTERVAL * 5))?;
+        let mut writer = stream.try_clone()?;
+        let mut handshake = self.primary_id.to_vec();
+        handshake.put_u64(self.position.load(Ordering::SeqCst));
+        writer.write_all(&handshake)?;
+        let mut reader = BufReader::new(stream);
+
+        let mut snapshot: Option<SnapshotState> = None;
+        while !stopped.load(Ordering::SeqCst) {
+            match read_u8(&mut reader)? {
+                FRAME_SNAPSHOT_BEGIN => {
+                    let mut primary_id = DatabaseId::default();
+                    reader.read_exact(&mut primary_id)?;
+                    let seq = read_u64(&mut reader)?;
+                    info!("bootstrapping follower from snapshot at seq {}", seq);
+                    snapshot = Some(SnapshotState {
+                        primary_id,
+                        seq,
+                        families: HashSet::new(),
+                        keys: HashSet::new(),
+                    });
+                }
+                FRAME_SNAPSHOT_FAMILY => {
+                    let name = read_string(&mut reader)?;
+                    let options = decode_family_options(&read_bytes(&mut reader)?);
+                    let state = snapshot.as_mut().ok_or_else(invalid_frame)?;
+                    self.engine
+                        .apply_create_cf(&name, options)
+                        .map_err(io::Error::other)?;
+                    state.families.insert(name);
+                }
+                FRAME_SNAPSHOT_ENTRY => {
+                    let cf = read_string(&mut reader)?;
+                    let key = read_bytes(&mut reader)?;
+                    let value = read_bytes(&mut reader)?;
+                    let expire_at = read_expire_at(&mut reader)?;
+                    let state = snapshot.as_mut().ok_or_else(invalid_frame)?;
+                    self.apply(&cf, &key, Some((&value, expire_at)))?;
+                    state.keys.insert((cf, key));
+                }
+                FRAME_SNAPSHOT_END => {
+                    let state = snapshot.take().ok_or_else(invalid_frame)?;
+                    self.remove_missing(&state)?;
+                    self.primary_id = state.primary_id;
+                    self.set_position(state.seq, true)?;
+                }
+                FRAME_PUT => {
+                    let seq = read_u64(&mut reader)?;
+                    let cf = read_string(&mut reader)?;
+                    let key = read_bytes(&mut reader)?;
+                    let value = read_bytes(&mut reader)?;
+                    let expire_at = read_expire_at(&mut reader)?;
+                    self.apply(&cf, &key, Some((&value, expire_at)))?;
+                    self.set_position(seq, seq % 100 == 0)?;
+                }
+                FRAME_DELETE => {
+                    let seq = read_u64(&mut reader)?;
+                    let cf = read_string(&mut reader)?;
+                    let key = read_bytes(&mut reader)?;
+                    self.apply(&cf, &key, None)?;
+                    self.set_position(seq, seq % 100 == 0)?;
+                }
+                FRAME_CREATE_FAMILY => {
+                    let seq = read_u64(&mut reader)?;
+                    let name = read_string(&mut reader)?;
+                    let options = decode_family_options(&read_bytes(&mut reader)?);
+                    self.engine
+                        .apply_create_cf(&name, options)
+                        .map_err(io::Error::other)?;
+                    self.set_position(seq, true)?;
+                }
+                FRAME_DROP_FAMILY => {
+                    let seq = read_u64(&mut reader)?;
+                    let name = read_string(&mut reader)?;
+                    self.engine.apply_drop_cf(&name).map_err(io::Error::other)?;
+                    self.set_position(seq, true)?;
+                }
+                FRAME_HEARTBEAT => {
+                    let position = self.position.load(Ordering::SeqCst);
+                    self.set_position(position, true)?;
+                }
+                _ => return Err(invalid_frame()),
+            }
+        }
+        Ok(())
+    }
+
+    // 应用一条变更，value 为 None 时删除 key，不受只读模式的限制
+    //
+    // 与本地写入一样持有 key 的锁并经过同一条写入路径，同时更新合并链和二级索引
+    fn apply(&self, cf: &str, key: &Bytes, value: Option<(&Bytes, Option<u64>)>) -> io::Result<()> {
+        let handle = self.engine.cf(cf).map_err(io::Error::other)?;
+        let _guard = self.engine.lock_key(key);
+        let result = match (handle.family(), value) {
+            (None, Some((value, _))) => self.engine.put_locked(key, value),
+            (None, None) => self.engine.delete_locked(key),
+            (Some(family), Some((value, expire_at))) => {
+                self.engine.put_family_locked(family, key, value, expire_at)
+            }
+            (Some(family), None) => self.engine.delete_family_locked(family, key),
+        };
+        result.map(|_| ()).map_err(io::Error::other)
+    }
+
+    // 快照结束后删除快照中没有的列族和 key
+    fn remove_missing(&self, state: &SnapshotState) -> io::Result<()> {
+        for name in self.engine.list_cfs() {
+            if name != DEFAULT_FAMILY_NAME && !state.families.contains(&name) {
+                self.engine.apply_drop_cf(&name).map_err(io::Error::other)?;
+                continue;
+            }
+            let handle = self.engine.cf(&name).map_err(io::Error::other)?;
+            for key in handle.list_keys() {
+                if !state.keys.contains(&(name.clone(), key.clone())) {
+                    self.apply(&name, &key, None)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 更新已应用的位置，persist 为 true 时同时写入数据目录
+    fn set_position(&self, seq: u64, persist: bool) -> io::Result<()> {
+        self.position.store(seq, Ordering::SeqCst);
+        if persist && self.engine.options.io_type != IOType::Memory {
+            // 先持久化数据再保存位置，重启后最多重复应用一部分变更
+            self.engine.sync().map_err(io::Error::other)?;
+            let mut data = self.primary_id.to_vec();
+            data.put_u64(seq);
+            write_file_atomic(&self.position_path, &data)?;
+        }
+        Ok(())
+    }
+}
+
+// 读取从节点保存的主节点数据库 id 和位置，只有 8 字节序号的旧格式没有 id，下次连接时重新同步快照
+fn load_position(path: &Path) -> Result<(DatabaseId, u64)> {
+    match fs::read(path) {
+        Ok(data) if data.len() == 8 => Ok((DatabaseId::default(), (&data[..]).get_u64())),
+        Ok(data) if data.len() == 24 => {
+            let primary_id = data[..16].try_into().unwrap();
+            Ok((primary_id, (&data[16..]).get_u64()))
+        }
+        Ok(_) => Err(Errors::InvalidReplicationPosition),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((DatabaseId::default(), 0)),
+        Err(e) => {
+            error!("failed to read replication position: {}", e);
+            Err(Errors::InvalidReplicationPosition)
+        }
+    }
+}
+
+// 读取主节点的数据库 id，不存在时生成一个新的
+fn load_database_id(path: &Path) -> Result<DatabaseId> {
+    match fs::read(path) {
+        Ok(data) => match data.try_into() {
+            Ok(database_id) => Ok(database_id),
+            Err(_) => Err(Errors::InvalidDatabaseId),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let database_id = new_database_id();
+            if let Err(e) = write_file_atomic(path, &database_id) {
+                error!("failed to save database id: {}", e);
+                return Err(Errors::InvalidDatabaseId);
+            }
+            Ok(database_id)
+        }
+        Err(e) => {
+            error!("failed to read database id: {}", e);
+            Err(Errors::InvalidDatabaseId)
+        }
+    }
+}
+
+// 由当前时间、进程 id 和随机种子的哈希生成，全 0 表示从节点还没有同步过任何主节点
+fn new_database_id() -> DatabaseId {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut database_id = DatabaseId::default();
+    for (i, part) in database_id.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u32(std::process::id());
+        hasher.write_usize(i);
+        part.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    database_id
+}
+
+// 先写临时文件再重命名，崩溃时文件要么是旧的内容，要么是新的内容
+fn write_file_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn put_frame(
+    seq: u64,
+    cf: &str,
+    key: &[u8],
+    value: &[u8],
+    expire_at: Option<u64>,
+) -> io::Result<Vec<u8>> {
+    let mut frame = vec![FRAME_PUT];
+    frame.put_u64(seq);
+    put_bytes(&mut frame, cf.as_bytes())?;
+    put_bytes(&mut frame, key)?;
+    put_bytes(&mut frame, value)?;
+    frame.put_u64(expire_at.unwrap_or(0));
+    Ok(frame)
+}
+
+fn delete_frame(seq: u64, cf: &str, key: &[u8]) -> io::Result<Vec<u8>> {
+    let mut frame = vec![FRAME_DELETE];
+    frame.put_u64(seq);
+    put_bytes(&mut frame, cf.as_bytes())?;
+    put_bytes(&mut frame, key)?;
+    Ok(frame)
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "key or value is too large to replicate",
+        ));
+    }
+    buf.put_u32(data.len() as u32);
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(buf.as_slice().get_u64())
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Bytes> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(invalid_frame());
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+    Ok(data.into())
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader)?.to_vec()).map_err(|_| invalid_frame())
+}
+
+// 过期时间为 0 表示没有过期时间
+fn read_expire_at(reader: &mut impl Read) -> io::Result<Option<u64>> {
+    match read_u64(reader)? {
+        0 => Ok(None),
+        expire_at => Ok(Some(expire_at)),
+    }
+}
+
+fn invalid_frame() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid replication frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{test_options, FamilyOptions};
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_replication_stream_and_resume() {
+        let primary_opts = test_options("bitcask-rs-replication-primary");
+        let follower_opts = test_options("bitcask-rs-replication-follower");
+        let primary_engine = Arc::new(Engine::open(primary_opts.clone()).unwrap());
+        let follower_engine = Arc::new(Engine::open(follower_opts.clone()).unwrap());
+
+        primary_engine
+            .put(Bytes::from("a"), Bytes::from("1"))
+            .unwrap();
+        let primary = Primary::start(primary_engine.clone(), "127.0.0.1:0").unwrap();
+        // 从节点应用的变更同样更新二级索引
+        follower_engine
+            .create_index("value", |value| vec![Bytes::copy_from_slice(value)])
+            .unwrap();
+        let follower = Follower::start(follower_engine.clone(), primary.local_addr()).unwrap();
+
+        primary_engine
+            .put(Bytes::from("b"), Bytes::from("2"))
+            .unwrap();
+        primary_engine.delete(Bytes::from("a")).unwrap();
+        assert!(follower.wait_for(primary_engine.latest_seq(), WAIT));
+        while !follower_engine.is_index_ready("value").unwrap() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            follower_engine.lookup_by("value", b"2").unwrap(),
+            vec![(Bytes::from("b"), Bytes::from("2"))]
+        );
+        assert!(follower_engine.lookup_by("value", b"1").unwrap().is_empty());
+        assert_eq!(
+            follower_engine.get(Bytes::from("b")).unwrap(),
+            Bytes::from("2")
+        );
+        assert_eq!(
+            follower_engine.get(Bytes::from("a")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        // 从节点只读
+        assert_eq!(
+            follower_engine
+                .put(Bytes::from("c"), Bytes::from("3"))
+                .err(),
+            Some(Errors::ReadOnly)
+        );
+
+        // 断开期间主节点继续写入，重启从节点后从保存的位置继续同步
+        drop(follower);
+        drop(follower_engine);
+        primary_engine
+            .put(Bytes::from("c"), Bytes::from("3"))
+            .unwrap();
+
+        let follower_engine = Arc::new(Engine::open(follower_opts.clone()).unwrap());
+        let follower = Follower::start(follower_engine.clone(), primary.local_addr()).unwrap();
+        assert!(follower.position() >= 3);
+        assert!(follower.wait_for(primary_engine.latest_seq(), WAIT));
+        assert_eq!(
+            follower_engine.get(Bytes::from("c")).unwrap(),
+            Bytes::from("3")
+        );
+        assert_eq!(follower_engine.list_keys().len(), 2);
+
+        drop(follower);
+        drop(primary);
+        fs::remove_dir_all(primary_opts.dir_path).unwrap();
+        fs::remove_dir_all(follower_opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_replication_bootstrap_from_snapshot() {
+        let primary_opts = test_options("bitcask-rs-replication-snapshot-primary");
+        let follower_opts = test_options("bitcask-rs-replication-snapshot-follower");
+
+        // 从节点已经同步过一个旧的主节点，位置比新的主节点还新
+        let follower_engine = Arc::new(Engine::open(follower_opts.clone()).unwrap());
+        follower_engine
+            .put(Bytes::from("stale"), Bytes::from("x"))
+            .unwrap();
+        follower_engine
+            .put(Bytes::from("k"), Bytes::from("old"))
+            .unwrap();
+        fs::write(
+            follower_opts.dir_path.join(REPLICATION_POSITION_FILE_NAME),
+            100u64.to_be_bytes(),
+        )
+        .unwrap();
+
+        let primary_engine = Arc::new(Engine::open(primary_opts.clone()).unwrap());
+        for i in 0..10 {
+            let key = Bytes::from(format!("key-{}", i));
+            primary_engine.put(key, Bytes::from("v")).unwrap();
+        }
+        primary_engine
+            .put(Bytes::from("k"), Bytes::from("new"))
+            .unwrap();
+
+        let primary = Primary::start(primary_engine.clone(), "127.0.0.1:0").unwrap();
+        let follower = Follower::start(follower_engine.clone(), primary.local_addr()).unwrap();
+
+        let start = std::time::Instant::now();
+        while follower.position() != primary_engine.latest_seq() {
+            assert!(start.elapsed() < WAIT);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(follower_engine.list_keys(), primary_engine.list_keys());
+        assert_eq!(
+            follower_engine.get(Bytes::from("k")).unwrap(),
+            Bytes::from("new")
+        );
+        assert_eq!(
+            follower_engine.get(Bytes::from("stale")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        // 快照之后继续同步增量变更
+        primary_engine
+            .put(Bytes::from("after"), Bytes::from("1"))
+            .unwrap();
+        assert!(follower.wait_for(primary_engine.latest_seq(), WAIT));
+        assert_eq!(
+            follower_engine.get(Bytes::from("after")).unwrap(),
+            Bytes::from("1")
+        );
+
+        drop(follower);
+        drop(primary);
+        fs::remove_dir_all(primary_opts.dir_path).unwrap();
+        fs::remove_dir_all(follower_opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_replication_switch_primary() {
+        let first_opts = test_options("bitcask-rs-replication-switch-first");
+        let second_opts = test_options("bitcask-rs-replication-switch-second");
+        let follower_opts = test_options("bitcask-rs-replication-switch-follower");
+        let follower_engine = Arc::new(Engine::open(follower_opts.clone()).unwrap());
+
+        let first_engine = Arc::new(Engine::open(first_opts.clone()).unwrap());
+        first_engine
+            .put(Bytes::from("only-first"), Bytes::from("1"))
+            .unwrap();
+        let first = Primary::start(first_engine.clone(), "127.0.0.1:0").unwrap();
+        let follower = Follower::start(follower_engine.clone(), first.local_addr()).unwrap();
+        assert!(follower.wait_for(first_engine.latest_seq(), WAIT));
+        drop(follower);
+        drop(first);
+
+        // 另一个数据库的序号更大，位置在范围内也需要重新同步快照
+        let second_engine = Arc::new(Engine::open(second_opts.clone()).unwrap());
+        for i in 0..5 {
+            let key = Bytes::from(format!("key-{}", i));
+            second_engine.put(key, Bytes::from("v")).unwrap();
+        }
+        let second = Primary::start(second_engine.clone(), "127.0.0.1:0").unwrap();
+        let follower = Follower::start(follower_engine.clone(), second.local_addr()).unwrap();
+        let start = std::time::Instant::now();
+        while follower_engine.list_keys() != second_engine.list_keys() {
+            assert!(start.elapsed() < WAIT);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            follower_engine.get(Bytes::from("only-first")).err(),
+            Some(Errors::RecordNotFound)
+        );
+        drop(follower);
+        drop(follower_engine);
+
+        // 主节点重启之后 id 不变，从节点从保存的位置继续同步
+        drop(second);
+        let second = Primary::start(second_engine.clone(), "127.0.0.1:0").unwrap();
+        second_engine
+            .put(Bytes::from("after"), Bytes::from("1"))
+            .unwrap();
+        let follower_engine = Arc::new(Engine::open(follower_opts.clone()).unwrap());
+        let follower = Follower::start(follower_engine.clone(), second.local_addr()).unwrap();
+        assert_eq!(follower.position(), 5);
+        assert!(follower.wait_for(second_engine.latest_seq(), WAIT));
+        assert_eq!(
+            follower_engine.get(Bytes::from("after")).unwrap(),
+            Bytes::from("1")
+        );
+
+        // 损坏的数据帧长度不会按照对方声明的长度分配内存
+        let mut frame = &(u32::MAX).to_be_bytes()[..];
+        assert_eq!(
+            read_bytes(&mut frame).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        drop(follower);
+        drop(second);
+        fs::remove_dir_all(first_opts.dir_path).unwrap();
+        fs::remove_dir_all(second_opts.dir_path).unwrap();
+        fs::remove_dir_all(follower_opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_replication_column_families() {
+        let primary_opts = test_options("bitcask-rs-replication-families-primary");
+        let follower_opts = test_options("bitcask-rs-replication-families-follower");
+        let primary_engine = Arc::new(Engine::open(primary_opts.clone()).unwrap());
+        let follower_engine = Arc::new(Engine::open(follower_opts.clone()).unwrap());
+        let ttl = FamilyOptions {
+            ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        // 快照包含已有的列族，TTL 列族中的 value 保留主节点上的过期时间
+        let users = primary_engine.create_cf("users", ttl).unwrap();
+        users.put(Bytes::from("u1"), Bytes::from("1")).unwrap();
+        follower_engine
+            .put(Bytes::from("stale"), Bytes::from("x"))
+            .unwrap();
+        let primary = Primary::start(primary_engine.clone(), "127.0.0.1:0").unwrap();
+        let follower = Follower::start(follower_engine.clone(), primary.local_addr()).unwrap();
+        assert!(follower.wait_for(primary_engine.latest_seq(), WAIT));
+        let replica = follower_engine.cf("users").unwrap();
+        assert_eq!(replica.options(), ttl);
+        assert_eq!(
+            replica.get_with_expiry(Bytes::from("u1")).unwrap(),
+            users.get_with_expiry(Bytes::from("u1")).unwrap()
+        );
+        assert!(follower_engine.list_keys().is_empty());
+        drop(replica);
+
+        // 之后的列族创建、写入和删除同样复制
+        let sessions = primary_engine
+            .create_cf("sessions", FamilyOptions::default())
+            .unwrap();
+        sessions.put(Bytes::from("s1"), Bytes::from("2")).unwrap();
+        users.delete(Bytes::from("u1")).unwrap();
+        drop(users);
+        primary_engine.drop_cf("users").unwrap();
+        assert!(follower.wait_for(primary_engine.latest_seq(), WAIT));
+        assert_eq!(follower_engine.list_cfs(), vec!["default", "sessions"]);
+        assert_eq!(
+            follower_engine
+                .cf("sessions")
+                .unwrap()
+                .get(Bytes::from("s1"))
+                .unwrap(),
+            Bytes::from("2")
+        );
+
+        drop(sessions);
+        drop(follower);
+        drop(primary);
+        fs::remove_dir_all(primary_opts.dir_path).unwrap();
+        fs::remove_dir_all(follower_opts.dir_path).unwrap();
+    }
+}
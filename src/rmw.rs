@@ -0,0 +1,212 @@
+use bytes::{Bytes, BytesMut};
+use parking_lot::MutexGuard;
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+impl Engine {
+    /// 比较并交换：key 当前的 value 等于 expected 时写入 new，返回是否写入
+    ///
+    /// expected 为 None 表示要求 key 不存在。
+    pub fn compare_and_swap(
+        &self,
+        key: Bytes,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> Result<bool> {
+        self.read_modify_write(key, |current| match current == expected {
+            true => Ok(Some(new)),
+            false => Ok(None),
+        })
+        .map(|written| written.is_some())
+    }
+
+    /// 将 key 保存的十进制整数加上 delta，key 不存在时视为 0，返回相加之后的值
+    pub fn incr_by(&self, key: Bytes, delta: i64) -> Result<i64> {
+        let mut result = 0;
+        self.read_modify_write(key, |current| {
+            let value = match current {
+                Some(value) => parse_integer(&value)?,
+                None => 0,
+            };
+            result = match value.checked_add(delta) {
+                Some(result) => result,
+                None => return Err(Errors::IntegerOverflow),
+            };
+            Ok(Some(Bytes::from(result.to_string())))
+        })?;
+        Ok(result)
+    }
+
+    /// 在 key 的 value 末尾追加 data，key 不存在时直接写入 data，返回追加之后 value 的长度
+    pub fn append(&self, key: Bytes, data: Bytes) -> Result<usize> {
+        let written = self.read_modify_write(key, |current| {
+            let value = match current {
+                Some(value) => {
+                    let mut buf = BytesMut::with_capacity(value.len() + data.len());
+                    buf.extend_from_slice(&value);
+                    buf.extend_from_slice(&data);
+                    buf.freeze()
+                }
+                None => data.clone(),
+            };
+            Ok(Some(value))
+        })?;
+        Ok(written.map_or(0, |value| value.len()))
+    }
+
+    /// 获取 key 对应的写锁，同一个分段内的写入互斥
+    pub(crate) fn lock_key(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.key_locks[self.key_lock_index(key)].lock()
+    }
+
+    /// key 所在的写锁分段
+    pub(crate) fn key_lock_index(&self, key: &[u8]) -> usize {
+        crc32fast::hash(key) as usize % self.key_locks.len()
+    }
+
+    // 持有 key 的锁读取当前 value，f 返回 Some 时写入新的 value 并返回
+    fn read_modify_write(
+        &self,
+        key: Bytes,
+        f: impl FnOnce(Option<Bytes>) -> Result<Option<Bytes>>,
+    ) -> Result<Option<Bytes>> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_writable()?;
+
+        let guard = self.lock_key(&key);
+        let current = match self.get(key.clone()) {
+            Ok(value) => Some(value),
+            Err(Errors::RecordNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let new = match f(current)? {
+            Some(new) => new,
+            None => return Ok(None),
+        };
+        let commit_seq = self.put_locked(&key, &new)?;
+        drop(guard);
+
+        self.wait_for_sync(commit_seq, self.options.sync)?;
+        Ok(Some(new))
+    }
+}
+
+fn parse_integer(value: &[u8]) -> Result<i64> {
+    match std::str::from_utf8(value).ok().and_then(|s| s.parse().ok()) {
+        Some(n) => Ok(n),
+        None => Err(Errors::ValueNotInteger),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::options::test_options;
+
+    #[test]
+    fn test_compare_and_swap() {
+        let opts = test_options("bitcask-rs-rmw-cas");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let key = Bytes::from("k");
+
+        // None 表示要求 key 不存在
+        assert!(engine
+            .compare_and_swap(key.clone(), None, Bytes::from("v1"))
+            .unwrap());
+        assert!(!engine
+            .compare_and_swap(key.clone(), None, Bytes::from("v2"))
+            .unwrap());
+        assert!(!engine
+            .compare_and_swap(key.clone(), Some(Bytes::from("v0")), Bytes::from("v2"))
+            .unwrap());
+        assert!(engine
+            .compare_and_swap(key.clone(), Some(Bytes::from("v1")), Bytes::from("v2"))
+            .unwrap());
+        assert_eq!(engine.get(key.clone()).unwrap(), Bytes::from("v2"));
+
+        // 并发的 CAS 只有一个能成功
+        let winners: usize = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let engine = &engine;
+                    let key = key.clone();
+                    scope.spawn(move || {
+                        engine
+                            .compare_and_swap(
+                                key,
+                                Some(Bytes::from("v2")),
+                                Bytes::from(i.to_string()),
+                            )
+                            .unwrap() as usize
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(winners, 1);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_incr_by_and_append() {
+        let opts = test_options("bitcask-rs-rmw-incr-append");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let counter = Bytes::from("counter");
+
+        // 并发递增不会丢失更新
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                let engine = &engine;
+                let counter = counter.clone();
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        engine.incr_by(counter.clone(), 1).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(engine.get(counter.clone()).unwrap(), Bytes::from("800"));
+        assert_eq!(engine.incr_by(counter.clone(), -1000).unwrap(), -200);
+
+        engine.put(Bytes::from("text"), Bytes::from("abc")).unwrap();
+        assert_eq!(
+            engine.incr_by(Bytes::from("text"), 1).err(),
+            Some(Errors::ValueNotInteger)
+        );
+        engine
+            .put(Bytes::from("max"), Bytes::from(i64::MAX.to_string()))
+            .unwrap();
+        assert_eq!(
+            engine.incr_by(Bytes::from("max"), 1).err(),
+            Some(Errors::IntegerOverflow)
+        );
+
+        assert_eq!(
+            engine.append(Bytes::from("log"), Bytes::from("a")).unwrap(),
+            1
+        );
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        engine.append(Bytes::from("log"), Bytes::from("b")).unwrap();
+                    }
+                });
+            }
+        });
+        let log = engine.get(Bytes::from("log")).unwrap();
+        assert_eq!(log.len(), 201);
+        assert!(log.starts_with(b"ab"));
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use bytes::Bytes;
+use log::warn;
+use parking_lot::RwLock;
+
+use crate::{
+    data::log_record::LogRecordType,
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// 从 value 中提取索引 key 的函数，一个 value 可以对应零个或多个索引 key
+pub type IndexExtractor = Box<dyn Fn(&[u8]) -> Vec<Bytes> + Send + Sync>;
+
+/// 二级索引，只保存在内存中，不写入数据文件
+///
+/// 每次 put/delete 更新主索引之后同步更新二级索引，更新带有记录的序号，
+/// 较旧的更新不会覆盖较新的结果，因此后台回填和并发写入的先后顺序不影响最终结果。
+///
+/// 只索引默认列族中通过 put 写入的完整 value：
+/// - 合并操作数（merge）写入的 key 从索引中移除，合并后的 value 不会被索引；
+/// - 流式写入的大 value 同样不建立二级索引；
+/// - 其他列族中的数据不会被索引。
+pub(crate) struct SecondaryIndex {
+    extractor: IndexExtractor,
+    state: RwLock<IndexState>,
+    ready: AtomicBool,   // 是否已经完成回填
+    dropped: AtomicBool, // 是否已经被删除，用于停止回填
+}
+
+struct IndexState {
+    entries: BTreeMap<Bytes, BTreeSet<Bytes>>, // 索引 key -> 主键
+    primary: HashMap<Bytes, (u64, Vec<Bytes>)>, // 主键 -> (最后应用的记录序号, 索引 key)
+}
+
+impl SecondaryIndex {
+    // 应用主键 key 的一次变更，value 为 None 表示删除
+    fn apply(&self, key: &[u8], value: Option<&[u8]>, seq: u64) {
+        let mut index_keys = match value {
+            Some(value) => (self.extractor)(value),
+            None => Vec::new(),
+        };
+        index_keys.sort();
+        index_keys.dedup();
+
+        let mut state = self.state.write();
+        if let Some((applied_seq, _)) = state.primary.get(key) {
+            if *applied_seq > seq {
+                return;
+            }
+        }
+        if let Some((_, old_keys)) = state.primary.remove(key) {
+            for old_key in old_keys {
+                if let Some(primary_keys) = state.entries.get_mut(&old_key) {
+                    primary_keys.remove(key);
+                    if primary_keys.is_empty() {
+                        state.entries.remove(&old_key);
+                    }
+                }
+            }
+        }
+        let key = Bytes::copy_from_slice(key);
+        for index_key in index_keys.iter() {
+            state
+                .entries
+                .entry(index_key.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        // 回填期间保留删除的序号，避免回填读到的旧 value 覆盖删除
+        if !index_keys.is_empty() || !self.ready.load(Ordering::SeqCst) {
+            state.primary.insert(key, (seq, index_keys));
+        }
+    }
+
+    // 回填完成，清理删除的主键
+    fn finish_backfill(&self) {
+        let mut state = self.state.write();
+        state
+            .primary
+            .retain(|_, (_, index_keys)| !index_keys.is_empty());
+        self.ready.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Engine {
+    /// 创建一个名为 name 的二级索引，extractor 从 value 中提取索引 key
+    ///
+    /// 已有的数据在后台线程中回填，回填完成之前查询返回 IndexNotReady。
+    /// 二级索引只保存在内存中，重新打开数据库之后需要再次创建，并且再次读取所有 key 完整回填，
+    /// 耗时与数据量成正比。
+    pub fn create_index<F>(self: &Arc<Self>, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Vec<Bytes> + Send + Sync + 'static,
+    {
+        let index = Arc::new(SecondaryIndex {
+            extractor: Box::new(extractor),
+            state: RwLock::new(IndexState {
+                entries: BTreeMap::new(),
+                primary: HashMap::new(),
+            }),
+            ready: AtomicBool::new(false),
+            dropped: AtomicBool::new(false),
+        });
+        {
+            let mut indexes = self.secondary_indexes.write();
+            if indexes.contains_key(name) {
+                return Err(Errors::IndexAlreadyExists);
+            }
+            indexes.insert(name.to_string(), index.clone());
+        }
+
+        // 注册之后写入的数据由写入路径维护，回填只需要处理注册时已经存在的 key
+        let engine = Arc::downgrade(self);
+        let name = name.to_string();
+        thread::spawn(move || {
+            let keys = match engine.upgrade() {
+                Some(engine) => engine.list_keys(),
+                None => return,
+            };
+            for key in keys {
+                if index.dropped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let engine = match engine.upgrade() {
+                    Some(engine) => engine,
+                    None => return,
+                };
+                while let Some(pos) = engine.index.get(key.to_vec()) {
+                    match engine.read_log_record_with(&pos, false) {
+                        Ok(record) if record.record_type == LogRecordType::NORMAL => {
+                            index.apply(&key, Some(&record.value), record.seq)
+                        }
+                        Ok(_) => {}
+                        // 记录被合并移动到了新的位置，重新读取
+                        Err(_) if engine.index.get(key.to_vec()) != Some(pos) => continue,
+                        Err(e) => {
+                            warn!("failed to backfill index {} for key {:?}: {}", name, key, e)
+                        }
+                    }
+                    break;
+                }
+            }
+            index.finish_backfill();
+        });
+        Ok(())
+    }
+
+    /// 删除二级索引
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        match self.secondary_indexes.write().remove(name) {
+            Some(index) => {
+                index.dropped.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            None => Err(Errors::IndexNotFound),
+        }
+    }
+
+    /// 二级索引是否已经完成回填
+    pub fn is_index_ready(&self, name: &str) -> Result<bool> {
+        match self.secondary_indexes.read().get(name) {
+            Some(index) => Ok(index.ready.load(Ordering::SeqCst)),
+            None => Err(Errors::IndexNotFound),
+        }
+    }
+
+    /// 通过二级索引查询，返回所有索引 key 为 index_key 的主键和 value，按主键排序
+    pub fn lookup_by(&self, index_name: &str, index_key: &[u8]) -> Result<Vec<(Bytes, Bytes)>> {
+        let index = match self.secondary_indexes.read().get(index_name) {
+            Some(index) => index.clone(),
+            None => return Err(Errors::IndexNotFound),
+        };
+        if !index.ready.load(Ordering::SeqCst) {
+            return Err(Errors::IndexNotReady);
+        }
+
+        let keys: Vec<Bytes> = match index.state.read().entries.get(index_key) {
+            Some(primary_keys) => primary_keys.iter().cloned().collect(),
+            None => return Ok(Vec::new()),
+        };
+        let mut result = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.get(key.clone()) {
+                Ok(value) => result.push((key, value)),
+                // 查询期间被并发删除
+                Err(Errors::RecordNotFound) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(result)
+    }
+
+    /// 默认列族中的 key 发生变更后更新所有二级索引，value 为 None 表示删除
+    pub(crate) fn update_secondary_indexes(&self, key: &[u8], value: Option<&[u8]>, seq: u64) {
+        let indexes = self.secondary_indexes.read();
+        for index in indexes.values() {
+            index.apply(key, value, seq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::options::test_options;
+
+    // value 的格式为 "city:name"，按 city 建立索引
+    fn city(value: &[u8]) -> Vec<Bytes> {
+        match value.iter().position(|b| *b == b':') {
+            Some(i) => vec![Bytes::copy_from_slice(&value[..i])],
+            None => Vec::new(),
+        }
+    }
+
+    fn wait_ready(engine: &Engine, name: &str) {
+        for _ in 0..1000 {
+            if engine.is_index_ready(name).unwrap() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("index {} is not ready", name);
+    }
+
+    fn primary_keys(result: Vec<(Bytes, Bytes)>) -> Vec<Bytes> {
+        result.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn test_secondary_index_backfill_and_update() {
+        let opts = test_options("bitcask-rs-secondary-index");
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        for i in 0..100 {
+            let city = if i % 2 == 0 { "paris" } else { "tokyo" };
+            let value = Bytes::from(format!("{}:user-{}", city, i));
+            engine
+                .put(Bytes::from(format!("user-{:03}", i)), value)
+                .unwrap();
+        }
+
+        engine.create_index("city", city).unwrap();
+        assert_eq!(
+            engine.create_index("city", city).err(),
+            Some(Errors::IndexAlreadyExists)
+        );
+        wait_ready(&engine, "city");
+        assert_eq!(engine.lookup_by("city", b"paris").unwrap().len(), 50);
+        assert_eq!(engine.lookup_by("city", b"tokyo").unwrap().len(), 50);
+
+        // 写入时同步更新索引
+        engine
+            .put(Bytes::from("user-000"), Bytes::from("berlin:user-0"))
+            .unwrap();
+        engine.delete(Bytes::from("user-001")).unwrap();
+        engine
+            .put(Bytes::from("user-100"), Bytes::from("berlin:user-100"))
+            .unwrap();
+        engine
+            .put(Bytes::from("user-002"), Bytes::from("no city"))
+            .unwrap();
+        let berlin = engine.lookup_by("city", b"berlin").unwrap();
+        assert_eq!(
+            berlin,
+            vec![
+                (Bytes::from("user-000"), Bytes::from("berlin:user-0")),
+                (Bytes::from("user-100"), Bytes::from("berlin:user-100")),
+            ]
+        );
+        assert_eq!(engine.lookup_by("city", b"paris").unwrap().len(), 48);
+        assert_eq!(engine.lookup_by("city", b"tokyo").unwrap().len(), 49);
+        assert!(engine.lookup_by("city", b"rome").unwrap().is_empty());
+
+        engine.drop_index("city").unwrap();
+        assert_eq!(
+            engine.lookup_by("city", b"paris").err(),
+            Some(Errors::IndexNotFound)
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_secondary_index_concurrent_backfill() {
+        let opts = test_options("bitcask-rs-secondary-index-concurrent");
+        let engine = Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        for i in 0..500 {
+            engine
+                .put(Bytes::from(format!("key-{:03}", i)), Bytes::from("old:v"))
+                .unwrap();
+        }
+
+        // 回填期间并发修改，最终结果与数据一致
+        engine.create_index("tag", city).unwrap();
+        for i in 0..500 {
+            let key = Bytes::from(format!("key-{:03}", i));
+            match i % 3 {
+                0 => engine.delete(key).unwrap(),
+                1 => engine.put(key, Bytes::from("new:v")).unwrap(),
+                _ => {}
+            }
+        }
+        wait_ready(&engine, "tag");
+
+        let old = primary_keys(engine.lookup_by("tag", b"old").unwrap());
+        let new = primary_keys(engine.lookup_by("tag", b"new").unwrap());
+        assert_eq!(old.len(), 166);
+        assert_eq!(new.len(), 167);
+        assert!(old
+            .iter()
+            .all(|key| engine.get(key.clone()).unwrap() == "old:v"));
+        assert!(new
+            .iter()
+            .all(|key| engine.get(key.clone()).unwrap() == "new:v"));
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use std::{cmp::Reverse, collections::BinaryHeap, fs, path::PathBuf, vec};
+
+use bytes::Bytes;
+use log::warn;
+
+use crate::{
+    db::Engine,
+    errors::{Errors, Result},
+    fio,
+    options::{IOType, Options},
+};
+
+/// 保存分片数量的文件名
+const SHARD_COUNT_FILE_NAME: &str = "SHARDS";
+
+/// 按 key 的哈希将数据分散到多个独立 Engine 的存储引擎
+///
+/// 每个分片是 dir_path 下的一个子目录，拥有各自的活跃文件和索引，不同分片的写入可以并行。
+/// 分片数量在第一次打开时保存到目录中，之后必须使用相同的数量打开。
+pub struct ShardedEngine {
+    shards: Vec<Engine>,
+}
+
+impl ShardedEngine {
+    /// 打开一个包含 shard_count 个分片的存储引擎，opts 中除 dir_path 外的配置应用到每个分片
+    pub fn open(opts: Options, shard_count: usize) -> Result<Self> {
+        if shard_count == 0 {
+            return Err(Errors::InvalidShardCount);
+        }
+        fio::create_dir(&opts.dir_path, opts.io_type)?;
+        // 内存中的分片随引擎一起释放，不需要检查分片数量
+        if opts.io_type != IOType::Memory {
+            check_shard_count(opts.dir_path.join(SHARD_COUNT_FILE_NAME), shard_count)?;
+        }
+
+        let mut shards = Vec::with_capacity(shard_count);
+        for i in 0..shard_count {
+            let mut shard_opts = opts.clone();
+            shard_opts.dir_path = opts.dir_path.join(format!("shard-{:03}", i));
+            shards.push(Engine::open(shard_opts)?);
+        }
+        Ok(ShardedEngine { shards })
+    }
+
+    /// 分片数量
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// 存储 key/value 数据，key 不能为空
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.shard(&key).put(key, value)
+    }
+
+    /// 删除 key 对应的数据
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        self.shard(&key).delete(key)
+    }
+
+    /// 获取 key 对应的 value
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        self.shard(&key).get(key)
+    }
+
+    /// 获取所有分片的 key，按顺序排列
+    pub fn list_keys(&self) -> Vec<Bytes> {
+        self.iter_keys().collect()
+    }
+
+    /// 按 key 的顺序遍历所有分片中的 key/value
+    pub fn iter(&self) -> ShardedIterator<'_> {
+        ShardedIterator {
+            shards: &self.shards,
+            keys: self.iter_keys(),
+        }
+    }
+
+    /// 持久化所有分片的数据
+    pub fn sync(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.sync()?;
+        }
+        Ok(())
+    }
+
+    fn shard(&self, key: &[u8]) -> &Engine {
+        &self.shards[shard_index(key, self.shards.len())]
+    }
+
+    // 多路归并各个分片中有序的 key
+    fn iter_keys(&self) -> MergedKeys {
+        let mut sources: Vec<vec::IntoIter<Bytes>> = self
+            .shards
+            .iter()
+            .map(|shard| shard.list_keys().into_iter())
+            .collect();
+        let mut heap = BinaryHeap::new();
+        for (i, source) in sources.iter_mut().enumerate() {
+            if let Some(key) = source.next() {
+                heap.push(Reverse((key, i)));
+            }
+        }
+        MergedKeys { sources, heap }
+    }
+}
+
+/// 按顺序遍历所有分片的迭代器，遍历期间被删除的 key 会被跳过
+pub struct ShardedIterator<'a> {
+    shards: &'a [Engine],
+    keys: MergedKeys,
+}
+
+impl Iterator for ShardedIterator<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            let shard = &self.shards[shard_index(&key, self.shards.len())];
+            match shard.get(key.clone()) {
+                Ok(value) => return Some(Ok((key, value))),
+                Err(Errors::RecordNotFound) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+struct MergedKeys {
+    sources: Vec<vec::IntoIter<Bytes>>,
+    heap: BinaryHeap<Reverse<(Bytes, usize)>>,
+}
+
+impl Iterator for MergedKeys {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        let Reverse((key, i)) = self.heap.pop()?;
+        if let Some(next) = self.sources[i].next() {
+            self.heap.push(Reverse((next, i)));
+        }
+        Some(key)
+    }
+}
+
+// key 所在的分片，使用 crc32 保证不同版本和平台之间结果一致
+fn shard_index(key: &[u8], shard_count: usize) -> usize {
+    crc32fast::hash(key) as usize % shard_count
+}
+
+// 第一次打开时保存分片数量，之后检查是否一致
+fn check_shard_count(path: PathBuf, shard_count: usize) -> Result<()> {
+    match fs::read_to_string(&path) {
+        Ok(content) => match content.trim().parse::<usize>() {
+            Ok(count) if count == shard_count => Ok(()),
+            Ok(_) => Err(Errors::ShardCountMismatch),
+            Err(_) => Err(Errors::InvalidShardCount),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if let Err(e) = fs::write(&path, shard_count.to_string()) {
+                warn!("failed to write shard count: {:?}", e);
+                return Err(Errors::FailedToWriteToDataFile);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            warn!("failed to read shard count: {:?}", e);
+            Err(Errors::FailedToReadFromDataFile)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::test_options;
+
+    #[test]
+    fn test_sharded_engine_put_get_delete() {
+        let opts = test_options("bitcask-rs-sharded-put-get-delete");
+        let engine = ShardedEngine::open(opts.clone(), 4).expect("failed to open engine");
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let key = Bytes::from(format!("key-{:03}", t * 50 + i));
+                        engine.put(key, Bytes::from("value")).unwrap();
+                    }
+                });
+            }
+        });
+        engine.delete(Bytes::from("key-007")).unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("key-123")).unwrap(),
+            Bytes::from("value")
+        );
+        assert_eq!(
+            engine.get(Bytes::from("key-007")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        // key 分散到了多个分片
+        let non_empty = engine
+            .shards
+            .iter()
+            .filter(|shard| !shard.list_keys().is_empty())
+            .count();
+        assert!(non_empty > 1);
+
+        // 跨分片按顺序遍历
+        let items: Vec<(Bytes, Bytes)> = engine.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(items.len(), 199);
+        assert!(items.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(items[0].0, Bytes::from("key-000"));
+        assert_eq!(items[7].0, Bytes::from("key-008"));
+
+        // 重新打开后数据仍然在对应的分片中
+        drop(engine);
+        let engine = ShardedEngine::open(opts.clone(), 4).expect("failed to reopen engine");
+        assert_eq!(engine.list_keys().len(), 199);
+        assert_eq!(
+            engine.get(Bytes::from("key-199")).unwrap(),
+            Bytes::from("value")
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_engine_shard_count_mismatch() {
+        let opts = test_options("bitcask-rs-sharded-count-mismatch");
+        assert_eq!(
+            ShardedEngine::open(opts.clone(), 0).err(),
+            Some(Errors::InvalidShardCount)
+        );
+
+        let engine = ShardedEngine::open(opts.clone(), 3).expect("failed to open engine");
+        assert_eq!(engine.shard_count(), 3);
+        drop(engine);
+
+        assert_eq!(
+            ShardedEngine::open(opts.clone(), 4).err(),
+            Some(Errors::ShardCountMismatch)
+        );
+        assert!(ShardedEngine::open(opts.clone(), 3).is_ok());
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
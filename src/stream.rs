@@ -0,0 +1,541 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use bytes::{Buf, Bytes, BytesMut};
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordPos, LogRecordType},
+    db::Engine,
+    errors::{Errors, Result},
+};
+
+/// 大 value 每个分块的最大大小
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+impl Engine {
+    /// 以流的方式写入 key/value 数据，适用于非常大的 value
+    ///
+    /// value 被拆分成多个 CHUNK 记录追加写入，可能跨越多个数据文件，
+    /// 最后写入一条 MANIFEST 记录保存所有分块的位置，索引指向 MANIFEST 记录。
+    /// 在 MANIFEST 写入之前崩溃，已写入的分块不会被索引引用。
+    pub fn put_stream(&self, key: Bytes, mut reader: impl Read) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.check_writable()?;
+        // 合并切换活跃文件时不能有写入了一半的分块
+        let _stream = self.stream_lock.read();
+
+        // 分块需要能放进一个数据文件
+        let chunk_size = STREAM_CHUNK_SIZE.min((self.options.file_size / 2).max(1) as usize);
+
+        let mut manifest = ValueManifest::default();
+        let mut buf = vec![0u8; chunk_size];
+        loop {
+            let n = read_full(&mut reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            let mut chunk = LogRecord {
+                key: key.to_vec(),
+                value: buf[..n].to_vec(),
+                record_type: LogRecordType::CHUNK,
+                seq: 0,
+                cf: 0,
+            };
+            let pos = self.append_log_record(&mut chunk)?;
+            manifest.chunks.push((pos, n as u64));
+            manifest.total_size += n as u64;
+
+            if n < chunk_size {
+                break;
+            }
+        }
+
+        let mut logrecord = LogRecord {
+            key: key.to_vec(),
+            value: manifest.encode(),
+            record_type: LogRecordType::MANIFEST,
+            seq: 0,
+            cf: 0,
+        };
+        let guard = self.lock_key(&key);
+        let (positions, commit_seq) =
+            self.write_log_records(std::slice::from_mut(&mut logrecord))?;
+
+        let ok = self.index.put(key.to_vec(), positions[0]);
+        if !ok {
+            return Err(Errors::IndexUpdateError);
+        }
+        // 流式写入的 value 不建立二级索引，只需要移除旧 value 的索引
+        self.update_secondary_indexes(&key, None, logrecord.seq);
+        drop(guard);
+
+        self.wait_for_sync(commit_seq, self.options.sync)
+    }
+
+    /// 获取 key 对应 value 的读取器，分块存储的 value 按需从数据文件中读取
+    ///
+    /// 还没有合并的操作数在打开读取器时完成合并，合并之后的 value 保存在内存中。
+    /// 读取期间合并移动了分块时，读取器重新查找 key 的清单继续读取；
+    /// value 已经被覆盖或删除并且旧的分块已经被合并回收时，读取返回错误。
+    pub fn get_reader(&self, key: Bytes) -> Result<ValueReader<'_>> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let log_record_pos = match self.index.get(key.to_vec()) {
+            Some(pos) => pos,
+            None => return Err(Errors::RecordNotFound),
+        };
+
+        let logrecord = self.read_log_record(&log_record_pos)?;
+        match logrecord.record_type {
+            LogRecordType::MANIFEST => ValueReader::from_manifest(self, &logrecord),
+            LogRecordType::NORMAL => Ok(ValueReader::from_value(self, logrecord.value.into())),
+            // 最新的记录是合并操作数时，和 get 一样计算合并之后的 value
+            LogRecordType::MERGE => Ok(ValueReader::from_value(self, self.get(key)?)),
+            _ => Err(Errors::RecordNotFound),
+        }
+    }
+}
+
+/// 分块存储的 value 的清单
+///
+///  +--------------+--------------+---------------------------------------+
+///  |  total size  | chunk count  |  (file id, offset, chunk size) * count |
+///  +--------------+--------------+---------------------------------------+
+///  所有字段均为 varint 编码
+#[derive(Default, Debug, PartialEq, Eq)]
+pub(crate) struct ValueManifest {
+    pub(crate) total_size: u64,
+    pub(crate) chunks: Vec<(LogRecordPos, u64)>,
+}
+
+impl ValueManifest {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        encode_varint(self.total_size, &mut buf);
+        encode_varint(self.chunks.len() as u64, &mut buf);
+        for (pos, size) in self.chunks.iter() {
+            encode_varint(pos.file_id as u64, &mut buf);
+            encode_varint(pos.offset, &mut buf);
+            encode_varint(*size, &mut buf);
+        }
+        buf.to_vec()
+    }
+
+    pub(crate) fn decode(mut buf: &[u8]) -> Result<Self> {
+        let next = |buf: &mut &[u8]| match decode_varint(buf) {
+            Ok(v) => Ok(v),
+            Err(_) => Err(Errors::InvalidValueManifest),
+        };
+
+        let total_size = next(&mut buf)?;
+        let count = next(&mut buf)?;
+        let mut chunks = Vec::new();
+        for _ in 0..count {
+            let file_id = next(&mut buf)? as u32;
+            let offset = next(&mut buf)?;
+            let size = next(&mut buf)?;
+            chunks.push((LogRecordPos { file_id, offset }, size));
+        }
+        if buf.has_remaining() || chunks.iter().map(|c| c.1).sum::<u64>() != total_size {
+            return Err(Errors::InvalidValueManifest);
+        }
+        Ok(ValueManifest { total_size, chunks })
+    }
+}
+
+/// value 读取器，支持随机定位
+pub struct ValueReader<'a> {
+    engine: &'a Engine,
+    key: Vec<u8>,
+    seq: u64, // MANIFEST 记录的序号，合并移动记录时保留原来的序号
+    manifest: ValueManifest,
+    chunk_starts: Vec<u64>,          // 每个分块在 value 中的起始偏移
+    current: Option<(usize, Bytes)>, // 最近读取的分块
+    position: u64,
+}
+
+impl<'a> ValueReader<'a> {
+    pub(crate) fn from_manifest(engine: &'a Engine, logrecord: &LogRecord) -> Result<Self> {
+        let manifest = ValueManifest::decode(&logrecord.value)?;
+        let mut chunk_starts = Vec::with_capacity(manifest.chunks.len());
+        let mut start = 0;
+        for (_, size) in manifest.chunks.iter() {
+            chunk_starts.push(start);
+            start += size;
+        }
+        Ok(ValueReader {
+            engine,
+            key: logrecord.key.clone(),
+            seq: logrecord.seq,
+            manifest,
+            chunk_starts,
+            current: None,
+            position: 0,
+        })
+    }
+
+    fn from_value(engine: &'a Engine, value: Bytes) -> Self {
+        ValueReader {
+            engine,
+            key: Vec::new(),
+            seq: 0,
+            manifest: ValueManifest {
+                total_size: value.len() as u64,
+                chunks: Vec::new(),
+            },
+            chunk_starts: vec![0],
+            current: Some((0, value)),
+            position: 0,
+        }
+    }
+
+    /// value 的总大小
+    pub fn len(&self) -> u64 {
+        self.manifest.total_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.manifest.total_size == 0
+    }
+
+    fn load_chunk(&mut self, index: usize) -> Result<Bytes> {
+        if let Some((i, chunk)) = self.current.as_ref() {
+            if *i == index {
+                return Ok(chunk.clone());
+            }
+        }
+
+        let chunk: Bytes = loop {
+            let err = match self.read_chunk(index) {
+                Ok(value) => break value.into(),
+                Err(e) => e,
+            };
+            // 合并移动了分块并删除了旧文件，重新查找清单，分块的位置没有变化时说明确实读取失败
+            let chunks = self.resolve_manifest(err.clone())?;
+            if chunks == self.manifest.chunks {
+                return Err(err);
+            }
+            self.manifest.chunks = chunks;
+        };
+        self.current = Some((index, chunk.clone()));
+        Ok(chunk)
+    }
+
+    fn read_chunk(&self, index: usize) -> Result<Vec<u8>> {
+        let (pos, size) = self.manifest.chunks[index];
+        // 大 value 的分块不放入缓存
+        let logrecord = self.engine.read_log_record_with(&pos, false)?;
+        if logrecord.record_type != LogRecordType::CHUNK
+            || logrecord.key != self.key
+            || logrecord.value.len() as u64 != size
+        {
+            return Err(Errors::InvalidValueManifest);
+        }
+        Ok(logrecord.value)
+    }
+
+    // 按 key 重新读取 MANIFEST 记录，只接受序号相同的记录，也就是合并移动之后的同一个 value
+    fn resolve_manifest(&self, err: Errors) -> Result<Vec<(LogRecordPos, u64)>> {
+        let pos = match self.engine.index.get(self.key.clone()) {
+            Some(pos) => pos,
+            None => return Err(err),
+        };
+        let logrecord = self.engine.read_log_record_with(&pos, false)?;
+        if logrecord.record_type != LogRecordType::MANIFEST || logrecord.seq != self.seq {
+            return Err(err);
+        }
+        let manifest = ValueManifest::decode(&logrecord.value)?;
+        if manifest.total_size != self.manifest.total_size
+            || manifest.chunks.len() != self.manifest.chunks.len()
+        {
+            return Err(Errors::InvalidValueManifest);
+        }
+        Ok(manifest.chunks)
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // 找到当前位置所在的分块
+        let index = self.chunk_starts.partition_point(|s| *s <= self.position) - 1;
+        let chunk = self.load_chunk(index).map_err(io::Error::other)?;
+
+        let start = (self.position - self.chunk_starts[index]) as usize;
+        let n = buf.len().min(chunk.len() - start);
+        buf[..n].copy_from_slice(&chunk[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ValueReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len().checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+        match new_position {
+            Some(n) => {
+                self.position = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+// 尽量读满缓冲区，返回实际读取的字节数，0 表示已经读到末尾
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                log::error!("failed to read value stream: {}", e);
+                return Err(Errors::FailedToReadValueStream);
+            }
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use super::*;
+    use crate::{
+        merge::MergeOperator,
+        options::{test_options, Options},
+    };
+
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes {
+            let mut value = existing.unwrap_or_default().to_vec();
+            for operand in operands {
+                value.extend_from_slice(operand);
+            }
+            Bytes::from(value)
+        }
+    }
+
+    fn large_value(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_put_stream_and_get_reader() {
+        let opts = Options {
+            file_size: 4 * 1024,
+            ..test_options("bitcask-rs-put-stream")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let value = large_value(20 * 1024 + 123);
+        engine
+            .put_stream(Bytes::from("large"), value.as_slice())
+            .unwrap();
+        engine
+            .put(Bytes::from("small"), Bytes::from("value"))
+            .unwrap();
+
+        let mut reader = engine.get_reader(Bytes::from("large")).unwrap();
+        assert_eq!(reader.len(), value.len() as u64);
+        let mut read_back = Vec::new();
+        reader.read_to_end(&mut read_back).unwrap();
+        assert!(read_back == value);
+
+        // 随机定位读取
+        let mut buf = [0u8; 100];
+        reader.seek(SeekFrom::Start(10000)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &value[10000..10100]);
+        reader.seek(SeekFrom::End(-50)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(&tail[..], &value[value.len() - 50..]);
+        assert!(reader.seek(SeekFrom::Current(-100000)).is_err());
+
+        // get 返回完整的 value
+        assert!(engine.get(Bytes::from("large")).unwrap() == value);
+
+        // 普通 value 也可以通过读取器读取
+        let mut small = String::new();
+        engine
+            .get_reader(Bytes::from("small"))
+            .unwrap()
+            .read_to_string(&mut small)
+            .unwrap();
+        assert_eq!(small, "value");
+
+        // 重启后依然可以读取
+        drop(reader);
+        drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to reopen engine");
+        let mut read_back = Vec::new();
+        engine
+            .get_reader(Bytes::from("large"))
+            .unwrap()
+            .read_to_end(&mut read_back)
+            .unwrap();
+        assert!(read_back == value);
+
+        // 覆盖和删除
+        engine
+            .put(Bytes::from("large"), Bytes::from("tiny"))
+            .unwrap();
+        assert_eq!(
+            engine.get(Bytes::from("large")).unwrap(),
+            Bytes::from("tiny")
+        );
+        engine.delete(Bytes::from("large")).unwrap();
+        assert_eq!(
+            engine.get_reader(Bytes::from("large")).err(),
+            Some(Errors::RecordNotFound)
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_get_reader_across_merge() {
+        let opts = Options {
+            file_size: 4 * 1024,
+            ..test_options("bitcask-rs-get-reader-across-merge")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let value = large_value(20 * 1024);
+        engine
+            .put_stream(Bytes::from("large"), value.as_slice())
+            .unwrap();
+
+        // 合并移动了分块并删除旧文件之后，打开的读取器继续读取同一个 value
+        let mut reader = engine.get_reader(Bytes::from("large")).unwrap();
+        let mut head = vec![0u8; 1000];
+        reader.read_exact(&mut head).unwrap();
+        engine.merge().unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        head.extend_from_slice(&rest);
+        assert!(head == value);
+
+        // value 被覆盖之后旧的分块被回收，读取返回错误而不是读到新的 value
+        let mut reader = engine.get_reader(Bytes::from("large")).unwrap();
+        reader.read_exact(&mut [0u8; 10]).unwrap();
+        engine
+            .put_stream(Bytes::from("large"), large_value(8 * 1024).as_slice())
+            .unwrap();
+        engine.merge().unwrap();
+        reader.seek(SeekFrom::Start(10 * 1024)).unwrap();
+        assert!(reader.read(&mut [0u8; 10]).is_err());
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_put_stream_empty_value() {
+        let opts = Options {
+            file_size: 4 * 1024,
+            ..test_options("bitcask-rs-put-stream-empty")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        engine
+            .put_stream(Bytes::from("empty"), io::empty())
+            .unwrap();
+        let reader = engine.get_reader(Bytes::from("empty")).unwrap();
+        assert!(reader.is_empty());
+        assert!(engine.get(Bytes::from("empty")).unwrap().is_empty());
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_value_manifest_encode() {
+        let manifest = ValueManifest {
+            total_size: 30,
+            chunks: vec![
+                (
+                    LogRecordPos {
+                        file_id: 1,
+                        offset: 0,
+                    },
+                    20,
+                ),
+                (
+                    LogRecordPos {
+                        file_id: 2,
+                        offset: 300,
+                    },
+                    10,
+                ),
+            ],
+        };
+        let decoded = ValueManifest::decode(&manifest.encode()).unwrap();
+        assert_eq!(decoded, manifest);
+
+        let mut corrupted = manifest.encode();
+        corrupted.push(0);
+        assert_eq!(
+            ValueManifest::decode(&corrupted).err(),
+            Some(Errors::InvalidValueManifest)
+        );
+    }
+
+    #[test]
+    fn test_get_reader_merge_operands() {
+        let opts = Options {
+            file_size: 4 * 1024,
+            ..test_options("bitcask-rs-get-reader-merge")
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.set_merge_operator(Arc::new(Append));
+
+        // 分块存储的 value 之后追加合并操作数
+        let value = large_value(10 * 1024);
+        engine
+            .put_stream(Bytes::from("large"), value.as_slice())
+            .unwrap();
+        engine
+            .merge_value(Bytes::from("large"), Bytes::from("-tail"))
+            .unwrap();
+        engine
+            .merge_value(Bytes::from("new"), Bytes::from("operand"))
+            .unwrap();
+
+        let mut expected = value.clone();
+        expected.extend_from_slice(b"-tail");
+        let mut reader = engine.get_reader(Bytes::from("large")).unwrap();
+        assert_eq!(reader.len(), expected.len() as u64);
+        let mut read_back = Vec::new();
+        reader.read_to_end(&mut read_back).unwrap();
+        assert!(read_back == expected);
+
+        let mut read_back = Vec::new();
+        engine
+            .get_reader(Bytes::from("new"))
+            .unwrap()
+            .read_to_end(&mut read_back)
+            .unwrap();
+        assert_eq!(read_back, b"operand");
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}
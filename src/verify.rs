@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    data::{
+        cipher::{self, RecordCipher},
+        log_record::{LogRecordPos, LogRecordType},
+    },
+    db::load_data_files,
+    errors::{Errors, Result},
+    family::{decode_family_options, family_value_expire_at, is_expired},
+    options::{FamilyOptions, Options},
+    stream::ValueManifest,
+};
+
+/// 一条损坏的记录
+#[derive(Debug, PartialEq, Eq)]
+pub struct CorruptRecord {
+    pub file_id: u32,
+    pub offset: u64,
+    pub error: Errors,
+}
+
+/// 数据目录的检查结果
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub data_files: usize,                   // 检查的数据文件数量
+    pub total_records: u64,                  // 完整可读的记录数量
+    pub live_records: u64,                   // 仍然有效的记录数量
+    pub dead_records: u64,                   // 已经被覆盖或删除的记录数量
+    pub corrupt_records: Vec<CorruptRecord>, // 损坏的记录
+}
+
+impl VerifyReport {
+    /// 是否没有发现任何损坏的记录
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_records.is_empty()
+    }
+}
+
+/// 离线检查数据目录的完整性
+///
+/// 依次遍历每个数据文件，校验每条记录的 header 和 crc，加密的记录同时校验能否解密。
+/// crc 损坏的记录根据 header 中的长度跳过后继续检查；header 损坏时无法确定下一条
+/// 记录的位置，该文件剩余的部分不再检查。
+/// 列族的创建记录在列族存在期间有效，删除记录、被删除列族中的记录和 TTL 列族中已经过期的记录都算作失效。
+/// 检查期间不能有引擎实例打开同一个目录。
+pub fn verify(opts: &Options) -> Result<VerifyReport> {
+    let cipher = opts.encryption_key.as_ref().map(RecordCipher::new);
+    let data_files = load_data_files(opts.dir_path.clone(), opts.io_type)?;
+
+    let mut report = VerifyReport {
+        data_files: data_files.len(),
+        ..Default::default()
+    };
+    // key 最新的记录位置，None 表示已经被删除
+    let mut latest: HashMap<(u32, Vec<u8>), Option<LogRecordPos>> = HashMap::new();
+    let mut manifests: HashMap<LogRecordPos, Vec<u8>> = HashMap::new();
+    // key 最新的 value 之后还没有合并的操作数
+    let mut operands: HashMap<(u32, Vec<u8>), Vec<LogRecordPos>> = HashMap::new();
+    // 仍然存在的列族最新的创建记录
+    let mut families: HashMap<u32, (LogRecordPos, FamilyOptions)> = HashMap::new();
+
+    for data_file in data_files.iter() {
+        let file_id = data_file.get_file_id();
+        let file_size = data_file.file_size()?;
+        let mut offset = 0;
+        while offset < file_size {
+            let header = match data_file.read_log_record_header(offset) {
+                Ok(header) => header,
+                Err(Errors::ReadDataFileEOF) => break,
+                Err(error) => {
+                    report.corrupt_records.push(CorruptRecord {
+                        file_id,
+                        offset,
+                        error,
+                    });
+                    break;
+                }
+            };
+            let record_size = header.record_size();
+
+            let corrupt = match data_file.read_log_record(offset) {
+                Ok(read) => match cipher::decode_log_record(cipher.as_ref(), read.record) {
+                    Ok(record) => {
+                        report.total_records += 1;
+                        let pos = LogRecordPos { file_id, offset };
+                        // 已经被删除的列族中的记录全部失效
+                        let dropped = record.cf != 0 && !families.contains_key(&record.cf);
+                        match record.record_type {
+                            LogRecordType::FAMILY => {
+                                let options = decode_family_options(&record.value);
+                                families.insert(record.cf, (pos, options));
+                            }
+                            LogRecordType::DROPFAMILY => {
+                                families.remove(&record.cf);
+                                latest.retain(|(cf, _), _| *cf != record.cf);
+                                operands.retain(|(cf, _), _| *cf != record.cf);
+                            }
+                            _ if dropped => {}
+                            LogRecordType::NORMAL => {
+                                let expired = match families.get(&record.cf) {
+                                    Some((_, options)) => matches!(
+                                        family_value_expire_at(options, &record.value),
+                                        Ok(expire_at) if is_expired(expire_at)
+                                    ),
+                                    None => false,
+                                };
+                                operands.remove(&(record.cf, record.key.clone()));
+                                latest.insert((record.cf, record.key), (!expired).then_some(pos));
+                            }
+                            LogRecordType::MANIFEST => {
+                                operands.remove(&(record.cf, record.key.clone()));
+                                latest.insert((record.cf, record.key), Some(pos));
+                                manifests.insert(pos, record.value);
+                            }
+                            LogRecordType::DELETE => {
+                                operands.remove(&(record.cf, record.key.clone()));
+                                latest.insert((record.cf, record.key), None);
+                            }
+                            LogRecordType::MERGE => {
+                                operands
+                                    .entry((record.cf, record.key))
+                                    .or_default()
+                                    .push(pos);
+                            }
+                            _ => {}
+                        }
+                        None
+                    }
+                    Err(error) => Some(error),
+                },
+                Err(error) => Some(error),
+            };
+            if let Some(error) = corrupt {
+                report.corrupt_records.push(CorruptRecord {
+                    file_id,
+                    offset,
+                    error,
+                });
+            }
+
+            offset += record_size;
+        }
+    }
+
+    // 统计有效记录，被有效 manifest 引用的分块也是有效的
+    let mut live: HashSet<LogRecordPos> = operands.into_values().flatten().collect();
+    live.extend(families.into_values().map(|(pos, _)| pos));
+    for pos in latest.values().flatten() {
+        live.insert(*pos);
+        if let Some(encoded) = manifests.get(pos) {
+            match ValueManifest::decode(encoded) {
+                Ok(manifest) => live.extend(manifest.chunks.iter().map(|c| c.0)),
+                Err(error) => report.corrupt_records.push(CorruptRecord {
+                    file_id: pos.file_id,
+                    offset: pos.offset,
+                    error,
+                }),
+            }
+        }
+    }
+    report.live_records = live.len() as u64;
+    report.dead_records = report.total_records.saturating_sub(report.live_records);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::{Seek, SeekFrom, Write},
+    };
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        db::Engine,
+        options::{test_options, FamilyOptions},
+    };
+
+    #[test]
+    fn test_verify_counts_live_and_dead_records() {
+        let opts = test_options("bitcask-rs-verify-counts");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        engine.put(Bytes::from("a"), Bytes::from("2")).unwrap();
+        engine.put(Bytes::from("b"), Bytes::from("1")).unwrap();
+        engine.delete(Bytes::from("b")).unwrap();
+        engine.put(Bytes::from("c"), Bytes::from("1")).unwrap();
+        drop(engine);
+
+        let report = verify(&opts).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.data_files, 1);
+        assert_eq!(report.total_records, 5);
+        assert_eq!(report.live_records, 2);
+        assert_eq!(report.dead_records, 3);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_counts_column_families() {
+        let opts = test_options("bitcask-rs-verify-families");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine.create_cf("users", FamilyOptions::default()).unwrap();
+        users.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        users.put(Bytes::from("b"), Bytes::from("1")).unwrap();
+        engine.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        let sessions = engine
+            .create_cf("sessions", FamilyOptions::default())
+            .unwrap();
+        sessions.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+        sessions.put(Bytes::from("b"), Bytes::from("1")).unwrap();
+        drop(sessions);
+        drop(users);
+        engine.drop_cf("sessions").unwrap();
+        drop(engine);
+
+        // 被删除的列族的创建记录、数据和删除记录都已经失效
+        let report = verify(&opts).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.total_records, 8);
+        assert_eq!(report.live_records, 4);
+        assert_eq!(report.dead_records, 4);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_reports_corrupt_record() {
+        let opts = test_options("bitcask-rs-verify-corrupt");
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine
+            .put(Bytes::from("key-1"), Bytes::from("value-1"))
+            .unwrap();
+        engine
+            .put(Bytes::from("key-2"), Bytes::from("value-2"))
+            .unwrap();
+        engine
+            .put(Bytes::from("key-3"), Bytes::from("value-3"))
+            .unwrap();
+        drop(engine);
+
+        // 每条记录 1 + 1 + 1 + 1 + 5 + 7 + 4 = 20 字节，破坏第二条记录的 value
+        let path = opts.dir_path.join("000000000.data");
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(20 + 10)).unwrap();
+        file.write_all(b"X").unwrap();
+        drop(file);
+
+        let report = verify(&opts).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.corrupt_records,
+            vec![CorruptRecord {
+                file_id: 0,
+                offset: 20,
+                error: Errors::InvalidLogRecordCrc,
+            }]
+        );
+        // 损坏记录之后的记录依然会被检查
+        assert_eq!(report.total_records, 2);
+        assert_eq!(report.live_records, 2);
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_encrypted_with_wrong_key() {
+        let mut opts = test_options("bitcask-rs-verify-encrypted");
+        opts.encryption_key = Some([1u8; 32]);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        engine
+            .put(Bytes::from("key"), Bytes::from("value"))
+            .unwrap();
+        drop(engine);
+
+        assert!(verify(&opts).unwrap().is_ok());
+
+        let mut wrong_opts = opts.clone();
+        wrong_opts.encryption_key = Some([2u8; 32]);
+        let report = verify(&wrong_opts).unwrap();
+        assert_eq!(report.corrupt_records.len(), 1);
+        assert_eq!(
+            report.corrupt_records[0].error,
+            Errors::FailedToDecryptLogRecord
+        );
+
+        fs::remove_dir_all(opts.dir_path).unwrap();
+    }
+}